@@ -1,86 +1,196 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// The currency of records that do not name one, and of balances stored before
+/// accounts held more than one currency
+pub const DEFAULT_CURRENCY: &str = "USD";
+
 /// The account as described in the problem  using rust_decimal to avoid rounding errors
 /// and to also avoid overflow since we probably won't have octillion dollar balances
 /// in the test case
 #[derive(Debug, Clone)]
 pub struct Account {
   pub client: u16,
+  /// Funds per currency code. A currency shows up the first time it is used
+  pub balances: BTreeMap<String, Balance>,
+  /// A chargeback in any currency locks the whole account
+  pub locked: bool,
+  /// Timestamp of the last applied record that carried one, used to keep a client's records in order
+  pub last_timestamp: Option<DateTime<Utc>>,
+}
+
+/// The available and held funds of an account in one currency
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Balance {
   pub available: Decimal,
   pub held: Decimal,
-  pub locked: bool,
+  /// Interest has been credited up to here, None before the first accrual
+  pub accrued_to: Option<DateTime<Utc>>,
+}
+
+impl Balance {
+  pub fn total(&self) -> Decimal {
+    self.available + self.held
+  }
 }
 
 impl Account {
   pub fn new(client: u16) -> Self {
-    Self { client, available: Decimal::ZERO, held: Decimal::ZERO, locked: false }
+    Self { client, balances: BTreeMap::new(), locked: false, last_timestamp: None }
   }
 
-  pub fn total(&self) -> Decimal {
-    self.available + self.held
+  /// The funds in `currency`, zero if it was never used
+  pub fn balance(&self, currency: &str) -> Balance {
+    self.balances.get(currency).copied().unwrap_or_default()
+  }
+
+  fn balance_mut(&mut self, currency: &str) -> &mut Balance {
+    self.balances.entry(currency.to_string()).or_default()
   }
 
-  pub fn deposit(&mut self, amount: Decimal) -> Result<(), AccountError> {
+  pub fn deposit(&mut self, currency: &str, amount: Decimal) -> Result<(), AccountError> {
     if self.locked {
       return Err(AccountError::AccountLocked);
     }
     if amount < Decimal::ZERO {
       return Err(AccountError::NegativeAmount);
     }
-    self.available += amount;
+    self.balance_mut(currency).available += amount;
     Ok(())
   }
-  pub fn withdraw(&mut self, amount: Decimal) -> Result<(), AccountError> {
+  /// `credit` is how far below zero `available` may go, zero without a credit line
+  pub fn withdraw(
+    &mut self,
+    currency: &str,
+    amount: Decimal,
+    credit: Decimal,
+  ) -> Result<(), AccountError> {
     if self.locked {
       return Err(AccountError::AccountLocked);
     }
     if amount < Decimal::ZERO {
       return Err(AccountError::NegativeAmount);
     }
-    if self.available < amount {
-      return Err(AccountError::InsufficientFunds { requested: amount, available: self.available });
+    self.check_funds(currency, amount, credit)?;
+    self.balance_mut(currency).available -= amount;
+    Ok(())
+  }
+
+  /// A dispute may draw on the credit line like a withdrawal
+  pub fn hold(
+    &mut self,
+    currency: &str,
+    amount: Decimal,
+    credit: Decimal,
+  ) -> Result<(), AccountError> {
+    if amount < Decimal::ZERO {
+      return Err(AccountError::NegativeAmount);
     }
-    self.available -= amount;
+    self.check_funds(currency, amount, credit)?;
+    let balance = self.balance_mut(currency);
+    balance.available -= amount;
+    balance.held += amount;
     Ok(())
   }
 
-  pub fn hold(&mut self, amount: Decimal) -> Result<(), AccountError> {
+  /// Debits a fee posting. Unlike a withdrawal it is taken from a locked account too.
+  /// `credit` is how far below zero it may take `available`, None for no bound
+  pub fn charge_fee(
+    &mut self,
+    currency: &str,
+    amount: Decimal,
+    credit: Option<Decimal>,
+  ) -> Result<(), AccountError> {
     if amount < Decimal::ZERO {
       return Err(AccountError::NegativeAmount);
     }
-    if self.available < amount {
-      return Err(AccountError::InsufficientFunds { requested: amount, available: self.available });
+    if let Some(credit) = credit {
+      self.check_funds(currency, amount, credit)?;
     }
-    self.available -= amount;
-    self.held += amount;
+    self.balance_mut(currency).available -= amount;
     Ok(())
   }
 
-  pub fn release(&mut self, amount: Decimal) -> Result<(), AccountError> {
+  /// Credits interest earned up to `accrued_to`. Whether a locked account earns any
+  /// is up to the caller
+  pub fn credit_interest(
+    &mut self,
+    currency: &str,
+    amount: Decimal,
+    accrued_to: DateTime<Utc>,
+  ) -> Result<(), AccountError> {
     if amount < Decimal::ZERO {
       return Err(AccountError::NegativeAmount);
     }
-    if self.held < amount {
-      return Err(AccountError::InsufficientHeldFunds { requested: amount, held: self.held });
+    let balance = self.balance_mut(currency);
+    balance.available += amount;
+    balance.accrued_to = Some(accrued_to);
+    Ok(())
+  }
+
+  fn check_funds(
+    &self,
+    currency: &str,
+    amount: Decimal,
+    credit: Decimal,
+  ) -> Result<(), AccountError> {
+    let available = self.balance(currency).available;
+    if available + credit >= amount {
+      Ok(())
+    } else if credit.is_zero() {
+      Err(AccountError::InsufficientFunds { requested: amount, available })
+    } else {
+      Err(AccountError::CreditLimitExceeded { requested: amount, available, credit })
     }
-    self.held -= amount;
-    self.available += amount;
+  }
+
+  pub fn release(&mut self, currency: &str, amount: Decimal) -> Result<(), AccountError> {
+    if amount < Decimal::ZERO {
+      return Err(AccountError::NegativeAmount);
+    }
+    let held = self.balance(currency).held;
+    if held < amount {
+      return Err(AccountError::InsufficientHeldFunds { requested: amount, held });
+    }
+    let balance = self.balance_mut(currency);
+    balance.held -= amount;
+    balance.available += amount;
     Ok(())
   }
 
-  pub fn chargeback(&mut self, amount: Decimal) -> Result<(), AccountError> {
+  pub fn chargeback(&mut self, currency: &str, amount: Decimal) -> Result<(), AccountError> {
     if amount < Decimal::ZERO {
       return Err(AccountError::NegativeAmount);
     }
-    if self.held < amount {
-      return Err(AccountError::InsufficientHeldFunds { requested: amount, held: self.held });
+    let held = self.balance(currency).held;
+    if held < amount {
+      return Err(AccountError::InsufficientHeldFunds { requested: amount, held });
     }
-    self.held -= amount;
+    self.balance_mut(currency).held -= amount;
     self.locked = true;
     Ok(())
   }
+
+  /// Gives back a charged back amount, the caller decides whether the lock goes too
+  pub fn represent(
+    &mut self,
+    currency: &str,
+    amount: Decimal,
+    unlock: bool,
+  ) -> Result<(), AccountError> {
+    if amount < Decimal::ZERO {
+      return Err(AccountError::NegativeAmount);
+    }
+    self.balance_mut(currency).available += amount;
+    if unlock {
+      self.locked = false;
+    }
+    Ok(())
+  }
 }
 
 /// THIS IS AI generated after initial testing
@@ -93,29 +203,60 @@ pub enum AccountError {
   NegativeAmount,
   #[error("insufficient funds: requested {requested}, available {available}")]
   InsufficientFunds { requested: Decimal, available: Decimal },
+  #[error(
+    "credit limit exceeded: requested {requested}, available {available}, credit line {credit}"
+  )]
+  CreditLimitExceeded { requested: Decimal, available: Decimal, credit: Decimal },
   #[error("insufficient held funds: requested {requested}, held {held}")]
   InsufficientHeldFunds { requested: Decimal, held: Decimal },
 }
 
+impl AccountError {
+  /// The variant name, for counting errors by kind
+  pub fn code(&self) -> &'static str {
+    match self {
+      AccountError::AccountLocked => "AccountLocked",
+      AccountError::NegativeAmount => "NegativeAmount",
+      AccountError::InsufficientFunds { .. } => "InsufficientFunds",
+      AccountError::CreditLimitExceeded { .. } => "CreditLimitExceeded",
+      AccountError::InsufficientHeldFunds { .. } => "InsufficientHeldFunds",
+    }
+  }
+}
+
 /// THIS IS HUMAN CREATED code
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccountOutput {
   pub client: u16,
+  /// Single currency output has no currency column
+  #[serde(default = "default_currency")]
+  pub currency: String,
   pub available: Decimal,
   pub held: Decimal,
   pub total: Decimal,
   pub locked: bool,
 }
 
-impl From<&Account> for AccountOutput {
-  fn from(account: &Account) -> Self {
-    Self {
+fn default_currency() -> String {
+  DEFAULT_CURRENCY.to_string()
+}
+
+impl AccountOutput {
+  /// One row per currency the account holds. An account that never held anything
+  /// still gets a zero row in the default currency
+  pub fn rows(account: &Account) -> Vec<Self> {
+    let row = |currency: &str, balance: Balance| Self {
       client: account.client,
-      available: account.available,
-      held: account.held,
-      total: account.total(),
+      currency: currency.to_string(),
+      available: balance.available,
+      held: balance.held,
+      total: balance.total(),
       locked: account.locked,
+    };
+    if account.balances.is_empty() {
+      return vec![row(DEFAULT_CURRENCY, Balance::default())];
     }
+    account.balances.iter().map(|(currency, balance)| row(currency, *balance)).collect()
   }
 }
 
@@ -125,66 +266,71 @@ impl From<&Account> for AccountOutput {
 mod tests {
   use super::*;
 
+  const USD: &str = DEFAULT_CURRENCY;
+
   #[test]
   fn test_deposit() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
-    assert_eq!(account.total(), Decimal::new(100, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).total(), Decimal::new(100, 0));
   }
 
   #[test]
   fn test_withdraw_success() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.withdraw(Decimal::new(50, 0)).unwrap();
-    assert_eq!(account.available, Decimal::new(50, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.withdraw(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(50, 0));
   }
 
   #[test]
   fn test_withdraw_insufficient_funds() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(50, 0)).unwrap();
-    let result = account.withdraw(Decimal::new(100, 0));
+    account.deposit(USD, Decimal::new(50, 0)).unwrap();
+    let result = account.withdraw(USD, Decimal::new(100, 0), Decimal::ZERO);
     assert!(matches!(result, Err(AccountError::InsufficientFunds { .. })));
   }
 
   #[test]
   fn test_hold_and_release() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(30, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(30, 0), Decimal::ZERO).unwrap();
 
-    assert_eq!(account.available, Decimal::new(70, 0));
-    assert_eq!(account.held, Decimal::new(30, 0));
-    assert_eq!(account.total(), Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).available, Decimal::new(70, 0));
+    assert_eq!(account.balance(USD).held, Decimal::new(30, 0));
+    assert_eq!(account.balance(USD).total(), Decimal::new(100, 0));
 
-    account.release(Decimal::new(30, 0)).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
-    assert_eq!(account.held, Decimal::ZERO);
+    account.release(USD, Decimal::new(30, 0)).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
   #[test]
   fn test_chargeback_locks_account() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(30, 0)).unwrap();
-    account.chargeback(Decimal::new(30, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(30, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(30, 0)).unwrap();
 
     assert!(account.locked);
-    assert_eq!(account.held, Decimal::ZERO);
-    assert_eq!(account.total(), Decimal::new(70, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::new(70, 0));
   }
 
   #[test]
   fn test_locked_account_rejects_operations() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(30, 0)).unwrap();
-    account.chargeback(Decimal::new(30, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(30, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(30, 0)).unwrap();
 
-    assert!(matches!(account.deposit(Decimal::new(10, 0)), Err(AccountError::AccountLocked)));
-    assert!(matches!(account.withdraw(Decimal::new(10, 0)), Err(AccountError::AccountLocked)));
+    assert!(matches!(account.deposit(USD, Decimal::new(10, 0)), Err(AccountError::AccountLocked)));
+    assert!(matches!(
+      account.withdraw(USD, Decimal::new(10, 0), Decimal::ZERO),
+      Err(AccountError::AccountLocked)
+    ));
   }
 
   // =========================================================================
@@ -194,154 +340,154 @@ mod tests {
   #[test]
   fn test_zero_amount_deposit() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::ZERO).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.total(), Decimal::ZERO);
+    account.deposit(USD, Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::ZERO);
   }
 
   #[test]
   fn test_zero_amount_withdrawal() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.withdraw(Decimal::ZERO).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.withdraw(USD, Decimal::ZERO, Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
   }
 
   #[test]
   fn test_zero_amount_hold() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::ZERO).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
-    assert_eq!(account.held, Decimal::ZERO);
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::ZERO, Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
   #[test]
   fn test_zero_amount_release() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    account.release(Decimal::ZERO).unwrap();
-    assert_eq!(account.available, Decimal::new(50, 0));
-    assert_eq!(account.held, Decimal::new(50, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    account.release(USD, Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(50, 0));
+    assert_eq!(account.balance(USD).held, Decimal::new(50, 0));
   }
 
   #[test]
   fn test_zero_amount_chargeback() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    account.chargeback(Decimal::ZERO).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::ZERO).unwrap();
     // Account gets locked even with zero chargeback
     assert!(account.locked);
-    assert_eq!(account.held, Decimal::new(50, 0));
+    assert_eq!(account.balance(USD).held, Decimal::new(50, 0));
   }
 
   #[test]
   fn test_negative_deposit_rejected() {
     let mut account = Account::new(1);
-    let result = account.deposit(Decimal::new(-100, 0));
+    let result = account.deposit(USD, Decimal::new(-100, 0));
     assert!(matches!(result, Err(AccountError::NegativeAmount)));
   }
 
   #[test]
   fn test_negative_withdrawal_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    let result = account.withdraw(Decimal::new(-50, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    let result = account.withdraw(USD, Decimal::new(-50, 0), Decimal::ZERO);
     assert!(matches!(result, Err(AccountError::NegativeAmount)));
   }
 
   #[test]
   fn test_negative_hold_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    let result = account.hold(Decimal::new(-50, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    let result = account.hold(USD, Decimal::new(-50, 0), Decimal::ZERO);
     assert!(matches!(result, Err(AccountError::NegativeAmount)));
   }
 
   #[test]
   fn test_negative_release_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    let result = account.release(Decimal::new(-25, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    let result = account.release(USD, Decimal::new(-25, 0));
     assert!(matches!(result, Err(AccountError::NegativeAmount)));
   }
 
   #[test]
   fn test_negative_chargeback_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    let result = account.chargeback(Decimal::new(-25, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    let result = account.chargeback(USD, Decimal::new(-25, 0));
     assert!(matches!(result, Err(AccountError::NegativeAmount)));
   }
 
   #[test]
   fn test_withdraw_exact_balance() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.withdraw(Decimal::new(100, 0)).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.total(), Decimal::ZERO);
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.withdraw(USD, Decimal::new(100, 0), Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::ZERO);
   }
 
   #[test]
   fn test_hold_exact_available() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(100, 0)).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.held, Decimal::new(100, 0));
-    assert_eq!(account.total(), Decimal::new(100, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(100, 0), Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).total(), Decimal::new(100, 0));
   }
 
   #[test]
   fn test_release_exact_held() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(100, 0)).unwrap();
-    account.release(Decimal::new(100, 0)).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
-    assert_eq!(account.held, Decimal::ZERO);
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(100, 0), Decimal::ZERO).unwrap();
+    account.release(USD, Decimal::new(100, 0)).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
   #[test]
   fn test_chargeback_exact_held() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(100, 0)).unwrap();
-    account.chargeback(Decimal::new(100, 0)).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.held, Decimal::ZERO);
-    assert_eq!(account.total(), Decimal::ZERO);
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(100, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(100, 0)).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::ZERO);
     assert!(account.locked);
   }
 
   #[test]
   fn test_hold_more_than_available_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    let result = account.hold(Decimal::new(150, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    let result = account.hold(USD, Decimal::new(150, 0), Decimal::ZERO);
     assert!(matches!(result, Err(AccountError::InsufficientFunds { .. })));
   }
 
   #[test]
   fn test_release_more_than_held_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    let result = account.release(Decimal::new(100, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    let result = account.release(USD, Decimal::new(100, 0));
     assert!(matches!(result, Err(AccountError::InsufficientHeldFunds { .. })));
   }
 
   #[test]
   fn test_chargeback_more_than_held_rejected() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    let result = account.chargeback(Decimal::new(100, 0));
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    let result = account.chargeback(USD, Decimal::new(100, 0));
     assert!(matches!(result, Err(AccountError::InsufficientHeldFunds { .. })));
   }
 
@@ -350,9 +496,9 @@ mod tests {
     let mut account = Account::new(1);
     // 0.0001
     let small = Decimal::new(1, 4);
-    account.deposit(small).unwrap();
-    account.deposit(small).unwrap();
-    assert_eq!(account.available, Decimal::new(2, 4)); // 0.0002
+    account.deposit(USD, small).unwrap();
+    account.deposit(USD, small).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(2, 4)); // 0.0002
   }
 
   #[test]
@@ -361,83 +507,95 @@ mod tests {
     let small = Decimal::new(1, 4); // 0.0001
 
     for _ in 0..10 {
-      account.deposit(small).unwrap();
+      account.deposit(USD, small).unwrap();
     }
 
-    assert_eq!(account.available, Decimal::new(10, 4)); // 0.0010
+    assert_eq!(account.balance(USD).available, Decimal::new(10, 4)); // 0.0010
   }
 
   #[test]
   fn test_total_invariant() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
 
     // available + held should always equal total
-    assert_eq!(account.available + account.held, account.total());
+    assert_eq!(
+      account.balance(USD).available + account.balance(USD).held,
+      account.balance(USD).total()
+    );
 
-    account.hold(Decimal::new(30, 0)).unwrap();
-    assert_eq!(account.available + account.held, account.total());
+    account.hold(USD, Decimal::new(30, 0), Decimal::ZERO).unwrap();
+    assert_eq!(
+      account.balance(USD).available + account.balance(USD).held,
+      account.balance(USD).total()
+    );
 
-    account.release(Decimal::new(10, 0)).unwrap();
-    assert_eq!(account.available + account.held, account.total());
+    account.release(USD, Decimal::new(10, 0)).unwrap();
+    assert_eq!(
+      account.balance(USD).available + account.balance(USD).held,
+      account.balance(USD).total()
+    );
 
-    account.chargeback(Decimal::new(20, 0)).unwrap();
-    assert_eq!(account.available + account.held, account.total());
+    account.chargeback(USD, Decimal::new(20, 0)).unwrap();
+    assert_eq!(
+      account.balance(USD).available + account.balance(USD).held,
+      account.balance(USD).total()
+    );
   }
 
   #[test]
   fn test_locked_account_allows_hold() {
     // Locked accounts should still allow hold operations (for disputes)
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    account.chargeback(Decimal::new(50, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(50, 0)).unwrap();
 
     // Account is locked, but hold should still work
-    account.hold(Decimal::new(25, 0)).unwrap();
-    assert_eq!(account.held, Decimal::new(25, 0));
+    account.hold(USD, Decimal::new(25, 0), Decimal::ZERO).unwrap();
+    assert_eq!(account.balance(USD).held, Decimal::new(25, 0));
   }
 
   #[test]
   fn test_locked_account_allows_release() {
     // Locked accounts should still allow release operations (for resolves)
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    account.chargeback(Decimal::new(25, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(25, 0)).unwrap();
 
     // Account is locked, but release should still work
-    account.release(Decimal::new(25, 0)).unwrap();
-    assert_eq!(account.available, Decimal::new(75, 0));
-    assert_eq!(account.held, Decimal::ZERO);
+    account.release(USD, Decimal::new(25, 0)).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(75, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
   #[test]
   fn test_locked_account_allows_chargeback() {
     // Locked accounts should still allow chargeback operations
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(50, 0)).unwrap();
-    account.chargeback(Decimal::new(25, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(25, 0)).unwrap();
 
     // Account is locked, but another chargeback should still work
-    account.chargeback(Decimal::new(25, 0)).unwrap();
-    assert_eq!(account.held, Decimal::ZERO);
+    account.chargeback(USD, Decimal::new(25, 0)).unwrap();
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
   #[test]
   fn test_multiple_hold_release_cycles() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
 
     for _ in 0..5 {
-      account.hold(Decimal::new(50, 0)).unwrap();
-      assert_eq!(account.held, Decimal::new(50, 0));
-      assert_eq!(account.available, Decimal::new(50, 0));
+      account.hold(USD, Decimal::new(50, 0), Decimal::ZERO).unwrap();
+      assert_eq!(account.balance(USD).held, Decimal::new(50, 0));
+      assert_eq!(account.balance(USD).available, Decimal::new(50, 0));
 
-      account.release(Decimal::new(50, 0)).unwrap();
-      assert_eq!(account.held, Decimal::ZERO);
-      assert_eq!(account.available, Decimal::new(100, 0));
+      account.release(USD, Decimal::new(50, 0)).unwrap();
+      assert_eq!(account.balance(USD).held, Decimal::ZERO);
+      assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
     }
   }
 
@@ -456,10 +614,10 @@ mod tests {
   #[test]
   fn test_account_output_conversion() {
     let mut account = Account::new(42);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(30, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(30, 0), Decimal::ZERO).unwrap();
 
-    let output = AccountOutput::from(&account);
+    let output = AccountOutput::rows(&account).remove(0);
     assert_eq!(output.client, 42);
     assert_eq!(output.available, Decimal::new(70, 0));
     assert_eq!(output.held, Decimal::new(30, 0));
@@ -470,12 +628,136 @@ mod tests {
   #[test]
   fn test_account_output_locked() {
     let mut account = Account::new(1);
-    account.deposit(Decimal::new(100, 0)).unwrap();
-    account.hold(Decimal::new(100, 0)).unwrap();
-    account.chargeback(Decimal::new(100, 0)).unwrap();
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.hold(USD, Decimal::new(100, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(100, 0)).unwrap();
 
-    let output = AccountOutput::from(&account);
+    let output = AccountOutput::rows(&account).remove(0);
     assert!(output.locked);
     assert_eq!(output.total, Decimal::ZERO);
   }
+
+  // =========================================================================
+  // CURRENCY TESTS
+  // =========================================================================
+
+  #[test]
+  fn test_currencies_kept_apart() {
+    let mut account = Account::new(1);
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.deposit("EUR", Decimal::new(40, 0)).unwrap();
+    account.hold("EUR", Decimal::new(10, 0), Decimal::ZERO).unwrap();
+
+    assert_eq!(
+      account.balance(USD),
+      Balance { available: Decimal::new(100, 0), held: Decimal::ZERO, accrued_to: None }
+    );
+    assert_eq!(account.balance("EUR").available, Decimal::new(30, 0));
+    assert_eq!(account.balance("EUR").held, Decimal::new(10, 0));
+    assert_eq!(account.balance("GBP"), Balance::default());
+  }
+
+  #[test]
+  fn test_funds_in_other_currency_do_not_count() {
+    let mut account = Account::new(1);
+    account.deposit("EUR", Decimal::new(100, 0)).unwrap();
+
+    let result = account.withdraw(USD, Decimal::new(1, 0), Decimal::ZERO);
+    assert!(matches!(result, Err(AccountError::InsufficientFunds { .. })));
+    // A failed withdrawal does not add an empty balance
+    assert!(!account.balances.contains_key(USD));
+  }
+
+  #[test]
+  fn test_chargeback_in_one_currency_locks_all() {
+    let mut account = Account::new(1);
+    account.deposit(USD, Decimal::new(100, 0)).unwrap();
+    account.deposit("EUR", Decimal::new(50, 0)).unwrap();
+    account.hold("EUR", Decimal::new(50, 0), Decimal::ZERO).unwrap();
+    account.chargeback("EUR", Decimal::new(50, 0)).unwrap();
+
+    assert!(matches!(
+      account.withdraw(USD, Decimal::ONE, Decimal::ZERO),
+      Err(AccountError::AccountLocked)
+    ));
+  }
+
+  #[test]
+  fn test_withdraw_on_credit() {
+    let mut account = Account::new(1);
+    let credit = Decimal::new(100, 0);
+    account.deposit(USD, Decimal::new(50, 0)).unwrap();
+    account.withdraw(USD, Decimal::new(150, 0), credit).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(-100, 0));
+
+    let result = account.withdraw(USD, Decimal::new(1, 2), credit);
+    assert_eq!(
+      result,
+      Err(AccountError::CreditLimitExceeded {
+        requested: Decimal::new(1, 2),
+        available: Decimal::new(-100, 0),
+        credit,
+      })
+    );
+  }
+
+  #[test]
+  fn test_hold_on_credit() {
+    let mut account = Account::new(1);
+    account.deposit(USD, Decimal::new(50, 0)).unwrap();
+    account.withdraw(USD, Decimal::new(40, 0), Decimal::ZERO).unwrap();
+    account.hold(USD, Decimal::new(50, 0), Decimal::new(40, 0)).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(-40, 0));
+    assert_eq!(account.balance(USD).held, Decimal::new(50, 0));
+
+    // The chargeback takes the held funds, the overdraft stays
+    account.chargeback(USD, Decimal::new(50, 0)).unwrap();
+    assert_eq!(account.balance(USD).total(), Decimal::new(-40, 0));
+  }
+
+  #[test]
+  fn test_charge_fee() {
+    let mut account = Account::new(1);
+    account.deposit(USD, Decimal::new(10, 0)).unwrap();
+    let result = account.charge_fee(USD, Decimal::new(11, 0), Some(Decimal::ZERO));
+    assert!(matches!(result, Err(AccountError::InsufficientFunds { .. })));
+
+    account.hold(USD, Decimal::new(10, 0), Decimal::ZERO).unwrap();
+    account.chargeback(USD, Decimal::new(10, 0)).unwrap();
+    // Locked and empty, an unbounded fee still goes through
+    account.charge_fee(USD, Decimal::new(15, 0), None).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(-15, 0));
+  }
+
+  #[test]
+  fn test_credit_interest_on_locked_account() {
+    let mut account = Account::new(1);
+    account.deposit(USD, Decimal::new(10, 0)).unwrap();
+    account.locked = true;
+    let at = Utc::now();
+    account.credit_interest(USD, Decimal::new(5, 2), at).unwrap();
+    assert_eq!(account.balance(USD).available, Decimal::new(1005, 2));
+    assert_eq!(account.balance(USD).accrued_to, Some(at));
+    assert!(account.credit_interest(USD, Decimal::new(-1, 0), at).is_err());
+  }
+
+  #[test]
+  fn test_account_output_row_per_currency() {
+    let mut account = Account::new(7);
+    account.deposit("EUR", Decimal::new(5, 0)).unwrap();
+    account.deposit(USD, Decimal::new(10, 0)).unwrap();
+
+    let rows = AccountOutput::rows(&account);
+    let currencies: Vec<&str> = rows.iter().map(|r| r.currency.as_str()).collect();
+    assert_eq!(currencies, vec!["EUR", "USD"]);
+    assert_eq!(rows[0].total, Decimal::new(5, 0));
+  }
+
+  #[test]
+  fn test_account_output_empty_account() {
+    let rows = AccountOutput::rows(&Account::new(1));
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].currency, USD);
+    assert_eq!(rows[0].total, Decimal::ZERO);
+  }
 }
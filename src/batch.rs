@@ -0,0 +1,116 @@
+use crate::transaction::TransactionRecord;
+
+/// What the engine gets handed, either a plain record or an all or nothing batch
+#[derive(Debug)]
+pub enum Unit {
+  Single(TransactionRecord),
+  Batch(u32, Vec<TransactionRecord>),
+}
+
+/// Groups consecutive records that carry the same batch id.
+/// A batch ends at the first record with a different (or no) batch id, so the same
+/// id showing up again later starts a new batch
+#[derive(Default)]
+pub struct Batcher {
+  current: Option<(u32, Vec<TransactionRecord>)>,
+}
+
+impl Batcher {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Takes the next record and returns what is ready to be processed, in input order
+  pub fn push(&mut self, record: TransactionRecord) -> Vec<Unit> {
+    let mut ready = Vec::new();
+
+    if let Some((id, records)) = self.current.as_mut() {
+      if record.batch == Some(*id) {
+        records.push(record);
+        return ready;
+      }
+      ready.extend(self.finish());
+    }
+
+    match record.batch {
+      Some(id) => self.current = Some((id, vec![record])),
+      None => ready.push(Unit::Single(record)),
+    }
+    ready
+  }
+
+  /// The batch still being collected, call at the end of the input
+  pub fn finish(&mut self) -> Option<Unit> {
+    self.current.take().map(|(id, records)| Unit::Batch(id, records))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transaction::TransactionType;
+
+  fn record(tx: u32, batch: Option<u32>) -> TransactionRecord {
+    TransactionRecord {
+      tx_type: TransactionType::Deposit,
+      client: 1,
+      tx,
+      amount: None,
+      timestamp: None,
+      batch,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  fn describe(units: Vec<Unit>) -> Vec<String> {
+    units
+      .into_iter()
+      .map(|unit| match unit {
+        Unit::Single(record) => format!("{}", record.tx),
+        Unit::Batch(id, records) => {
+          let txs: Vec<String> = records.iter().map(|r| r.tx.to_string()).collect();
+          format!("b{}:{}", id, txs.join("+"))
+        }
+      })
+      .collect()
+  }
+
+  fn run(records: Vec<TransactionRecord>) -> Vec<String> {
+    let mut batcher = Batcher::new();
+    let mut units = Vec::new();
+    for record in records {
+      units.extend(batcher.push(record));
+    }
+    units.extend(batcher.finish());
+    describe(units)
+  }
+
+  #[test]
+  fn test_singles_pass_straight_through() {
+    assert_eq!(run(vec![record(1, None), record(2, None)]), vec!["1", "2"]);
+  }
+
+  #[test]
+  fn test_consecutive_records_grouped() {
+    let records = vec![
+      record(1, None),
+      record(2, Some(7)),
+      record(3, Some(7)),
+      record(4, Some(8)),
+      record(5, None),
+    ];
+    assert_eq!(run(records), vec!["1", "b7:2+3", "b8:4", "5"]);
+  }
+
+  #[test]
+  fn test_batch_at_end_of_input() {
+    assert_eq!(run(vec![record(1, Some(1)), record(2, Some(1))]), vec!["b1:1+2"]);
+  }
+
+  #[test]
+  fn test_reused_id_starts_new_batch() {
+    let records = vec![record(1, Some(1)), record(2, None), record(3, Some(1))];
+    assert_eq!(run(records), vec!["b1:1", "2", "b1:3"]);
+  }
+}
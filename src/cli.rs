@@ -0,0 +1,484 @@
+use std::path::PathBuf;
+
+use rust_decimal::Decimal;
+
+use crate::query::Query;
+use crate::replay::Until;
+use crate::summary::SummaryFormat;
+use crate::transaction::parse_timestamp;
+
+/// Command line options. Parsed by hand since there are only a few of them
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+  /// `-` reads stdin until it is closed
+  pub input: PathBuf,
+  /// Engine policy file, see `EngineConfig`
+  pub config: Option<PathBuf>,
+  /// Per client statement of every applied record
+  pub statements: Option<PathBuf>,
+  /// Journal of every applied conversion and the rate it used
+  pub journal: Option<PathBuf>,
+  /// Every record a screening rule matched
+  pub alerts: Option<PathBuf>,
+  /// NDJSON event per account change, a file or a named pipe
+  pub events: Option<PathBuf>,
+  /// Every record with its outcome, for `replay`. Appended to across runs with `sqlite:<path>`
+  /// storage, rewritten otherwise
+  pub record_log: Option<PathBuf>,
+  /// Count and total of the fees charged, per fee type and currency
+  pub fee_summary: Option<PathBuf>,
+  /// Statistics of the run, `-` for stderr
+  pub summary: Option<PathBuf>,
+  pub summary_format: SummaryFormat,
+  /// Storage backend spec, see `storage::open`. Defaults to memory
+  pub storage: Option<String>,
+  /// Same as `idempotent = true` in the config
+  pub idempotent: bool,
+  /// Address to serve Prometheus metrics on while the input is processed
+  pub metrics: Option<String>,
+  /// Validate the input on a scratch engine and report the errors instead of the accounts
+  pub dry_run: bool,
+  /// Percentage of failed rows above which a dry run fails, defaults to 0
+  pub max_error_rate: Option<Decimal>,
+  /// Address to serve the engine on as an HTTP/JSON service, replaces the input file
+  pub serve: Option<String>,
+  /// Address to accept CSV lines on over TCP, replaces the input file
+  pub listen: Option<String>,
+}
+
+pub fn usage(program: &str) -> String {
+  format!(
+    "Usage: {} [--config <engine.toml>] [--statements <statements.csv>] \
+     [--journal <conversions.csv>] [--fee-summary <fees.csv>] [--alerts <alerts.csv>] \
+     [--events <events.ndjson>] [--record-log <records.log>] [--summary <summary.txt|->] [--summary-format <text|json>] \
+     [--storage <memory|sqlite|sqlite:path>] [--idempotent] \
+     [--metrics <host:port>] [--dry-run [--max-error-rate <percent>]] \
+     <transactions.csv|-|--serve <host:port>|--listen <host:port>>",
+    program
+  )
+}
+
+/// `query` command options, for looking into a saved engine state
+#[derive(Debug, PartialEq)]
+pub struct QueryOptions {
+  pub config: Option<PathBuf>,
+  /// SQLite file written by a run with `--storage sqlite:<path>`
+  pub state: PathBuf,
+  pub client: u16,
+  /// All of them when none are given
+  pub queries: Vec<Query>,
+}
+
+pub fn query_usage(program: &str) -> String {
+  format!(
+    "Usage: {} query [--config <engine.toml>] <state.db> <client> [balances] [transactions] [lock]",
+    program
+  )
+}
+
+impl QueryOptions {
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let mut config = None;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--config" => config = Some(value(&mut args, arg)?.into()),
+        flag if flag.starts_with("--") => return Err(format!("unknown option '{}'", flag)),
+        other => positional.push(other),
+      }
+    }
+
+    let mut positional = positional.into_iter();
+    let state = PathBuf::from(positional.next().ok_or("missing state file")?);
+    let client = positional.next().ok_or("missing client")?;
+    let client = client.parse().map_err(|_| format!("invalid client '{}'", client))?;
+    let mut queries = positional.map(str::parse).collect::<Result<Vec<Query>, _>>()?;
+    if queries.is_empty() {
+      queries = vec![Query::Balances, Query::Transactions, Query::Lock];
+    }
+    Ok(Self { config, state, client, queries })
+  }
+}
+
+/// `replay` command options, for rebuilding the accounts from a record log
+#[derive(Debug, PartialEq)]
+pub struct ReplayOptions {
+  /// Has to be the config of the logged runs for the replay to end the same way
+  pub config: Option<PathBuf>,
+  pub log: PathBuf,
+  pub until: Until,
+}
+
+pub fn replay_usage(program: &str) -> String {
+  format!(
+    "Usage: {} replay [--config <engine.toml>] <records.log> [--at-record <n> | --at <timestamp>]",
+    program
+  )
+}
+
+impl ReplayOptions {
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let mut config = None;
+    let mut log = None;
+    let mut until = Until::End;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--config" => config = Some(value(&mut args, arg)?.into()),
+        "--at-record" | "--at" if until != Until::End => {
+          return Err("--at-record and --at cannot be combined".into());
+        }
+        "--at-record" => {
+          let n = value(&mut args, arg)?;
+          until = Until::Record(n.parse().map_err(|_| format!("invalid record number '{}'", n))?);
+        }
+        "--at" => until = Until::Time(parse_timestamp(&value(&mut args, arg)?)?),
+        flag if flag.starts_with("--") => return Err(format!("unknown option '{}'", flag)),
+        path if log.is_none() => log = Some(PathBuf::from(path)),
+        extra => return Err(format!("unexpected argument '{}'", extra)),
+      }
+    }
+    Ok(Self { config, log: log.ok_or("missing record log")?, until })
+  }
+}
+
+/// `diff` command options, for comparing two account states
+#[derive(Debug, PartialEq)]
+pub struct DiffOptions {
+  /// Only used for the decimal places of the report
+  pub config: Option<PathBuf>,
+  /// Account output CSV or saved SQLite state
+  pub before: PathBuf,
+  pub after: PathBuf,
+  /// Largest change of an amount that does not fail the command, defaults to 0
+  pub tolerance: Decimal,
+}
+
+pub fn diff_usage(program: &str) -> String {
+  format!(
+    "Usage: {} diff [--config <engine.toml>] [--tolerance <amount>] \
+     <before.csv|before.db> <after.csv|after.db>",
+    program
+  )
+}
+
+impl DiffOptions {
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let mut config = None;
+    let mut tolerance = Decimal::ZERO;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--config" => config = Some(value(&mut args, arg)?.into()),
+        "--tolerance" => tolerance = parse_tolerance(&value(&mut args, arg)?)?,
+        flag if flag.starts_with("--") => return Err(format!("unknown option '{}'", flag)),
+        other => positional.push(PathBuf::from(other)),
+      }
+    }
+    let [before, after] = <[PathBuf; 2]>::try_from(positional)
+      .map_err(|_| "expected the two account states to compare".to_string())?;
+    Ok(Self { config, before, after, tolerance })
+  }
+}
+
+/// `reconcile` command options, for checking a saved state against the bank's balances
+#[derive(Debug, PartialEq)]
+pub struct ReconcileOptions {
+  pub config: Option<PathBuf>,
+  /// SQLite file written by a run with `--storage sqlite:<path>`
+  pub state: PathBuf,
+  /// CSV of expected balances, `client[,currency][,available][,held][,total][,locked]`
+  pub expected: PathBuf,
+  /// Largest difference of an amount that is not a break, defaults to 0
+  pub tolerance: Decimal,
+}
+
+pub fn reconcile_usage(program: &str) -> String {
+  format!(
+    "Usage: {} reconcile [--config <engine.toml>] [--tolerance <amount>] <state.db> <balances.csv>",
+    program
+  )
+}
+
+impl ReconcileOptions {
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let mut config = None;
+    let mut tolerance = Decimal::ZERO;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--config" => config = Some(value(&mut args, arg)?.into()),
+        "--tolerance" => tolerance = parse_tolerance(&value(&mut args, arg)?)?,
+        flag if flag.starts_with("--") => return Err(format!("unknown option '{}'", flag)),
+        other => positional.push(PathBuf::from(other)),
+      }
+    }
+    let [state, expected] = <[PathBuf; 2]>::try_from(positional)
+      .map_err(|_| "expected a state file and a balance file".to_string())?;
+    Ok(Self { config, state, expected, tolerance })
+  }
+}
+
+impl Options {
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let mut input = None;
+    let mut options = Options::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--config" => options.config = Some(value(&mut args, arg)?.into()),
+        "--statements" => options.statements = Some(value(&mut args, arg)?.into()),
+        "--journal" => options.journal = Some(value(&mut args, arg)?.into()),
+        "--alerts" => options.alerts = Some(value(&mut args, arg)?.into()),
+        "--events" => options.events = Some(value(&mut args, arg)?.into()),
+        "--record-log" => options.record_log = Some(value(&mut args, arg)?.into()),
+        "--fee-summary" => options.fee_summary = Some(value(&mut args, arg)?.into()),
+        "--summary" => options.summary = Some(value(&mut args, arg)?.into()),
+        "--summary-format" => options.summary_format = value(&mut args, arg)?.parse()?,
+        "--storage" => options.storage = Some(value(&mut args, arg)?),
+        "--idempotent" => options.idempotent = true,
+        "--metrics" => options.metrics = Some(value(&mut args, arg)?),
+        "--dry-run" => options.dry_run = true,
+        "--serve" => options.serve = Some(value(&mut args, arg)?),
+        "--listen" => options.listen = Some(value(&mut args, arg)?),
+        "--max-error-rate" => {
+          let rate = value(&mut args, arg)?;
+          let rate = rate
+            .parse::<Decimal>()
+            .ok()
+            .filter(|rate| *rate >= Decimal::ZERO && *rate <= Decimal::ONE_HUNDRED)
+            .ok_or_else(|| format!("--max-error-rate expects a percentage, got '{}'", rate))?;
+          options.max_error_rate = Some(rate);
+        }
+        flag if flag.starts_with("--") => return Err(format!("unknown option '{}'", flag)),
+        path if input.is_none() => input = Some(PathBuf::from(path)),
+        extra => return Err(format!("unexpected argument '{}'", extra)),
+      }
+    }
+
+    let reports = [
+      &options.statements,
+      &options.journal,
+      &options.alerts,
+      &options.events,
+      &options.record_log,
+      &options.fee_summary,
+      &options.summary,
+    ];
+    // A database file would keep what the scratch engine wrote
+    let persistent = options.storage.as_deref().is_some_and(|spec| spec.starts_with("sqlite:"));
+    let outputs = persistent || options.metrics.is_some() || reports.iter().any(|r| r.is_some());
+    if options.dry_run && outputs {
+      return Err(
+        "--dry-run only writes its report, drop the database file, reports and metrics".into(),
+      );
+    }
+    if options.max_error_rate.is_some() && !options.dry_run {
+      return Err("--max-error-rate needs --dry-run".into());
+    }
+
+    if options.serve.is_some() && options.listen.is_some() {
+      return Err("--serve and --listen cannot be combined".into());
+    }
+    if options.serve.is_some() || options.listen.is_some() {
+      // Records come from the network and the process runs until it is stopped
+      if input.is_some() || options.dry_run || reports.iter().any(|r| r.is_some()) {
+        return Err("--serve and --listen take no input file, reports or --dry-run".into());
+      }
+      return Ok(options);
+    }
+
+    options.input = input.ok_or("missing input file")?;
+    Ok(options)
+  }
+}
+
+fn parse_tolerance(amount: &str) -> Result<Decimal, String> {
+  amount
+    .parse::<Decimal>()
+    .ok()
+    .filter(|amount| *amount >= Decimal::ZERO)
+    .ok_or_else(|| format!("--tolerance expects an amount, got '{}'", amount))
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<String, String> {
+  args.next().cloned().ok_or_else(|| format!("{} requires a value", flag))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn test_input_only() {
+    let options = Options::parse(&args(&["tx.csv"])).unwrap();
+    assert_eq!(options.input, PathBuf::from("tx.csv"));
+    assert_eq!(options.config, None);
+  }
+
+  #[test]
+  fn test_options_any_order() {
+    let options =
+      Options::parse(&args(&["--statements", "s.csv", "tx.csv", "--config", "e.toml"])).unwrap();
+    assert_eq!(options.input, PathBuf::from("tx.csv"));
+    assert_eq!(options.config, Some(PathBuf::from("e.toml")));
+    assert_eq!(options.statements, Some(PathBuf::from("s.csv")));
+  }
+
+  #[test]
+  fn test_journal() {
+    let options = Options::parse(&args(&["--journal", "j.csv", "tx.csv"])).unwrap();
+    assert_eq!(options.journal, Some(PathBuf::from("j.csv")));
+  }
+
+  #[test]
+  fn test_fee_summary() {
+    let options = Options::parse(&args(&["tx.csv", "--fee-summary", "fees.csv"])).unwrap();
+    assert_eq!(options.fee_summary, Some(PathBuf::from("fees.csv")));
+  }
+
+  #[test]
+  fn test_alerts() {
+    let options = Options::parse(&args(&["--alerts", "alerts.csv", "tx.csv"])).unwrap();
+    assert_eq!(options.alerts, Some(PathBuf::from("alerts.csv")));
+  }
+
+  #[test]
+  fn test_events() {
+    let options = Options::parse(&args(&["--events", "events.ndjson", "tx.csv"])).unwrap();
+    assert_eq!(options.events, Some(PathBuf::from("events.ndjson")));
+    assert!(Options::parse(&args(&["--dry-run", "--events", "e.ndjson", "tx.csv"])).is_err());
+  }
+
+  #[test]
+  fn test_summary() {
+    let options = Options::parse(&args(&["--summary", "-", "tx.csv"])).unwrap();
+    assert_eq!(options.summary, Some(PathBuf::from("-")));
+    assert_eq!(options.summary_format, SummaryFormat::Text);
+    let options = Options::parse(&args(&["--summary-format", "json", "tx.csv"])).unwrap();
+    assert_eq!(options.summary_format, SummaryFormat::Json);
+    assert!(Options::parse(&args(&["--summary-format", "xml", "tx.csv"])).is_err());
+  }
+
+  #[test]
+  fn test_metrics_on_stdin() {
+    let options = Options::parse(&args(&["--metrics", "127.0.0.1:9100", "-"])).unwrap();
+    assert_eq!(options.metrics.as_deref(), Some("127.0.0.1:9100"));
+    assert_eq!(options.input, PathBuf::from("-"));
+  }
+
+  #[test]
+  fn test_dry_run() {
+    let options =
+      Options::parse(&args(&["--dry-run", "--max-error-rate", "2.5", "tx.csv"])).unwrap();
+    assert!(options.dry_run);
+    assert_eq!(options.max_error_rate, Some(Decimal::new(25, 1)));
+
+    assert!(Options::parse(&args(&["--max-error-rate", "1", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--dry-run", "--max-error-rate", "101", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--dry-run", "--storage", "sqlite", "tx.csv"])).is_ok());
+    assert!(Options::parse(&args(&["--dry-run", "--storage", "sqlite:s.db", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--dry-run", "--statements", "s.csv", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--dry-run", "--metrics", ":9100", "tx.csv"])).is_err());
+  }
+
+  #[test]
+  fn test_serve() {
+    let options =
+      Options::parse(&args(&["--serve", "127.0.0.1:8080", "--metrics", ":9100"])).unwrap();
+    assert_eq!(options.serve.as_deref(), Some("127.0.0.1:8080"));
+    assert!(Options::parse(&args(&["--serve", ":8080", "--storage", "sqlite:s.db"])).is_ok());
+    assert!(Options::parse(&args(&["--serve", ":8080", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--serve", ":8080", "--summary", "-"])).is_err());
+    assert!(Options::parse(&args(&["--serve", ":8080", "--dry-run"])).is_err());
+  }
+
+  #[test]
+  fn test_listen() {
+    let options = Options::parse(&args(&["--listen", "127.0.0.1:7000"])).unwrap();
+    assert_eq!(options.listen.as_deref(), Some("127.0.0.1:7000"));
+    assert!(Options::parse(&args(&["--listen", ":7000", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--listen", ":7000", "--serve", ":8080"])).is_err());
+  }
+
+  #[test]
+  fn test_query() {
+    let options = QueryOptions::parse(&args(&["state.db", "42", "lock"])).unwrap();
+    assert_eq!(options.state, PathBuf::from("state.db"));
+    assert_eq!(options.client, 42);
+    assert_eq!(options.queries, [Query::Lock]);
+    let options = QueryOptions::parse(&args(&["--config", "e.toml", "state.db", "42"])).unwrap();
+    assert_eq!(options.config, Some(PathBuf::from("e.toml")));
+    assert_eq!(options.queries.len(), 3);
+
+    assert!(QueryOptions::parse(&args(&["state.db"])).is_err());
+    assert!(QueryOptions::parse(&args(&["state.db", "x"])).is_err());
+    assert!(QueryOptions::parse(&args(&["state.db", "42", "why"])).is_err());
+  }
+
+  #[test]
+  fn test_record_log() {
+    let options = Options::parse(&args(&["--record-log", "r.log", "tx.csv"])).unwrap();
+    assert_eq!(options.record_log, Some(PathBuf::from("r.log")));
+    assert!(Options::parse(&args(&["--dry-run", "--record-log", "r.log", "tx.csv"])).is_err());
+    assert!(Options::parse(&args(&["--serve", ":8080", "--record-log", "r.log"])).is_err());
+  }
+
+  #[test]
+  fn test_replay() {
+    let options = ReplayOptions::parse(&args(&["r.log"])).unwrap();
+    assert_eq!(options.log, PathBuf::from("r.log"));
+    assert_eq!(options.until, Until::End);
+    let options = ReplayOptions::parse(&args(&["r.log", "--at-record", "12"])).unwrap();
+    assert_eq!(options.until, Until::Record(12));
+    let options = ReplayOptions::parse(&args(&["--at", "2024-01-01T00:00:00Z", "r.log"])).unwrap();
+    assert_eq!(options.until, Until::Time("2024-01-01T00:00:00Z".parse().unwrap()));
+
+    assert!(ReplayOptions::parse(&args(&[])).is_err());
+    assert!(ReplayOptions::parse(&args(&["r.log", "--at-record", "x"])).is_err());
+    assert!(ReplayOptions::parse(&args(&["r.log", "--at-record", "1", "--at", "0"])).is_err());
+  }
+
+  #[test]
+  fn test_diff() {
+    let options = DiffOptions::parse(&args(&["a.csv", "--tolerance", "0.01", "b.db"])).unwrap();
+    assert_eq!(options.before, PathBuf::from("a.csv"));
+    assert_eq!(options.after, PathBuf::from("b.db"));
+    assert_eq!(options.tolerance, Decimal::new(1, 2));
+    assert_eq!(DiffOptions::parse(&args(&["a.csv", "b.csv"])).unwrap().tolerance, Decimal::ZERO);
+
+    assert!(DiffOptions::parse(&args(&["a.csv"])).is_err());
+    assert!(DiffOptions::parse(&args(&["a.csv", "b.csv", "c.csv"])).is_err());
+    assert!(DiffOptions::parse(&args(&["--tolerance", "-1", "a.csv", "b.csv"])).is_err());
+  }
+
+  #[test]
+  fn test_reconcile() {
+    let options = ReconcileOptions::parse(&args(&["state.db", "bank.csv"])).unwrap();
+    assert_eq!(options.state, PathBuf::from("state.db"));
+    assert_eq!(options.expected, PathBuf::from("bank.csv"));
+    assert_eq!(options.tolerance, Decimal::ZERO);
+    let options =
+      ReconcileOptions::parse(&args(&["--tolerance", "0.01", "state.db", "bank.csv"])).unwrap();
+    assert_eq!(options.tolerance, Decimal::new(1, 2));
+
+    assert!(ReconcileOptions::parse(&args(&["state.db"])).is_err());
+    assert!(ReconcileOptions::parse(&args(&["--tolerance", "x", "state.db", "bank.csv"])).is_err());
+  }
+
+  #[test]
+  fn test_errors() {
+    assert!(Options::parse(&args(&[])).is_err());
+    assert!(Options::parse(&args(&["a.csv", "b.csv"])).is_err());
+    assert!(Options::parse(&args(&["a.csv", "--config"])).is_err());
+    assert!(Options::parse(&args(&["a.csv", "--bogus"])).is_err());
+  }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, Utc};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+
+use crate::fees::FeeSchedule;
+
+/// Engine policy loaded from a TOML file. Every setting is optional, an empty file
+/// gives the behaviour described in the spec
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+  /// Disputes raised more than this many days after the deposit are rejected.
+  /// Only enforced when both the deposit and the dispute carry a timestamp
+  pub dispute_window_days: Option<u32>,
+  /// Skip exact copies of records that were already applied instead of rejecting them,
+  /// for running a file again on top of restored state
+  pub idempotent: bool,
+  /// Whether a representment unlocks the account its chargeback locked
+  pub representment_unlock: UnlockPolicy,
+  /// Rate table and rounding for `convert` records
+  pub conversion: ConversionConfig,
+  /// Decimal places per currency and what to do with amounts that have more
+  pub precision: PrecisionConfig,
+  /// Fees charged on deposits, withdrawals and chargebacks
+  pub fees: FeeSchedule,
+  /// Rate and day count of `accrue_interest` records, which are rejected without it
+  pub interest: Option<InterestConfig>,
+  /// Withdrawal limits file (TOML). A relative path is taken from the config file's directory
+  pub limits: Option<PathBuf>,
+  /// Screening rules file (TOML). A relative path is taken from the config file's directory
+  pub rules: Option<PathBuf>,
+  /// Notification sinks file (TOML). A relative path is taken from the config file's directory
+  pub notifications: Option<PathBuf>,
+}
+
+/// The `[conversion]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConversionConfig {
+  /// Rate table, CSV or TOML. A relative path is taken from the config file's directory
+  pub rates: Option<PathBuf>,
+  /// How the converted amount is brought to the precision of the currency it buys
+  pub rounding: Rounding,
+}
+
+/// The `[precision]` table
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrecisionConfig {
+  /// Decimal places of every currency not listed in `currencies`
+  pub default_scale: u32,
+  /// Decimal places per currency code, e.g. `JPY = 0`
+  pub currencies: BTreeMap<String, u32>,
+  /// An input amount with more places than its currency allows is rejected or rounded
+  pub excess: Excess,
+  /// Only used when `excess = "round"`
+  pub rounding: Rounding,
+}
+
+impl Default for PrecisionConfig {
+  fn default() -> Self {
+    Self {
+      default_scale: 4,
+      currencies: BTreeMap::new(),
+      excess: Excess::default(),
+      rounding: Rounding::default(),
+    }
+  }
+}
+
+impl PrecisionConfig {
+  /// Decimal places kept and printed for `currency`
+  pub fn scale(&self, currency: &str) -> u32 {
+    self
+      .currencies
+      .iter()
+      .find(|(code, _)| code.eq_ignore_ascii_case(currency))
+      .map_or(self.default_scale, |(_, scale)| *scale)
+  }
+
+  /// `amount` as it will be booked in `currency`, None when it has too many places
+  /// and those are rejected
+  pub fn fit(&self, currency: &str, amount: Decimal) -> Option<Decimal> {
+    let scale = self.scale(currency);
+    if amount.normalize().scale() <= scale {
+      return Some(amount);
+    }
+    match self.excess {
+      Excess::Reject => None,
+      Excess::Round => Some(self.rounding.round(amount, scale)),
+    }
+  }
+}
+
+/// The `[interest]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterestConfig {
+  /// Yearly rate in percent, paid on the positive available balance
+  pub annual_percent: Decimal,
+  pub day_count: DayCount,
+  /// Whether locked accounts earn interest
+  pub locked: LockedInterest,
+  /// How the interest is brought to the precision of its currency
+  pub rounding: Rounding,
+}
+
+impl InterestConfig {
+  /// Interest on `balance` for the time from `from` to `to`
+  pub fn interest(&self, balance: Decimal, from: DateTime<Utc>, to: DateTime<Utc>) -> Decimal {
+    let (days, year) = self.day_count.period(from, to);
+    // Divided last, so whole results stay exact
+    balance * self.annual_percent * Decimal::from(days) / Decimal::from(100 * year)
+  }
+}
+
+/// How the days of an accrual period are counted and how many make a year
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum DayCount {
+  /// Actual days over 365
+  #[default]
+  #[serde(rename = "actual_365")]
+  Actual365,
+  /// Actual days over 360
+  #[serde(rename = "actual_360")]
+  Actual360,
+  /// Every month has 30 days, over 360 (the US 30/360 rule for the 31st)
+  #[serde(rename = "thirty_360")]
+  Thirty360,
+}
+
+impl DayCount {
+  /// The days from `from` to `to` by calendar date, zero if `to` is not later, and the
+  /// days in a year
+  pub fn period(self, from: DateTime<Utc>, to: DateTime<Utc>) -> (i64, i64) {
+    let (from, to) = (from.date_naive(), to.date_naive());
+    if to <= from {
+      return (0, 365);
+    }
+    match self {
+      DayCount::Actual365 => ((to - from).num_days(), 365),
+      DayCount::Actual360 => ((to - from).num_days(), 360),
+      DayCount::Thirty360 => {
+        let d1 = from.day().min(30);
+        let d2 = if d1 == 30 { to.day().min(30) } else { to.day() };
+        let days = 360 * (to.year() - from.year())
+          + 30 * (to.month() as i32 - from.month() as i32)
+          + (d2 as i32 - d1 as i32);
+        (days.into(), 360)
+      }
+    }
+  }
+}
+
+/// Whether a locked account earns interest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedInterest {
+  /// A locked account earns nothing, the accrual still moves its period forward
+  #[default]
+  Skip,
+  Accrue,
+}
+
+/// What happens to an input amount with more decimal places than its currency has
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Excess {
+  #[default]
+  Reject,
+  Round,
+}
+
+/// What happens to the digits past the scale
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+  /// Banker's rounding, a tie goes to the even digit
+  #[default]
+  #[serde(alias = "bankers")]
+  HalfEven,
+  /// A tie goes away from zero
+  HalfUp,
+  /// The extra digits are dropped
+  Truncate,
+}
+
+impl Rounding {
+  pub fn round(self, value: Decimal, scale: u32) -> Decimal {
+    let strategy = match self {
+      Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+      Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+      Rounding::Truncate => RoundingStrategy::ToZero,
+    };
+    value.round_dp_with_strategy(scale, strategy)
+  }
+}
+
+/// When a won back chargeback lifts the account lock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlockPolicy {
+  /// The account stays locked, unlocking is left to an operator
+  #[default]
+  Never,
+  /// Every representment unlocks the account
+  Always,
+  /// Unlock once none of the client's transactions is still charged back
+  LastChargeback,
+}
+
+impl EngineConfig {
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read config '{}'", path.display()))?;
+    let mut config: Self = toml::from_str(&text)
+      .with_context(|| format!("Failed to parse config '{}'", path.display()))?;
+    config.fees.validate().with_context(|| format!("Invalid fees in '{}'", path.display()))?;
+
+    let files = [
+      config.conversion.rates.as_mut(),
+      config.limits.as_mut(),
+      config.rules.as_mut(),
+      config.notifications.as_mut(),
+    ];
+    for file in files.into_iter().flatten() {
+      if let Some(dir) = path.parent() {
+        *file = dir.join(&*file);
+      }
+    }
+    Ok(config)
+  }
+
+  pub fn dispute_window(&self) -> Option<Duration> {
+    self.dispute_window_days.map(|days| Duration::days(days.into()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_empty_config_is_default() {
+    let config: EngineConfig = toml::from_str("").unwrap();
+    assert!(config.dispute_window().is_none());
+    assert!(!config.idempotent);
+    assert_eq!(config.representment_unlock, UnlockPolicy::Never);
+  }
+
+  #[test]
+  fn test_representment_unlock() {
+    let config: EngineConfig =
+      toml::from_str("representment_unlock = \"last_chargeback\"").unwrap();
+    assert_eq!(config.representment_unlock, UnlockPolicy::LastChargeback);
+    assert!(toml::from_str::<EngineConfig>("representment_unlock = \"sometimes\"").is_err());
+  }
+
+  #[test]
+  fn test_dispute_window() {
+    let config: EngineConfig = toml::from_str("dispute_window_days = 30").unwrap();
+    assert_eq!(config.dispute_window(), Some(Duration::days(30)));
+  }
+
+  #[test]
+  fn test_conversion_defaults() {
+    let config: EngineConfig = toml::from_str("").unwrap();
+    assert_eq!(config.conversion.rates, None);
+    assert_eq!(config.conversion.rounding, Rounding::HalfEven);
+  }
+
+  #[test]
+  fn test_conversion_table() {
+    let config: EngineConfig =
+      toml::from_str("[conversion]\nrates = \"rates.csv\"\nrounding = \"truncate\"").unwrap();
+    assert_eq!(config.conversion.rates, Some(PathBuf::from("rates.csv")));
+    assert_eq!(config.conversion.rounding, Rounding::Truncate);
+  }
+
+  #[test]
+  fn test_precision_table() {
+    let config: EngineConfig = toml::from_str(
+      "[precision]\ndefault_scale = 2\nexcess = \"round\"\nrounding = \"bankers\"\n\
+       [precision.currencies]\nJPY = 0\nbtc = 8",
+    )
+    .unwrap();
+    let precision = &config.precision;
+    assert_eq!(precision.scale("USD"), 2);
+    assert_eq!(precision.scale("JPY"), 0);
+    assert_eq!(precision.scale("BTC"), 8);
+    assert_eq!(precision.excess, Excess::Round);
+    assert_eq!(precision.rounding, Rounding::HalfEven);
+  }
+
+  #[test]
+  fn test_precision_fit() {
+    let mut precision = PrecisionConfig::default();
+    // Trailing zeros are not extra precision
+    assert_eq!(precision.fit("USD", Decimal::new(1234500, 6)), Some(Decimal::new(1234500, 6)));
+    assert_eq!(precision.fit("USD", Decimal::new(123456, 5)), None);
+
+    precision.excess = Excess::Round;
+    assert_eq!(precision.fit("USD", Decimal::new(123455, 5)), Some(Decimal::new(12346, 4)));
+    assert_eq!(precision.fit("USD", Decimal::new(123465, 5)), Some(Decimal::new(12346, 4)));
+    precision.rounding = Rounding::HalfUp;
+    assert_eq!(precision.fit("USD", Decimal::new(123465, 5)), Some(Decimal::new(12347, 4)));
+    precision.rounding = Rounding::Truncate;
+    assert_eq!(precision.fit("USD", Decimal::new(123469, 5)), Some(Decimal::new(12346, 4)));
+  }
+
+  #[test]
+  fn test_paths_relative_to_config() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("engine.toml");
+    fs::write(
+      &path,
+      "limits = \"limits.toml\"\nrules = \"rules.toml\"\nnotifications = \"notify.toml\"\n\
+       [conversion]\nrates = \"rates.csv\"\n",
+    )
+    .unwrap();
+
+    let config = EngineConfig::load(&path).unwrap();
+    assert_eq!(config.conversion.rates, Some(dir.path().join("rates.csv")));
+    assert_eq!(config.limits, Some(dir.path().join("limits.toml")));
+    assert_eq!(config.rules, Some(dir.path().join("rules.toml")));
+    assert_eq!(config.notifications, Some(dir.path().join("notify.toml")));
+  }
+
+  #[test]
+  fn test_rounding() {
+    let value = Decimal::new(12345, 4); // 1.2345
+    assert_eq!(Rounding::HalfEven.round(value, 3), Decimal::new(1234, 3));
+    assert_eq!(Rounding::HalfUp.round(value, 3), Decimal::new(1235, 3));
+    assert_eq!(Rounding::Truncate.round(Decimal::new(12349, 4), 3), Decimal::new(1234, 3));
+    assert_eq!(Rounding::Truncate.round(Decimal::new(-12349, 4), 3), Decimal::new(-1234, 3));
+  }
+
+  #[test]
+  fn test_invalid_fees_rejected_on_load() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("engine.toml");
+    fs::write(&path, "[fees.withdrawal]\nmin = \"2\"\nmax = \"1\"\n").unwrap();
+    assert!(EngineConfig::load(&path).is_err());
+  }
+
+  #[test]
+  fn test_interest_table() {
+    let config: EngineConfig = toml::from_str("").unwrap();
+    assert!(config.interest.is_none());
+
+    let config: EngineConfig = toml::from_str(
+      "[interest]\nannual_percent = \"3.65\"\nday_count = \"thirty_360\"\nlocked = \"accrue\"",
+    )
+    .unwrap();
+    let interest = config.interest.unwrap();
+    assert_eq!(interest.annual_percent, Decimal::new(365, 2));
+    assert_eq!(interest.day_count, DayCount::Thirty360);
+    assert_eq!(interest.locked, LockedInterest::Accrue);
+  }
+
+  #[test]
+  fn test_day_counts() {
+    let at = |text: &str| DateTime::parse_from_rfc3339(text).unwrap().to_utc();
+    let (jan31, mar1) = (at("2024-01-31T12:00:00Z"), at("2024-03-01T00:00:00Z"));
+    // 30 days in the leap year February
+    assert_eq!(DayCount::Actual365.period(jan31, mar1), (30, 365));
+    assert_eq!(DayCount::Actual360.period(jan31, mar1), (30, 360));
+    // The 31st counts as the 30th
+    assert_eq!(DayCount::Thirty360.period(jan31, mar1), (31, 360));
+    assert_eq!(DayCount::Actual365.period(mar1, jan31).0, 0);
+
+    let interest = InterestConfig { annual_percent: Decimal::new(365, 2), ..Default::default() };
+    let jan1 = at("2024-01-01T00:00:00Z");
+    assert_eq!(
+      interest.interest(Decimal::new(1000, 0), jan1, at("2024-01-11T00:00:00Z")),
+      Decimal::ONE
+    );
+  }
+
+  #[test]
+  fn test_unknown_key_rejected() {
+    assert!(toml::from_str::<EngineConfig>("dispute_window = 30").is_err());
+  }
+}
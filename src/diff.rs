@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use rust_decimal::Decimal;
+
+use crate::account::AccountOutput;
+use crate::config::PrecisionConfig;
+use crate::format_decimal;
+use crate::storage;
+
+/// What a SQLite database file starts with
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Account rows by client and currency
+pub type State = BTreeMap<(u16, String), AccountOutput>;
+
+/// Reads an account output CSV, or the accounts of a state saved with
+/// `--storage sqlite:<path>`
+pub fn load(path: &Path) -> Result<State> {
+  let mut file =
+    File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+  let mut magic = [0; 16];
+  let is_sqlite = file.read_exact(&mut magic).is_ok() && &magic == SQLITE_MAGIC;
+
+  let rows: Vec<AccountOutput> = if is_sqlite {
+    let storage = storage::open_read_only(path)?;
+    storage.accounts()?.iter().flat_map(AccountOutput::rows).collect()
+  } else {
+    csv::ReaderBuilder::new()
+      .trim(csv::Trim::All)
+      .from_path(path)?
+      .deserialize()
+      .collect::<Result<_, _>>()
+      .with_context(|| format!("Invalid account output '{}'", path.display()))?
+  };
+
+  let mut state = State::new();
+  for row in rows {
+    let key = (row.client, row.currency.clone());
+    if state.contains_key(&key) {
+      bail!("client {} has two {} rows in '{}'", key.0, key.1, path.display());
+    }
+    state.insert(key, row);
+  }
+  Ok(state)
+}
+
+/// One client and currency whose row is not the same on both sides
+#[derive(Debug)]
+pub struct RowDiff {
+  pub client: u16,
+  pub currency: String,
+  /// None when the row is only on the other side
+  pub before: Option<AccountOutput>,
+  pub after: Option<AccountOutput>,
+}
+
+impl RowDiff {
+  /// After minus before, a missing row counting as zero
+  fn delta(&self, amount: fn(&AccountOutput) -> Decimal) -> Decimal {
+    self.after.as_ref().map_or(Decimal::ZERO, amount)
+      - self.before.as_ref().map_or(Decimal::ZERO, amount)
+  }
+
+  fn locked(&self) -> (bool, bool) {
+    let locked = |row: &Option<AccountOutput>| row.as_ref().is_some_and(|row| row.locked);
+    (locked(&self.before), locked(&self.after))
+  }
+
+  fn status(&self) -> &'static str {
+    match (&self.before, &self.after) {
+      (None, _) => "added",
+      (_, None) => "removed",
+      _ => "changed",
+    }
+  }
+
+  /// A row on one side only or a lock change always counts, amounts count when one of
+  /// them moved by more than `tolerance`
+  fn exceeds(&self, tolerance: Decimal) -> bool {
+    let (locked_before, locked_after) = self.locked();
+    self.before.is_none()
+      || self.after.is_none()
+      || locked_before != locked_after
+      || [self.delta(available), self.delta(held), self.delta(total)]
+        .iter()
+        .any(|delta| delta.abs() > tolerance)
+  }
+}
+
+fn available(row: &AccountOutput) -> Decimal {
+  row.available
+}
+
+fn held(row: &AccountOutput) -> Decimal {
+  row.held
+}
+
+fn total(row: &AccountOutput) -> Decimal {
+  row.total
+}
+
+/// The rows that differ between two account states, by client and currency
+#[derive(Debug)]
+pub struct Diff {
+  pub rows: Vec<RowDiff>,
+  /// Rows on either side per currency, changed or not
+  pub compared: BTreeMap<String, u64>,
+}
+
+pub fn compare(mut before: State, mut after: State) -> Diff {
+  let mut keys: Vec<(u16, String)> = before.keys().chain(after.keys()).cloned().collect();
+  keys.sort();
+  keys.dedup();
+
+  let mut diff = Diff { rows: Vec::new(), compared: BTreeMap::new() };
+  for (client, currency) in keys {
+    *diff.compared.entry(currency.clone()).or_default() += 1;
+    let key = (client, currency);
+    let row = RowDiff {
+      before: before.remove(&key),
+      after: after.remove(&key),
+      client: key.0,
+      currency: key.1,
+    };
+    if row.exceeds(Decimal::ZERO) {
+      diff.rows.push(row);
+    }
+  }
+  diff
+}
+
+impl Diff {
+  /// The differing rows, then a blank line and the totals per currency
+  pub fn write(&self, precision: &PrecisionConfig, out: &mut impl Write) -> Result<()> {
+    writeln!(
+      out,
+      "client,currency,status,available_diff,held_diff,total_diff,locked_before,locked_after"
+    )?;
+    for row in &self.rows {
+      let scale = precision.scale(&row.currency);
+      let (locked_before, locked_after) = row.locked();
+      writeln!(
+        out,
+        "{},{},{},{},{},{},{},{}",
+        row.client,
+        row.currency,
+        row.status(),
+        format_decimal(row.delta(available), scale),
+        format_decimal(row.delta(held), scale),
+        format_decimal(row.delta(total), scale),
+        locked_before,
+        locked_after
+      )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "currency,rows,changed,available_diff,held_diff,total_diff,locked,unlocked")?;
+    for (currency, compared) in &self.compared {
+      let rows: Vec<&RowDiff> = self.rows.iter().filter(|row| &row.currency == currency).collect();
+      let sum = |amount: fn(&AccountOutput) -> Decimal| {
+        rows.iter().map(|row| row.delta(amount)).sum::<Decimal>()
+      };
+      let count = |change: (bool, bool)| rows.iter().filter(|row| row.locked() == change).count();
+      let scale = precision.scale(currency);
+      writeln!(
+        out,
+        "{},{},{},{},{},{},{},{}",
+        currency,
+        compared,
+        rows.len(),
+        format_decimal(sum(available), scale),
+        format_decimal(sum(held), scale),
+        format_decimal(sum(total), scale),
+        count((false, true)),
+        count((true, false))
+      )?;
+    }
+    Ok(())
+  }
+
+  /// Fails when a row differs by more than `tolerance`
+  pub fn check(&self, tolerance: Decimal) -> Result<()> {
+    let over = self.rows.iter().filter(|row| row.exceeds(tolerance)).count();
+    if over > 0 {
+      bail!(
+        "{} of {} rows differ by more than {}",
+        over,
+        self.compared.values().sum::<u64>(),
+        tolerance
+      );
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn row(client: u16, available: i64, held: i64, locked: bool) -> AccountOutput {
+    AccountOutput {
+      client,
+      currency: "USD".to_string(),
+      available: Decimal::from(available),
+      held: Decimal::from(held),
+      total: Decimal::from(available + held),
+      locked,
+    }
+  }
+
+  fn state(rows: Vec<AccountOutput>) -> State {
+    rows.into_iter().map(|row| ((row.client, row.currency.clone()), row)).collect()
+  }
+
+  #[test]
+  fn test_diff_report_and_totals() {
+    let before = state(vec![row(1, 10, 0, false), row(2, 5, 0, false), row(3, 1, 0, false)]);
+    let after = state(vec![row(1, 10, 0, false), row(2, 0, 0, true), row(4, 2, 1, false)]);
+    let diff = compare(before, after);
+
+    let mut out = Vec::new();
+    diff.write(&PrecisionConfig::default(), &mut out).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "client,currency,status,available_diff,held_diff,total_diff,locked_before,locked_after\n\
+       2,USD,changed,-5.0000,0.0000,-5.0000,false,true\n\
+       3,USD,removed,-1.0000,0.0000,-1.0000,false,false\n\
+       4,USD,added,2.0000,1.0000,3.0000,false,false\n\
+       \n\
+       currency,rows,changed,available_diff,held_diff,total_diff,locked,unlocked\n\
+       USD,4,3,-4.0000,1.0000,-3.0000,1,0\n"
+    );
+    assert_eq!(
+      diff.check(Decimal::from(100)).unwrap_err().to_string(),
+      "3 of 4 rows differ by more than 100"
+    );
+  }
+
+  #[test]
+  fn test_tolerance() {
+    let before = state(vec![row(1, 10, 0, false)]);
+    let after =
+      state(vec![AccountOutput { available: Decimal::new(10001, 3), ..row(1, 10, 0, false) }]);
+    let diff = compare(before, after);
+    assert_eq!(diff.rows.len(), 1);
+    assert!(diff.check(Decimal::new(1, 2)).is_ok());
+    assert!(diff.check(Decimal::ZERO).is_err());
+    assert!(compare(State::new(), State::new()).check(Decimal::ZERO).is_ok());
+  }
+}
@@ -1,10 +1,22 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
 
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
 use thiserror::Error;
 use tracing::{debug, instrument, trace};
 
-use crate::account::{Account, AccountError};
-use crate::transaction::{StoredTransaction, TransactionRecord, TransactionType};
+use crate::account::{Account, AccountError, DEFAULT_CURRENCY};
+use crate::config::{EngineConfig, LockedInterest, UnlockPolicy};
+use crate::events::{self, AccountEvent};
+use crate::fees::FeeOverflow;
+use crate::limits::LimitsTable;
+use crate::rates::RateTable;
+use crate::rules::{Alert, RuleAction, RuleSet};
+use crate::storage::{MemoryStorage, Storage, StorageError};
+use crate::transaction::{
+  Conversion, DisputeState, Fee, StoredTransaction, TransactionRecord, TransactionType,
+};
 
 /// Can you stream values through memory as opposed to loading the entire dataset upfront? YES.
 /// This code processes each line of the csv individually and is limited by host memory.
@@ -23,211 +35,861 @@ use crate::transaction::{StoredTransaction, TransactionRecord, TransactionType};
 /// Would have a Per-client RwLock<Account> with separate transaction storage
 /// would implement external storage (redis or postgres) for horizontal scaling.
 /// This is currently not thread safe
+///
+/// State lives behind the `Storage` trait, the hashmaps by default or SQLite.
+/// Each record is applied inside one storage transaction so its writes land together
 pub struct Engine {
-  /// Client accounts indexed by client id
-  accounts: HashMap<u16, Account>,
-  ///  The stored transactions that can be disputed
-  transactions: HashMap<u32, StoredTransaction>,
+  /// Client accounts and the stored transactions that can be disputed
+  storage: Box<dyn Storage>,
+  config: EngineConfig,
+  /// Idempotent mode only: how many history entries of each transaction this run has
+  /// matched or added, so a replayed dispute lines up with the one it copies
+  replayed: HashMap<u32, usize>,
+  /// Exchange rates for convert records, empty unless configured
+  rates: RateTable,
+  /// Withdrawal limits per client or tier, empty unless configured
+  limits: LimitsTable,
+  /// Input records seen by this engine, the clock of `max_withdrawals_per_records`.
+  /// A rolled back batch takes its members back off
+  records: u64,
+  /// Transactions this engine added to storage, net of rollbacks, and the most there were
+  /// at once. Counted here since a count of the storage is a table scan with SQLite
+  stored: u64,
+  peak_stored: u64,
+  /// Record numbers of each client's recent withdrawals, only as far back as its
+  /// velocity window reaches. Kept for this run only
+  recent_withdrawals: HashMap<u16, VecDeque<u64>>,
+  /// Count and sum of each client's timestamped withdrawals per currency and UTC day.
+  /// Loaded from storage the first time the client's daily limits are checked and kept
+  /// up to date from then on
+  daily_withdrawals: HashMap<u16, DailyWithdrawals>,
+  /// Screening rules checked before a record is applied, empty unless configured
+  rules: RuleSet,
+  /// Rules matched since the last `take_alerts`
+  alerts: Vec<Alert>,
+  /// Account changes since the last `take_events`, None unless asked for with `with_events`
+  events: Option<Vec<AccountEvent>>,
+}
+
+/// What happened to a record that did not fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  Applied,
+  /// An exact copy of an applied record, only in idempotent mode
+  Skipped,
 }
 
 impl Engine {
   pub fn new() -> Self {
-    Self { accounts: HashMap::new(), transactions: HashMap::new() }
+    Self::with_config(EngineConfig::default())
+  }
+
+  pub fn with_config(config: EngineConfig) -> Self {
+    Self::with_storage(Box::new(MemoryStorage::new()), config)
+  }
+
+  pub fn with_storage(storage: Box<dyn Storage>, config: EngineConfig) -> Self {
+    Self {
+      storage,
+      config,
+      replayed: HashMap::new(),
+      rates: RateTable::default(),
+      limits: LimitsTable::default(),
+      records: 0,
+      stored: 0,
+      peak_stored: 0,
+      recent_withdrawals: HashMap::new(),
+      daily_withdrawals: HashMap::new(),
+      rules: RuleSet::default(),
+      alerts: Vec::new(),
+      events: None,
+    }
+  }
+
+  pub fn with_rates(mut self, rates: RateTable) -> Self {
+    self.rates = rates;
+    self
+  }
+
+  pub fn with_limits(mut self, limits: LimitsTable) -> Self {
+    self.limits = limits;
+    self
+  }
+
+  pub fn with_rules(mut self, rules: RuleSet) -> Self {
+    self.rules = rules;
+    self
+  }
+
+  /// Records an event per account change of every applied record, see `take_events`
+  pub fn with_events(mut self) -> Self {
+    self.events = Some(Vec::new());
+    self
+  }
+
+  /// The account events of the records applied since the last call, in the order they were
+  /// applied. A rolled back batch leaves none behind
+  pub fn take_events(&mut self) -> Vec<AccountEvent> {
+    self.events.as_mut().map(std::mem::take).unwrap_or_default()
+  }
+
+  /// The alerts raised by the screening rules since the last call. A record that failed
+  /// or was rolled back with its batch keeps its alerts
+  pub fn take_alerts(&mut self) -> Vec<Alert> {
+    std::mem::take(&mut self.alerts)
+  }
+
+  pub fn limits(&self) -> &LimitsTable {
+    &self.limits
+  }
+
+  pub fn config(&self) -> &EngineConfig {
+    &self.config
+  }
+
+  pub fn process(&mut self, record: TransactionRecord) -> Result<Outcome, EngineError> {
+    self.storage.begin()?;
+    let stored = self.stored;
+    let result = self.apply(record);
+    if let Err(EngineError::Storage(_)) = result {
+      // A storage failure can leave half a record written, throw it away and report the original error
+      let _ = self.storage.rollback();
+      self.stored = stored;
+      self.daily_withdrawals.clear();
+    } else {
+      // Business errors are checked before anything is written, whatever was written
+      // (like a new account on a failed withdrawal) is meant to stay
+      self.storage.commit()?;
+    }
+    result
+  }
+
+  /// Applies every record of the batch or none of them. The error names the member that failed
+  pub fn process_batch(
+    &mut self,
+    batch: u32,
+    records: Vec<TransactionRecord>,
+  ) -> Result<Vec<Outcome>, EngineError> {
+    self.storage.begin()?;
+    let replayed = self.replayed.clone();
+    let records_seen = self.records;
+    let stored = self.stored;
+    let recent_withdrawals = self.recent_withdrawals.clone();
+    let events = self.events.as_ref().map(Vec::len);
+    let mut outcomes = Vec::with_capacity(records.len());
+    for record in records {
+      let tx = record.tx;
+      match self.apply(record) {
+        Ok(outcome) => outcomes.push(outcome),
+        Err(error) => {
+          debug!(batch, tx, "Rolling back batch");
+          self.storage.rollback()?;
+          self.replayed = replayed;
+          self.records = records_seen;
+          self.stored = stored;
+          self.recent_withdrawals = recent_withdrawals;
+          // Loaded again from the rolled back storage when next needed
+          self.daily_withdrawals.clear();
+          if let (Some(kept), Some(events)) = (events, self.events.as_mut()) {
+            events.truncate(kept);
+          }
+          return Err(EngineError::BatchFailed { batch, tx, error: Box::new(error) });
+        }
+      }
+    }
+    self.storage.commit()?;
+    Ok(outcomes)
   }
 
-  pub fn process(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+  fn apply(&mut self, record: TransactionRecord) -> Result<Outcome, EngineError> {
+    self.records += 1;
+
+    // Before the timestamp check, a replayed record is older than the restored state
+    if self.config.idempotent && self.is_replay(&record)? {
+      trace!(tx = record.tx, "Skipping replayed record");
+      return Ok(Outcome::Skipped);
+    }
+
+    let (tx, client, timestamp) = (record.tx, record.client, record.timestamp);
+    let tracks_history = !matches!(
+      record.tx_type,
+      TransactionType::Deposit
+        | TransactionType::Withdrawal
+        | TransactionType::Convert
+        | TransactionType::AccrueInterest
+    );
+    if let Some(timestamp) = timestamp {
+      self.check_timestamp(tx, client, timestamp)?;
+    }
+    self.screen(&record)?;
+
+    let tx_type = record.tx_type;
+    let currency = record_currency(&record).to_string();
+    let before = match self.events {
+      Some(_) => Some(self.storage.account(client)?.unwrap_or_else(|| Account::new(client))),
+      None => None,
+    };
+
     match record.tx_type {
       TransactionType::Deposit => self.proc_deposit(record),
       TransactionType::Withdrawal => self.proc_withdrawal(record),
       TransactionType::Dispute => self.proc_dispute(record),
       TransactionType::Resolve => self.proc_resolve(record),
       TransactionType::Chargeback => self.proc_chargeback(record),
+      TransactionType::Representment => self.proc_representment(record),
+      TransactionType::Convert => self.proc_convert(record),
+      TransactionType::AccrueInterest => self.proc_accrue_interest(record),
+    }?;
+
+    // Only records that were applied move the client's clock forward
+    if let (Some(timestamp), Some(mut account)) = (timestamp, self.storage.account(client)?) {
+      account.last_timestamp = Some(timestamp);
+      self.storage.save_account(&account)?;
+    }
+
+    if let Some(before) = before {
+      let after = self.storage.account(client)?.unwrap_or_else(|| Account::new(client));
+      let stored = self.storage.transaction(tx)?;
+      let events =
+        events::for_record(tx_type, tx, timestamp, stored.as_ref(), &currency, before, after);
+      self.events.get_or_insert_with(Vec::new).extend(events);
+    }
+
+    // The new history entry counts as seen, a later copy of it is a replay
+    if self.config.idempotent && tracks_history {
+      if let Some(stored) = self.storage.transaction(tx)? {
+        self.replayed.insert(tx, stored.history.len());
+      }
     }
+    Ok(Outcome::Applied)
   }
 
-  #[instrument(skip(self), fields(tx = record.tx, client = record.client))]
-  fn proc_deposit(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+  /// Runs the screening rules against the client's stored transactions. Every match is
+  /// kept as an alert, a blocking one rejects the record before anything is written.
+  /// The history is only loaded when a rule looks at this type of record
+  fn screen(&mut self, record: &TransactionRecord) -> Result<(), EngineError> {
+    if !self.rules.screens(record.tx_type) {
+      return Ok(());
+    }
+    let history = self.storage.client_transactions(record.client)?;
+    let alerts = self.rules.evaluate(record, record_currency(record), &history);
+    let blocked = alerts.iter().find(|alert| alert.action == RuleAction::Block);
+    let error =
+      blocked.map(|alert| EngineError::Blocked { tx: record.tx, rule: alert.rule.clone() });
+    for alert in &alerts {
+      debug!(tx = record.tx, rule = %alert.rule, action = %alert.action, "Screening rule matched");
+    }
+    self.alerts.extend(alerts);
+    error.map_or(Ok(()), Err)
+  }
+
+  /// Idempotent mode. A deposit or withdrawal is a replay when the stored one has the same
+  /// type, client and amount. Disputes, resolves and chargebacks share the tx id of the
+  /// deposit, so they are matched in order against its history: the n-th dispute type
+  /// record seen for a tx is a replay of the n-th history entry.
+  /// A copy that does not match what was applied is a conflict and is rejected, and so is
+  /// one stamped at another time than what it copies when both sides have a timestamp
+  fn is_replay(&mut self, record: &TransactionRecord) -> Result<bool, EngineError> {
+    let Some(stored) = self.storage.transaction(record.tx)? else {
+      return Ok(false);
+    };
+    let conflict = |expected: TransactionType, expected_at: Option<DateTime<Utc>>| {
+      EngineError::ConflictingDuplicate {
+        tx: record.tx,
+        expected,
+        expected_at,
+        actual: record.tx_type,
+        actual_at: record.timestamp,
+      }
+    };
+    let same_time = |applied: Option<DateTime<Utc>>| match (applied, record.timestamp) {
+      (Some(applied), Some(copy)) => applied == copy,
+      _ => true,
+    };
+
+    let copies = match record.tx_type {
+      // A mismatch falls through to the usual DuplicateTransaction
+      TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Convert => {
+        stored.tx_type == record.tx_type
+          && stored.client == record.client
+          && stored.currency == record_currency(record)
+          && self.booked_amount(record).ok() == Some(stored.amount)
+          && stored.conversion.as_ref().map(|c| c.to_currency.as_str())
+            == record.to_currency.as_deref()
+      }
+      TransactionType::AccrueInterest => {
+        stored.tx_type == record.tx_type
+          && stored.client == record.client
+          && stored.currency == record_currency(record)
+      }
+      // Rejected with ClientMismatch as usual
+      _ if stored.client != record.client => return Ok(false),
+      _ => {
+        let seen = self.replayed.entry(record.tx).or_default();
+        return match stored.history.get(*seen) {
+          None => Ok(false),
+          Some(entry) if entry.action == record.tx_type && same_time(entry.timestamp) => {
+            *seen += 1;
+            Ok(true)
+          }
+          Some(entry) => Err(conflict(entry.action, entry.timestamp)),
+        };
+      }
+    };
+    if copies && !same_time(stored.timestamp) {
+      return Err(conflict(stored.tx_type, stored.timestamp));
+    }
+    Ok(copies)
+  }
+
+  /// The amount of a deposit, withdrawal or convert at the precision of its currency
+  fn booked_amount(&self, record: &TransactionRecord) -> Result<Decimal, EngineError> {
     let amount =
       record.amount.ok_or(EngineError::MissingAmount { tx: record.tx, tx_type: record.tx_type })?;
+    let currency = record_currency(record);
+    self.config.precision.fit(currency, amount).ok_or_else(|| EngineError::ExcessPrecision {
+      tx: record.tx,
+      amount,
+      currency: currency.to_string(),
+      scale: self.config.precision.scale(currency),
+    })
+  }
+
+  /// Records that carry a timestamp must not go back in time for the same client.
+  /// Equal timestamps are fine since the file order breaks the tie
+  fn check_timestamp(
+    &self,
+    tx: u32,
+    client: u16,
+    timestamp: DateTime<Utc>,
+  ) -> Result<(), EngineError> {
+    match self.storage.account(client)?.and_then(|a| a.last_timestamp) {
+      Some(last) if timestamp < last => {
+        Err(EngineError::TimestampOutOfOrder { tx, client, timestamp, last })
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Deposits and withdrawals create the account on first use, even if they then fail
+  fn open_account(&mut self, client: u16) -> Result<Account, EngineError> {
+    if let Some(account) = self.storage.account(client)? {
+      return Ok(account);
+    }
+    debug!(client, "Created new account");
+    let account = Account::new(client);
+    self.storage.save_account(&account)?;
+    Ok(account)
+  }
+
+  /// The stored transaction a dispute, resolve or chargeback refers to, checked against the client
+  fn disputed_transaction(
+    &self,
+    record: &TransactionRecord,
+  ) -> Result<StoredTransaction, EngineError> {
+    let stored_tx = self
+      .storage
+      .transaction(record.tx)?
+      .ok_or(EngineError::TransactionNotFound { tx: record.tx })?;
+
+    // Verify the client matches
+    if stored_tx.client != record.client {
+      return Err(EngineError::ClientMismatch {
+        tx: record.tx,
+        expected: stored_tx.client,
+        actual: record.client,
+      });
+    }
+
+    // The currency column is optional on disputes, but must not contradict the deposit
+    if let Some(currency) = &record.currency {
+      if *currency != stored_tx.currency {
+        return Err(EngineError::CurrencyMismatch {
+          tx: record.tx,
+          expected: stored_tx.currency,
+          actual: currency.clone(),
+        });
+      }
+    }
+    Ok(stored_tx)
+  }
+
+  fn existing_account(&self, client: u16) -> Result<Account, EngineError> {
+    self.storage.account(client)?.ok_or(EngineError::ClientNotFound { client })
+  }
+
+  #[instrument(skip(self), fields(tx = record.tx, client = record.client))]
+  fn proc_deposit(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+    let amount = self.booked_amount(&record)?;
 
     trace!(%amount, "Processing deposit");
 
     // Do we have a dupe Id?
-    if self.transactions.contains_key(&record.tx) {
+    if self.storage.contains_transaction(record.tx)? {
       return Err(EngineError::DuplicateTransaction { tx: record.tx });
     }
 
-    let is_new_account = !self.accounts.contains_key(&record.client);
-    let account = self.accounts.entry(record.client).or_insert_with(|| Account::new(record.client));
-
-    if is_new_account {
-      debug!(client = record.client, "Created new account");
-    }
+    let currency = record_currency(&record);
+    let mut account = self.open_account(record.client)?;
 
-    account.deposit(amount).map_err(|e| EngineError::AccountError {
+    account.deposit(currency, amount).map_err(|e| EngineError::AccountError {
       tx: record.tx,
       client: record.client,
       error: e,
     })?;
 
-    // Save the transaction
-    self
-      .transactions
-      .insert(record.tx, StoredTransaction::new(TransactionType::Deposit, record.client, amount));
+    let mut stored = StoredTransaction::new(
+      TransactionType::Deposit,
+      record.client,
+      currency,
+      amount,
+      record.timestamp,
+    );
+    let credit = self.limits.credit_line(record.client, currency);
+    self.charge_fee(&record, &mut account, &mut stored, Some(credit))?;
+
+    // Save the account and the transaction
+    self.storage.save_account(&account)?;
+    self.save_new_transaction(record.tx, &stored)?;
 
-    trace!(new_balance = %account.available, "Deposit complete");
+    trace!(new_balance = %account.balance(currency).available, "Deposit complete");
     Ok(())
   }
 
   fn proc_withdrawal(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
-    let amount =
-      record.amount.ok_or(EngineError::MissingAmount { tx: record.tx, tx_type: record.tx_type })?;
+    let amount = self.booked_amount(&record)?;
 
     // Do we have a dupe ID?
-    if self.transactions.contains_key(&record.tx) {
+    if self.storage.contains_transaction(record.tx)? {
       return Err(EngineError::DuplicateTransaction { tx: record.tx });
     }
 
-    let account = self.accounts.entry(record.client).or_insert_with(|| Account::new(record.client));
+    let currency = record_currency(&record);
+    let mut account = self.open_account(record.client)?;
 
-    account.withdraw(amount).map_err(|e| EngineError::AccountError {
+    let credit = self.limits.credit_line(record.client, currency);
+    account.withdraw(currency, amount, credit).map_err(|e| EngineError::AccountError {
       tx: record.tx,
       client: record.client,
       error: e,
     })?;
+    self.check_limits(&record, currency, amount)?;
+
+    let mut stored = StoredTransaction::new(
+      TransactionType::Withdrawal,
+      record.client,
+      currency,
+      amount,
+      record.timestamp,
+    );
+    self.charge_fee(&record, &mut account, &mut stored, Some(credit))?;
 
     // Store the transaction for potential future disputes
     // Note: The spec is ambiguous about whether withdrawals can be disputed
     // We store them to be safe, but only deposits make sense to dispute
-    self.transactions.insert(
-      record.tx,
-      StoredTransaction::new(TransactionType::Withdrawal, record.client, amount),
-    );
+    self.storage.save_account(&account)?;
+    self.save_new_transaction(record.tx, &stored)?;
+    self.recent_withdrawals.entry(record.client).or_default().push_back(self.records);
+    if let (Some(timestamp), Some(daily)) =
+      (record.timestamp, self.daily_withdrawals.get_mut(&record.client))
+    {
+      daily.add(currency, timestamp.date_naive(), amount);
+    }
 
     Ok(())
   }
 
-  fn proc_dispute(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
-    let stored_tx = self
-      .transactions
-      .get_mut(&record.tx)
-      .ok_or(EngineError::TransactionNotFound { tx: record.tx })?;
+  /// Debits the fee the schedule sets for `record` on the stored amount and notes the
+  /// posting on the stored transaction
+  fn charge_fee(
+    &self,
+    record: &TransactionRecord,
+    account: &mut Account,
+    stored: &mut StoredTransaction,
+    credit: Option<Decimal>,
+  ) -> Result<(), EngineError> {
+    let scale = self.config.precision.scale(&stored.currency);
+    let fee =
+      self.config.fees.fee(record.tx_type, stored.amount, scale).map_err(|FeeOverflow| {
+        EngineError::AmountOverflow { tx: record.tx, amount: stored.amount }
+      })?;
+    let Some(amount) = fee else {
+      return Ok(());
+    };
+    trace!(%amount, "Charging fee");
+    account
+      .charge_fee(&stored.currency, amount, credit)
+      .map_err(|e| EngineError::AccountError { tx: record.tx, client: record.client, error: e })?;
+    stored.fees.push(Fee { tx_type: record.tx_type, amount });
+    Ok(())
+  }
 
-    // Verify the client matches
-    if stored_tx.client != record.client {
-      return Err(EngineError::ClientMismatch {
-        tx: record.tx,
-        expected: stored_tx.client,
-        actual: record.client,
-      });
+  /// The client's withdrawal limits, checked once the funds are known to be there.
+  /// The daily limits count the stored withdrawals in the same currency on the record's
+  /// UTC day, so they need a timestamp and carry over between runs on saved state
+  fn check_limits(
+    &mut self,
+    record: &TransactionRecord,
+    currency: &str,
+    amount: Decimal,
+  ) -> Result<(), EngineError> {
+    let (tx, client) = (record.tx, record.client);
+    let limits = self.limits.for_client(client);
+
+    if let Some(limit) = limits.max_withdrawal {
+      if amount > limit {
+        return Err(EngineError::WithdrawalLimitExceeded { tx, client, amount, limit });
+      }
     }
 
-    // Check if already under dispute
-    if stored_tx.disputed {
-      return Err(EngineError::AlreadyDisputed { tx: record.tx });
+    if let Some(velocity) = limits.max_withdrawals_per_records {
+      let recent = self.recent_withdrawals.entry(client).or_default();
+      // The window is this record and the `records - 1` before it
+      let start = self.records.saturating_sub(velocity.records.into());
+      while recent.front().is_some_and(|&seen| seen <= start) {
+        recent.pop_front();
+      }
+      if recent.len() >= velocity.count as usize {
+        return Err(EngineError::VelocityLimitExceeded {
+          tx,
+          client,
+          count: velocity.count,
+          records: velocity.records,
+        });
+      }
     }
 
-    // Only deposits can be meaningfully disputed (reversing a deposit)
-    // Disputing a withdrawal would mean giving money back, which doesn't make sense
-    if stored_tx.tx_type != TransactionType::Deposit {
-      return Err(EngineError::CannotDisputeWithdrawal { tx: record.tx });
+    let daily = limits.max_withdrawals_per_day.is_some() || limits.max_daily_outflow.is_some();
+    if let (true, Some(timestamp)) = (daily, record.timestamp) {
+      let (count, outflow) = self.daily_withdrawals(client)?.get(currency, timestamp.date_naive());
+
+      if let Some(limit) = limits.max_withdrawals_per_day {
+        if count >= limit {
+          return Err(EngineError::DailyWithdrawalCountExceeded { tx, client, limit });
+        }
+      }
+      if let Some(limit) = limits.max_daily_outflow {
+        if outflow + amount > limit {
+          return Err(EngineError::DailyOutflowExceeded {
+            tx,
+            client,
+            outflow: outflow + amount,
+            limit,
+          });
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// The client's daily withdrawal totals, read from its stored transactions on first use
+  fn daily_withdrawals(&mut self, client: u16) -> Result<&DailyWithdrawals, EngineError> {
+    match self.daily_withdrawals.entry(client) {
+      Entry::Occupied(entry) => Ok(entry.into_mut()),
+      Entry::Vacant(entry) => {
+        let mut daily = DailyWithdrawals::default();
+        for (_, stored) in self.storage.client_transactions(client)? {
+          if let (TransactionType::Withdrawal, Some(at)) = (stored.tx_type, stored.timestamp) {
+            daily.add(&stored.currency, at.date_naive(), stored.amount);
+          }
+        }
+        Ok(entry.insert(daily))
+      }
     }
+  }
 
-    let account = self
-      .accounts
-      .get_mut(&record.client)
-      .ok_or(EngineError::ClientNotFound { client: record.client })?;
+  /// Sells `amount` of the record's currency for `to_currency` at the table rate.
+  /// The rate and the rounded result are kept with the stored transaction
+  fn proc_convert(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+    let amount = self.booked_amount(&record)?;
 
-    // Move funds from available to held
-    account.hold(stored_tx.amount).map_err(|e| EngineError::AccountError {
+    if self.storage.contains_transaction(record.tx)? {
+      return Err(EngineError::DuplicateTransaction { tx: record.tx });
+    }
+
+    let from = record_currency(&record);
+    let to =
+      record.to_currency.as_deref().ok_or(EngineError::MissingTargetCurrency { tx: record.tx })?;
+    if from == to {
+      return Err(EngineError::SameCurrency { tx: record.tx, currency: to.to_string() });
+    }
+    let rate = self.rates.rate(from, to).ok_or_else(|| EngineError::RateNotFound {
       tx: record.tx,
-      client: record.client,
-      error: e,
+      from: from.to_string(),
+      to: to.to_string(),
     })?;
+    let converted =
+      amount.checked_mul(rate).ok_or(EngineError::AmountOverflow { tx: record.tx, amount })?;
+    let converted =
+      self.config.conversion.rounding.round(converted, self.config.precision.scale(to));
+    trace!(%amount, %rate, %converted, from, to, "Processing conversion");
+
+    let mut account = self.open_account(record.client)?;
+
+    let account_error =
+      |e| EngineError::AccountError { tx: record.tx, client: record.client, error: e };
+    // A conversion only spends what is there, never the credit line
+    account.withdraw(from, amount, Decimal::ZERO).map_err(account_error)?;
+    account.deposit(to, converted).map_err(account_error)?;
+
+    let mut stored_tx = StoredTransaction::new(
+      TransactionType::Convert,
+      record.client,
+      from,
+      amount,
+      record.timestamp,
+    );
+    stored_tx.conversion = Some(Conversion { to_currency: to.to_string(), rate, converted });
+    self.storage.save_account(&account)?;
+    self.save_new_transaction(record.tx, &stored_tx)?;
+
+    Ok(())
+  }
+
+  /// Credits the interest on the client's available balance in the record's currency for
+  /// the days since the last accrual, or since its first stored transaction in that
+  /// currency. The credit is stored like a deposit but cannot be disputed
+  fn proc_accrue_interest(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+    let Some(interest) = self.config.interest.as_ref() else {
+      return Err(EngineError::InterestNotConfigured { tx: record.tx });
+    };
+    let timestamp = record
+      .timestamp
+      .ok_or(EngineError::MissingTimestamp { tx: record.tx, tx_type: record.tx_type })?;
+
+    if self.storage.contains_transaction(record.tx)? {
+      return Err(EngineError::DuplicateTransaction { tx: record.tx });
+    }
 
-    stored_tx.disputed = true;
+    let currency = record_currency(&record);
+    let mut account = self.existing_account(record.client)?;
+    let balance = account.balance(currency);
+    let from = match balance.accrued_to {
+      Some(from) => Some(from),
+      None => self
+        .storage
+        .client_transactions(record.client)?
+        .into_iter()
+        .filter(|(_, stored)| stored.currency == currency)
+        .filter_map(|(_, stored)| stored.timestamp)
+        .min(),
+    };
+
+    let skipped = account.locked && interest.locked == LockedInterest::Skip;
+    let amount = match from {
+      Some(from) if !skipped => {
+        let earned = interest.interest(balance.available.max(Decimal::ZERO), from, timestamp);
+        interest.rounding.round(earned, self.config.precision.scale(currency))
+      }
+      _ => Decimal::ZERO,
+    };
+    trace!(%amount, skipped, "Accruing interest");
+
+    account
+      .credit_interest(currency, amount, timestamp)
+      .map_err(|e| EngineError::AccountError { tx: record.tx, client: record.client, error: e })?;
+    self.storage.save_account(&account)?;
+    self.save_new_transaction(
+      record.tx,
+      &StoredTransaction::new(record.tx_type, record.client, currency, amount, Some(timestamp)),
+    )?;
+    Ok(())
+  }
 
+  /// Stores the transaction of a deposit, withdrawal, conversion or interest record,
+  /// whose tx id is known not to be taken
+  fn save_new_transaction(
+    &mut self,
+    tx: u32,
+    stored: &StoredTransaction,
+  ) -> Result<(), EngineError> {
+    self.storage.save_transaction(tx, stored)?;
+    self.stored += 1;
+    self.peak_stored = self.peak_stored.max(self.stored);
     Ok(())
   }
 
-  fn proc_resolve(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
-    let stored_tx = self
-      .transactions
-      .get_mut(&record.tx)
-      .ok_or(EngineError::TransactionNotFound { tx: record.tx })?;
+  fn proc_dispute(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+    let mut stored_tx = self.disputed_transaction(&record)?;
 
-    // Verify the client matches
-    if stored_tx.client != record.client {
-      return Err(EngineError::ClientMismatch {
-        tx: record.tx,
-        expected: stored_tx.client,
-        actual: record.client,
-      });
+    // Only normal or resolved transactions can be disputed
+    let next = next_state(record.tx, &stored_tx, record.tx_type)?;
+
+    // Only deposits can be meaningfully disputed (reversing a deposit)
+    // Disputing a withdrawal would mean giving money back, which doesn't make sense
+    match stored_tx.tx_type {
+      TransactionType::Deposit => {}
+      TransactionType::Withdrawal => {
+        return Err(EngineError::CannotDisputeWithdrawal { tx: record.tx });
+      }
+      tx_type => return Err(EngineError::NotDisputable { tx: record.tx, tx_type }),
     }
 
-    // Must be under dispute to resolve
-    if !stored_tx.disputed {
-      return Err(EngineError::NotUnderDispute { tx: record.tx });
+    // The window is only checked when both sides know when they happened
+    if let (Some(window), Some(opened), Some(disputed_at)) =
+      (self.config.dispute_window(), stored_tx.timestamp, record.timestamp)
+    {
+      if disputed_at - opened > window {
+        return Err(EngineError::DisputeWindowExpired { tx: record.tx });
+      }
     }
 
-    let account = self
-      .accounts
-      .get_mut(&record.client)
-      .ok_or(EngineError::ClientNotFound { client: record.client })?;
+    let mut account = self.existing_account(record.client)?;
+
+    // Move funds from available to held
+    let credit = self.limits.credit_line(record.client, &stored_tx.currency);
+    account
+      .hold(&stored_tx.currency, stored_tx.disputed_amount(), credit)
+      .map_err(|e| EngineError::AccountError { tx: record.tx, client: record.client, error: e })?;
+
+    stored_tx.transition(record.tx_type, next, record.timestamp);
+    self.storage.save_account(&account)?;
+    self.storage.save_transaction(record.tx, &stored_tx)?;
+
+    Ok(())
+  }
+
+  fn proc_resolve(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+    let mut stored_tx = self.disputed_transaction(&record)?;
+
+    // Must be under dispute to resolve
+    let next = next_state(record.tx, &stored_tx, record.tx_type)?;
+
+    let mut account = self.existing_account(record.client)?;
 
     // Move funds from held back to available
-    account.release(stored_tx.amount).map_err(|e| EngineError::AccountError {
-      tx: record.tx,
-      client: record.client,
-      error: e,
-    })?;
+    account
+      .release(&stored_tx.currency, stored_tx.disputed_amount())
+      .map_err(|e| EngineError::AccountError { tx: record.tx, client: record.client, error: e })?;
 
-    stored_tx.disputed = false;
+    stored_tx.transition(record.tx_type, next, record.timestamp);
+    self.storage.save_account(&account)?;
+    self.storage.save_transaction(record.tx, &stored_tx)?;
 
     Ok(())
   }
 
   fn proc_chargeback(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
-    let stored_tx = self
-      .transactions
-      .get_mut(&record.tx)
-      .ok_or(EngineError::TransactionNotFound { tx: record.tx })?;
-
-    // does the client match?
-    if stored_tx.client != record.client {
-      return Err(EngineError::ClientMismatch {
-        tx: record.tx,
-        expected: stored_tx.client,
-        actual: record.client,
-      });
-    }
+    let mut stored_tx = self.disputed_transaction(&record)?;
 
-    if !stored_tx.disputed {
-      return Err(EngineError::NotUnderDispute { tx: record.tx });
-    }
+    // Must be under dispute to charge back
+    let next = next_state(record.tx, &stored_tx, record.tx_type)?;
 
-    let account = self
-      .accounts
-      .get_mut(&record.client)
-      .ok_or(EngineError::ClientNotFound { client: record.client })?;
+    let mut account = self.existing_account(record.client)?;
 
     // Remove held funds and lock the account
-    account.chargeback(stored_tx.amount).map_err(|e| EngineError::AccountError {
-      tx: record.tx,
-      client: record.client,
-      error: e,
-    })?;
+    account
+      .chargeback(&stored_tx.currency, stored_tx.disputed_amount())
+      .map_err(|e| EngineError::AccountError { tx: record.tx, client: record.client, error: e })?;
+    // The chargeback fee is owed even when it overdraws the account
+    self.charge_fee(&record, &mut account, &mut stored_tx, None)?;
+
+    stored_tx.transition(record.tx_type, next, record.timestamp);
+    self.storage.save_account(&account)?;
+    self.storage.save_transaction(record.tx, &stored_tx)?;
+
+    Ok(())
+  }
+
+  fn proc_representment(&mut self, record: TransactionRecord) -> Result<(), EngineError> {
+    let mut stored_tx = self.disputed_transaction(&record)?;
+
+    // Only a charged back transaction can be won back
+    let next = next_state(record.tx, &stored_tx, record.tx_type)?;
 
-    stored_tx.disputed = false;
+    let unlock = match self.config.representment_unlock {
+      UnlockPolicy::Never => false,
+      UnlockPolicy::Always => true,
+      UnlockPolicy::LastChargeback => self
+        .storage
+        .client_transactions(record.client)?
+        .iter()
+        .all(|(tx, other)| *tx == record.tx || other.state != DisputeState::ChargedBack),
+    };
+
+    let mut account = self.existing_account(record.client)?;
+
+    // Give the charged back funds back, the lock depends on policy
+    account
+      .represent(&stored_tx.currency, stored_tx.disputed_amount(), unlock)
+      .map_err(|e| EngineError::AccountError { tx: record.tx, client: record.client, error: e })?;
+
+    stored_tx.transition(record.tx_type, next, record.timestamp);
+    self.storage.save_account(&account)?;
+    self.storage.save_transaction(record.tx, &stored_tx)?;
 
     Ok(())
   }
 
-  pub fn accounts(&self) -> impl Iterator<Item = &Account> {
-    self.accounts.values()
+  pub fn accounts(&self) -> Result<Vec<Account>, EngineError> {
+    Ok(self.storage.accounts()?)
+  }
+
+  pub fn account(&self, client: u16) -> Result<Option<Account>, EngineError> {
+    Ok(self.storage.account(client)?)
+  }
+
+  pub fn transaction(&self, tx: u32) -> Result<Option<StoredTransaction>, EngineError> {
+    Ok(self.storage.transaction(tx)?)
+  }
+
+  /// Every stored transaction of the client, in no particular order
+  pub fn client_transactions(
+    &self,
+    client: u16,
+  ) -> Result<Vec<(u32, StoredTransaction)>, EngineError> {
+    Ok(self.storage.client_transactions(client)?)
+  }
+
+  pub fn transaction_count(&self) -> Result<u64, EngineError> {
+    Ok(self.storage.transaction_count()?)
+  }
+
+  /// The most transactions the storage held at once, those it was opened with included.
+  /// A batch that was rolled back counts at its high point
+  pub fn peak_transaction_count(&self) -> Result<u64, EngineError> {
+    Ok(self.transaction_count()? - self.stored + self.peak_stored)
+  }
+}
+
+/// Withdrawal count and sum per currency and UTC day of one client
+#[derive(Debug, Default)]
+struct DailyWithdrawals(HashMap<(String, NaiveDate), (u32, Decimal)>);
+
+impl DailyWithdrawals {
+  fn add(&mut self, currency: &str, day: NaiveDate, amount: Decimal) {
+    let (count, sum) = self.0.entry((currency.to_string(), day)).or_default();
+    *count += 1;
+    *sum += amount;
+  }
+
+  fn get(&self, currency: &str, day: NaiveDate) -> (u32, Decimal) {
+    self.0.get(&(currency.to_string(), day)).copied().unwrap_or_default()
   }
 }
 
+/// The currency a deposit or withdrawal moves, the default when the column is empty
+fn record_currency(record: &TransactionRecord) -> &str {
+  record.currency.as_deref().unwrap_or(DEFAULT_CURRENCY)
+}
+
+/// Where a dispute type record takes the stored transaction, or the error for why it can't.
+/// The common mistakes keep their own errors, anything else is an invalid transition
+fn next_state(
+  tx: u32,
+  stored_tx: &StoredTransaction,
+  action: TransactionType,
+) -> Result<DisputeState, EngineError> {
+  stored_tx.state.next(action).ok_or(match (stored_tx.state, action) {
+    (DisputeState::Disputed, TransactionType::Dispute) => EngineError::AlreadyDisputed { tx },
+    (
+      DisputeState::Normal | DisputeState::Resolved,
+      TransactionType::Resolve | TransactionType::Chargeback,
+    ) => EngineError::NotUnderDispute { tx },
+    (from, action) => EngineError::InvalidTransition { tx, from, action },
+  })
+}
+
 impl Default for Engine {
   fn default() -> Self {
     Self::new()
@@ -240,6 +902,8 @@ impl Default for Engine {
 pub enum EngineError {
   #[error("tx {tx}: {tx_type:?} requires an amount")]
   MissingAmount { tx: u32, tx_type: TransactionType },
+  #[error("tx {tx}: {amount} has more than {scale} decimal places allowed for {currency}")]
+  ExcessPrecision { tx: u32, amount: Decimal, currency: String, scale: u32 },
   #[error("tx {tx}: duplicate transaction ID")]
   DuplicateTransaction { tx: u32 },
   #[error("tx {tx}: transaction not found")]
@@ -250,10 +914,54 @@ pub enum EngineError {
   ClientMismatch { tx: u32, expected: u16, actual: u16 },
   #[error("tx {tx}: already under dispute")]
   AlreadyDisputed { tx: u32 },
+  #[error("tx {tx}: currency mismatch (expected {expected}, got {actual})")]
+  CurrencyMismatch { tx: u32, expected: String, actual: String },
   #[error("tx {tx}: not under dispute")]
   NotUnderDispute { tx: u32 },
+  #[error("tx {tx}: {action} is not allowed once the transaction is {from}")]
+  InvalidTransition { tx: u32, from: DisputeState, action: TransactionType },
+  #[error(
+    "tx {tx}: conflicting duplicate, already applied {expected}{} but got {actual}{}",
+    at(expected_at),
+    at(actual_at)
+  )]
+  ConflictingDuplicate {
+    tx: u32,
+    expected: TransactionType,
+    expected_at: Option<DateTime<Utc>>,
+    actual: TransactionType,
+    actual_at: Option<DateTime<Utc>>,
+  },
   #[error("tx {tx}: cannot dispute a withdrawal")]
   CannotDisputeWithdrawal { tx: u32 },
+  #[error("tx {tx}: {tx_type} transactions cannot be disputed")]
+  NotDisputable { tx: u32, tx_type: TransactionType },
+  #[error("tx {tx}: convert requires a to_currency")]
+  MissingTargetCurrency { tx: u32 },
+  #[error("tx {tx}: cannot convert {currency} to itself")]
+  SameCurrency { tx: u32, currency: String },
+  #[error("tx {tx}: no rate from {from} to {to}")]
+  RateNotFound { tx: u32, from: String, to: String },
+  #[error("tx {tx}: {amount} is too large to convert or charge a fee on")]
+  AmountOverflow { tx: u32, amount: Decimal },
+  #[error("tx {tx} (client {client}): withdrawal of {amount} is over the limit of {limit}")]
+  WithdrawalLimitExceeded { tx: u32, client: u16, amount: Decimal, limit: Decimal },
+  #[error("tx {tx} (client {client}): more than {count} withdrawals in {records} records")]
+  VelocityLimitExceeded { tx: u32, client: u16, count: u32, records: u32 },
+  #[error("tx {tx} (client {client}): more than {limit} withdrawals in a day")]
+  DailyWithdrawalCountExceeded { tx: u32, client: u16, limit: u32 },
+  #[error("tx {tx} (client {client}): daily outflow of {outflow} is over the limit of {limit}")]
+  DailyOutflowExceeded { tx: u32, client: u16, outflow: Decimal, limit: Decimal },
+  #[error("tx {tx}: {tx_type} requires a timestamp")]
+  MissingTimestamp { tx: u32, tx_type: TransactionType },
+  #[error("tx {tx}: interest is not configured")]
+  InterestNotConfigured { tx: u32 },
+  #[error("tx {tx}: blocked by screening rule '{rule}'")]
+  Blocked { tx: u32, rule: String },
+  #[error("tx {tx}: dispute window has expired")]
+  DisputeWindowExpired { tx: u32 },
+  #[error("tx {tx} (client {client}): timestamp {timestamp} is before {last}")]
+  TimestampOutOfOrder { tx: u32, client: u16, timestamp: DateTime<Utc>, last: DateTime<Utc> },
   #[error("tx {tx} (client {client}): {error}")]
   AccountError {
     tx: u32,
@@ -261,6 +969,66 @@ pub enum EngineError {
     #[source]
     error: AccountError,
   },
+  #[error("batch {batch} rolled back, tx {tx} failed: {error}")]
+  BatchFailed {
+    batch: u32,
+    tx: u32,
+    #[source]
+    error: Box<EngineError>,
+  },
+  #[error("storage error: {0}")]
+  Storage(#[from] StorageError),
+}
+
+/// " at <timestamp>" for a conflicting duplicate that has one
+fn at(timestamp: &Option<DateTime<Utc>>) -> String {
+  timestamp.map(|ts| format!(" at {}", ts.to_rfc3339())).unwrap_or_default()
+}
+
+impl EngineError {
+  /// The variant name, for counting rejections by kind. An account error gives the
+  /// variant of the `AccountError` inside it
+  pub fn code(&self) -> &'static str {
+    match self {
+      EngineError::MissingAmount { .. } => "MissingAmount",
+      EngineError::ExcessPrecision { .. } => "ExcessPrecision",
+      EngineError::DuplicateTransaction { .. } => "DuplicateTransaction",
+      EngineError::TransactionNotFound { .. } => "TransactionNotFound",
+      EngineError::ClientNotFound { .. } => "ClientNotFound",
+      EngineError::ClientMismatch { .. } => "ClientMismatch",
+      EngineError::AlreadyDisputed { .. } => "AlreadyDisputed",
+      EngineError::CurrencyMismatch { .. } => "CurrencyMismatch",
+      EngineError::NotUnderDispute { .. } => "NotUnderDispute",
+      EngineError::InvalidTransition { .. } => "InvalidTransition",
+      EngineError::ConflictingDuplicate { .. } => "ConflictingDuplicate",
+      EngineError::CannotDisputeWithdrawal { .. } => "CannotDisputeWithdrawal",
+      EngineError::NotDisputable { .. } => "NotDisputable",
+      EngineError::MissingTargetCurrency { .. } => "MissingTargetCurrency",
+      EngineError::SameCurrency { .. } => "SameCurrency",
+      EngineError::RateNotFound { .. } => "RateNotFound",
+      EngineError::AmountOverflow { .. } => "AmountOverflow",
+      EngineError::WithdrawalLimitExceeded { .. } => "WithdrawalLimitExceeded",
+      EngineError::VelocityLimitExceeded { .. } => "VelocityLimitExceeded",
+      EngineError::DailyWithdrawalCountExceeded { .. } => "DailyWithdrawalCountExceeded",
+      EngineError::DailyOutflowExceeded { .. } => "DailyOutflowExceeded",
+      EngineError::MissingTimestamp { .. } => "MissingTimestamp",
+      EngineError::InterestNotConfigured { .. } => "InterestNotConfigured",
+      EngineError::Blocked { .. } => "Blocked",
+      EngineError::DisputeWindowExpired { .. } => "DisputeWindowExpired",
+      EngineError::TimestampOutOfOrder { .. } => "TimestampOutOfOrder",
+      EngineError::AccountError { error, .. } => error.code(),
+      EngineError::BatchFailed { .. } => "BatchFailed",
+      EngineError::Storage(_) => "Storage",
+    }
+  }
+
+  /// The error of the record that failed a batch, otherwise the error itself
+  pub fn cause(&self) -> &EngineError {
+    match self {
+      EngineError::BatchFailed { error, .. } => error,
+      error => error,
+    }
+  }
 }
 
 /// AI GENERATED TESTS
@@ -268,14 +1036,57 @@ pub enum EngineError {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::account::Balance;
+  use crate::config::{ConversionConfig, Excess, InterestConfig, PrecisionConfig, Rounding};
+  use crate::events::EventKind;
+  use crate::storage::SqliteStorage;
   use rust_decimal::Decimal;
 
+  const USD: &str = DEFAULT_CURRENCY;
+
+  /// Every test is generic over the storage backend, `backend_tests!` at the bottom
+  /// instantiates each one for all of them
+  trait Backend {
+    fn storage() -> Box<dyn Storage>;
+  }
+
+  struct Memory;
+  struct Sqlite;
+
+  impl Backend for Memory {
+    fn storage() -> Box<dyn Storage> {
+      Box::new(MemoryStorage::new())
+    }
+  }
+
+  impl Backend for Sqlite {
+    fn storage() -> Box<dyn Storage> {
+      Box::new(SqliteStorage::open_in_memory().unwrap())
+    }
+  }
+
+  fn new_engine<B: Backend>() -> Engine {
+    engine_with_config::<B>(EngineConfig::default())
+  }
+
+  fn engine_with_config<B: Backend>(config: EngineConfig) -> Engine {
+    Engine::with_storage(B::storage(), config)
+  }
+
+  fn account_of(engine: &Engine, client: u16) -> Account {
+    engine.account(client).unwrap().unwrap()
+  }
+
   fn deposit(client: u16, tx: u32, amount: &str) -> TransactionRecord {
     TransactionRecord {
       tx_type: TransactionType::Deposit,
       client,
       tx,
       amount: Some(amount.parse().unwrap()),
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
     }
   }
 
@@ -285,37 +1096,79 @@ mod tests {
       client,
       tx,
       amount: Some(amount.parse().unwrap()),
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
     }
   }
 
   fn dispute(client: u16, tx: u32) -> TransactionRecord {
-    TransactionRecord { tx_type: TransactionType::Dispute, client, tx, amount: None }
+    TransactionRecord {
+      tx_type: TransactionType::Dispute,
+      client,
+      tx,
+      amount: None,
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
   }
 
   fn resolve(client: u16, tx: u32) -> TransactionRecord {
-    TransactionRecord { tx_type: TransactionType::Resolve, client, tx, amount: None }
+    TransactionRecord {
+      tx_type: TransactionType::Resolve,
+      client,
+      tx,
+      amount: None,
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
   }
 
   fn chargeback(client: u16, tx: u32) -> TransactionRecord {
-    TransactionRecord { tx_type: TransactionType::Chargeback, client, tx, amount: None }
+    TransactionRecord {
+      tx_type: TransactionType::Chargeback,
+      client,
+      tx,
+      amount: None,
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  fn representment(client: u16, tx: u32) -> TransactionRecord {
+    TransactionRecord {
+      tx_type: TransactionType::Representment,
+      client,
+      tx,
+      amount: None,
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
   }
 
-  #[test]
-  fn test_basic_deposit_withdrawal() {
-    let mut engine = Engine::new();
+  fn test_basic_deposit_withdrawal<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(deposit(1, 2, "50.0")).unwrap();
     engine.process(withdrawal(1, 3, "75.0")).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::new(75, 0));
-    assert_eq!(account.total(), Decimal::new(75, 0));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(75, 0));
+    assert_eq!(account.balance(USD).total(), Decimal::new(75, 0));
   }
 
-  #[test]
-  fn test_withdrawal_insufficient_funds() {
-    let mut engine = Engine::new();
+  fn test_withdrawal_insufficient_funds<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "50.0")).unwrap();
     let result = engine.process(withdrawal(1, 2, "100.0"));
@@ -323,43 +1176,40 @@ mod tests {
     assert!(matches!(result, Err(EngineError::AccountError { .. })));
   }
 
-  #[test]
-  fn test_dispute_resolve_flow() {
-    let mut engine = Engine::new();
+  fn test_dispute_resolve_flow<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(dispute(1, 1)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.held, Decimal::new(100, 0));
-    assert_eq!(account.total(), Decimal::new(100, 0));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).total(), Decimal::new(100, 0));
 
     engine.process(resolve(1, 1)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
-    assert_eq!(account.held, Decimal::ZERO);
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
-  #[test]
-  fn test_dispute_chargeback_flow() {
-    let mut engine = Engine::new();
+  fn test_dispute_chargeback_flow<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(dispute(1, 1)).unwrap();
     engine.process(chargeback(1, 1)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.held, Decimal::ZERO);
-    assert_eq!(account.total(), Decimal::ZERO);
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::ZERO);
     assert!(account.locked);
   }
 
-  #[test]
-  fn test_dispute_nonexistent_tx() {
-    let mut engine = Engine::new();
+  fn test_dispute_nonexistent_tx<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     let result = engine.process(dispute(1, 999));
@@ -367,9 +1217,8 @@ mod tests {
     assert!(matches!(result, Err(EngineError::TransactionNotFound { .. })));
   }
 
-  #[test]
-  fn test_resolve_not_disputed() {
-    let mut engine = Engine::new();
+  fn test_resolve_not_disputed<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     let result = engine.process(resolve(1, 1));
@@ -377,9 +1226,8 @@ mod tests {
     assert!(matches!(result, Err(EngineError::NotUnderDispute { .. })));
   }
 
-  #[test]
-  fn test_client_mismatch() {
-    let mut engine = Engine::new();
+  fn test_client_mismatch<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     // Client 2 tries to dispute client 1's transaction
@@ -388,24 +1236,22 @@ mod tests {
     assert!(matches!(result, Err(EngineError::ClientMismatch { .. })));
   }
 
-  #[test]
-  fn test_multiple_clients() {
-    let mut engine = Engine::new();
+  fn test_multiple_clients<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(deposit(2, 2, "200.0")).unwrap();
     engine.process(withdrawal(1, 3, "50.0")).unwrap();
 
-    let account1 = engine.accounts.get(&1).unwrap();
-    let account2 = engine.accounts.get(&2).unwrap();
+    let account1 = account_of(&engine, 1);
+    let account2 = account_of(&engine, 2);
 
-    assert_eq!(account1.available, Decimal::new(50, 0));
-    assert_eq!(account2.available, Decimal::new(200, 0));
+    assert_eq!(account1.balance(USD).available, Decimal::new(50, 0));
+    assert_eq!(account2.balance(USD).available, Decimal::new(200, 0));
   }
 
-  #[test]
-  fn test_duplicate_transaction_id() {
-    let mut engine = Engine::new();
+  fn test_duplicate_transaction_id<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     let result = engine.process(deposit(1, 1, "50.0"));
@@ -417,46 +1263,41 @@ mod tests {
   // EDGE CASE UNIT TESTS
   // =========================================================================
 
-  #[test]
-  fn test_zero_amount_deposit() {
-    let mut engine = Engine::new();
+  fn test_zero_amount_deposit<B: Backend>() {
+    let mut engine = new_engine::<B>();
     engine.process(deposit(1, 1, "0.0")).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.total(), Decimal::ZERO);
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::ZERO);
   }
 
-  #[test]
-  fn test_zero_amount_withdrawal() {
-    let mut engine = Engine::new();
+  fn test_zero_amount_withdrawal<B: Backend>() {
+    let mut engine = new_engine::<B>();
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(withdrawal(1, 2, "0.0")).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
   }
 
-  #[test]
-  fn test_negative_deposit_rejected() {
-    let mut engine = Engine::new();
+  fn test_negative_deposit_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
     let result = engine.process(deposit(1, 1, "-100.0"));
 
     assert!(matches!(result, Err(EngineError::AccountError { .. })));
   }
 
-  #[test]
-  fn test_negative_withdrawal_rejected() {
-    let mut engine = Engine::new();
+  fn test_negative_withdrawal_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
     engine.process(deposit(1, 1, "100.0")).unwrap();
     let result = engine.process(withdrawal(1, 2, "-50.0"));
 
     assert!(matches!(result, Err(EngineError::AccountError { .. })));
   }
 
-  #[test]
-  fn test_redispute_after_resolve() {
-    let mut engine = Engine::new();
+  fn test_redispute_after_resolve<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(dispute(1, 1)).unwrap();
@@ -464,14 +1305,13 @@ mod tests {
     // Should be able to dispute again
     engine.process(dispute(1, 1)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.held, Decimal::new(100, 0));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
   }
 
-  #[test]
-  fn test_double_dispute_rejected() {
-    let mut engine = Engine::new();
+  fn test_double_dispute_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(dispute(1, 1)).unwrap();
@@ -480,9 +1320,8 @@ mod tests {
     assert!(matches!(result, Err(EngineError::AlreadyDisputed { .. })));
   }
 
-  #[test]
-  fn test_dispute_withdrawal_rejected() {
-    let mut engine = Engine::new();
+  fn test_dispute_withdrawal_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(withdrawal(1, 2, "50.0")).unwrap();
@@ -491,9 +1330,8 @@ mod tests {
     assert!(matches!(result, Err(EngineError::CannotDisputeWithdrawal { .. })));
   }
 
-  #[test]
-  fn test_chargeback_without_dispute_rejected() {
-    let mut engine = Engine::new();
+  fn test_chargeback_without_dispute_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     let result = engine.process(chargeback(1, 1));
@@ -501,9 +1339,8 @@ mod tests {
     assert!(matches!(result, Err(EngineError::NotUnderDispute { .. })));
   }
 
-  #[test]
-  fn test_dispute_insufficient_funds() {
-    let mut engine = Engine::new();
+  fn test_dispute_insufficient_funds<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(withdrawal(1, 2, "80.0")).unwrap();
@@ -513,9 +1350,8 @@ mod tests {
     assert!(matches!(result, Err(EngineError::AccountError { .. })));
   }
 
-  #[test]
-  fn test_duplicate_tx_id_different_clients() {
-    let mut engine = Engine::new();
+  fn test_duplicate_tx_id_different_clients<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     // Same tx ID, different client - should fail
@@ -524,22 +1360,20 @@ mod tests {
     assert!(matches!(result, Err(EngineError::DuplicateTransaction { .. })));
   }
 
-  #[test]
-  fn test_withdrawal_creates_account() {
-    let mut engine = Engine::new();
+  fn test_withdrawal_creates_account<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     // Withdrawal on non-existent account creates it then fails
     let result = engine.process(withdrawal(1, 1, "50.0"));
     assert!(matches!(result, Err(EngineError::AccountError { .. })));
 
     // Account should exist with zero balance
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::ZERO);
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
   }
 
-  #[test]
-  fn test_dispute_on_locked_account() {
-    let mut engine = Engine::new();
+  fn test_dispute_on_locked_account<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(deposit(1, 2, "50.0")).unwrap();
@@ -549,15 +1383,14 @@ mod tests {
     // Account is now locked, but dispute on tx 2 should still work
     engine.process(dispute(1, 2)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
+    let account = account_of(&engine, 1);
     assert!(account.locked);
-    assert_eq!(account.available, Decimal::ZERO);
-    assert_eq!(account.held, Decimal::new(50, 0));
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::new(50, 0));
   }
 
-  #[test]
-  fn test_resolve_on_locked_account() {
-    let mut engine = Engine::new();
+  fn test_resolve_on_locked_account<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
     engine.process(deposit(1, 2, "50.0")).unwrap();
@@ -568,117 +1401,1407 @@ mod tests {
     // Account is now locked, but resolve on tx 2 should still work
     engine.process(resolve(1, 2)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
+    let account = account_of(&engine, 1);
     assert!(account.locked);
-    assert_eq!(account.available, Decimal::new(50, 0));
-    assert_eq!(account.held, Decimal::ZERO);
+    assert_eq!(account.balance(USD).available, Decimal::new(50, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
   }
 
-  #[test]
-  fn test_transaction_id_zero() {
-    let mut engine = Engine::new();
+  fn test_transaction_id_zero<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 0, "100.0")).unwrap();
     engine.process(dispute(1, 0)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.held, Decimal::new(100, 0));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
   }
 
-  #[test]
-  fn test_transaction_id_max() {
-    let mut engine = Engine::new();
+  fn test_transaction_id_max<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, u32::MAX, "100.0")).unwrap();
     engine.process(dispute(1, u32::MAX)).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.held, Decimal::new(100, 0));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
   }
 
-  #[test]
-  fn test_client_id_zero() {
-    let mut engine = Engine::new();
+  fn test_client_id_zero<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(0, 1, "100.0")).unwrap();
 
-    let account = engine.accounts.get(&0).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
+    let account = account_of(&engine, 0);
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
   }
 
-  #[test]
-  fn test_client_id_max() {
-    let mut engine = Engine::new();
+  fn test_client_id_max<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(u16::MAX, 1, "100.0")).unwrap();
 
-    let account = engine.accounts.get(&u16::MAX).unwrap();
-    assert_eq!(account.available, Decimal::new(100, 0));
+    let account = account_of(&engine, u16::MAX);
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
   }
 
-  #[test]
-  fn test_very_small_amount() {
-    let mut engine = Engine::new();
+  fn test_very_small_amount<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "0.0001")).unwrap();
     engine.process(deposit(1, 2, "0.0001")).unwrap();
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::new(2, 4)); // 0.0002
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(2, 4)); // 0.0002
   }
 
-  #[test]
-  fn test_precision_accumulation() {
-    let mut engine = Engine::new();
+  fn test_precision_accumulation<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     // 10 deposits of 0.0001 should equal exactly 0.001
     for i in 1..=10 {
       engine.process(deposit(1, i, "0.0001")).unwrap();
     }
 
-    let account = engine.accounts.get(&1).unwrap();
-    assert_eq!(account.available, Decimal::new(10, 4)); // 0.0010
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(10, 4)); // 0.0010
   }
 
-  #[test]
-  fn test_multiple_dispute_resolve_cycles() {
-    let mut engine = Engine::new();
+  fn test_multiple_dispute_resolve_cycles<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
 
     // Multiple cycles
     for _ in 0..5 {
       engine.process(dispute(1, 1)).unwrap();
-      let account = engine.accounts.get(&1).unwrap();
-      assert_eq!(account.held, Decimal::new(100, 0));
+      let account = account_of(&engine, 1);
+      assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
 
       engine.process(resolve(1, 1)).unwrap();
-      let account = engine.accounts.get(&1).unwrap();
-      assert_eq!(account.available, Decimal::new(100, 0));
+      let account = account_of(&engine, 1);
+      assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
     }
   }
 
-  #[test]
-  fn test_missing_amount_deposit() {
-    let mut engine = Engine::new();
+  fn test_missing_amount_deposit<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
-    let record =
-      TransactionRecord { tx_type: TransactionType::Deposit, client: 1, tx: 1, amount: None };
+    let record = TransactionRecord {
+      tx_type: TransactionType::Deposit,
+      client: 1,
+      tx: 1,
+      amount: None,
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    };
     let result = engine.process(record);
 
     assert!(matches!(result, Err(EngineError::MissingAmount { .. })));
   }
 
-  #[test]
-  fn test_missing_amount_withdrawal() {
-    let mut engine = Engine::new();
+  fn test_missing_amount_withdrawal<B: Backend>() {
+    let mut engine = new_engine::<B>();
 
     engine.process(deposit(1, 1, "100.0")).unwrap();
 
-    let record =
-      TransactionRecord { tx_type: TransactionType::Withdrawal, client: 1, tx: 2, amount: None };
+    let record = TransactionRecord {
+      tx_type: TransactionType::Withdrawal,
+      client: 1,
+      tx: 2,
+      amount: None,
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    };
     let result = engine.process(record);
 
     assert!(matches!(result, Err(EngineError::MissingAmount { .. })));
   }
+
+  // =========================================================================
+  // TIMESTAMP TESTS
+  // =========================================================================
+
+  fn at(record: TransactionRecord, timestamp: &str) -> TransactionRecord {
+    let timestamp = crate::transaction::parse_timestamp(timestamp).unwrap();
+    TransactionRecord { timestamp: Some(timestamp), ..record }
+  }
+
+  fn test_timestamps_in_order_accepted<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process(at(deposit(1, 1, "100.0"), "2024-01-01T00:00:00Z")).unwrap();
+    engine.process(at(deposit(1, 2, "50.0"), "2024-01-01T00:00:00Z")).unwrap();
+    engine.process(at(withdrawal(1, 3, "25.0"), "2024-01-02T00:00:00Z")).unwrap();
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(125, 0));
+    assert_eq!(
+      account.last_timestamp,
+      Some(crate::transaction::parse_timestamp("1704153600").unwrap())
+    );
+  }
+
+  fn test_timestamp_out_of_order_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process(at(deposit(1, 1, "100.0"), "2024-01-02T00:00:00Z")).unwrap();
+    let result = engine.process(at(deposit(1, 2, "50.0"), "2024-01-01T00:00:00Z"));
+
+    assert!(matches!(result, Err(EngineError::TimestampOutOfOrder { tx: 2, client: 1, .. })));
+    assert!(!engine.storage.contains_transaction(2).unwrap());
+  }
+
+  fn test_timestamps_are_per_client<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process(at(deposit(1, 1, "100.0"), "2024-01-02T00:00:00Z")).unwrap();
+    engine.process(at(deposit(2, 2, "50.0"), "2024-01-01T00:00:00Z")).unwrap();
+  }
+
+  fn test_rejected_record_does_not_advance_clock<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process(at(deposit(1, 1, "100.0"), "2024-01-01T00:00:00Z")).unwrap();
+    assert!(engine.process(at(withdrawal(1, 2, "500.0"), "2024-01-05T00:00:00Z")).is_err());
+    engine.process(at(withdrawal(1, 3, "50.0"), "2024-01-02T00:00:00Z")).unwrap();
+  }
+
+  fn test_dispute_window_expired<B: Backend>() {
+    let config = EngineConfig { dispute_window_days: Some(30), ..Default::default() };
+    let mut engine = engine_with_config::<B>(config);
+
+    engine.process(at(deposit(1, 1, "100.0"), "2024-01-01T00:00:00Z")).unwrap();
+    engine.process(at(deposit(1, 2, "100.0"), "2024-01-20T00:00:00Z")).unwrap();
+
+    let result = engine.process(at(dispute(1, 1), "2024-02-15T00:00:00Z"));
+    assert!(matches!(result, Err(EngineError::DisputeWindowExpired { tx: 1 })));
+
+    engine.process(at(dispute(1, 2), "2024-02-15T00:00:00Z")).unwrap();
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
+  }
+
+  fn test_dispute_window_needs_both_timestamps<B: Backend>() {
+    let config = EngineConfig { dispute_window_days: Some(1), ..Default::default() };
+    let mut engine = engine_with_config::<B>(config);
+
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    engine.process(at(dispute(1, 1), "2030-01-01T00:00:00Z")).unwrap();
+  }
+
+  // =========================================================================
+  // BATCH TESTS
+  // =========================================================================
+
+  fn test_batch_applied_together<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    engine.process(deposit(2, 2, "100.0")).unwrap();
+    engine.process_batch(7, vec![withdrawal(1, 3, "40.0"), withdrawal(2, 4, "60.0")]).unwrap();
+
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(60, 0));
+    assert_eq!(account_of(&engine, 2).balance(USD).available, Decimal::new(40, 0));
+  }
+
+  fn test_batch_rolled_back_on_first_error<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    engine.process(deposit(2, 2, "10.0")).unwrap();
+    let result = engine.process_batch(
+      7,
+      vec![withdrawal(1, 3, "40.0"), deposit(3, 4, "5.0"), withdrawal(2, 5, "60.0")],
+    );
+
+    match result {
+      Err(EngineError::BatchFailed { batch: 7, tx: 5, error }) => {
+        assert!(matches!(*error, EngineError::AccountError { client: 2, .. }))
+      }
+      other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account_of(&engine, 2).balance(USD).available, Decimal::new(10, 0));
+    // Nothing from the batch survives, not even the account it created or its tx ids
+    assert!(engine.account(3).unwrap().is_none());
+    assert!(!engine.storage.contains_transaction(3).unwrap());
+    engine.process(withdrawal(1, 3, "40.0")).unwrap();
+  }
+
+  fn test_batch_members_see_each_other<B: Backend>() {
+    let mut engine = new_engine::<B>();
+
+    engine.process_batch(1, vec![deposit(1, 1, "100.0"), dispute(1, 1), resolve(1, 1)]).unwrap();
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+  }
+
+  // =========================================================================
+  // IDEMPOTENCY TESTS
+  // =========================================================================
+
+  fn history_file() -> Vec<TransactionRecord> {
+    vec![
+      deposit(1, 1, "100.0"),
+      deposit(1, 2, "50.0"),
+      withdrawal(1, 3, "30.0"),
+      dispute(1, 1),
+      resolve(1, 1),
+      dispute(1, 1),
+    ]
+  }
+
+  /// A second run over the state the first one left behind
+  fn rerun(engine: Engine) -> Engine {
+    let config = EngineConfig { idempotent: true, ..Default::default() };
+    Engine::with_storage(engine.storage, config)
+  }
+
+  fn test_idempotent_replay_skips_exact_duplicates<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    for record in history_file() {
+      engine.process(record).unwrap();
+    }
+
+    let mut engine = rerun(engine);
+    for record in history_file() {
+      assert_eq!(engine.process(record).unwrap(), Outcome::Skipped);
+    }
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(20, 0));
+    assert_eq!(account.balance(USD).held, Decimal::new(100, 0));
+  }
+
+  fn test_idempotent_new_records_after_replay_applied<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    for record in history_file() {
+      engine.process(record).unwrap();
+    }
+
+    let mut engine = rerun(engine);
+    let mut records = history_file();
+    records.push(chargeback(1, 1));
+    records.push(deposit(2, 4, "5.0"));
+    let outcomes: Vec<Outcome> = records.into_iter().map(|r| engine.process(r).unwrap()).collect();
+
+    assert_eq!(outcomes[6..], [Outcome::Applied, Outcome::Applied]);
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+    assert!(account.locked);
+  }
+
+  fn test_idempotent_conflicting_duplicates_rejected<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    for record in history_file() {
+      engine.process(record).unwrap();
+    }
+
+    let mut engine = rerun(engine);
+    let result = engine.process(deposit(1, 1, "99.0"));
+    assert!(matches!(result, Err(EngineError::DuplicateTransaction { tx: 1 })));
+    let result = engine.process(deposit(2, 2, "50.0"));
+    assert!(matches!(result, Err(EngineError::DuplicateTransaction { tx: 2 })));
+
+    // History of tx 1 is dispute, resolve, dispute
+    assert_eq!(engine.process(dispute(1, 1)).unwrap(), Outcome::Skipped);
+    let result = engine.process(chargeback(1, 1));
+    assert!(matches!(
+      result,
+      Err(EngineError::ConflictingDuplicate {
+        tx: 1,
+        expected: TransactionType::Resolve,
+        actual: TransactionType::Chargeback,
+        ..
+      })
+    ));
+  }
+
+  fn test_idempotent_timestamps_must_match<B: Backend>() {
+    let timed = || {
+      vec![
+        at(deposit(1, 1, "100.0"), "2024-01-01T00:00:00Z"),
+        at(dispute(1, 1), "2024-01-02T00:00:00Z"),
+        at(resolve(1, 1), "2024-01-03T00:00:00Z"),
+      ]
+    };
+    let mut engine = new_engine::<B>();
+    for record in timed() {
+      engine.process(record).unwrap();
+    }
+
+    let mut engine = rerun(engine);
+    // A new dispute on the resolved deposit is not a copy of the first one
+    let result = engine.process(at(dispute(1, 1), "2024-02-01T00:00:00Z"));
+    assert!(matches!(
+      result,
+      Err(EngineError::ConflictingDuplicate {
+        tx: 1,
+        expected: TransactionType::Dispute,
+        actual: TransactionType::Dispute,
+        ..
+      })
+    ));
+    assert_eq!(
+      result.unwrap_err().to_string(),
+      "tx 1: conflicting duplicate, already applied dispute at 2024-01-02T00:00:00+00:00 \
+       but got dispute at 2024-02-01T00:00:00+00:00"
+    );
+    let result = engine.process(at(deposit(1, 1, "100.0"), "2024-01-05T00:00:00Z"));
+    assert!(matches!(result, Err(EngineError::ConflictingDuplicate { tx: 1, .. })));
+
+    for record in timed() {
+      assert_eq!(engine.process(record).unwrap(), Outcome::Skipped);
+    }
+    // Past the history a new dispute is applied as usual
+    assert_eq!(
+      engine.process(at(dispute(1, 1), "2024-02-01T00:00:00Z")).unwrap(),
+      Outcome::Applied
+    );
+    assert_eq!(account_of(&engine, 1).balance(USD).held, Decimal::new(100, 0));
+  }
+
+  fn test_idempotent_duplicates_within_one_run<B: Backend>() {
+    let config = EngineConfig { idempotent: true, ..Default::default() };
+    let mut engine = engine_with_config::<B>(config);
+
+    assert_eq!(engine.process(deposit(1, 1, "100.0")).unwrap(), Outcome::Applied);
+    assert_eq!(engine.process(deposit(1, 1, "100.0")).unwrap(), Outcome::Skipped);
+    assert_eq!(engine.process(dispute(1, 1)).unwrap(), Outcome::Applied);
+    // Not a replay, a second dispute while the first is open
+    let result = engine.process(dispute(1, 1));
+    assert!(matches!(result, Err(EngineError::AlreadyDisputed { .. })));
+  }
+
+  fn test_history_recorded<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    for record in history_file() {
+      engine.process(record).unwrap();
+    }
+
+    let stored = engine.storage.transaction(1).unwrap().unwrap();
+    let actions: Vec<TransactionType> = stored.history.iter().map(|h| h.action).collect();
+    assert_eq!(
+      actions,
+      vec![TransactionType::Dispute, TransactionType::Resolve, TransactionType::Dispute]
+    );
+  }
+
+  // =========================================================================
+  // DISPUTE LIFECYCLE
+  // =========================================================================
+
+  fn test_states_follow_lifecycle<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "10.0")).unwrap();
+    let state = |engine: &Engine| engine.storage.transaction(1).unwrap().unwrap().state;
+    assert_eq!(state(&engine), DisputeState::Normal);
+
+    engine.process(dispute(1, 1)).unwrap();
+    assert_eq!(state(&engine), DisputeState::Disputed);
+    engine.process(resolve(1, 1)).unwrap();
+    assert_eq!(state(&engine), DisputeState::Resolved);
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(chargeback(1, 1)).unwrap();
+    assert_eq!(state(&engine), DisputeState::ChargedBack);
+  }
+
+  fn test_charged_back_cannot_be_disputed_again<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "10.0")).unwrap();
+    engine.process(deposit(1, 2, "5.0")).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(chargeback(1, 1)).unwrap();
+
+    let result = engine.process(dispute(1, 1));
+    assert!(matches!(
+      result,
+      Err(EngineError::InvalidTransition {
+        tx: 1,
+        from: DisputeState::ChargedBack,
+        action: TransactionType::Dispute
+      })
+    ));
+    let result = engine.process(resolve(1, 1));
+    assert!(matches!(result, Err(EngineError::InvalidTransition { .. })));
+
+    // Nothing moved
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(5, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+  }
+
+  fn test_resolved_cannot_be_charged_back<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "10.0")).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(resolve(1, 1)).unwrap();
+
+    let result = engine.process(chargeback(1, 1));
+    assert!(matches!(result, Err(EngineError::NotUnderDispute { .. })));
+    assert!(!account_of(&engine, 1).locked);
+  }
+
+  fn test_transitions_recorded<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "10.0")).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(resolve(1, 1)).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(chargeback(1, 1)).unwrap();
+    // Rejected records leave no trace
+    engine.process(dispute(1, 1)).unwrap_err();
+
+    let stored = engine.storage.transaction(1).unwrap().unwrap();
+    let transitions: Vec<_> = stored.history.iter().map(|t| (t.action, t.from, t.to)).collect();
+    assert_eq!(
+      transitions,
+      vec![
+        (TransactionType::Dispute, DisputeState::Normal, DisputeState::Disputed),
+        (TransactionType::Resolve, DisputeState::Disputed, DisputeState::Resolved),
+        (TransactionType::Dispute, DisputeState::Resolved, DisputeState::Disputed),
+        (TransactionType::Chargeback, DisputeState::Disputed, DisputeState::ChargedBack),
+      ]
+    );
+  }
+
+  // =========================================================================
+  // REPRESENTMENT
+  // =========================================================================
+
+  fn charged_back<B: Backend>(config: EngineConfig) -> Engine {
+    let mut engine = engine_with_config::<B>(config);
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    engine.process(deposit(1, 2, "20.0")).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(chargeback(1, 1)).unwrap();
+    engine
+  }
+
+  fn test_representment_restores_funds<B: Backend>() {
+    let mut engine = charged_back::<B>(EngineConfig::default());
+    engine.process(representment(1, 1)).unwrap();
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(120, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+    // Unlocking is off by default
+    assert!(account.locked);
+    let stored = engine.storage.transaction(1).unwrap().unwrap();
+    assert_eq!(stored.state, DisputeState::Represented);
+  }
+
+  fn test_representment_only_after_chargeback<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    let result = engine.process(representment(1, 1));
+    assert!(matches!(
+      result,
+      Err(EngineError::InvalidTransition { from: DisputeState::Normal, .. })
+    ));
+
+    engine.process(dispute(1, 1)).unwrap();
+    let result = engine.process(representment(1, 1));
+    assert!(matches!(
+      result,
+      Err(EngineError::InvalidTransition { from: DisputeState::Disputed, .. })
+    ));
+
+    engine.process(chargeback(1, 1)).unwrap();
+    engine.process(representment(1, 1)).unwrap();
+    let result = engine.process(representment(1, 1));
+    assert!(matches!(
+      result,
+      Err(EngineError::InvalidTransition { from: DisputeState::Represented, .. })
+    ));
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(100, 0));
+  }
+
+  fn test_representment_always_unlocks<B: Backend>() {
+    let config = EngineConfig { representment_unlock: UnlockPolicy::Always, ..Default::default() };
+    let mut engine = charged_back::<B>(config);
+    engine.process(representment(1, 1)).unwrap();
+
+    assert!(!account_of(&engine, 1).locked);
+    engine.process(deposit(1, 3, "1.0")).unwrap();
+  }
+
+  fn test_representment_unlocks_after_last_chargeback<B: Backend>() {
+    let config =
+      EngineConfig { representment_unlock: UnlockPolicy::LastChargeback, ..Default::default() };
+    let mut engine = charged_back::<B>(config);
+    engine.process(dispute(1, 2)).unwrap();
+    engine.process(chargeback(1, 2)).unwrap();
+
+    // tx 2 is still charged back
+    engine.process(representment(1, 1)).unwrap();
+    assert!(account_of(&engine, 1).locked);
+
+    engine.process(representment(1, 2)).unwrap();
+    let account = account_of(&engine, 1);
+    assert!(!account.locked);
+    assert_eq!(account.balance(USD).available, Decimal::new(120, 0));
+  }
+
+  // =========================================================================
+  // CURRENCY
+  // =========================================================================
+
+  fn in_currency(record: TransactionRecord, currency: &str) -> TransactionRecord {
+    TransactionRecord { currency: Some(currency.to_string()), ..record }
+  }
+
+  fn test_currencies_have_separate_balances<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    engine.process(in_currency(deposit(1, 2, "40.0"), "EUR")).unwrap();
+
+    // Euros cannot pay for a dollar withdrawal
+    let result = engine.process(withdrawal(1, 3, "120.0"));
+    assert!(matches!(result, Err(EngineError::AccountError { .. })));
+    engine.process(in_currency(withdrawal(1, 4, "15.0"), "EUR")).unwrap();
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::new(100, 0));
+    assert_eq!(account.balance("EUR").available, Decimal::new(25, 0));
+  }
+
+  fn test_dispute_in_deposit_currency<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "100.0")).unwrap();
+    engine.process(in_currency(deposit(1, 2, "40.0"), "EUR")).unwrap();
+    engine.process(dispute(1, 2)).unwrap();
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance("EUR").held, Decimal::new(40, 0));
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+
+    engine.process(in_currency(chargeback(1, 2), "EUR")).unwrap();
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance("EUR").total(), Decimal::ZERO);
+    assert_eq!(account.balance(USD).total(), Decimal::new(100, 0));
+    assert!(account.locked);
+  }
+
+  fn test_dispute_currency_mismatch<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(in_currency(deposit(1, 1, "40.0"), "EUR")).unwrap();
+
+    let result = engine.process(in_currency(dispute(1, 1), "USD"));
+    assert!(matches!(result, Err(EngineError::CurrencyMismatch { .. })));
+    assert_eq!(account_of(&engine, 1).balance("EUR").held, Decimal::ZERO);
+  }
+
+  fn test_idempotent_replay_compares_currency<B: Backend>() {
+    let config = EngineConfig { idempotent: true, ..Default::default() };
+    let mut engine = engine_with_config::<B>(config);
+    engine.process(in_currency(deposit(1, 1, "40.0"), "EUR")).unwrap();
+
+    let replay = engine.process(in_currency(deposit(1, 1, "40.0"), "EUR")).unwrap();
+    assert_eq!(replay, Outcome::Skipped);
+    let result = engine.process(deposit(1, 1, "40.0"));
+    assert!(matches!(result, Err(EngineError::DuplicateTransaction { .. })));
+  }
+
+  // =========================================================================
+  // CONVERSION
+  // =========================================================================
+
+  fn converting<B: Backend>(config: EngineConfig) -> Engine {
+    let rates = RateTable::from_csv("from,to,rate\nEUR,USD,1.0825\nUSD,JPY,151.37\n".as_bytes());
+    engine_with_config::<B>(config).with_rates(rates.unwrap())
+  }
+
+  fn convert(client: u16, tx: u32, amount: &str, from: &str, to: &str) -> TransactionRecord {
+    TransactionRecord {
+      tx_type: TransactionType::Convert,
+      to_currency: Some(to.to_string()),
+      ..in_currency(deposit(client, tx, amount), from)
+    }
+  }
+
+  fn test_convert_moves_value<B: Backend>() {
+    let mut engine = converting::<B>(EngineConfig::default());
+    engine.process(in_currency(deposit(1, 1, "100.0"), "EUR")).unwrap();
+    engine.process(convert(1, 2, "40.0", "EUR", "USD")).unwrap();
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance("EUR").available, Decimal::new(60, 0));
+    assert_eq!(account.balance(USD).available, Decimal::new(433, 1));
+
+    let stored = engine.storage.transaction(2).unwrap().unwrap();
+    assert_eq!(
+      stored.conversion,
+      Some(Conversion {
+        to_currency: USD.to_string(),
+        rate: Decimal::new(10825, 4),
+        converted: Decimal::new(433, 1),
+      })
+    );
+  }
+
+  /// Rounds yen to two places so the conversion rounding shows
+  fn jpy_cents() -> PrecisionConfig {
+    PrecisionConfig { currencies: [("JPY".to_string(), 2)].into(), ..Default::default() }
+  }
+
+  fn test_convert_rounding<B: Backend>() {
+    // 0.0001 EUR is 0.00010825 USD
+    let mut engine = converting::<B>(EngineConfig::default());
+    engine.process(in_currency(deposit(1, 1, "1.0"), "EUR")).unwrap();
+    engine.process(convert(1, 2, "0.0001", "EUR", "USD")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(1, 4));
+
+    let config = EngineConfig {
+      conversion: ConversionConfig { rounding: Rounding::HalfUp, ..Default::default() },
+      precision: jpy_cents(),
+      ..Default::default()
+    };
+    let mut engine = converting::<B>(config);
+    // 0.5 USD is 75.685 JPY
+    engine.process(deposit(1, 1, "0.5")).unwrap();
+    engine.process(convert(1, 2, "0.5", USD, "JPY")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance("JPY").available, Decimal::new(7569, 2));
+
+    let config = EngineConfig {
+      conversion: ConversionConfig { rounding: Rounding::Truncate, ..Default::default() },
+      precision: jpy_cents(),
+      ..Default::default()
+    };
+    let mut engine = converting::<B>(config);
+    engine.process(deposit(1, 1, "0.5")).unwrap();
+    engine.process(convert(1, 2, "0.5", USD, "JPY")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance("JPY").available, Decimal::new(7568, 2));
+  }
+
+  fn test_convert_rejections<B: Backend>() {
+    let mut engine = converting::<B>(EngineConfig::default());
+    engine.process(in_currency(deposit(1, 1, "10.0"), "EUR")).unwrap();
+
+    let result = engine.process(convert(1, 2, "1.0", "USD", "EUR"));
+    assert!(matches!(result, Err(EngineError::RateNotFound { .. })));
+    let result = engine.process(convert(1, 3, "1.0", "EUR", "EUR"));
+    assert!(matches!(result, Err(EngineError::SameCurrency { .. })));
+    let result =
+      engine.process(TransactionRecord { to_currency: None, ..convert(1, 4, "1.0", "EUR", "USD") });
+    assert!(matches!(result, Err(EngineError::MissingTargetCurrency { .. })));
+    let result = engine.process(convert(1, 5, "20.0", "EUR", "USD"));
+    assert!(matches!(result, Err(EngineError::AccountError { .. })));
+    let result = engine.process(convert(1, 1, "1.0", "EUR", "USD"));
+    assert!(matches!(result, Err(EngineError::DuplicateTransaction { .. })));
+    let result = engine.process(convert(1, 6, "70000000000000000000000000000", USD, "JPY"));
+    assert!(matches!(result, Err(EngineError::AmountOverflow { tx: 6, .. })));
+
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance("EUR").available, Decimal::new(10, 0));
+    assert_eq!(account.balance(USD), Balance::default());
+  }
+
+  fn test_convert_not_disputable<B: Backend>() {
+    let mut engine = converting::<B>(EngineConfig::default());
+    engine.process(in_currency(deposit(1, 1, "10.0"), "EUR")).unwrap();
+    engine.process(convert(1, 2, "10.0", "EUR", "USD")).unwrap();
+
+    let result = engine.process(dispute(1, 2));
+    assert!(matches!(
+      result,
+      Err(EngineError::NotDisputable { tx: 2, tx_type: TransactionType::Convert })
+    ));
+  }
+
+  fn test_convert_on_locked_account<B: Backend>() {
+    let mut engine = converting::<B>(EngineConfig::default());
+    engine.process(in_currency(deposit(1, 1, "10.0"), "EUR")).unwrap();
+    engine.process(in_currency(deposit(1, 2, "5.0"), "EUR")).unwrap();
+    engine.process(dispute(1, 2)).unwrap();
+    engine.process(chargeback(1, 2)).unwrap();
+
+    let result = engine.process(convert(1, 3, "1.0", "EUR", "USD"));
+    assert!(matches!(
+      result,
+      Err(EngineError::AccountError { error: AccountError::AccountLocked, .. })
+    ));
+  }
+
+  fn test_idempotent_convert_replay<B: Backend>() {
+    let mut engine = converting::<B>(EngineConfig { idempotent: true, ..Default::default() });
+    engine.process(in_currency(deposit(1, 1, "10.0"), "EUR")).unwrap();
+    engine.process(convert(1, 2, "4.0", "EUR", "USD")).unwrap();
+
+    assert_eq!(engine.process(convert(1, 2, "4.0", "EUR", "USD")).unwrap(), Outcome::Skipped);
+    let result = engine.process(convert(1, 2, "4.0", "EUR", "JPY"));
+    assert!(matches!(result, Err(EngineError::DuplicateTransaction { .. })));
+    assert_eq!(account_of(&engine, 1).balance("EUR").available, Decimal::new(6, 0));
+  }
+
+  // =========================================================================
+  // PRECISION
+  // =========================================================================
+
+  fn rounding(rounding: Rounding) -> EngineConfig {
+    EngineConfig {
+      precision: PrecisionConfig { excess: Excess::Round, rounding, ..Default::default() },
+      ..Default::default()
+    }
+  }
+
+  fn test_excess_precision_rejected_by_default<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "1.0")).unwrap();
+
+    let result = engine.process(deposit(1, 2, "1.23456"));
+    assert!(matches!(result, Err(EngineError::ExcessPrecision { scale: 4, .. })));
+    let result = engine.process(withdrawal(1, 3, "0.00001"));
+    assert!(matches!(result, Err(EngineError::ExcessPrecision { .. })));
+    // Trailing zeros are fine
+    engine.process(deposit(1, 4, "1.000000")).unwrap();
+
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(2, 0));
+    assert!(!engine.storage.contains_transaction(2).unwrap());
+  }
+
+  fn test_excess_precision_rounded<B: Backend>() {
+    for (strategy, expected) in [
+      (Rounding::HalfEven, Decimal::new(12346, 4)),
+      (Rounding::HalfUp, Decimal::new(12347, 4)),
+      (Rounding::Truncate, Decimal::new(12346, 4)),
+    ] {
+      let mut engine = engine_with_config::<B>(rounding(strategy));
+      engine.process(deposit(1, 1, "1.23465")).unwrap();
+      assert_eq!(account_of(&engine, 1).balance(USD).available, expected);
+      // The dispute holds what was booked, not what was in the file
+      engine.process(dispute(1, 1)).unwrap();
+      assert_eq!(account_of(&engine, 1).balance(USD).held, expected);
+    }
+  }
+
+  fn test_precision_per_currency<B: Backend>() {
+    let config = EngineConfig {
+      precision: PrecisionConfig {
+        currencies: [("JPY".to_string(), 0), ("BTC".to_string(), 8)].into(),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let mut engine = engine_with_config::<B>(config);
+    engine.process(in_currency(deposit(1, 1, "0.12345678"), "BTC")).unwrap();
+    engine.process(in_currency(deposit(1, 2, "1500"), "JPY")).unwrap();
+    let result = engine.process(in_currency(deposit(1, 3, "1.5"), "JPY"));
+    assert!(matches!(result, Err(EngineError::ExcessPrecision { scale: 0, .. })));
+    let result = engine.process(deposit(1, 4, "0.12345"));
+    assert!(matches!(result, Err(EngineError::ExcessPrecision { scale: 4, .. })));
+  }
+
+  fn test_idempotent_replay_of_rounded_amount<B: Backend>() {
+    let mut config = rounding(Rounding::HalfEven);
+    config.idempotent = true;
+    let mut engine = engine_with_config::<B>(config);
+    engine.process(deposit(1, 1, "1.23456")).unwrap();
+
+    assert_eq!(engine.process(deposit(1, 1, "1.23456")).unwrap(), Outcome::Skipped);
+  }
+
+  /// Whatever the input, a balance has no more places than are printed, so the output
+  /// read back is exactly the internal state
+  fn test_balances_never_drift_from_output<B: Backend>() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(34);
+    for strategy in [Rounding::HalfEven, Rounding::HalfUp, Rounding::Truncate] {
+      let mut engine = engine_with_config::<B>(rounding(strategy));
+      for tx in 0..500u32 {
+        let client = rng.gen_range(0..5);
+        let amount = Decimal::new(rng.gen_range(0..10_000_000), rng.gen_range(0..8)).to_string();
+        let _ = match rng.gen_range(0..5) {
+          0 | 1 => engine.process(deposit(client, tx, &amount)),
+          2 => engine.process(withdrawal(client, tx, &amount)),
+          3 => engine.process(dispute(client, rng.gen_range(0..=tx))),
+          _ => engine.process(resolve(client, rng.gen_range(0..=tx))),
+        };
+      }
+
+      for account in engine.accounts().unwrap() {
+        let balance = account.balance(USD);
+        for value in [balance.available, balance.held, balance.total()] {
+          let printed: Decimal = format!("{:.4}", value).parse().unwrap();
+          assert_eq!(printed, value);
+        }
+      }
+    }
+  }
+
+  // ===== WITHDRAWAL LIMITS =====
+
+  fn limited<B: Backend>(limits: &str) -> Engine {
+    new_engine::<B>().with_limits(LimitsTable::from_toml(limits).unwrap())
+  }
+
+  fn test_max_single_withdrawal<B: Backend>() {
+    let mut engine = limited::<B>(
+      "[default]\nmax_withdrawal = \"50\"\n[tiers.gold]\nmax_withdrawal = \"500\"\n\
+       [clients.2]\ntier = \"gold\"",
+    );
+    engine.process(deposit(1, 1, "1000")).unwrap();
+    engine.process(deposit(2, 2, "1000")).unwrap();
+
+    engine.process(withdrawal(1, 3, "50")).unwrap();
+    let result = engine.process(withdrawal(1, 4, "50.01"));
+    assert!(matches!(result, Err(EngineError::WithdrawalLimitExceeded { tx: 4, client: 1, .. })));
+    engine.process(withdrawal(2, 5, "500")).unwrap();
+
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(950, 0));
+    assert!(!engine.storage.contains_transaction(4).unwrap());
+  }
+
+  fn test_insufficient_funds_reported_before_limits<B: Backend>() {
+    let mut engine = limited::<B>("[default]\nmax_withdrawal = \"1\"");
+    let result = engine.process(withdrawal(1, 1, "5"));
+    assert!(matches!(result, Err(EngineError::AccountError { .. })));
+  }
+
+  fn test_withdrawals_per_records<B: Backend>() {
+    let mut engine =
+      limited::<B>("[default]\nmax_withdrawals_per_records = { count = 2, records = 4 }");
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(withdrawal(1, 2, "1")).unwrap();
+    engine.process(withdrawal(1, 3, "1")).unwrap();
+    // Records 2, 3 and 4 are in the window
+    let result = engine.process(withdrawal(1, 4, "1"));
+    assert!(matches!(result, Err(EngineError::VelocityLimitExceeded { count: 2, records: 4, .. })));
+    // Other clients have their own count
+    engine.process(deposit(2, 5, "100")).unwrap();
+    engine.process(withdrawal(2, 6, "1")).unwrap();
+    // Record 7, the window is back to record 4 and only holds tx 3
+    engine.process(withdrawal(1, 7, "1")).unwrap();
+
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(97, 0));
+  }
+
+  fn test_rolled_back_batch_does_not_count<B: Backend>() {
+    let mut engine =
+      limited::<B>("[default]\nmax_withdrawals_per_records = { count = 1, records = 10 }");
+    engine.process(deposit(1, 1, "100")).unwrap();
+    let result = engine.process_batch(1, vec![withdrawal(1, 2, "1"), withdrawal(1, 3, "1000")]);
+    assert!(result.is_err());
+
+    engine.process(withdrawal(1, 4, "1")).unwrap();
+  }
+
+  fn test_rolled_back_batch_does_not_move_record_clock<B: Backend>() {
+    let mut engine =
+      limited::<B>("[default]\nmax_withdrawals_per_records = { count = 1, records = 3 }");
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(withdrawal(1, 2, "1")).unwrap();
+    let result = engine.process_batch(1, vec![deposit(1, 3, "1"), withdrawal(1, 4, "1000")]);
+    assert!(result.is_err());
+
+    // Record 3, the window still holds tx 2
+    let result = engine.process(withdrawal(1, 5, "1"));
+    assert!(matches!(result, Err(EngineError::VelocityLimitExceeded { .. })));
+  }
+
+  fn test_peak_transaction_count<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "100")).unwrap();
+    let result = engine
+      .process_batch(1, vec![deposit(1, 2, "1"), deposit(1, 3, "1"), withdrawal(1, 4, "1000")]);
+    assert!(result.is_err());
+    engine.process(deposit(1, 5, "1")).unwrap();
+    engine.process(dispute(1, 5)).unwrap();
+
+    assert_eq!(engine.transaction_count().unwrap(), 2);
+    assert_eq!(engine.peak_transaction_count().unwrap(), 3);
+    // A restored state counts from what it was opened with
+    let engine = rerun(engine);
+    assert_eq!(engine.peak_transaction_count().unwrap(), 2);
+  }
+
+  fn test_daily_withdrawal_count<B: Backend>() {
+    let mut engine = limited::<B>("[default]\nmax_withdrawals_per_day = 2");
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(at(withdrawal(1, 2, "1"), "2024-01-01T08:00:00Z")).unwrap();
+    engine.process(at(withdrawal(1, 3, "1"), "2024-01-01T12:00:00Z")).unwrap();
+    let result = engine.process(at(withdrawal(1, 4, "1"), "2024-01-01T23:59:59Z"));
+    assert!(matches!(result, Err(EngineError::DailyWithdrawalCountExceeded { limit: 2, .. })));
+    // A new UTC day
+    engine.process(at(withdrawal(1, 5, "1"), "2024-01-02T00:00:00Z")).unwrap();
+    // Untimed withdrawals are not counted against a day
+    engine.process(withdrawal(1, 6, "1")).unwrap();
+  }
+
+  fn test_daily_totals_restored_and_rolled_back<B: Backend>() {
+    let limits = "[default]\nmax_daily_outflow = \"100\"";
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "1000")).unwrap();
+    engine.process(at(withdrawal(1, 2, "60"), "2024-01-01T08:00:00Z")).unwrap();
+
+    // A later run on the saved state counts what the earlier one withdrew
+    let mut engine = Engine::with_storage(engine.storage, EngineConfig::default())
+      .with_limits(LimitsTable::from_toml(limits).unwrap());
+    let result = engine.process(at(withdrawal(1, 3, "41"), "2024-01-01T09:00:00Z"));
+    assert!(matches!(result, Err(EngineError::DailyOutflowExceeded { .. })));
+
+    let result = engine.process_batch(
+      1,
+      vec![at(withdrawal(1, 4, "30"), "2024-01-01T10:00:00Z"), withdrawal(1, 5, "5000")],
+    );
+    assert!(result.is_err());
+    engine.process(at(withdrawal(1, 6, "40"), "2024-01-01T11:00:00Z")).unwrap();
+    let result = engine.process(at(withdrawal(1, 7, "0.01"), "2024-01-01T12:00:00Z"));
+    assert!(matches!(result, Err(EngineError::DailyOutflowExceeded { .. })));
+  }
+
+  fn test_daily_outflow<B: Backend>() {
+    let mut engine = limited::<B>("[default]\nmax_daily_outflow = \"100\"");
+    engine.process(deposit(1, 1, "1000")).unwrap();
+    engine.process(in_currency(deposit(1, 2, "1000"), "EUR")).unwrap();
+    engine.process(at(withdrawal(1, 3, "60"), "2024-01-01T08:00:00Z")).unwrap();
+    let result = engine.process(at(withdrawal(1, 4, "40.01"), "2024-01-01T09:00:00Z"));
+    assert!(matches!(
+      result,
+      Err(EngineError::DailyOutflowExceeded { outflow, .. }) if outflow == Decimal::new(10001, 2)
+    ));
+    engine.process(at(withdrawal(1, 5, "40"), "2024-01-01T10:00:00Z")).unwrap();
+    // Each currency has its own outflow
+    engine
+      .process(at(in_currency(withdrawal(1, 6, "100"), "EUR"), "2024-01-01T11:00:00Z"))
+      .unwrap();
+  }
+
+  // ===== CREDIT LINES =====
+
+  fn test_withdrawal_on_credit_line<B: Backend>() {
+    let mut engine = limited::<B>("[clients.1]\ncredit = { USD = \"100\" }");
+    engine.process(deposit(1, 1, "50")).unwrap();
+    engine.process(withdrawal(1, 2, "120")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(-70, 0));
+
+    let result = engine.process(withdrawal(1, 3, "30.01"));
+    assert!(matches!(
+      result,
+      Err(EngineError::AccountError { error: AccountError::CreditLimitExceeded { .. }, .. })
+    ));
+    // No credit line, no overdraft
+    engine.process(deposit(2, 4, "50")).unwrap();
+    let result = engine.process(withdrawal(2, 5, "60"));
+    assert!(matches!(
+      result,
+      Err(EngineError::AccountError { error: AccountError::InsufficientFunds { .. }, .. })
+    ));
+  }
+
+  fn test_dispute_on_overdrawn_account<B: Backend>() {
+    let mut engine = limited::<B>("[default]\ncredit = { USD = \"100\" }");
+    engine.process(deposit(1, 1, "80")).unwrap();
+    engine.process(withdrawal(1, 2, "100")).unwrap();
+
+    // The hold draws the rest of the credit line
+    engine.process(dispute(1, 1)).unwrap();
+    let balance = account_of(&engine, 1).balance(USD);
+    assert_eq!((balance.available, balance.held), (Decimal::new(-100, 0), Decimal::new(80, 0)));
+
+    // The chargeback takes the held funds and leaves the debt
+    engine.process(chargeback(1, 1)).unwrap();
+    let account = account_of(&engine, 1);
+    assert!(account.locked);
+    assert_eq!(account.balance(USD).total(), Decimal::new(-100, 0));
+  }
+
+  fn test_dispute_beyond_credit_line_rejected<B: Backend>() {
+    let mut engine = limited::<B>("[default]\ncredit = { USD = \"10\" }");
+    engine.process(deposit(1, 1, "80")).unwrap();
+    engine.process(withdrawal(1, 2, "80")).unwrap();
+    let result = engine.process(dispute(1, 1));
+    assert!(matches!(
+      result,
+      Err(EngineError::AccountError { error: AccountError::CreditLimitExceeded { .. }, .. })
+    ));
+  }
+
+  fn test_convert_does_not_use_credit_line<B: Backend>() {
+    let mut engine = converting::<B>(EngineConfig::default())
+      .with_limits(LimitsTable::from_toml("[default]\ncredit = { EUR = \"100\" }").unwrap());
+    let result = engine.process(convert(1, 1, "10", "EUR", "USD"));
+    assert!(matches!(
+      result,
+      Err(EngineError::AccountError { error: AccountError::InsufficientFunds { .. }, .. })
+    ));
+  }
+
+  // ===== FEES =====
+
+  fn with_fees<B: Backend>(fees: &str) -> Engine {
+    let config = EngineConfig { fees: toml::from_str(fees).unwrap(), ..Default::default() };
+    engine_with_config::<B>(config)
+  }
+
+  fn test_fees_posted<B: Backend>() {
+    let mut engine = with_fees::<B>(
+      "[deposit]\npercent = \"1\"\n[withdrawal]\nfixed = \"0.5\"\n[chargeback]\nfixed = \"15\"",
+    );
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(withdrawal(1, 2, "10")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(885, 1));
+
+    let stored = engine.transaction(1).unwrap().unwrap();
+    assert_eq!(stored.amount, Decimal::new(100, 0));
+    assert_eq!(stored.fee(TransactionType::Deposit), Some(Decimal::ONE));
+    assert_eq!(
+      engine.transaction(2).unwrap().unwrap().fee(TransactionType::Withdrawal),
+      Some(Decimal::new(5, 1))
+    );
+
+    // The chargeback fee overdraws what is left, 9.9 of the other deposit
+    engine.process(deposit(2, 3, "10")).unwrap();
+    engine.process(deposit(2, 4, "10")).unwrap();
+    engine.process(dispute(2, 3)).unwrap();
+    engine.process(chargeback(2, 3)).unwrap();
+    let account = account_of(&engine, 2);
+    assert_eq!(account.balance(USD).available, Decimal::new(-51, 1));
+    let stored = engine.transaction(3).unwrap().unwrap();
+    assert_eq!(stored.fee(TransactionType::Chargeback), Some(Decimal::new(15, 0)));
+
+    // A fee too large to compute rejects the record, the run carries on
+    let mut engine = with_fees::<B>("[deposit]\npercent = \"2\"");
+    let result = engine.process(deposit(1, 1, "70000000000000000000000000000"));
+    assert!(matches!(result, Err(EngineError::AmountOverflow { tx: 1, .. })));
+    assert_eq!(account_of(&engine, 1).balance(USD), Balance::default());
+    engine.process(deposit(1, 2, "10")).unwrap();
+  }
+
+  fn test_dispute_holds_deposit_net_of_fee<B: Backend>() {
+    let mut engine = with_fees::<B>("[deposit]\nfixed = \"1\"");
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).available, Decimal::ZERO);
+    assert_eq!(account.balance(USD).held, Decimal::new(99, 0));
+
+    engine.process(resolve(1, 1)).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(99, 0));
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(chargeback(1, 1)).unwrap();
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).total(), Decimal::ZERO);
+    assert!(account.locked);
+  }
+
+  fn test_withdrawal_must_cover_fee<B: Backend>() {
+    let mut engine = with_fees::<B>("[withdrawal]\nfixed = \"1\"");
+    engine.process(deposit(1, 1, "10")).unwrap();
+    let result = engine.process(withdrawal(1, 2, "10"));
+    assert!(matches!(
+      result,
+      Err(EngineError::AccountError { error: AccountError::InsufficientFunds { .. }, .. })
+    ));
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(10, 0));
+    assert!(!engine.storage.contains_transaction(2).unwrap());
+  }
+
+  fn test_fee_rounded_to_currency<B: Backend>() {
+    let config = EngineConfig {
+      fees: toml::from_str("rounding = \"half_up\"\n[deposit]\npercent = \"0.5\"").unwrap(),
+      precision: PrecisionConfig {
+        currencies: [("JPY".to_string(), 0)].into(),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let mut engine = engine_with_config::<B>(config);
+    // 0.5% of 999 is 4.995, JPY has no places
+    engine.process(in_currency(deposit(1, 1, "999"), "JPY")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance("JPY").available, Decimal::new(994, 0));
+  }
+
+  fn test_idempotent_replay_not_charged_again<B: Backend>() {
+    let config = EngineConfig {
+      idempotent: true,
+      fees: toml::from_str("[deposit]\nfixed = \"1\"").unwrap(),
+      ..Default::default()
+    };
+    let mut engine = engine_with_config::<B>(config);
+    engine.process(deposit(1, 1, "10")).unwrap();
+    assert_eq!(engine.process(deposit(1, 1, "10")).unwrap(), Outcome::Skipped);
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(9, 0));
+  }
+
+  // ===== INTEREST =====
+
+  /// 3.65% a year on actual/365 is 0.01% a day
+  fn accruing<B: Backend>(locked: LockedInterest) -> Engine {
+    let interest =
+      InterestConfig { annual_percent: Decimal::new(365, 2), locked, ..Default::default() };
+    engine_with_config::<B>(EngineConfig { interest: Some(interest), ..Default::default() })
+  }
+
+  fn accrue(client: u16, tx: u32, timestamp: &str) -> TransactionRecord {
+    at(
+      TransactionRecord {
+        tx_type: TransactionType::AccrueInterest,
+        amount: None,
+        ..deposit(client, tx, "0")
+      },
+      timestamp,
+    )
+  }
+
+  fn test_interest_accrued_since_last_accrual<B: Backend>() {
+    let mut engine = accruing::<B>(LockedInterest::Skip);
+    engine.process(at(deposit(1, 1, "1000"), "2024-01-01T09:00:00Z")).unwrap();
+    // Ten days since the first deposit
+    engine.process(accrue(1, 2, "2024-01-11T00:00:00Z")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(1001, 0));
+    // Five more days on the new balance
+    engine.process(accrue(1, 3, "2024-01-16T00:00:00Z")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(10_015_005, 4));
+
+    let stored = engine.transaction(3).unwrap().unwrap();
+    assert_eq!(stored.tx_type, TransactionType::AccrueInterest);
+    assert_eq!(stored.amount, Decimal::new(5005, 4));
+  }
+
+  fn test_interest_not_disputable<B: Backend>() {
+    let mut engine = accruing::<B>(LockedInterest::Skip);
+    engine.process(at(deposit(1, 1, "1000"), "2024-01-01T00:00:00Z")).unwrap();
+    engine.process(accrue(1, 2, "2024-01-11T00:00:00Z")).unwrap();
+    let result = engine.process(dispute(1, 2));
+    assert!(matches!(
+      result,
+      Err(EngineError::NotDisputable { tx: 2, tx_type: TransactionType::AccrueInterest })
+    ));
+  }
+
+  fn test_interest_on_locked_accounts<B: Backend>() {
+    for (policy, expected) in
+      [(LockedInterest::Skip, Decimal::ZERO), (LockedInterest::Accrue, Decimal::new(5, 1))]
+    {
+      let mut engine = accruing::<B>(policy);
+      engine.process(at(deposit(1, 1, "500"), "2024-01-01T00:00:00Z")).unwrap();
+      engine.process(at(deposit(1, 2, "100"), "2024-01-01T00:00:00Z")).unwrap();
+      engine.process(dispute(1, 2)).unwrap();
+      engine.process(chargeback(1, 2)).unwrap();
+
+      engine.process(accrue(1, 3, "2024-01-11T00:00:00Z")).unwrap();
+      assert_eq!(engine.transaction(3).unwrap().unwrap().amount, expected);
+      // The period moves on either way
+      let accrued_to = account_of(&engine, 1).balance(USD).accrued_to;
+      let expected_to = crate::transaction::parse_timestamp("2024-01-11T00:00:00Z").unwrap();
+      assert_eq!(accrued_to, Some(expected_to));
+    }
+  }
+
+  fn test_no_interest_on_overdraft<B: Backend>() {
+    let mut engine = accruing::<B>(LockedInterest::Skip)
+      .with_limits(LimitsTable::from_toml("[default]\ncredit = { USD = \"100\" }").unwrap());
+    engine.process(at(withdrawal(1, 1, "50"), "2024-01-01T00:00:00Z")).unwrap();
+    engine.process(accrue(1, 2, "2024-02-01T00:00:00Z")).unwrap();
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::new(-50, 0));
+  }
+
+  fn test_interest_rejections<B: Backend>() {
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "10")).unwrap();
+    let result = engine.process(accrue(1, 2, "2024-01-01T00:00:00Z"));
+    assert!(matches!(result, Err(EngineError::InterestNotConfigured { tx: 2 })));
+
+    let mut engine = accruing::<B>(LockedInterest::Skip);
+    engine.process(deposit(1, 1, "10")).unwrap();
+    let result =
+      engine.process(TransactionRecord { timestamp: None, ..accrue(1, 2, "2024-01-01T00:00:00Z") });
+    assert!(matches!(result, Err(EngineError::MissingTimestamp { tx: 2, .. })));
+    let result = engine.process(accrue(2, 3, "2024-01-01T00:00:00Z"));
+    assert!(matches!(result, Err(EngineError::ClientNotFound { client: 2 })));
+    let result = engine.process(accrue(1, 1, "2024-01-01T00:00:00Z"));
+    assert!(matches!(result, Err(EngineError::DuplicateTransaction { tx: 1 })));
+  }
+
+  // ===== SCREENING RULES =====
+
+  fn screened<B: Backend>(rules: &str) -> Engine {
+    new_engine::<B>().with_rules(RuleSet::from_toml(rules).unwrap())
+  }
+
+  fn test_flagged_record_applied<B: Backend>() {
+    let mut engine =
+      screened::<B>("[[rapid_cycle]]\nname = \"cycle\"\naction = \"flag\"\nwithin_minutes = 60");
+    engine.process(at(deposit(1, 1, "100"), "2024-01-01T10:00:00Z")).unwrap();
+    engine.process(at(withdrawal(1, 2, "100"), "2024-01-01T10:15:00Z")).unwrap();
+
+    assert_eq!(account_of(&engine, 1).balance(USD).available, Decimal::ZERO);
+    let alerts = engine.take_alerts();
+    assert_eq!(alerts.len(), 1);
+    assert_eq!((alerts[0].tx, alerts[0].rule.as_str()), (2, "cycle"));
+    assert!(engine.take_alerts().is_empty());
+  }
+
+  fn test_blocked_record_rejected<B: Backend>() {
+    let mut engine =
+      screened::<B>("[[dispute_count]]\nname = \"disputes\"\naction = \"block\"\nmax = 1");
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(deposit(1, 2, "50")).unwrap();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(resolve(1, 1)).unwrap();
+
+    let result = engine.process(dispute(1, 2));
+    assert!(matches!(result, Err(EngineError::Blocked { tx: 2, ref rule }) if rule == "disputes"));
+    let account = account_of(&engine, 1);
+    assert_eq!(account.balance(USD).held, Decimal::ZERO);
+    assert_eq!(engine.transaction(2).unwrap().unwrap().state, DisputeState::Normal);
+    assert_eq!(engine.take_alerts()[0].action, RuleAction::Block);
+  }
+
+  // ===== ACCOUNT EVENTS =====
+
+  fn test_events_in_applied_order<B: Backend>() {
+    let mut engine = new_engine::<B>().with_events();
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process(withdrawal(1, 2, "500")).unwrap_err();
+    engine.process(dispute(1, 1)).unwrap();
+    engine.process(chargeback(1, 1)).unwrap();
+
+    let events = engine.take_events();
+    let kinds: Vec<EventKind> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(
+      kinds,
+      [EventKind::Deposited, EventKind::Held, EventKind::ChargedBack, EventKind::Locked]
+    );
+    assert_eq!(events[0].before.balance(USD).available, Decimal::ZERO);
+    assert_eq!(events[1].before.balance(USD).available, Decimal::from(100));
+    assert_eq!(events[1].after.balance(USD).held, Decimal::from(100));
+    assert_eq!(events[2].amount, Some(Decimal::from(100)));
+    assert!(!events[2].after.locked && events[3].after.locked);
+    assert!(engine.take_events().is_empty());
+  }
+
+  fn test_rolled_back_batch_has_no_events<B: Backend>() {
+    let mut engine = new_engine::<B>().with_events();
+    engine.process(deposit(1, 1, "100")).unwrap();
+    engine.process_batch(1, vec![deposit(2, 2, "10"), withdrawal(2, 3, "50")]).unwrap_err();
+
+    let events = engine.take_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].tx, 1);
+    // Not recorded unless asked for
+    let mut engine = new_engine::<B>();
+    engine.process(deposit(1, 1, "100")).unwrap();
+    assert!(engine.take_events().is_empty());
+  }
+
+  macro_rules! backend_tests {
+    ($($test:ident),* $(,)?) => {
+      mod memory {
+        $(#[test] fn $test() { super::$test::<super::Memory>() })*
+      }
+      mod sqlite {
+        $(#[test] fn $test() { super::$test::<super::Sqlite>() })*
+      }
+    };
+  }
+
+  backend_tests!(
+    test_basic_deposit_withdrawal,
+    test_withdrawal_insufficient_funds,
+    test_dispute_resolve_flow,
+    test_dispute_chargeback_flow,
+    test_dispute_nonexistent_tx,
+    test_resolve_not_disputed,
+    test_client_mismatch,
+    test_multiple_clients,
+    test_duplicate_transaction_id,
+    test_zero_amount_deposit,
+    test_zero_amount_withdrawal,
+    test_negative_deposit_rejected,
+    test_negative_withdrawal_rejected,
+    test_redispute_after_resolve,
+    test_double_dispute_rejected,
+    test_dispute_withdrawal_rejected,
+    test_chargeback_without_dispute_rejected,
+    test_dispute_insufficient_funds,
+    test_duplicate_tx_id_different_clients,
+    test_withdrawal_creates_account,
+    test_dispute_on_locked_account,
+    test_resolve_on_locked_account,
+    test_transaction_id_zero,
+    test_transaction_id_max,
+    test_client_id_zero,
+    test_client_id_max,
+    test_very_small_amount,
+    test_precision_accumulation,
+    test_multiple_dispute_resolve_cycles,
+    test_missing_amount_deposit,
+    test_missing_amount_withdrawal,
+    test_timestamps_in_order_accepted,
+    test_timestamp_out_of_order_rejected,
+    test_timestamps_are_per_client,
+    test_rejected_record_does_not_advance_clock,
+    test_dispute_window_expired,
+    test_dispute_window_needs_both_timestamps,
+    test_batch_applied_together,
+    test_batch_rolled_back_on_first_error,
+    test_batch_members_see_each_other,
+    test_idempotent_replay_skips_exact_duplicates,
+    test_idempotent_new_records_after_replay_applied,
+    test_idempotent_conflicting_duplicates_rejected,
+    test_idempotent_timestamps_must_match,
+    test_idempotent_duplicates_within_one_run,
+    test_history_recorded,
+    test_states_follow_lifecycle,
+    test_charged_back_cannot_be_disputed_again,
+    test_resolved_cannot_be_charged_back,
+    test_transitions_recorded,
+    test_representment_restores_funds,
+    test_representment_only_after_chargeback,
+    test_representment_always_unlocks,
+    test_representment_unlocks_after_last_chargeback,
+    test_currencies_have_separate_balances,
+    test_dispute_in_deposit_currency,
+    test_dispute_currency_mismatch,
+    test_idempotent_replay_compares_currency,
+    test_convert_moves_value,
+    test_convert_rounding,
+    test_convert_rejections,
+    test_convert_not_disputable,
+    test_convert_on_locked_account,
+    test_idempotent_convert_replay,
+    test_excess_precision_rejected_by_default,
+    test_excess_precision_rounded,
+    test_precision_per_currency,
+    test_idempotent_replay_of_rounded_amount,
+    test_balances_never_drift_from_output,
+    test_max_single_withdrawal,
+    test_insufficient_funds_reported_before_limits,
+    test_withdrawals_per_records,
+    test_rolled_back_batch_does_not_count,
+    test_rolled_back_batch_does_not_move_record_clock,
+    test_peak_transaction_count,
+    test_daily_withdrawal_count,
+    test_daily_outflow,
+    test_daily_totals_restored_and_rolled_back,
+    test_withdrawal_on_credit_line,
+    test_dispute_on_overdrawn_account,
+    test_dispute_beyond_credit_line_rejected,
+    test_convert_does_not_use_credit_line,
+    test_fees_posted,
+    test_dispute_holds_deposit_net_of_fee,
+    test_withdrawal_must_cover_fee,
+    test_fee_rounded_to_currency,
+    test_idempotent_replay_not_charged_again,
+    test_interest_accrued_since_last_accrual,
+    test_interest_not_disputable,
+    test_interest_on_locked_accounts,
+    test_no_interest_on_overdraft,
+    test_interest_rejections,
+    test_flagged_record_applied,
+    test_blocked_record_rejected,
+    test_events_in_applied_order,
+    test_rolled_back_batch_has_no_events,
+  );
 }
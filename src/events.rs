@@ -0,0 +1,266 @@
+use std::fmt;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde_json::{Map, Value, json};
+
+use crate::account::Account;
+use crate::config::PrecisionConfig;
+use crate::transaction::{StoredTransaction, TransactionType};
+
+/// What an applied record did to an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+  Deposited,
+  Withdrew,
+  Held,
+  Released,
+  ChargedBack,
+  Represented,
+  Converted,
+  InterestAccrued,
+  /// The fee of the record before it, see `[fees]`
+  FeeCharged,
+  Locked,
+  Unlocked,
+}
+
+impl fmt::Display for EventKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      EventKind::Deposited => "deposited",
+      EventKind::Withdrew => "withdrew",
+      EventKind::Held => "held",
+      EventKind::Released => "released",
+      EventKind::ChargedBack => "charged_back",
+      EventKind::Represented => "represented",
+      EventKind::Converted => "converted",
+      EventKind::InterestAccrued => "interest_accrued",
+      EventKind::FeeCharged => "fee_charged",
+      EventKind::Locked => "locked",
+      EventKind::Unlocked => "unlocked",
+    })
+  }
+}
+
+impl From<TransactionType> for EventKind {
+  fn from(tx_type: TransactionType) -> Self {
+    match tx_type {
+      TransactionType::Deposit => EventKind::Deposited,
+      TransactionType::Withdrawal => EventKind::Withdrew,
+      TransactionType::Dispute => EventKind::Held,
+      TransactionType::Resolve => EventKind::Released,
+      TransactionType::Chargeback => EventKind::ChargedBack,
+      TransactionType::Representment => EventKind::Represented,
+      TransactionType::Convert => EventKind::Converted,
+      TransactionType::AccrueInterest => EventKind::InterestAccrued,
+    }
+  }
+}
+
+/// One change to an account with its state on both sides of it
+#[derive(Debug, Clone)]
+pub struct AccountEvent {
+  pub kind: EventKind,
+  pub tx: u32,
+  pub client: u16,
+  /// Currency of the stored transaction, for a conversion the one that was sold
+  pub currency: String,
+  /// The booked amount, for dispute type records the amount held or released.
+  /// None for lock changes
+  pub amount: Option<Decimal>,
+  pub timestamp: Option<DateTime<Utc>>,
+  pub before: Account,
+  pub after: Account,
+}
+
+/// The events of one applied record. A fee the record was charged follows as its own
+/// event, as does a change of the lock, and each event's `after` is the next one's `before`
+pub fn for_record(
+  tx_type: TransactionType,
+  tx: u32,
+  timestamp: Option<DateTime<Utc>>,
+  stored: Option<&StoredTransaction>,
+  currency: &str,
+  before: Account,
+  after: Account,
+) -> Vec<AccountEvent> {
+  let disputes = matches!(
+    tx_type,
+    TransactionType::Dispute
+      | TransactionType::Resolve
+      | TransactionType::Chargeback
+      | TransactionType::Representment
+  );
+  let (currency, amount) = match stored {
+    Some(stored) if disputes => (stored.currency.clone(), Some(stored.disputed_amount())),
+    Some(stored) => (stored.currency.clone(), Some(stored.amount)),
+    None => (currency.to_string(), None),
+  };
+  let client = after.client;
+  let fee = stored.and_then(|stored| stored.fee(tx_type));
+  let mut balances_changed = after.clone();
+  balances_changed.locked = before.locked;
+  // The record's own change is the one before the fee was debited
+  let mut before_fee = balances_changed.clone();
+  if let (Some(fee), Some(balance)) = (fee, before_fee.balances.get_mut(&currency)) {
+    balance.available += fee;
+  }
+  let event = |kind, amount, before, after| AccountEvent {
+    kind,
+    tx,
+    client,
+    currency: currency.clone(),
+    amount,
+    timestamp,
+    before,
+    after,
+  };
+
+  let lock_changed = before.locked != after.locked;
+  let mut events = Vec::with_capacity(3);
+  events.push(event(tx_type.into(), amount, before, before_fee.clone()));
+  if fee.is_some() {
+    events.push(event(EventKind::FeeCharged, fee, before_fee, balances_changed.clone()));
+  }
+  if lock_changed {
+    let kind = if after.locked { EventKind::Locked } else { EventKind::Unlocked };
+    events.push(event(kind, None, balances_changed, after));
+  }
+  events
+}
+
+/// Writes events as NDJSON, one object per line numbered from 1 in the order applied
+pub struct EventSink<W: Write> {
+  writer: W,
+  seq: u64,
+}
+
+impl<W: Write> EventSink<W> {
+  pub fn new(writer: W) -> Self {
+    Self { writer, seq: 0 }
+  }
+
+  pub fn write(
+    &mut self,
+    precision: &PrecisionConfig,
+    events: Vec<AccountEvent>,
+  ) -> std::io::Result<()> {
+    for event in events {
+      self.seq += 1;
+      let amount =
+        |currency: &str, amount: Decimal| crate::format_decimal(amount, precision.scale(currency));
+      let state = |account: &Account| {
+        let balances: Map<String, Value> = account
+          .balances
+          .iter()
+          .map(|(currency, balance)| {
+            let view = json!({
+              "available": amount(currency, balance.available),
+              "held": amount(currency, balance.held),
+              "total": amount(currency, balance.total()),
+            });
+            (currency.clone(), view)
+          })
+          .collect();
+        json!({ "locked": account.locked, "balances": balances })
+      };
+      let line = json!({
+        "seq": self.seq,
+        "event": event.kind.to_string(),
+        "tx": event.tx,
+        "client": event.client,
+        "currency": event.currency,
+        "amount": event.amount.map(|value| amount(&event.currency, value)),
+        "timestamp": event.timestamp.map(|ts| ts.to_rfc3339()),
+        "before": state(&event.before),
+        "after": state(&event.after),
+      });
+      writeln!(self.writer, "{}", line)?;
+    }
+    // A reader on the other end of a pipe sees each record as soon as it is applied
+    self.writer.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn account(available: i64, held: i64, locked: bool) -> Account {
+    let mut account = Account::new(1);
+    account.deposit("USD", Decimal::from(available + held)).unwrap();
+    account.hold("USD", Decimal::from(held), Decimal::ZERO).unwrap();
+    account.locked = locked;
+    account
+  }
+
+  #[test]
+  fn test_chargeback_then_lock() {
+    let events = for_record(
+      TransactionType::Chargeback,
+      7,
+      None,
+      None,
+      "USD",
+      account(0, 10, false),
+      account(0, 0, true),
+    );
+    let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(kinds, [EventKind::ChargedBack, EventKind::Locked]);
+    assert!(!events[0].after.locked);
+    assert_eq!(events[0].after.balance("USD"), events[1].before.balance("USD"));
+    assert!(events[1].after.locked);
+  }
+
+  #[test]
+  fn test_fee_is_its_own_event() {
+    let mut stored = StoredTransaction::new(TransactionType::Deposit, 1, "USD", 100.into(), None);
+    stored
+      .fees
+      .push(crate::transaction::Fee { tx_type: TransactionType::Deposit, amount: Decimal::ONE });
+    let events = for_record(
+      TransactionType::Deposit,
+      1,
+      None,
+      Some(&stored),
+      "USD",
+      Account::new(1),
+      account(99, 0, false),
+    );
+    let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(kinds, [EventKind::Deposited, EventKind::FeeCharged]);
+    assert_eq!(events[0].amount, Some(Decimal::from(100)));
+    assert_eq!(events[0].after.balance("USD").available, Decimal::from(100));
+    assert_eq!(events[1].amount, Some(Decimal::ONE));
+    assert_eq!(events[1].before.balance("USD"), events[0].after.balance("USD"));
+    assert_eq!(events[1].after.balance("USD").available, Decimal::from(99));
+  }
+
+  #[test]
+  fn test_sink_writes_ndjson() {
+    let mut out = Vec::new();
+    let mut sink = EventSink::new(&mut out);
+    let events = for_record(
+      TransactionType::Deposit,
+      1,
+      None,
+      None,
+      "USD",
+      Account::new(1),
+      account(5, 0, false),
+    );
+    sink.write(&PrecisionConfig::default(), events.clone()).unwrap();
+    sink.write(&PrecisionConfig::default(), events).unwrap();
+
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<Value> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["seq"], 2);
+    assert_eq!(lines[0]["event"], "deposited");
+    assert_eq!(lines[0]["before"]["balances"], json!({}));
+    assert_eq!(lines[0]["after"]["balances"]["USD"]["available"], "5.0000");
+    assert_eq!(lines[0]["amount"], Value::Null);
+  }
+}
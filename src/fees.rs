@@ -0,0 +1,229 @@
+use anyhow::{Result, bail};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::config::Rounding;
+use crate::transaction::TransactionType;
+
+/// The `[fees]` table. Each record type that carries a fee has its own rule:
+///
+/// ```toml
+/// [fees.withdrawal]
+/// fixed = "0.50"
+/// percent = "1.5"
+/// min = "1"
+/// max = "25"
+///
+/// [fees.chargeback]
+/// fixed = "15"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+  pub deposit: Option<FeeRule>,
+  pub withdrawal: Option<FeeRule>,
+  pub chargeback: Option<FeeRule>,
+  /// How a fee is brought to the precision of its currency
+  pub rounding: Rounding,
+}
+
+/// `fixed + amount * percent / 100`, plus the matching tier, kept within `min` and `max`.
+/// Amounts are in units of the record's currency
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeRule {
+  pub fixed: Decimal,
+  pub percent: Decimal,
+  /// Checked in order, the first tier whose `up_to` covers the amount is added
+  pub tiers: Vec<FeeTier>,
+  pub min: Option<Decimal>,
+  pub max: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeTier {
+  /// Largest amount of this tier, none for the last one
+  pub up_to: Option<Decimal>,
+  pub fixed: Decimal,
+  pub percent: Decimal,
+}
+
+impl FeeSchedule {
+  fn rule(&self, tx_type: TransactionType) -> Option<&FeeRule> {
+    match tx_type {
+      TransactionType::Deposit => self.deposit.as_ref(),
+      TransactionType::Withdrawal => self.withdrawal.as_ref(),
+      TransactionType::Chargeback => self.chargeback.as_ref(),
+      _ => None,
+    }
+  }
+
+  /// The fee on a `tx_type` record moving `amount`, rounded to `scale` places.
+  /// None when that type has no fee or it comes to zero
+  pub fn fee(
+    &self,
+    tx_type: TransactionType,
+    amount: Decimal,
+    scale: u32,
+  ) -> Result<Option<Decimal>, FeeOverflow> {
+    let Some(rule) = self.rule(tx_type) else {
+      return Ok(None);
+    };
+    let fee = self.rounding.round(rule.fee(amount).ok_or(FeeOverflow)?, scale);
+    Ok((fee > Decimal::ZERO).then_some(fee))
+  }
+
+  pub fn validate(&self) -> Result<()> {
+    let rules = [
+      ("deposit", &self.deposit),
+      ("withdrawal", &self.withdrawal),
+      ("chargeback", &self.chargeback),
+    ];
+    for (name, rule) in rules {
+      if let Some(rule) = rule {
+        rule.validate().map_err(|e| e.context(format!("[fees.{}]", name)))?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A fee too large for a `Decimal`, so the record cannot be charged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeOverflow;
+
+impl FeeRule {
+  /// None when the fee does not fit a `Decimal`
+  fn fee(&self, amount: Decimal) -> Option<Decimal> {
+    let part = |fixed: Decimal, percent| {
+      fixed.checked_add(amount.checked_mul(percent)? / Decimal::ONE_HUNDRED)
+    };
+    let mut fee = part(self.fixed, self.percent)?;
+    let tier = self.tiers.iter().find(|tier| tier.up_to.is_none_or(|up_to| amount <= up_to));
+    if let Some(tier) = tier {
+      fee = fee.checked_add(part(tier.fixed, tier.percent)?)?;
+    }
+    if let Some(min) = self.min {
+      fee = fee.max(min);
+    }
+    if let Some(max) = self.max {
+      fee = fee.min(max);
+    }
+    Some(fee)
+  }
+
+  fn validate(&self) -> Result<()> {
+    let negative = |value: Decimal| value < Decimal::ZERO;
+    if negative(self.fixed) || negative(self.percent) {
+      bail!("fixed and percent must not be negative");
+    }
+    if self.min.is_some_and(negative) || self.max.is_some_and(negative) {
+      bail!("min and max must not be negative");
+    }
+    if let (Some(min), Some(max)) = (self.min, self.max) {
+      if min > max {
+        bail!("min {} is above max {}", min, max);
+      }
+    }
+    let mut previous: Option<Decimal> = None;
+    for (i, tier) in self.tiers.iter().enumerate() {
+      if negative(tier.fixed) || negative(tier.percent) {
+        bail!("tier {}: fixed and percent must not be negative", i + 1);
+      }
+      match tier.up_to {
+        Some(up_to) if previous.is_some_and(|previous| up_to <= previous) => {
+          bail!("tier {}: up_to must be above the tier before it", i + 1);
+        }
+        Some(up_to) => previous = Some(up_to),
+        None if i + 1 < self.tiers.len() => {
+          bail!("tier {}: only the last tier can omit up_to", i + 1)
+        }
+        None => {}
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn schedule(toml: &str) -> FeeSchedule {
+    let schedule: FeeSchedule = toml::from_str(toml).unwrap();
+    schedule.validate().unwrap();
+    schedule
+  }
+
+  #[test]
+  fn test_fixed_and_percent() {
+    let fees = schedule("[withdrawal]\nfixed = \"0.5\"\npercent = \"1.5\"");
+    assert_eq!(
+      fees.fee(TransactionType::Withdrawal, Decimal::new(100, 0), 4),
+      Ok(Some(Decimal::new(2, 0)))
+    );
+    assert_eq!(fees.fee(TransactionType::Deposit, Decimal::new(100, 0), 4), Ok(None));
+    assert_eq!(fees.fee(TransactionType::Dispute, Decimal::new(100, 0), 4), Ok(None));
+    assert_eq!(
+      fees.fee(TransactionType::Withdrawal, Decimal::ZERO, 4),
+      Ok(Some(Decimal::new(5, 1)))
+    );
+    // Zero fees are not charged
+    let fees = schedule("[withdrawal]\npercent = \"1\"");
+    assert_eq!(fees.fee(TransactionType::Withdrawal, Decimal::ZERO, 4), Ok(None));
+  }
+
+  #[test]
+  fn test_min_and_max() {
+    let fees = schedule("[withdrawal]\npercent = \"1\"\nmin = \"1\"\nmax = \"5\"");
+    let fee = |amount| fees.fee(TransactionType::Withdrawal, Decimal::new(amount, 0), 4).unwrap();
+    assert_eq!(fee(10), Some(Decimal::ONE));
+    assert_eq!(fee(300), Some(Decimal::new(3, 0)));
+    assert_eq!(fee(10_000), Some(Decimal::new(5, 0)));
+  }
+
+  #[test]
+  fn test_tiers() {
+    let fees = schedule(
+      "[deposit]\ntiers = [\n  { up_to = \"100\", fixed = \"1\" },\n  { up_to = \"1000\", percent = \"0.5\" },\n  { fixed = \"2\" },\n]",
+    );
+    let fee = |amount| fees.fee(TransactionType::Deposit, Decimal::new(amount, 0), 4).unwrap();
+    assert_eq!(fee(100), Some(Decimal::ONE));
+    assert_eq!(fee(500), Some(Decimal::new(25, 1)));
+    assert_eq!(fee(5000), Some(Decimal::new(2, 0)));
+  }
+
+  #[test]
+  fn test_rounded_to_scale() {
+    let fees = schedule("rounding = \"half_up\"\n[withdrawal]\npercent = \"1.5\"");
+    // 1.5% of 0.33 is 0.00495
+    assert_eq!(fees.fee(TransactionType::Withdrawal, Decimal::new(33, 2), 2), Ok(None));
+    assert_eq!(
+      fees.fee(TransactionType::Withdrawal, Decimal::new(33, 2), 4),
+      Ok(Some(Decimal::new(50, 4)))
+    );
+  }
+
+  #[test]
+  fn test_overflow_is_an_error() {
+    let fees =
+      schedule("[withdrawal]\npercent = \"50\"\n[deposit]\ntiers = [{ percent = \"200\" }]");
+    assert_eq!(fees.fee(TransactionType::Withdrawal, Decimal::MAX, 4), Err(FeeOverflow));
+    assert_eq!(fees.fee(TransactionType::Deposit, Decimal::MAX, 4), Err(FeeOverflow));
+  }
+
+  #[test]
+  fn test_invalid_rules_rejected() {
+    for toml in [
+      "[withdrawal]\nfixed = \"-1\"",
+      "[withdrawal]\nmin = \"5\"\nmax = \"1\"",
+      "[deposit]\ntiers = [{ fixed = \"1\" }, { up_to = \"10\" }]",
+      "[deposit]\ntiers = [{ up_to = \"10\" }, { up_to = \"5\" }]",
+    ] {
+      let schedule: FeeSchedule = toml::from_str(toml).unwrap();
+      assert!(schedule.validate().is_err(), "{}", toml);
+    }
+    assert!(toml::from_str::<FeeSchedule>("[dispute]\nfixed = \"1\"").is_err());
+  }
+}
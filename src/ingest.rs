@@ -0,0 +1,189 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use csv::StringRecord;
+use tracing::{debug, warn};
+
+use crate::engine::{Engine, Outcome};
+use crate::metrics::Metrics;
+use crate::notify::{self, Deliveries};
+use crate::transaction::TransactionRecord;
+
+/// Columns of a connection that does not start with a header line
+const DEFAULT_HEADER: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Accepts TCP connections on `addr` until the process is stopped. Every line is one CSV
+/// row, answered with `OK` or `ERR <code> <message>` once the engine has applied or
+/// rejected it. A connection may open with a header line to use the optional columns
+pub fn listen(
+  addr: &str,
+  engine: Arc<Mutex<Engine>>,
+  metrics: Option<Arc<Metrics>>,
+  deliveries: Option<Deliveries>,
+) -> Result<()> {
+  let listener =
+    TcpListener::bind(addr).with_context(|| format!("Failed to listen on '{}'", addr))?;
+  debug!(addr, "Accepting records");
+  for stream in listener.incoming() {
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        warn!(error = %e, "Failed to accept a connection");
+        continue;
+      }
+    };
+    let (engine, metrics, deliveries) = (Arc::clone(&engine), metrics.clone(), deliveries.clone());
+    thread::spawn(move || {
+      let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+      debug!(peer, "Connection opened");
+      if let Err(e) = connection(stream, &engine, metrics.as_deref(), deliveries.as_ref()) {
+        warn!(peer, error = %e, "Connection failed");
+      }
+      debug!(peer, "Connection closed");
+    });
+  }
+  Ok(())
+}
+
+fn connection(
+  stream: TcpStream,
+  engine: &Mutex<Engine>,
+  metrics: Option<&Metrics>,
+  deliveries: Option<&Deliveries>,
+) -> Result<()> {
+  let reader = BufReader::new(stream.try_clone()?);
+  let mut writer = BufWriter::new(stream);
+  let mut header = None;
+  for line in reader.lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let ack = match &header {
+      Some(header) => ack(engine, metrics, deliveries, header, &line),
+      None => {
+        let first = row(&line);
+        if first.iter().any(|column| column == "batch") {
+          // A batch only ends at the row after it, which the producer sends once its
+          // last row is answered. Applying the rows one by one would break all or nothing
+          writeln!(writer, "ERR UnsupportedColumn batches are not supported over TCP")?;
+          writer.flush()?;
+          return Ok(());
+        }
+        if first.get(0) == Some("type") {
+          header = Some(first);
+          "OK".to_string()
+        } else {
+          let default = header.insert(StringRecord::from(DEFAULT_HEADER.to_vec()));
+          ack(engine, metrics, deliveries, default, &line)
+        }
+      }
+    };
+    writeln!(writer, "{}", ack)?;
+    // Producers wait for the answer before they send the next line
+    writer.flush()?;
+  }
+  Ok(())
+}
+
+fn row(line: &str) -> StringRecord {
+  let mut reader = csv::ReaderBuilder::new()
+    .has_headers(false)
+    .trim(csv::Trim::All)
+    .flexible(true)
+    .from_reader(line.as_bytes());
+  reader.records().next().and_then(Result::ok).unwrap_or_default()
+}
+
+/// Applies one line and gives its answer. The engine is held for the record only,
+/// so lines from different connections interleave but tx ids stay globally unique
+fn ack(
+  engine: &Mutex<Engine>,
+  metrics: Option<&Metrics>,
+  deliveries: Option<&Deliveries>,
+  header: &StringRecord,
+  line: &str,
+) -> String {
+  let record: TransactionRecord = match row(line).deserialize(Some(header)) {
+    Ok(record) => record,
+    Err(e) => return format!("ERR InvalidRecord {}", e),
+  };
+  let tx_type = record.tx_type;
+  let started = Instant::now();
+  let result = {
+    let mut engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+    let result = engine.process(record);
+    notify::flush(&mut engine, deliveries);
+    // Nothing reads alerts here, keep them from piling up
+    engine.take_alerts();
+    result
+  };
+  if let Some(metrics) = metrics {
+    metrics.observe(started.elapsed());
+  }
+  match result {
+    Ok(outcome) => {
+      if let (Some(metrics), Outcome::Applied) = (metrics, outcome) {
+        metrics.applied(tx_type);
+      }
+      "OK".to_string()
+    }
+    Err(e) => {
+      if let Some(metrics) = metrics {
+        metrics.rejected(&e);
+      }
+      format!("ERR {} {}", e.code(), e)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header() -> StringRecord {
+    StringRecord::from(DEFAULT_HEADER.to_vec())
+  }
+
+  #[test]
+  fn test_ack() {
+    let engine = Mutex::new(Engine::new());
+    assert_eq!(ack(&engine, None, None, &header(), "deposit, 1, 1, 10.0"), "OK");
+    assert_eq!(
+      ack(&engine, None, None, &header(), "deposit,2,1,5.0"),
+      "ERR DuplicateTransaction tx 1: duplicate transaction ID"
+    );
+    assert!(
+      ack(&engine, None, None, &header(), "withdrawal,1,2,50").starts_with("ERR InsufficientFunds")
+    );
+    assert!(ack(&engine, None, None, &header(), "refund,1,3,5").starts_with("ERR InvalidRecord"));
+    assert_eq!(ack(&engine, None, None, &header(), "dispute,1,1,"), "OK");
+  }
+
+  #[test]
+  fn test_ack_with_header_columns() {
+    let engine = Mutex::new(Engine::new());
+    let header = row("type,client,tx,amount,currency");
+    assert_eq!(ack(&engine, None, None, &header, "deposit,1,1,10.0,eur"), "OK");
+    let account = engine.lock().unwrap().account(1).unwrap().unwrap();
+    assert!(account.balances.contains_key("EUR"));
+  }
+
+  #[test]
+  fn test_batch_header_refused() {
+    let engine = Mutex::new(Engine::new());
+    // Only the header, rows left unread would reset the connection before the answer is read
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut producer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    writeln!(producer, "type,client,tx,amount,batch").unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    connection(stream, &engine, None, None).unwrap();
+
+    let answers: Vec<String> = BufReader::new(producer).lines().map(Result::unwrap).collect();
+    assert_eq!(answers, ["ERR UnsupportedColumn batches are not supported over TCP"]);
+  }
+}
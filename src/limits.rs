@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::transaction::parse_currency;
+
+/// Withdrawal limits from a local TOML file. A client gets its own entry, then its
+/// tier's, then the default, setting by setting:
+///
+/// ```toml
+/// [default]
+/// max_withdrawal = "1000"
+///
+/// [tiers.gold]
+/// max_withdrawal = "10000"
+/// max_daily_outflow = "50000"
+///
+/// [clients.7]
+/// tier = "gold"
+/// max_withdrawals_per_day = 3
+/// credit = { USD = "500" }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LimitsTable {
+  default: Limits,
+  tiers: HashMap<String, Limits>,
+  clients: HashMap<u16, Limits>,
+}
+
+/// Every limit is optional, a missing one is not enforced. Amounts are in units of the
+/// withdrawal's currency
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+  /// Only in a `[clients.N]` entry, the tier whose limits apply underneath
+  pub tier: Option<String>,
+  /// Largest amount of a single withdrawal
+  pub max_withdrawal: Option<Decimal>,
+  /// Withdrawals per UTC day, counted on the record timestamps
+  pub max_withdrawals_per_day: Option<u32>,
+  /// At most `count` withdrawals within any `records` consecutive input records
+  pub max_withdrawals_per_records: Option<Velocity>,
+  /// Sum of withdrawals per UTC day, counted on the record timestamps
+  pub max_daily_outflow: Option<Decimal>,
+  /// Credit line per currency, how far below zero `available` may go
+  pub credit: Option<BTreeMap<String, Decimal>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Velocity {
+  pub count: u32,
+  pub records: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+  #[serde(default)]
+  default: Limits,
+  #[serde(default)]
+  tiers: HashMap<String, Limits>,
+  /// TOML keys are strings, the client ids are parsed afterwards
+  #[serde(default)]
+  clients: HashMap<String, Limits>,
+}
+
+impl Limits {
+  /// Each setting from `self`, falling back to `other`
+  fn or(self, other: &Limits) -> Limits {
+    Limits {
+      tier: self.tier.or_else(|| other.tier.clone()),
+      max_withdrawal: self.max_withdrawal.or(other.max_withdrawal),
+      max_withdrawals_per_day: self.max_withdrawals_per_day.or(other.max_withdrawals_per_day),
+      max_withdrawals_per_records: self
+        .max_withdrawals_per_records
+        .or(other.max_withdrawals_per_records),
+      max_daily_outflow: self.max_daily_outflow.or(other.max_daily_outflow),
+      credit: self.credit.or_else(|| other.credit.clone()),
+    }
+  }
+}
+
+impl LimitsTable {
+  pub fn load(path: &Path) -> Result<Self> {
+    let context = || format!("Failed to load limits '{}'", path.display());
+    let text = fs::read_to_string(path).with_context(context)?;
+    Self::from_toml(&text).with_context(context)
+  }
+
+  pub fn from_toml(text: &str) -> Result<Self> {
+    let mut file: LimitsFile = toml::from_str(text)?;
+
+    if file.default.tier.is_some() || file.tiers.values().any(|tier| tier.tier.is_some()) {
+      bail!("only client entries can name a tier");
+    }
+    for limits in
+      file.clients.values_mut().chain(file.tiers.values_mut()).chain([&mut file.default])
+    {
+      if let Some(credit) = limits.credit.take() {
+        limits.credit = Some(credit_lines(credit)?);
+      }
+    }
+    let mut clients = HashMap::with_capacity(file.clients.len());
+    for (client, limits) in file.clients {
+      let id: u16 = client.parse().with_context(|| format!("invalid client id '{}'", client))?;
+      if let Some(tier) = &limits.tier {
+        if !file.tiers.contains_key(tier) {
+          bail!("client {} names unknown tier '{}'", id, tier);
+        }
+      }
+      clients.insert(id, limits);
+    }
+    for limits in clients.values().chain(file.tiers.values()).chain([&file.default]) {
+      if let Some(velocity) = limits.max_withdrawals_per_records {
+        if velocity.records == 0 {
+          bail!("max_withdrawals_per_records needs records of at least 1");
+        }
+      }
+    }
+
+    Ok(Self { default: file.default, tiers: file.tiers, clients })
+  }
+
+  /// How far below zero the client's `available` may go in `currency`
+  pub fn credit_line(&self, client: u16, currency: &str) -> Decimal {
+    let credit = self.for_client(client).credit;
+    credit.and_then(|lines| lines.get(currency).copied()).unwrap_or_default()
+  }
+
+  /// Whether any client or tier has a credit line, the output then shows what is left of it
+  pub fn has_credit_lines(&self) -> bool {
+    self.clients.values().chain(self.tiers.values()).chain([&self.default]).any(|limits| {
+      limits.credit.as_ref().is_some_and(|lines| lines.values().any(|line| !line.is_zero()))
+    })
+  }
+
+  /// The limits that apply to `client`
+  pub fn for_client(&self, client: u16) -> Limits {
+    let own = self.clients.get(&client).cloned().unwrap_or_default();
+    let tier = own.tier.as_ref().and_then(|tier| self.tiers.get(tier));
+    match tier {
+      Some(tier) => own.or(tier).or(&self.default),
+      None => own.or(&self.default),
+    }
+  }
+}
+
+/// Currency codes normalized like the input's, lines must not be negative
+fn credit_lines(lines: BTreeMap<String, Decimal>) -> Result<BTreeMap<String, Decimal>> {
+  let mut normalized = BTreeMap::new();
+  for (currency, line) in lines {
+    let currency = parse_currency(currency.trim()).map_err(anyhow::Error::msg)?;
+    if line < Decimal::ZERO {
+      bail!("credit line in {} must not be negative, got {}", currency, line);
+    }
+    if normalized.insert(currency.clone(), line).is_some() {
+      bail!("credit line in {} is listed twice", currency);
+    }
+  }
+  Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LIMITS: &str = r#"
+    [default]
+    max_withdrawal = "100"
+    max_withdrawals_per_day = 5
+
+    [tiers.gold]
+    max_withdrawal = "1000"
+    max_daily_outflow = "5000"
+
+    [clients.1]
+    tier = "gold"
+    max_withdrawals_per_records = { count = 2, records = 10 }
+
+    [clients.2]
+    max_withdrawal = "50"
+    credit = { usd = "250" }
+  "#;
+
+  #[test]
+  fn test_resolution_order() {
+    let table = LimitsTable::from_toml(LIMITS).unwrap();
+
+    let gold = table.for_client(1);
+    assert_eq!(gold.max_withdrawal, Some(Decimal::new(1000, 0)));
+    assert_eq!(gold.max_withdrawals_per_day, Some(5));
+    assert_eq!(gold.max_withdrawals_per_records, Some(Velocity { count: 2, records: 10 }));
+    assert_eq!(gold.max_daily_outflow, Some(Decimal::new(5000, 0)));
+
+    let own = table.for_client(2);
+    assert_eq!(own.max_withdrawal, Some(Decimal::new(50, 0)));
+    assert_eq!(own.max_daily_outflow, None);
+
+    let default = table.for_client(3);
+    assert_eq!(default.max_withdrawal, Some(Decimal::new(100, 0)));
+  }
+
+  #[test]
+  fn test_credit_lines() {
+    let table = LimitsTable::from_toml(LIMITS).unwrap();
+    assert!(table.has_credit_lines());
+    assert_eq!(table.credit_line(2, "USD"), Decimal::new(250, 0));
+    assert_eq!(table.credit_line(2, "EUR"), Decimal::ZERO);
+    assert_eq!(table.credit_line(1, "USD"), Decimal::ZERO);
+  }
+
+  #[test]
+  fn test_empty_file_has_no_limits() {
+    let table = LimitsTable::from_toml("").unwrap();
+    assert_eq!(table.for_client(1), Limits::default());
+    assert!(!table.has_credit_lines());
+  }
+
+  #[test]
+  fn test_invalid_files_rejected() {
+    assert!(LimitsTable::from_toml("[clients.x]\nmax_withdrawal = \"1\"").is_err());
+    assert!(LimitsTable::from_toml("[clients.1]\ntier = \"gold\"").is_err());
+    assert!(LimitsTable::from_toml("[default]\ntier = \"gold\"").is_err());
+    assert!(LimitsTable::from_toml("[default]\nmax_withdrawl = \"1\"").is_err());
+    assert!(LimitsTable::from_toml("[default]\ncredit = { USD = \"-1\" }").is_err());
+    assert!(LimitsTable::from_toml("[default]\ncredit = { USD = \"1\", usd = \"2\" }").is_err());
+    assert!(
+      LimitsTable::from_toml("[default]\nmax_withdrawals_per_records = { count = 1, records = 0 }")
+        .is_err()
+    );
+  }
+}
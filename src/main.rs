@@ -1,19 +1,56 @@
 mod account;
+mod batch;
+mod cli;
+mod config;
+mod diff;
 mod engine;
+mod events;
+mod fees;
+mod ingest;
+mod limits;
+mod metrics;
+mod notify;
+mod query;
+mod rates;
+mod reconcile;
+mod replay;
+mod rules;
+mod service;
+mod storage;
+mod summary;
 mod transaction;
+mod validate;
 
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
 
 use anyhow::{Context, Result};
+use rust_decimal::Decimal;
 use tracing::{Level, debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 use account::AccountOutput;
-use engine::Engine;
-use transaction::TransactionRecord;
+use batch::{Batcher, Unit};
+use cli::{DiffOptions, Options, QueryOptions, ReconcileOptions, ReplayOptions};
+use config::{EngineConfig, PrecisionConfig};
+use engine::{Engine, EngineError, Outcome};
+use events::EventSink;
+use limits::LimitsTable;
+use metrics::Metrics;
+use notify::{Deliveries, Notifier, NotifyConfig};
+use rates::RateTable;
+use replay::RecordLog;
+use rules::{Alert, RuleSet};
+use service::Service;
+use storage::Storage;
+use summary::Summary;
+use transaction::{TransactionRecord, TransactionType};
 
 /// THIS error file is created to log the ignored errors
 const ERROR_FILE: &str = "errors.log";
@@ -33,23 +70,97 @@ fn main() {
 
 fn run() -> Result<()> {
   let args: Vec<String> = env::args().collect();
-
-  if args.len() != 2 {
-    eprintln!("Usage: {} <transactions.csv>", args[0]);
-    process::exit(1);
+  if args.get(1).is_some_and(|arg| arg == "query") {
+    return run_query(&args);
+  }
+  if args.get(1).is_some_and(|arg| arg == "replay") {
+    return run_replay(&args);
+  }
+  if args.get(1).is_some_and(|arg| arg == "diff") {
+    return run_diff(&args);
+  }
+  if args.get(1).is_some_and(|arg| arg == "reconcile") {
+    return run_reconcile(&args);
   }
 
-  let input_path = &args[1];
+  let options = match Options::parse(&args[1..]) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("Error: {}", e);
+      eprintln!("{}", cli::usage(&args[0]));
+      process::exit(1);
+    }
+  };
+
+  let input_path = options.input.display();
 
   info!(input = %input_path, "Starting transaction processing");
 
-  // Open the input file
-  let file = File::open(input_path).with_context(|| format!("Failed to open '{}'", input_path))?;
-  let reader = BufReader::new(file);
+  let mut config = match &options.config {
+    Some(path) => EngineConfig::load(path)?,
+    None => EngineConfig::default(),
+  };
+  config.idempotent |= options.idempotent;
+
+  let storage = storage::open(options.storage.as_deref().unwrap_or("memory"))?;
+  // A dry run changes nothing the risk team needs to hear about
+  let notifier = match &config.notifications {
+    Some(path) if !options.dry_run => Some(Notifier::new(NotifyConfig::load(path)?)),
+    _ => None,
+  };
+  let mut engine = build_engine(storage, config)?;
+  if options.events.is_some() || notifier.is_some() {
+    engine = engine.with_events();
+  }
+
+  if options.serve.is_some() || options.listen.is_some() {
+    let deliveries =
+      notifier.map(|notifier| Deliveries::spawn(notifier, engine.config().precision.clone()));
+    let engine = Arc::new(Mutex::new(engine));
+    let metrics = match &options.metrics {
+      Some(metrics_addr) => {
+        let metrics = Arc::new(Metrics::default());
+        metrics::serve(metrics_addr, Arc::clone(&metrics), Arc::clone(&engine))?;
+        Some(metrics)
+      }
+      None => None,
+    };
+    return match (&options.serve, &options.listen) {
+      (Some(addr), _) => Service::new(engine, metrics, deliveries).run(addr),
+      (_, Some(addr)) => ingest::listen(addr, engine, metrics, deliveries),
+      _ => unreachable!("checked above"),
+    };
+  }
+
+  // Open the input file, `-` reads stdin until it is closed
+  let reader: Box<dyn Read> = if options.input.as_os_str() == "-" {
+    Box::new(io::stdin())
+  } else {
+    let file =
+      File::open(&options.input).with_context(|| format!("Failed to open '{}'", input_path))?;
+    Box::new(BufReader::new(file))
+  };
   debug!(path = %input_path, "Opened input file");
 
+  let statements = report_file(
+    options.statements.as_ref(),
+    "client,tx,type,amount,currency,timestamp,available,held,total",
+  )?;
+  let journal =
+    report_file(options.journal.as_ref(), "tx,client,from,to,amount,rate,converted,timestamp")?;
+  let alerts = report_file(options.alerts.as_ref(), "tx,client,type,rule,action,timestamp")?;
+  // Opening a named pipe waits for its reader
+  let events = match &options.events {
+    Some(path) => {
+      let file =
+        File::create(path).with_context(|| format!("Failed to create '{}'", path.display()))?;
+      Some(EventSink::new(BufWriter::new(file)))
+    }
+    None => None,
+  };
+
   // Create the error file, fall back to sink if it fails
-  let mut error_writer: Box<dyn Write> = match File::create(ERROR_FILE) {
+  let errors: Box<dyn Write> = match File::create(ERROR_FILE) {
     Ok(file) => {
       debug!(path = %ERROR_FILE, "Writing errors to file");
       Box::new(BufWriter::new(file))
@@ -59,67 +170,507 @@ fn run() -> Result<()> {
       Box::new(io::sink())
     }
   };
+  let mut reports = Reports {
+    errors,
+    statements,
+    journal,
+    alerts,
+    events,
+    notifier,
+    record_log: match &options.record_log {
+      Some(path) => {
+        Some(RecordLog::open(path, storage::is_persistent(options.storage.as_deref()))?)
+      }
+      None => None,
+    },
+    fee_summary: options.fee_summary.clone(),
+    fees: BTreeMap::new(),
+    summary: options.summary.as_ref().map(|_| Summary::new()),
+    metrics: None,
+  };
 
   let mut csv_reader =
     csv::ReaderBuilder::new().trim(csv::Trim::All).flexible(true).from_reader(reader);
 
-  let mut engine = Engine::new();
+  if options.dry_run {
+    let validation = validate::validate(&mut engine, &mut csv_reader, &mut reports.errors)?;
+    reports.finish(&engine.config().precision)?;
+    validation.write(&mut io::stdout().lock())?;
+    return validation.check(options.max_error_rate.unwrap_or_default());
+  }
+
+  // Scrapes read the engine between records
+  let engine = Arc::new(Mutex::new(engine));
+  if let Some(addr) = &options.metrics {
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(addr, Arc::clone(&metrics), Arc::clone(&engine))?;
+    reports.metrics = Some(metrics);
+  }
 
+  // Waits for the first line when reading stdin
+  let currency_column = csv_reader.headers()?.iter().any(|h| h == "currency");
+  let mut batcher = Batcher::new();
   for result in csv_reader.deserialize::<TransactionRecord>() {
     match result {
       Ok(record) => {
         debug!(tx = record.tx, client = record.client, "Processing transaction");
-        if let Err(e) = engine.process(record) {
-          warn!(error = %e, "Transaction processing failed");
-          let _ = writeln!(error_writer, "{}", e);
+        for unit in batcher.push(record) {
+          process_unit(&mut lock(&engine), unit, &mut reports)?;
         }
       }
       Err(e) => {
         warn!(error = %e, "Failed to parse record");
-        let _ = writeln!(error_writer, "Failed to parse record: {}", e);
+        let _ = writeln!(reports.errors, "Failed to parse record: {}", e);
+        if let Some(summary) = reports.summary.as_mut() {
+          summary.unparsable();
+        }
       }
     }
   }
+  if let Some(unit) = batcher.finish() {
+    process_unit(&mut lock(&engine), unit, &mut reports)?;
+  }
 
-  let _ = error_writer.flush();
+  let engine = lock(&engine);
+  if let (Some(mut summary), Some(path)) = (reports.summary.take(), &options.summary) {
+    summary.finish(&engine)?;
+    write_summary(&summary, path, options.summary_format)?;
+  }
+  reports.finish(&engine.config().precision)?;
 
   // Output account states
-  write_output(&engine)?;
+  write_output(&engine, currency_column)?;
+
+  Ok(())
+}
+
+/// The engine with the rate, limit and rule tables the config points to
+fn build_engine(storage: Box<dyn Storage>, config: EngineConfig) -> Result<Engine> {
+  let rates = match &config.conversion.rates {
+    Some(path) => RateTable::load(path)?,
+    None => RateTable::default(),
+  };
+  let limits = match &config.limits {
+    Some(path) => LimitsTable::load(path)?,
+    None => LimitsTable::default(),
+  };
+  let rules = match &config.rules {
+    Some(path) => RuleSet::load(path)?,
+    None => RuleSet::default(),
+  };
+  Ok(Engine::with_storage(storage, config).with_rates(rates).with_limits(limits).with_rules(rules))
+}
+
+/// `query`: answers questions about one client of a saved state without processing anything
+fn run_query(args: &[String]) -> Result<()> {
+  let options = match QueryOptions::parse(&args[2..]) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("Error: {}", e);
+      eprintln!("{}", cli::query_usage(&args[0]));
+      process::exit(1);
+    }
+  };
+  let engine = open_state(&options.state, options.config.as_deref())?;
+  query::run(&engine, options.client, &options.queries, &mut io::stdout().lock())
+}
+
+/// `reconcile`: compares a saved state to the bank's balances and fails on a break
+fn run_reconcile(args: &[String]) -> Result<()> {
+  let options = match ReconcileOptions::parse(&args[2..]) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("Error: {}", e);
+      eprintln!("{}", cli::reconcile_usage(&args[0]));
+      process::exit(1);
+    }
+  };
+  let engine = open_state(&options.state, options.config.as_deref())?;
+  let expected = reconcile::load(&options.expected)?;
+  let reconciliation = reconcile::reconcile(&engine, expected, options.tolerance)?;
+  reconciliation.write(&engine.config().precision, &mut io::stdout().lock())?;
+  reconciliation.check()
+}
+
+/// An engine over a state saved with `--storage sqlite:<path>`
+fn open_state(state: &Path, config: Option<&Path>) -> Result<Engine> {
+  // Opening a missing file would create an empty state
+  if !state.is_file() {
+    anyhow::bail!("No saved state at '{}'", state.display());
+  }
+  let config = match config {
+    Some(path) => EngineConfig::load(path)?,
+    None => EngineConfig::default(),
+  };
+  Ok(Engine::with_storage(storage::open_read_only(state)?, config))
+}
 
+/// `replay`: rebuilds the accounts from a record log on a fresh engine and writes them
+/// as of the cut
+fn run_replay(args: &[String]) -> Result<()> {
+  let options = match ReplayOptions::parse(&args[2..]) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("Error: {}", e);
+      eprintln!("{}", cli::replay_usage(&args[0]));
+      process::exit(1);
+    }
+  };
+  let config = match &options.config {
+    Some(path) => EngineConfig::load(path)?,
+    None => EngineConfig::default(),
+  };
+  let mut engine = build_engine(storage::open("memory")?, config)?;
+  let last = replay::replay(&mut engine, &options.log, options.until)?;
+  info!(last, "Replayed record log");
+  write_output(&engine, false)?;
   Ok(())
 }
 
-fn write_output(engine: &Engine) -> Result<usize> {
+/// `diff`: compares two account states and fails when they differ by more than the
+/// tolerance
+fn run_diff(args: &[String]) -> Result<()> {
+  let options = match DiffOptions::parse(&args[2..]) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("Error: {}", e);
+      eprintln!("{}", cli::diff_usage(&args[0]));
+      process::exit(1);
+    }
+  };
+  let config = match &options.config {
+    Some(path) => EngineConfig::load(path)?,
+    None => EngineConfig::default(),
+  };
+  let diff = diff::compare(diff::load(&options.before)?, diff::load(&options.after)?);
+  diff.write(&config.precision, &mut io::stdout().lock())?;
+  diff.check(options.tolerance)
+}
+
+/// The engine stays usable when a metrics scrape panicked while holding it
+fn lock(engine: &Mutex<Engine>) -> MutexGuard<'_, Engine> {
+  engine.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// One row per client per currency. The currency column is only written when the input
+/// had one or some balance is not in the default currency, so single currency output
+/// keeps the format from the spec
+fn write_output(engine: &Engine, currency_column: bool) -> Result<usize> {
   let stdout = io::stdout();
   let mut handle = stdout.lock();
 
-  // the csv header
-  writeln!(handle, "client,available,held,total,locked")?;
-
-  // Since we have a u16, we can sort the accounts with reasonably low overhead
-  let mut accounts: Vec<AccountOutput> = engine.accounts().map(AccountOutput::from).collect();
+  // Since we have a u16, we can sort the accounts with reasonably low overhead.
+  // The rows of one account are already in currency order
+  let mut accounts = engine.accounts()?;
   accounts.sort_by_key(|a| a.client);
+  let rows: Vec<AccountOutput> = accounts.iter().flat_map(AccountOutput::rows).collect();
+  let precision = &engine.config().precision;
+  let currency_column =
+    currency_column || rows.iter().any(|row| row.currency != account::DEFAULT_CURRENCY);
+
+  // the csv header
+  let currency_header = if currency_column { "currency," } else { "" };
+  let credit_column = engine.limits().has_credit_lines();
+  let credit_header = if credit_column { ",credit_remaining" } else { "" };
+  writeln!(handle, "client,{}available,held,total,locked{}", currency_header, credit_header)?;
 
-  let count = accounts.len();
+  let count = rows.len();
 
-  for account in accounts {
+  for row in rows {
+    let currency = if currency_column { format!("{},", row.currency) } else { String::new() };
+    let scale = precision.scale(&row.currency);
+    // What is left of the credit line once an overdrawn `available` is taken off it
+    let credit = if credit_column {
+      let line = engine.limits().credit_line(row.client, &row.currency);
+      format!(",{}", format_decimal(line + row.available.min(Decimal::ZERO), scale))
+    } else {
+      String::new()
+    };
     writeln!(
       handle,
-      "{},{},{},{},{}",
-      account.client,
-      format_decimal(account.available),
-      format_decimal(account.held),
-      format_decimal(account.total),
-      account.locked
+      "{},{}{},{},{},{}{}",
+      row.client,
+      currency,
+      format_decimal(row.available, scale),
+      format_decimal(row.held, scale),
+      format_decimal(row.total, scale),
+      row.locked,
+      credit
     )?;
   }
 
   Ok(count)
 }
 
-///  Per the spec "You can assume a precision of 4 places past the decimal"
-fn format_decimal(d: rust_decimal::Decimal) -> String {
-  format!("{:.4}", d)
+fn process_unit(engine: &mut Engine, unit: Unit, reports: &mut Reports) -> Result<()> {
+  let started = Instant::now();
+  match unit {
+    Unit::Single(record) => {
+      let applied = record.clone();
+      match engine.process(record) {
+        Ok(Outcome::Applied) => reports.applied(engine, &applied)?,
+        Ok(Outcome::Skipped) => reports.skipped(&applied)?,
+        Err(e) => reports.rejected(&e, std::slice::from_ref(&applied))?,
+      }
+    }
+    Unit::Batch(id, records) => {
+      debug!(batch = id, size = records.len(), "Processing batch");
+      let applied = records.clone();
+      match engine.process_batch(id, records) {
+        Ok(outcomes) => {
+          for (record, outcome) in applied.iter().zip(outcomes) {
+            match outcome {
+              Outcome::Applied => reports.applied(engine, record)?,
+              Outcome::Skipped => reports.skipped(record)?,
+            }
+          }
+        }
+        Err(e) => reports.rejected(&e, &applied)?,
+      }
+    }
+  }
+  if let Some(metrics) = &reports.metrics {
+    metrics.observe(started.elapsed());
+  }
+  let events = engine.take_events();
+  if let Some(notifier) = reports.notifier.as_mut() {
+    notifier.notify(&engine.config().precision, &events)?;
+  }
+  if let Some(sink) = reports.events.as_mut() {
+    sink.write(&engine.config().precision, events)?;
+  }
+  reports.alerts(engine.take_alerts())
+}
+
+/// The run summary to a file, or to stderr for `-`
+fn write_summary(summary: &Summary, path: &PathBuf, format: summary::SummaryFormat) -> Result<()> {
+  if path.as_os_str() == "-" {
+    summary.write(&mut io::stderr().lock(), format)?;
+    return Ok(());
+  }
+  let file =
+    File::create(path).with_context(|| format!("Failed to create '{}'", path.display()))?;
+  let mut writer = BufWriter::new(file);
+  summary.write(&mut writer, format)?;
+  writer.flush()?;
+  Ok(())
+}
+
+/// A report file with its CSV header, None when the option is not given
+fn report_file(path: Option<&PathBuf>, header: &str) -> Result<Option<BufWriter<File>>> {
+  let Some(path) = path else {
+    return Ok(None);
+  };
+  let file =
+    File::create(path).with_context(|| format!("Failed to create '{}'", path.display()))?;
+  let mut writer = BufWriter::new(file);
+  writeln!(writer, "{}", header)?;
+  Ok(Some(writer))
+}
+
+/// Where the outcome of each record goes, besides the final account table
+struct Reports {
+  errors: Box<dyn Write>,
+  statements: Option<BufWriter<File>>,
+  journal: Option<BufWriter<File>>,
+  /// Every screening rule a record matched
+  alerts: Option<BufWriter<File>>,
+  /// Every account change, flushed after each record or batch
+  events: Option<EventSink<BufWriter<File>>>,
+  /// Tells the risk team about locks, chargebacks and large disputes
+  notifier: Option<Notifier>,
+  /// Every record and its outcome, what `replay` rebuilds the engine from
+  record_log: Option<RecordLog>,
+  /// Written by `finish` from `fees`
+  fee_summary: Option<PathBuf>,
+  /// Count and total per fee type and currency
+  fees: BTreeMap<(String, String), (u64, Decimal)>,
+  /// Only collected when `--summary` asks for it
+  summary: Option<Summary>,
+  /// Only collected when `--metrics` serves them
+  metrics: Option<Arc<Metrics>>,
+}
+
+impl Reports {
+  /// One statement line per applied record with the client's balances right after it,
+  /// and a line for its fee. Members of a batch show the balances after the whole batch
+  fn applied(&mut self, engine: &Engine, record: &TransactionRecord) -> Result<()> {
+    if let Some(log) = self.record_log.as_mut() {
+      log.applied(record)?;
+    }
+    self.journal(engine, record)?;
+    // Disputes carry no currency of their own, the stored transaction knows it
+    let stored = engine.transaction(record.tx)?;
+    if let Some(summary) = self.summary.as_mut() {
+      summary.applied(record, stored.as_ref());
+    }
+    if let Some(metrics) = &self.metrics {
+      metrics.applied(record.tx_type);
+    }
+    let Some(stored) = stored else {
+      return Ok(());
+    };
+    let fee_type = format!("{}_fee", record.tx_type);
+    let fee = stored.fee(record.tx_type);
+    if let Some(fee) = fee {
+      let (count, total) =
+        self.fees.entry((fee_type.clone(), stored.currency.clone())).or_default();
+      *count += 1;
+      *total += fee;
+    }
+
+    let Some(writer) = self.statements.as_mut() else {
+      return Ok(());
+    };
+    let Some(account) = engine.account(record.client)? else {
+      return Ok(());
+    };
+    let balance = account.balance(&stored.currency);
+    let scale = engine.config().precision.scale(&stored.currency);
+    let timestamp = record.timestamp.map(|ts| ts.to_rfc3339()).unwrap_or_default();
+    let mut line = |tx_type: &str, amount: String, available: Decimal| {
+      writeln!(
+        writer,
+        "{},{},{},{},{},{},{},{},{}",
+        record.client,
+        record.tx,
+        tx_type,
+        amount,
+        stored.currency,
+        timestamp,
+        format_decimal(available, scale),
+        format_decimal(balance.held, scale),
+        format_decimal(available + balance.held, scale)
+      )
+    };
+    // The fee is posted after the record, so the record's own line has it added back
+    let fee_amount = fee.unwrap_or_default();
+    // The amount as booked, which may have been rounded. An interest record has none
+    // of its own, its line shows what was credited
+    let amount = match (record.amount, record.tx_type) {
+      (Some(_), _) | (None, TransactionType::AccrueInterest) => {
+        format_decimal(stored.amount, scale)
+      }
+      (None, _) => String::new(),
+    };
+    line(&record.tx_type.to_string(), amount, balance.available + fee_amount)?;
+    if let Some(fee) = fee {
+      line(&fee_type, format_decimal(fee, scale), balance.available)?;
+    }
+    Ok(())
+  }
+
+  /// The journal line of an applied conversion
+  fn journal(&mut self, engine: &Engine, record: &TransactionRecord) -> Result<()> {
+    let Some(writer) = self.journal.as_mut() else {
+      return Ok(());
+    };
+    if record.tx_type != TransactionType::Convert {
+      return Ok(());
+    }
+    let Some(stored) = engine.transaction(record.tx)? else {
+      return Ok(());
+    };
+    let Some(conversion) = stored.conversion else {
+      return Ok(());
+    };
+    writeln!(
+      writer,
+      "{},{},{},{},{},{},{},{}",
+      record.tx,
+      stored.client,
+      stored.currency,
+      conversion.to_currency,
+      stored.amount,
+      conversion.rate,
+      conversion.converted,
+      stored.timestamp.map(|ts| ts.to_rfc3339()).unwrap_or_default()
+    )?;
+    Ok(())
+  }
+
+  fn alerts(&mut self, alerts: Vec<Alert>) -> Result<()> {
+    let Some(writer) = self.alerts.as_mut() else {
+      return Ok(());
+    };
+    for alert in alerts {
+      writeln!(
+        writer,
+        "{},{},{},{},{},{}",
+        alert.tx,
+        alert.client,
+        alert.tx_type,
+        alert.rule,
+        alert.action,
+        alert.timestamp.map(|ts| ts.to_rfc3339()).unwrap_or_default()
+      )?;
+    }
+    Ok(())
+  }
+
+  fn skipped(&mut self, record: &TransactionRecord) -> Result<()> {
+    debug!(tx = record.tx, "Skipped replayed record");
+    if let Some(summary) = self.summary.as_mut() {
+      summary.skipped();
+    }
+    if let Some(log) = self.record_log.as_mut() {
+      log.skipped(record)?;
+    }
+    Ok(())
+  }
+
+  /// `records` is the rejected record, or every member of a rolled back batch
+  fn rejected(&mut self, error: &EngineError, records: &[TransactionRecord]) -> Result<()> {
+    warn!(error = %error, "Transaction processing failed");
+    if let Some(log) = self.record_log.as_mut() {
+      log.rejected(records)?;
+    }
+    if let Some(summary) = self.summary.as_mut() {
+      summary.rejected(error, records);
+    }
+    if let Some(metrics) = &self.metrics {
+      metrics.rejected(error);
+    }
+    let failed = match error {
+      EngineError::BatchFailed { tx, .. } => records.iter().find(|r| r.tx == *tx),
+      _ if records.len() == 1 => records.first(),
+      _ => None,
+    };
+    let timestamp = failed.and_then(|r| r.timestamp);
+    let _ = match timestamp {
+      Some(timestamp) => writeln!(self.errors, "{} (at {})", error, timestamp.to_rfc3339()),
+      None => writeln!(self.errors, "{}", error),
+    };
+    Ok(())
+  }
+
+  fn finish(mut self, precision: &PrecisionConfig) -> Result<()> {
+    let _ = self.errors.flush();
+    if let Some(log) = self.record_log.as_mut() {
+      log.finish()?;
+    }
+    if let Some(path) = &self.fee_summary {
+      let file =
+        File::create(path).with_context(|| format!("Failed to create '{}'", path.display()))?;
+      let mut writer = BufWriter::new(file);
+      writeln!(writer, "type,currency,count,total")?;
+      for ((fee_type, currency), (count, total)) in &self.fees {
+        let total = format_decimal(*total, precision.scale(currency));
+        writeln!(writer, "{},{},{},{}", fee_type, currency, count, total)?;
+      }
+      writer.flush()?;
+    }
+    for mut writer in [self.statements, self.journal, self.alerts].into_iter().flatten() {
+      writer.flush()?;
+    }
+    Ok(())
+  }
+}
+
+///  Per the spec "You can assume a precision of 4 places past the decimal", which is the
+///  default. Balances never have more places than their currency, so this only pads
+fn format_decimal(d: Decimal, scale: u32) -> String {
+  format!("{:.*}", scale as usize, d)
 }
 
 /// AI GENERATED TESTS
@@ -132,9 +683,11 @@ mod tests {
   fn test_format_decimal() {
     use rust_decimal::Decimal;
 
-    assert_eq!(format_decimal(Decimal::new(15, 1)), "1.5000");
-    assert_eq!(format_decimal(Decimal::new(100, 0)), "100.0000");
-    assert_eq!(format_decimal(Decimal::new(12345, 4)), "1.2345");
-    assert_eq!(format_decimal(Decimal::new(10000, 4)), "1.0000");
+    assert_eq!(format_decimal(Decimal::new(15, 1), 4), "1.5000");
+    assert_eq!(format_decimal(Decimal::new(100, 0), 4), "100.0000");
+    assert_eq!(format_decimal(Decimal::new(12345, 4), 4), "1.2345");
+    assert_eq!(format_decimal(Decimal::new(10000, 4), 4), "1.0000");
+    assert_eq!(format_decimal(Decimal::new(100, 0), 0), "100");
+    assert_eq!(format_decimal(Decimal::new(15, 1), 8), "1.50000000");
   }
 }
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use rust_decimal::Decimal;
+use tiny_http::{Header, Method, Response, Server};
+use tracing::{debug, warn};
+
+use crate::engine::{Engine, EngineError};
+use crate::transaction::TransactionType;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] =
+  [0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+/// Counters fed by the processing loop. The gauges are read from the engine on each
+/// scrape, so they are never stale
+#[derive(Debug, Default)]
+pub struct Metrics {
+  counters: Mutex<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+  records: BTreeMap<TransactionType, u64>,
+  rejections: BTreeMap<&'static str, u64>,
+  latency: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+  /// Observations per bucket, not cumulative
+  buckets: [u64; LATENCY_BUCKETS.len()],
+  count: u64,
+  sum: f64,
+}
+
+impl Histogram {
+  fn observe(&mut self, seconds: f64) {
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+      self.buckets[bucket] += 1;
+    }
+    self.count += 1;
+    self.sum += seconds;
+  }
+}
+
+impl Metrics {
+  fn counters(&self) -> MutexGuard<'_, Counters> {
+    // Counters stay usable even if a holder panicked, they are only ever added to
+    self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  pub fn applied(&self, tx_type: TransactionType) {
+    *self.counters().records.entry(tx_type).or_default() += 1;
+  }
+
+  /// A rolled back batch counts once, under the error that failed it
+  pub fn rejected(&self, error: &EngineError) {
+    *self.counters().rejections.entry(error.cause().code()).or_default() += 1;
+  }
+
+  /// Time taken by one record or one whole batch
+  pub fn observe(&self, latency: Duration) {
+    self.counters().latency.observe(latency.as_secs_f64());
+  }
+
+  /// Everything in the Prometheus text format
+  pub fn render(&self, engine: &Engine) -> Result<String, EngineError> {
+    let accounts = engine.accounts()?;
+    let mut held: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for (currency, balance) in accounts.iter().flat_map(|account| &account.balances) {
+      *held.entry(currency).or_default() += balance.held;
+    }
+    let stored = engine.transaction_count()?;
+    let counters = self.counters();
+
+    let mut out = String::new();
+    family(&mut out, "records_total", "counter", "Records applied, by type");
+    for (tx_type, count) in &counters.records {
+      let _ = writeln!(out, "toypayments_records_total{{type=\"{}\"}} {}", tx_type, count);
+    }
+    family(&mut out, "rejections_total", "counter", "Records or batches rejected, by error code");
+    for (code, count) in &counters.rejections {
+      let _ = writeln!(out, "toypayments_rejections_total{{code=\"{}\"}} {}", code, count);
+    }
+    family(&mut out, "accounts", "gauge", "Client accounts");
+    let _ = writeln!(out, "toypayments_accounts {}", accounts.len());
+    family(&mut out, "stored_transactions", "gauge", "Transactions kept for disputes");
+    let _ = writeln!(out, "toypayments_stored_transactions {}", stored);
+    family(&mut out, "held", "gauge", "Funds held by disputes, by currency");
+    for (currency, amount) in &held {
+      let _ = writeln!(out, "toypayments_held{{currency=\"{}\"}} {}", currency, amount);
+    }
+
+    let name = "toypayments_record_duration_seconds";
+    family(&mut out, "record_duration_seconds", "histogram", "Time to apply a record or a batch");
+    let latency = &counters.latency;
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+      cumulative += count;
+      let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, latency.count);
+    let _ = writeln!(out, "{}_sum {}", name, latency.sum);
+    let _ = writeln!(out, "{}_count {}", name, latency.count);
+    Ok(out)
+  }
+}
+
+/// The HELP and TYPE lines of a metric
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP toypayments_{} {}", name, help);
+  let _ = writeln!(out, "# TYPE toypayments_{} {}", name, kind);
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread for as long as the process runs
+pub fn serve(addr: &str, metrics: Arc<Metrics>, engine: Arc<Mutex<Engine>>) -> Result<()> {
+  let server =
+    Server::http(addr).map_err(|e| anyhow!("Failed to listen for metrics on '{}': {}", addr, e))?;
+  debug!(addr, "Serving metrics");
+  thread::spawn(move || {
+    for request in server.incoming_requests() {
+      let response = match (request.method(), request.url()) {
+        (Method::Get, "/metrics") => {
+          let engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+          match metrics.render(&engine) {
+            Ok(body) => {
+              let content_type = "text/plain; version=0.0.4";
+              let header = Header::from_bytes("Content-Type", content_type).expect("valid header");
+              Response::from_string(body).with_header(header)
+            }
+            Err(e) => Response::from_string(e.to_string()).with_status_code(500),
+          }
+        }
+        _ => Response::from_string("not found").with_status_code(404),
+      };
+      if let Err(e) = request.respond(response) {
+        warn!(error = %e, "Failed to answer a metrics request");
+      }
+    }
+  });
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transaction::TransactionRecord;
+
+  fn deposit(tx: u32, amount: i64) -> TransactionRecord {
+    TransactionRecord {
+      tx_type: TransactionType::Deposit,
+      client: 1,
+      tx,
+      amount: Some(Decimal::from(amount)),
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  #[test]
+  fn test_render() {
+    let mut engine = Engine::new();
+    let metrics = Metrics::default();
+    engine.process(deposit(1, 100)).unwrap();
+    metrics.applied(TransactionType::Deposit);
+    metrics.observe(Duration::from_micros(20));
+    let error = engine.process(deposit(1, 5)).unwrap_err();
+    metrics.rejected(&error);
+    metrics.observe(Duration::from_secs(2));
+    let dispute =
+      TransactionRecord { tx_type: TransactionType::Dispute, amount: None, ..deposit(1, 0) };
+    engine.process(dispute).unwrap();
+
+    let text = metrics.render(&engine).unwrap();
+    for line in [
+      "# TYPE toypayments_records_total counter",
+      "toypayments_records_total{type=\"deposit\"} 1",
+      "toypayments_rejections_total{code=\"DuplicateTransaction\"} 1",
+      "toypayments_accounts 1",
+      "toypayments_stored_transactions 1",
+      "toypayments_held{currency=\"USD\"} 100",
+      "toypayments_record_duration_seconds_bucket{le=\"0.00001\"} 0",
+      "toypayments_record_duration_seconds_bucket{le=\"0.00005\"} 1",
+      "toypayments_record_duration_seconds_bucket{le=\"0.5\"} 1",
+      "toypayments_record_duration_seconds_bucket{le=\"+Inf\"} 2",
+      "toypayments_record_duration_seconds_count 2",
+    ] {
+      assert!(text.lines().any(|l| l == line), "missing {}\n{}", line, text);
+    }
+  }
+}
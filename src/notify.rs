@@ -0,0 +1,536 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use crate::config::PrecisionConfig;
+use crate::engine::Engine;
+use crate::events::{AccountEvent, EventKind};
+
+/// How often a command sink is checked for having exited
+const COMMAND_POLL: Duration = Duration::from_millis(10);
+
+/// Where and when the risk team hears about an account, from a local TOML file:
+///
+/// ```toml
+/// # Disputes holding at least this much, in any currency. No large dispute alerts without it
+/// large_dispute = "10000"
+/// # Attempts after the first one, and the wait between them
+/// retries = 3
+/// retry_delay_ms = 500
+/// # Notifications no attempt could deliver, one JSON line each
+/// dead_letter = "notifications.dead.ndjson"
+///
+/// [[sink]]
+/// type = "http"
+/// url = "http://risk.internal/hooks/payments"
+///
+/// [[sink]]
+/// type = "file"
+/// path = "notifications.ndjson"
+///
+/// [[sink]]
+/// type = "command"
+/// program = "page-risk"
+/// args = ["--team", "payments"]
+/// timeout_ms = 5000
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+  pub large_dispute: Option<Decimal>,
+  pub retries: u32,
+  pub retry_delay_ms: u64,
+  /// A relative path, like those of the sinks, is taken from the file's directory
+  pub dead_letter: PathBuf,
+  #[serde(rename = "sink")]
+  pub sinks: Vec<SinkConfig>,
+}
+
+impl Default for NotifyConfig {
+  fn default() -> Self {
+    Self {
+      large_dispute: None,
+      retries: 3,
+      retry_delay_ms: 500,
+      dead_letter: PathBuf::from("notifications.dead.ndjson"),
+      sinks: Vec::new(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SinkConfig {
+  /// POSTs the notification as JSON, any 2xx answer is a delivery
+  Http {
+    url: String,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+  },
+  /// Appends the notification as one JSON line
+  File { path: PathBuf },
+  /// Runs the program with the notification on its stdin, exit code 0 is a delivery.
+  /// One still running after the timeout is killed
+  Command {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+  },
+}
+
+fn default_timeout_ms() -> u64 {
+  5000
+}
+
+impl NotifyConfig {
+  pub fn load(path: &Path) -> Result<Self> {
+    let context = || format!("Failed to load notifications '{}'", path.display());
+    let text = fs::read_to_string(path).with_context(context)?;
+    let mut config = Self::from_toml(&text).with_context(context)?;
+    if let Some(dir) = path.parent() {
+      config.dead_letter = dir.join(&config.dead_letter);
+      for sink in &mut config.sinks {
+        if let SinkConfig::File { path } = sink {
+          *path = dir.join(&*path);
+        }
+      }
+    }
+    Ok(config)
+  }
+
+  pub fn from_toml(text: &str) -> Result<Self> {
+    let config: Self = toml::from_str(text)?;
+    if config.sinks.is_empty() {
+      bail!("no [[sink]] to notify");
+    }
+    if config.large_dispute.is_some_and(|amount| amount <= Decimal::ZERO) {
+      bail!("large_dispute needs a positive amount");
+    }
+    Ok(config)
+  }
+}
+
+/// Why the risk team is told
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+  AccountLocked,
+  Chargeback,
+  LargeDispute,
+}
+
+impl fmt::Display for Trigger {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Trigger::AccountLocked => "account_locked",
+      Trigger::Chargeback => "chargeback",
+      Trigger::LargeDispute => "large_dispute",
+    })
+  }
+}
+
+/// A place notifications are delivered to. One attempt per call, retries are up to the caller
+pub trait Sink: Send {
+  /// Names the sink in logs and dead letters
+  fn describe(&self) -> String;
+  fn send(&mut self, payload: &str) -> Result<()>;
+}
+
+struct HttpSink {
+  url: String,
+  agent: ureq::Agent,
+}
+
+impl Sink for HttpSink {
+  fn describe(&self) -> String {
+    format!("http {}", self.url)
+  }
+
+  fn send(&mut self, payload: &str) -> Result<()> {
+    self
+      .agent
+      .post(&self.url)
+      .set("Content-Type", "application/json")
+      .send_string(payload)
+      .map_err(|e| anyhow!("{}", e))?;
+    Ok(())
+  }
+}
+
+struct FileSink {
+  path: PathBuf,
+  writer: Option<BufWriter<File>>,
+}
+
+impl Sink for FileSink {
+  fn describe(&self) -> String {
+    format!("file {}", self.path.display())
+  }
+
+  fn send(&mut self, payload: &str) -> Result<()> {
+    let writer = match &mut self.writer {
+      Some(writer) => writer,
+      None => self.writer.insert(BufWriter::new(append(&self.path)?)),
+    };
+    writeln!(writer, "{}", payload)?;
+    writer.flush()?;
+    Ok(())
+  }
+}
+
+struct CommandSink {
+  program: String,
+  args: Vec<String>,
+  timeout: Duration,
+}
+
+impl Sink for CommandSink {
+  fn describe(&self) -> String {
+    format!("command {}", self.program)
+  }
+
+  fn send(&mut self, payload: &str) -> Result<()> {
+    let mut child = Command::new(&self.program)
+      .args(&self.args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .spawn()
+      .with_context(|| format!("Failed to run '{}'", self.program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+      writeln!(stdin, "{}", payload)?;
+    }
+    let started = Instant::now();
+    let status = loop {
+      if let Some(status) = child.try_wait()? {
+        break status;
+      }
+      if started.elapsed() >= self.timeout {
+        let _ = child.kill();
+        let _ = child.wait();
+        bail!("'{}' did not finish within {}ms", self.program, self.timeout.as_millis());
+      }
+      thread::sleep(COMMAND_POLL);
+    };
+    if !status.success() {
+      bail!("'{}' exited with {}", self.program, status);
+    }
+    Ok(())
+  }
+}
+
+/// Hands the events of what `engine` applied since the last call to the delivery thread,
+/// for the service and TCP modes. Meant to be called with the engine held, so the events
+/// queue up in the order they were applied
+pub fn flush(engine: &mut Engine, deliveries: Option<&Deliveries>) {
+  let events = engine.take_events();
+  if let Some(deliveries) = deliveries {
+    deliveries.send(events);
+  }
+}
+
+/// A notifier on a thread of its own, so a sink that is down or slow holds up neither the
+/// engine nor the callers waiting for it. Notifications still go out in the order queued
+#[derive(Clone)]
+pub struct Deliveries {
+  sender: Sender<Vec<AccountEvent>>,
+}
+
+impl Deliveries {
+  pub fn spawn(mut notifier: Notifier, precision: PrecisionConfig) -> Self {
+    let (sender, receiver) = mpsc::channel::<Vec<AccountEvent>>();
+    thread::spawn(move || {
+      for events in receiver {
+        if let Err(e) = notifier.notify(&precision, &events) {
+          warn!(error = %e, "Failed to notify");
+        }
+      }
+    });
+    Self { sender }
+  }
+
+  fn send(&self, events: Vec<AccountEvent>) {
+    if !events.is_empty() {
+      // Only fails once the thread is gone, which a dead letter error does not do
+      let _ = self.sender.send(events);
+    }
+  }
+}
+
+fn append(path: &Path) -> Result<File> {
+  OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .with_context(|| format!("Failed to open '{}'", path.display()))
+}
+
+/// Turns account events into notifications and delivers each to every sink. Delivery is
+/// done in line with processing, so notifications go out in the order records were applied
+pub struct Notifier {
+  sinks: Vec<Box<dyn Sink>>,
+  large_dispute: Option<Decimal>,
+  retries: u32,
+  retry_delay: Duration,
+  dead_letter: PathBuf,
+  /// Opened on the first notification that could not be delivered
+  dead_letters: Option<BufWriter<File>>,
+}
+
+impl Notifier {
+  pub fn new(config: NotifyConfig) -> Self {
+    let sinks = config
+      .sinks
+      .into_iter()
+      .map(|sink| -> Box<dyn Sink> {
+        match sink {
+          SinkConfig::Http { url, timeout_ms } => {
+            let agent =
+              ureq::AgentBuilder::new().timeout(Duration::from_millis(timeout_ms)).build();
+            Box::new(HttpSink { url, agent })
+          }
+          SinkConfig::File { path } => Box::new(FileSink { path, writer: None }),
+          SinkConfig::Command { program, args, timeout_ms } => {
+            Box::new(CommandSink { program, args, timeout: Duration::from_millis(timeout_ms) })
+          }
+        }
+      })
+      .collect();
+    Self {
+      sinks,
+      large_dispute: config.large_dispute,
+      retries: config.retries,
+      retry_delay: Duration::from_millis(config.retry_delay_ms),
+      dead_letter: config.dead_letter,
+      dead_letters: None,
+    }
+  }
+
+  /// Only fails when a dead letter cannot be written, a sink that is down is not an error
+  pub fn notify(&mut self, precision: &PrecisionConfig, events: &[AccountEvent]) -> Result<()> {
+    for event in events {
+      let Some(trigger) = self.trigger(event) else {
+        continue;
+      };
+      let payload = payload(trigger, event, precision);
+      let text = payload.to_string();
+      for index in 0..self.sinks.len() {
+        if let Err(e) = self.deliver(index, &text) {
+          let sink = self.sinks[index].describe();
+          warn!(sink = %sink, error = %e, "Notification not delivered");
+          self.dead_letter(&sink, &e, payload.clone())?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn trigger(&self, event: &AccountEvent) -> Option<Trigger> {
+    match event.kind {
+      EventKind::Locked => Some(Trigger::AccountLocked),
+      EventKind::ChargedBack => Some(Trigger::Chargeback),
+      EventKind::Held => {
+        let (threshold, amount) = (self.large_dispute?, event.amount?);
+        (amount >= threshold).then_some(Trigger::LargeDispute)
+      }
+      _ => None,
+    }
+  }
+
+  fn deliver(&mut self, index: usize, text: &str) -> Result<()> {
+    let sink = &mut self.sinks[index];
+    let mut attempt = 0;
+    loop {
+      match sink.send(text) {
+        Ok(()) => return Ok(()),
+        Err(e) if attempt >= self.retries => return Err(e),
+        Err(e) => {
+          attempt += 1;
+          debug!(sink = %sink.describe(), attempt, error = %e, "Retrying notification");
+          thread::sleep(self.retry_delay);
+        }
+      }
+    }
+  }
+
+  fn dead_letter(&mut self, sink: &str, error: &anyhow::Error, notification: Value) -> Result<()> {
+    let writer = match &mut self.dead_letters {
+      Some(writer) => writer,
+      None => self.dead_letters.insert(BufWriter::new(append(&self.dead_letter)?)),
+    };
+    let line = json!({ "sink": sink, "error": error.to_string(), "notification": notification });
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+    Ok(())
+  }
+}
+
+fn payload(trigger: Trigger, event: &AccountEvent, precision: &PrecisionConfig) -> Value {
+  let amount = |value: Decimal| crate::format_decimal(value, precision.scale(&event.currency));
+  let balance = event.after.balance(&event.currency);
+  json!({
+    "trigger": trigger.to_string(),
+    "tx": event.tx,
+    "client": event.client,
+    "currency": event.currency,
+    "amount": event.amount.map(amount),
+    "timestamp": event.timestamp.map(|ts| ts.to_rfc3339()),
+    "available": amount(balance.available),
+    "held": amount(balance.held),
+    "total": amount(balance.total()),
+    "locked": event.after.locked,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use super::*;
+  use crate::account::Account;
+  use crate::events;
+  use crate::transaction::TransactionType;
+
+  /// Fails the first `failures` attempts, then keeps what it is sent
+  struct Flaky {
+    failures: u32,
+    received: Arc<Mutex<Vec<String>>>,
+  }
+
+  impl Sink for Flaky {
+    fn describe(&self) -> String {
+      "flaky".to_string()
+    }
+
+    fn send(&mut self, payload: &str) -> Result<()> {
+      if self.failures > 0 {
+        self.failures -= 1;
+        bail!("down");
+      }
+      self.received.lock().unwrap().push(payload.to_string());
+      Ok(())
+    }
+  }
+
+  fn flaky_notifier(dir: &Path, failures: u32, received: &Arc<Mutex<Vec<String>>>) -> Notifier {
+    let config = NotifyConfig {
+      large_dispute: Some(Decimal::from(1000)),
+      retries: 2,
+      retry_delay_ms: 0,
+      dead_letter: dir.join("dead.ndjson"),
+      sinks: Vec::new(),
+    };
+    let mut notifier = Notifier::new(config);
+    notifier.sinks.push(Box::new(Flaky { failures, received: Arc::clone(received) }));
+    notifier
+  }
+
+  fn chargeback_events() -> Vec<AccountEvent> {
+    let mut before = Account::new(1);
+    before.deposit("USD", Decimal::from(10)).unwrap();
+    before.hold("USD", Decimal::from(10), Decimal::ZERO).unwrap();
+    let mut after = Account::new(1);
+    after.locked = true;
+    events::for_record(TransactionType::Chargeback, 1, None, None, "USD", before, after)
+  }
+
+  fn dispute_event(amount: i64) -> Vec<AccountEvent> {
+    let mut events = events::for_record(
+      TransactionType::Dispute,
+      2,
+      None,
+      None,
+      "USD",
+      Account::new(1),
+      Account::new(1),
+    );
+    events[0].amount = Some(Decimal::from(amount));
+    events
+  }
+
+  #[test]
+  fn test_triggers() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut notifier = flaky_notifier(dir.path(), 0, &received);
+    let precision = PrecisionConfig::default();
+    notifier.notify(&precision, &chargeback_events()).unwrap();
+    notifier.notify(&precision, &dispute_event(999)).unwrap();
+    notifier.notify(&precision, &dispute_event(1000)).unwrap();
+
+    let received = received.lock().unwrap();
+    let triggers: Vec<String> = received
+      .iter()
+      .map(|text| serde_json::from_str::<Value>(text).unwrap()["trigger"].to_string())
+      .collect();
+    assert_eq!(triggers, ["\"chargeback\"", "\"account_locked\"", "\"large_dispute\""]);
+  }
+
+  #[test]
+  fn test_retried_then_dead_lettered() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    // Two retries get past two failures
+    let mut notifier = flaky_notifier(dir.path(), 2, &received);
+    notifier.notify(&PrecisionConfig::default(), &chargeback_events()[..1]).unwrap();
+    assert_eq!(received.lock().unwrap().len(), 1);
+    assert!(!dir.path().join("dead.ndjson").exists());
+
+    let mut notifier = flaky_notifier(dir.path(), 3, &received);
+    notifier.notify(&PrecisionConfig::default(), &chargeback_events()[..1]).unwrap();
+    assert_eq!(received.lock().unwrap().len(), 1);
+    let dead = fs::read_to_string(dir.path().join("dead.ndjson")).unwrap();
+    let dead: Value = serde_json::from_str(dead.trim()).unwrap();
+    assert_eq!(dead["sink"], "flaky");
+    assert_eq!(dead["error"], "down");
+    assert_eq!(dead["notification"]["trigger"], "chargeback");
+  }
+
+  #[test]
+  fn test_command_killed_after_timeout() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut notifier = Notifier::new(NotifyConfig {
+      retries: 0,
+      dead_letter: dir.path().join("dead.ndjson"),
+      sinks: vec![SinkConfig::Command {
+        program: "sleep".to_string(),
+        args: vec!["10".to_string()],
+        timeout_ms: 50,
+      }],
+      ..Default::default()
+    });
+    let started = std::time::Instant::now();
+    notifier.notify(&PrecisionConfig::default(), &chargeback_events()[..1]).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let dead = fs::read_to_string(dir.path().join("dead.ndjson")).unwrap();
+    let dead: Value = serde_json::from_str(dead.trim()).unwrap();
+    assert_eq!(dead["error"], "'sleep' did not finish within 50ms");
+  }
+
+  #[test]
+  fn test_config() {
+    let config = NotifyConfig::from_toml(
+      "large_dispute = \"500\"\n[[sink]]\ntype = \"http\"\nurl = \"http://127.0.0.1:1/\"\n\
+       [[sink]]\ntype = \"command\"\nprogram = \"true\"\n",
+    )
+    .unwrap();
+    assert_eq!(config.retries, 3);
+    assert_eq!(
+      config.sinks[0],
+      SinkConfig::Http { url: "http://127.0.0.1:1/".to_string(), timeout_ms: 5000 }
+    );
+    assert!(NotifyConfig::from_toml("retries = 1").is_err());
+    assert!(NotifyConfig::from_toml("[[sink]]\ntype = \"pigeon\"").is_err());
+  }
+}
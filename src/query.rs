@@ -0,0 +1,212 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+
+use crate::engine::Engine;
+use crate::format_decimal;
+use crate::transaction::{DisputeState, StoredTransaction, TransactionType};
+
+/// What to show about a client of a saved engine state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+  /// One line per currency, as in the account output
+  Balances,
+  /// The client's stored transactions with their dispute state and history
+  Transactions,
+  /// The chargeback that locked the account
+  Lock,
+}
+
+impl FromStr for Query {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "balances" => Ok(Query::Balances),
+      "transactions" => Ok(Query::Transactions),
+      "lock" => Ok(Query::Lock),
+      _ => Err(format!("unknown query '{}', expected balances, transactions or lock", s)),
+    }
+  }
+}
+
+/// Answers the queries about `client` in order, separated by a blank line
+pub fn run(engine: &Engine, client: u16, queries: &[Query], out: &mut impl Write) -> Result<()> {
+  let Some(account) = engine.account(client)? else {
+    bail!("client {} not found", client);
+  };
+  let precision = &engine.config().precision;
+  let mut transactions = engine.client_transactions(client)?;
+  transactions.sort_by_key(|(tx, _)| *tx);
+
+  for (index, query) in queries.iter().enumerate() {
+    if index > 0 {
+      writeln!(out)?;
+    }
+    match query {
+      Query::Balances => {
+        writeln!(out, "client,currency,available,held,total,locked")?;
+        for (currency, balance) in &account.balances {
+          let scale = precision.scale(currency);
+          writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            client,
+            currency,
+            format_decimal(balance.available, scale),
+            format_decimal(balance.held, scale),
+            format_decimal(balance.total(), scale),
+            account.locked
+          )?;
+        }
+      }
+      Query::Transactions => {
+        writeln!(out, "tx,type,currency,amount,state,timestamp,history")?;
+        for (tx, stored) in &transactions {
+          // Each step is action@timestamp, or just the action when the record had none
+          let history: Vec<String> = stored
+            .history
+            .iter()
+            .map(|step| match step.timestamp {
+              Some(ts) => format!("{}@{}", step.action, ts.to_rfc3339()),
+              None => step.action.to_string(),
+            })
+            .collect();
+          writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            tx,
+            stored.tx_type,
+            stored.currency,
+            format_decimal(stored.amount, precision.scale(&stored.currency)),
+            stored.state,
+            stored.timestamp.map(|ts| ts.to_rfc3339()).unwrap_or_default(),
+            history.join(" ")
+          )?;
+        }
+      }
+      Query::Lock => {
+        if !account.locked {
+          writeln!(out, "client {} is not locked", client)?;
+          continue;
+        }
+        match locking_chargeback(&transactions) {
+          Some((tx, stored, at)) => {
+            let at = at.map(|ts| format!(" at {}", ts.to_rfc3339())).unwrap_or_default();
+            writeln!(
+              out,
+              "client {} is locked by the chargeback of tx {}{} ({} of {} {}, now {})",
+              client,
+              tx,
+              at,
+              stored.tx_type,
+              format_decimal(stored.amount, precision.scale(&stored.currency)),
+              stored.currency,
+              stored.state
+            )?;
+          }
+          None => writeln!(
+            out,
+            "client {} is locked but none of its transactions was charged back",
+            client
+          )?,
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// The earliest chargeback that still stands, or the earliest one when every chargeback
+/// was represented since (the account stays locked under `representment_unlock = "never"`).
+/// Chargebacks without a timestamp are taken in tx order after those with one
+fn locking_chargeback(
+  transactions: &[(u32, StoredTransaction)],
+) -> Option<(u32, &StoredTransaction, Option<DateTime<Utc>>)> {
+  let chargebacks = || {
+    transactions.iter().filter_map(|(tx, stored)| {
+      let step = stored.history.iter().find(|step| step.action == TransactionType::Chargeback)?;
+      Some((*tx, stored, step.timestamp))
+    })
+  };
+  let earliest =
+    |(tx, _, at): &(u32, &StoredTransaction, Option<DateTime<Utc>>)| (at.is_none(), *at, *tx);
+  chargebacks()
+    .filter(|(_, stored, _)| stored.state == DisputeState::ChargedBack)
+    .min_by_key(earliest)
+    .or_else(|| chargebacks().min_by_key(earliest))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transaction::TransactionRecord;
+
+  fn record(
+    tx_type: TransactionType,
+    tx: u32,
+    amount: Option<&str>,
+    at: &str,
+  ) -> TransactionRecord {
+    TransactionRecord {
+      tx_type,
+      client: 1,
+      tx,
+      amount: amount.map(|a| a.parse().unwrap()),
+      timestamp: Some(at.parse().unwrap()),
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  fn query(engine: &Engine, queries: &[Query]) -> String {
+    let mut out = Vec::new();
+    run(engine, 1, queries, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn test_lock_names_first_standing_chargeback() {
+    let mut engine = Engine::new();
+    for (tx, at) in [(1, "2024-01-01T00:00:00Z"), (2, "2024-01-02T00:00:00Z")] {
+      engine.process(record(TransactionType::Deposit, tx, Some("10"), at)).unwrap();
+    }
+    engine.process(record(TransactionType::Dispute, 2, None, "2024-01-03T00:00:00Z")).unwrap();
+    engine.process(record(TransactionType::Chargeback, 2, None, "2024-01-04T00:00:00Z")).unwrap();
+    engine.process(record(TransactionType::Dispute, 1, None, "2024-01-05T00:00:00Z")).unwrap();
+
+    assert_eq!(
+      query(&engine, &[Query::Lock]),
+      "client 1 is locked by the chargeback of tx 2 at 2024-01-04T00:00:00+00:00 \
+       (deposit of 10.0000 USD, now charged_back)\n"
+    );
+    let transactions = query(&engine, &[Query::Transactions]);
+    assert_eq!(
+      transactions.lines().collect::<Vec<_>>(),
+      [
+        "tx,type,currency,amount,state,timestamp,history",
+        "1,deposit,USD,10.0000,disputed,2024-01-01T00:00:00+00:00,dispute@2024-01-05T00:00:00+00:00",
+        "2,deposit,USD,10.0000,charged_back,2024-01-02T00:00:00+00:00,\
+         dispute@2024-01-03T00:00:00+00:00 chargeback@2024-01-04T00:00:00+00:00",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_balances_and_unknown_client() {
+    let mut engine = Engine::new();
+    engine
+      .process(record(TransactionType::Deposit, 1, Some("10"), "2024-01-01T00:00:00Z"))
+      .unwrap();
+    assert_eq!(
+      query(&engine, &[Query::Balances, Query::Lock]),
+      "client,currency,available,held,total,locked\n1,USD,10.0000,0.0000,10.0000,false\n\n\
+       client 1 is not locked\n"
+    );
+    let error = run(&engine, 2, &[Query::Balances], &mut Vec::new()).unwrap_err();
+    assert_eq!(error.to_string(), "client 2 not found");
+  }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::transaction::parse_currency;
+
+/// Exchange rates for `convert` records, loaded from a local file.
+/// A rate is only used in the direction it is listed, EUR to USD says nothing about
+/// USD to EUR
+#[derive(Debug, Clone, Default)]
+pub struct RateTable {
+  rates: HashMap<(String, String), Decimal>,
+}
+
+/// One line of the CSV file or one `[[rate]]` table of the TOML file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateRow {
+  from: String,
+  to: String,
+  rate: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateFile {
+  #[serde(default)]
+  rate: Vec<RateRow>,
+}
+
+impl RateTable {
+  /// A `.csv` file has a `from,to,rate` header, anything else is read as TOML
+  pub fn load(path: &Path) -> Result<Self> {
+    let context = || format!("Failed to load rates '{}'", path.display());
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+      let file = File::open(path).with_context(context)?;
+      Self::from_csv(file).with_context(context)
+    } else {
+      let text = fs::read_to_string(path).with_context(context)?;
+      Self::from_toml(&text).with_context(context)
+    }
+  }
+
+  pub fn from_toml(text: &str) -> Result<Self> {
+    let file: RateFile = toml::from_str(text)?;
+    Self::from_rows(file.rate)
+  }
+
+  pub fn from_csv(reader: impl std::io::Read) -> Result<Self> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let rows = reader.deserialize().collect::<Result<Vec<RateRow>, _>>()?;
+    Self::from_rows(rows)
+  }
+
+  fn from_rows(rows: Vec<RateRow>) -> Result<Self> {
+    let mut rates = HashMap::new();
+    for row in rows {
+      let from = parse_currency(row.from.trim()).map_err(anyhow::Error::msg)?;
+      let to = parse_currency(row.to.trim()).map_err(anyhow::Error::msg)?;
+      if from == to {
+        bail!("rate from {} to itself", from);
+      }
+      if row.rate <= Decimal::ZERO {
+        bail!("rate from {} to {} must be positive, got {}", from, to, row.rate);
+      }
+      if rates.insert((from.clone(), to.clone()), row.rate).is_some() {
+        bail!("rate from {} to {} is listed twice", from, to);
+      }
+    }
+    Ok(Self { rates })
+  }
+
+  /// How many units of `to` one unit of `from` buys
+  pub fn rate(&self, from: &str, to: &str) -> Option<Decimal> {
+    self.rates.get(&(from.to_string(), to.to_string())).copied()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_toml_rates() {
+    let table = RateTable::from_toml(
+      "[[rate]]\nfrom = \"eur\"\nto = \"USD\"\nrate = \"1.0825\"\n\n\
+       [[rate]]\nfrom = \"USD\"\nto = \"JPY\"\nrate = 151.5\n",
+    )
+    .unwrap();
+    assert_eq!(table.rate("EUR", "USD"), Some(Decimal::new(10825, 4)));
+    assert_eq!(table.rate("USD", "JPY"), Some(Decimal::new(1515, 1)));
+    // Not derived from the other direction
+    assert_eq!(table.rate("USD", "EUR"), None);
+  }
+
+  #[test]
+  fn test_csv_rates() {
+    let table = RateTable::from_csv("from,to,rate\nEUR, USD, 1.0825\n".as_bytes()).unwrap();
+    assert_eq!(table.rate("EUR", "USD"), Some(Decimal::new(10825, 4)));
+  }
+
+  #[test]
+  fn test_empty_toml_is_empty_table() {
+    let table = RateTable::from_toml("").unwrap();
+    assert_eq!(table.rate("EUR", "USD"), None);
+  }
+
+  #[test]
+  fn test_invalid_rates_rejected() {
+    assert!(RateTable::from_csv("from,to,rate\nEUR,USD,0\n".as_bytes()).is_err());
+    assert!(RateTable::from_csv("from,to,rate\nEUR,USD,-1\n".as_bytes()).is_err());
+    assert!(RateTable::from_csv("from,to,rate\nEUR,EUR,1\n".as_bytes()).is_err());
+    assert!(RateTable::from_csv("from,to,rate\nEUR,USD,1\neur,usd,2\n".as_bytes()).is_err());
+    assert!(RateTable::from_csv("from,to,rate\nE$R,USD,1\n".as_bytes()).is_err());
+  }
+
+  #[test]
+  fn test_load_by_extension() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let csv = dir.path().join("rates.csv");
+    fs::write(&csv, "from,to,rate\nEUR,USD,1.1\n").unwrap();
+    let toml = dir.path().join("rates.toml");
+    fs::write(&toml, "[[rate]]\nfrom = \"EUR\"\nto = \"USD\"\nrate = \"1.2\"\n").unwrap();
+
+    assert_eq!(RateTable::load(&csv).unwrap().rate("EUR", "USD"), Some(Decimal::new(11, 1)));
+    assert_eq!(RateTable::load(&toml).unwrap().rate("EUR", "USD"), Some(Decimal::new(12, 1)));
+    assert!(RateTable::load(&dir.path().join("missing.toml")).is_err());
+  }
+}
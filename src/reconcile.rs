@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::account::{AccountOutput, DEFAULT_CURRENCY};
+use crate::config::PrecisionConfig;
+use crate::engine::Engine;
+use crate::format_decimal;
+
+/// One line of the bank's balance file. Only the given columns are compared, a file
+/// with just `client,total` checks totals
+#[derive(Debug, Clone, Deserialize)]
+pub struct Expected {
+  pub client: u16,
+  #[serde(default = "default_currency")]
+  pub currency: String,
+  #[serde(default)]
+  pub available: Option<Decimal>,
+  #[serde(default)]
+  pub held: Option<Decimal>,
+  #[serde(default)]
+  pub total: Option<Decimal>,
+  #[serde(default)]
+  pub locked: Option<bool>,
+}
+
+fn default_currency() -> String {
+  DEFAULT_CURRENCY.to_string()
+}
+
+/// Reads the expected balances, one row per client and currency
+pub fn load(path: &Path) -> Result<Vec<Expected>> {
+  let rows: Vec<Expected> = csv::ReaderBuilder::new()
+    .trim(csv::Trim::All)
+    .from_path(path)
+    .with_context(|| format!("Failed to open '{}'", path.display()))?
+    .deserialize()
+    .collect::<Result<_, _>>()
+    .with_context(|| format!("Invalid balance file '{}'", path.display()))?;
+  let mut seen = BTreeSet::new();
+  for row in &rows {
+    if !seen.insert((row.client, row.currency.as_str())) {
+      bail!("client {} has two {} rows in '{}'", row.client, row.currency, path.display());
+    }
+  }
+  Ok(rows)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BreakKind {
+  /// The client and currency is only in the engine or only in the bank's file
+  MissingClient,
+  AmountMismatch,
+  LockMismatch,
+}
+
+impl fmt::Display for BreakKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      BreakKind::MissingClient => "missing_client",
+      BreakKind::AmountMismatch => "amount_mismatch",
+      BreakKind::LockMismatch => "lock_mismatch",
+    })
+  }
+}
+
+/// One difference between the bank and the engine
+#[derive(Debug, PartialEq)]
+pub struct Break {
+  pub client: u16,
+  pub currency: String,
+  pub kind: BreakKind,
+  /// available, held, total or locked
+  pub field: &'static str,
+  pub values: Values,
+}
+
+/// The two sides of a break, None where a side has no row
+#[derive(Debug, PartialEq)]
+pub enum Values {
+  Amount { expected: Option<Decimal>, actual: Option<Decimal> },
+  Locked { expected: bool, actual: bool },
+}
+
+/// Counts and totals of one currency
+#[derive(Debug, Default, PartialEq)]
+pub struct CurrencySummary {
+  pub expected_rows: u64,
+  pub engine_rows: u64,
+  /// Rows on both sides without a break
+  pub matched: u64,
+  pub breaks: BTreeMap<BreakKind, u64>,
+  /// Sum of the totals the bank gave
+  pub expected_total: Decimal,
+  pub engine_total: Decimal,
+}
+
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+  pub breaks: Vec<Break>,
+  pub currencies: BTreeMap<String, CurrencySummary>,
+}
+
+/// Compares every account of `engine` to the bank's balances. An amount is a break when
+/// it is off by more than `tolerance`
+pub fn reconcile(
+  engine: &Engine,
+  expected: Vec<Expected>,
+  tolerance: Decimal,
+) -> Result<Reconciliation> {
+  let mut actual: BTreeMap<(u16, String), AccountOutput> = engine
+    .accounts()?
+    .iter()
+    .flat_map(AccountOutput::rows)
+    .map(|row| ((row.client, row.currency.clone()), row))
+    .collect();
+  let mut expected: BTreeMap<(u16, String), Expected> =
+    expected.into_iter().map(|row| ((row.client, row.currency.clone()), row)).collect();
+  let mut keys: Vec<(u16, String)> = actual.keys().chain(expected.keys()).cloned().collect();
+  keys.sort();
+  keys.dedup();
+
+  let mut reconciliation = Reconciliation::default();
+  for key in keys {
+    let (expected, actual) = (expected.remove(&key), actual.remove(&key));
+    let (client, currency) = key;
+    let summary = reconciliation.currencies.entry(currency.clone()).or_default();
+    let breaks = &mut reconciliation.breaks;
+    let mut push = |kind, field, values| {
+      *summary.breaks.entry(kind).or_default() += 1;
+      breaks.push(Break { client, currency: currency.clone(), kind, field, values });
+    };
+
+    if let Some(expected) = &expected {
+      summary.expected_rows += 1;
+      summary.expected_total += expected.total.unwrap_or_default();
+    }
+    if let Some(actual) = &actual {
+      summary.engine_rows += 1;
+      summary.engine_total += actual.total;
+    }
+    let (expected, actual) = match (expected, actual) {
+      (Some(expected), Some(actual)) => (expected, actual),
+      (expected, actual) => {
+        let values = Values::Amount {
+          expected: expected.and_then(|row| row.total),
+          actual: actual.map(|row| row.total),
+        };
+        push(BreakKind::MissingClient, "total", values);
+        continue;
+      }
+    };
+
+    let mut clean = true;
+    let amounts = [
+      ("available", expected.available, actual.available),
+      ("held", expected.held, actual.held),
+      ("total", expected.total, actual.total),
+    ];
+    for (field, expected, actual) in amounts {
+      if let Some(expected) = expected.filter(|expected| (*expected - actual).abs() > tolerance) {
+        clean = false;
+        push(
+          BreakKind::AmountMismatch,
+          field,
+          Values::Amount { expected: Some(expected), actual: Some(actual) },
+        );
+      }
+    }
+    if let Some(locked) = expected.locked.filter(|locked| *locked != actual.locked) {
+      clean = false;
+      push(
+        BreakKind::LockMismatch,
+        "locked",
+        Values::Locked { expected: locked, actual: actual.locked },
+      );
+    }
+    if clean {
+      summary.matched += 1;
+    }
+  }
+  Ok(reconciliation)
+}
+
+impl Reconciliation {
+  /// The breaks, then a blank line and the counts and totals per currency
+  pub fn write(&self, precision: &PrecisionConfig, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "client,currency,break,field,expected,actual,difference")?;
+    for entry in &self.breaks {
+      let scale = precision.scale(&entry.currency);
+      let amount = |value: Option<Decimal>| {
+        value.map(|value| format_decimal(value, scale)).unwrap_or_default()
+      };
+      let (expected, actual, difference) = match entry.values {
+        Values::Amount { expected, actual } => (
+          amount(expected),
+          amount(actual),
+          amount(Some(actual.unwrap_or_default() - expected.unwrap_or_default())),
+        ),
+        Values::Locked { expected, actual } => {
+          (expected.to_string(), actual.to_string(), String::new())
+        }
+      };
+      writeln!(
+        out,
+        "{},{},{},{},{},{},{}",
+        entry.client, entry.currency, entry.kind, entry.field, expected, actual, difference
+      )?;
+    }
+
+    writeln!(out)?;
+    writeln!(
+      out,
+      "currency,expected_rows,engine_rows,matched,missing_client,amount_mismatch,\
+       lock_mismatch,expected_total,engine_total,difference"
+    )?;
+    for (currency, summary) in &self.currencies {
+      let scale = precision.scale(currency);
+      let count = |kind| summary.breaks.get(&kind).copied().unwrap_or_default();
+      writeln!(
+        out,
+        "{},{},{},{},{},{},{},{},{},{}",
+        currency,
+        summary.expected_rows,
+        summary.engine_rows,
+        summary.matched,
+        count(BreakKind::MissingClient),
+        count(BreakKind::AmountMismatch),
+        count(BreakKind::LockMismatch),
+        format_decimal(summary.expected_total, scale),
+        format_decimal(summary.engine_total, scale),
+        format_decimal(summary.engine_total - summary.expected_total, scale)
+      )?;
+    }
+    Ok(())
+  }
+
+  /// Fails when there is a break, so a scheduled reconciliation can alert on it
+  pub fn check(&self) -> Result<()> {
+    if !self.breaks.is_empty() {
+      bail!("reconciliation found {} breaks", self.breaks.len());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transaction::{TransactionRecord, TransactionType};
+
+  fn record(
+    tx_type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<&str>,
+  ) -> TransactionRecord {
+    TransactionRecord {
+      tx_type,
+      client,
+      tx,
+      amount: amount.map(|a| a.parse().unwrap()),
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  fn expected(client: u16, total: &str, locked: Option<bool>) -> Expected {
+    Expected {
+      client,
+      currency: "USD".to_string(),
+      available: None,
+      held: None,
+      total: Some(total.parse().unwrap()),
+      locked,
+    }
+  }
+
+  fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.process(record(TransactionType::Deposit, 1, 1, Some("10"))).unwrap();
+    engine.process(record(TransactionType::Deposit, 2, 2, Some("5"))).unwrap();
+    engine.process(record(TransactionType::Dispute, 2, 2, None)).unwrap();
+    engine.process(record(TransactionType::Chargeback, 2, 2, None)).unwrap();
+    engine.process(record(TransactionType::Deposit, 3, 3, Some("7"))).unwrap();
+    engine
+  }
+
+  #[test]
+  fn test_breaks_are_classified() {
+    let bank = vec![
+      expected(1, "10.001", Some(false)),
+      expected(2, "0", Some(false)),
+      expected(4, "3", None),
+    ];
+    let reconciliation = reconcile(&engine(), bank, Decimal::ZERO).unwrap();
+
+    let mut out = Vec::new();
+    reconciliation.write(&PrecisionConfig::default(), &mut out).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "client,currency,break,field,expected,actual,difference\n\
+       1,USD,amount_mismatch,total,10.0010,10.0000,-0.0010\n\
+       2,USD,lock_mismatch,locked,false,true,\n\
+       3,USD,missing_client,total,,7.0000,7.0000\n\
+       4,USD,missing_client,total,3.0000,,-3.0000\n\
+       \n\
+       currency,expected_rows,engine_rows,matched,missing_client,amount_mismatch,\
+       lock_mismatch,expected_total,engine_total,difference\n\
+       USD,3,3,0,2,1,1,13.0010,17.0000,3.9990\n"
+    );
+    assert_eq!(reconciliation.check().unwrap_err().to_string(), "reconciliation found 4 breaks");
+  }
+
+  #[test]
+  fn test_tolerance_and_clean_run() {
+    let bank = vec![
+      expected(1, "10.001", None),
+      expected(2, "0", Some(true)),
+      expected(3, "7", Some(false)),
+    ];
+    let reconciliation = reconcile(&engine(), bank, Decimal::new(1, 2)).unwrap();
+    assert!(reconciliation.breaks.is_empty());
+    assert_eq!(reconciliation.currencies["USD"].matched, 3);
+    assert!(reconciliation.check().is_ok());
+  }
+}
@@ -0,0 +1,303 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use csv::StringRecord;
+use tracing::debug;
+
+use crate::engine::{Engine, EngineError, Outcome};
+use crate::transaction::TransactionRecord;
+
+/// Columns of a logged record after `seq` and `outcome`, the input columns
+const RECORD_COLUMNS: [&str; 8] =
+  ["type", "client", "tx", "amount", "timestamp", "batch", "currency", "to_currency"];
+
+/// Every input record in the order the engine got it, with what became of it. Rejected
+/// records are kept too: they move the engine's record clock and can leave an empty
+/// account behind, so replaying without them would not end in the same state
+pub struct RecordLog {
+  writer: BufWriter<File>,
+  seq: u64,
+}
+
+impl RecordLog {
+  /// With `append`, for a state saved across runs, numbers on from the last record of an
+  /// existing log so it covers every run the state was built by. Otherwise the log starts
+  /// over, since the run starts from empty accounts
+  pub fn open(path: &Path, append: bool) -> Result<Self> {
+    let context = || format!("Failed to open record log '{}'", path.display());
+    let seq = match File::open(path) {
+      Ok(file) if append => BufReader::new(file).lines().skip(1).count() as u64,
+      _ => 0,
+    };
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(append)
+      .truncate(!append)
+      .open(path)
+      .with_context(context)?;
+    let mut writer = BufWriter::new(file);
+    if seq == 0 && (!append || file_is_empty(path)) {
+      writeln!(writer, "seq,outcome,{}", RECORD_COLUMNS.join(","))?;
+    }
+    Ok(Self { writer, seq })
+  }
+
+  pub fn applied(&mut self, record: &TransactionRecord) -> Result<()> {
+    self.write("applied", record)
+  }
+
+  pub fn skipped(&mut self, record: &TransactionRecord) -> Result<()> {
+    self.write("skipped", record)
+  }
+
+  /// The rejected record, or every member of a rolled back batch
+  pub fn rejected(&mut self, records: &[TransactionRecord]) -> Result<()> {
+    records.iter().try_for_each(|record| self.write("rejected", record))
+  }
+
+  pub fn finish(&mut self) -> Result<()> {
+    self.writer.flush()?;
+    Ok(())
+  }
+
+  fn write(&mut self, outcome: &str, record: &TransactionRecord) -> Result<()> {
+    self.seq += 1;
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    writeln!(
+      self.writer,
+      "{},{},{},{},{},{},{},{},{},{}",
+      self.seq,
+      outcome,
+      record.tx_type,
+      record.client,
+      record.tx,
+      optional(record.amount.map(|amount| amount.to_string())),
+      optional(record.timestamp.map(|ts| ts.to_rfc3339())),
+      optional(record.batch.map(|batch| batch.to_string())),
+      optional(record.currency.clone()),
+      optional(record.to_currency.clone())
+    )?;
+    Ok(())
+  }
+}
+
+fn file_is_empty(path: &Path) -> bool {
+  path.metadata().map(|meta| meta.len() == 0).unwrap_or(true)
+}
+
+/// How far a replay goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+  End,
+  /// Up to and including this record number
+  Record(u64),
+  /// Up to the first record stamped after this time. Records without a timestamp
+  /// before that one are included
+  Time(DateTime<Utc>),
+}
+
+struct Entry {
+  seq: u64,
+  outcome: String,
+  record: TransactionRecord,
+}
+
+/// Rebuilds the engine from the log. The engine should be new and have the config of the
+/// logged runs. A batch the cut falls in is left out whole, as it was applied all at once.
+/// Fails when a record does not end the way it was logged. Returns the last record
+/// number applied
+pub fn replay(engine: &mut Engine, path: &Path, until: Until) -> Result<u64> {
+  let file =
+    File::open(path).with_context(|| format!("Failed to open record log '{}'", path.display()))?;
+  let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(BufReader::new(file));
+  let header = StringRecord::from(RECORD_COLUMNS.to_vec());
+
+  let mut pending: Vec<Entry> = Vec::new();
+  let mut last = 0;
+  for row in reader.records() {
+    let row = row.with_context(|| format!("Failed to read record log '{}'", path.display()))?;
+    let entry = parse(&row, &header).with_context(|| {
+      format!("Invalid record log line {}", row.position().map_or(0, |p| p.line()))
+    })?;
+    let past = match until {
+      Until::End => false,
+      Until::Record(n) => entry.seq > n,
+      Until::Time(t) => entry.record.timestamp.is_some_and(|ts| ts > t),
+    };
+    let continues = matches!(
+      (pending.first(), entry.record.batch),
+      (Some(first), Some(batch)) if first.record.batch == Some(batch)
+    );
+    if past {
+      if continues {
+        debug!(batch = entry.record.batch, "Leaving out the batch the cut falls in");
+        pending.clear();
+      }
+      break;
+    }
+    if !continues && !pending.is_empty() {
+      last = apply(engine, std::mem::take(&mut pending))?;
+    }
+    if entry.record.batch.is_some() {
+      pending.push(entry);
+    } else {
+      last = apply(engine, vec![entry])?;
+    }
+  }
+  if !pending.is_empty() {
+    last = apply(engine, pending)?;
+  }
+  Ok(last)
+}
+
+fn parse(row: &StringRecord, header: &StringRecord) -> Result<Entry> {
+  let seq = row.get(0).unwrap_or_default().parse().context("invalid seq")?;
+  let outcome = row.get(1).unwrap_or_default().to_string();
+  let fields: StringRecord = row.iter().skip(2).collect();
+  let record = fields.deserialize(Some(header))?;
+  Ok(Entry { seq, outcome, record })
+}
+
+/// One record, or the members of one batch, checked against the logged outcomes
+fn apply(engine: &mut Engine, entries: Vec<Entry>) -> Result<u64> {
+  let last = entries.last().map_or(0, |entry| entry.seq);
+  let logged: Vec<(u64, String)> =
+    entries.iter().map(|entry| (entry.seq, entry.outcome.clone())).collect();
+  let mut records: Vec<TransactionRecord> = entries.into_iter().map(|entry| entry.record).collect();
+  let outcomes: Vec<&str> = match records[0].batch {
+    Some(batch) => match engine.process_batch(batch, records) {
+      Ok(outcomes) => outcomes.into_iter().map(outcome_name).collect(),
+      Err(e) => rejected(e, logged.len()),
+    },
+    None => match engine.process(records.remove(0)) {
+      Ok(outcome) => vec![outcome_name(outcome)],
+      Err(e) => rejected(e, 1),
+    },
+  };
+  for ((seq, expected), actual) in logged.iter().zip(outcomes) {
+    if expected != actual {
+      bail!("replay diverged at record {}: logged as {}, replayed as {}", seq, expected, actual);
+    }
+  }
+  Ok(last)
+}
+
+fn outcome_name(outcome: Outcome) -> &'static str {
+  match outcome {
+    Outcome::Applied => "applied",
+    Outcome::Skipped => "skipped",
+  }
+}
+
+fn rejected(error: EngineError, count: usize) -> Vec<&'static str> {
+  debug!(error = %error, "Replayed a rejection");
+  vec!["rejected"; count]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transaction::TransactionType;
+
+  fn record(
+    tx_type: TransactionType,
+    tx: u32,
+    amount: &str,
+    batch: Option<u32>,
+  ) -> TransactionRecord {
+    TransactionRecord {
+      tx_type,
+      client: 1,
+      tx,
+      amount: (!amount.is_empty()).then(|| amount.parse().unwrap()),
+      timestamp: Some(DateTime::from_timestamp(1_700_000_000 + i64::from(tx) * 60, 0).unwrap()),
+      batch,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  /// Runs the records through an engine like the main loop does, logging each outcome
+  fn logged(path: &Path, records: Vec<TransactionRecord>) {
+    let mut engine = Engine::new();
+    let mut log = RecordLog::open(path, true).unwrap();
+    for record in records {
+      match engine.process(record.clone()) {
+        Ok(Outcome::Applied) => log.applied(&record).unwrap(),
+        Ok(Outcome::Skipped) => log.skipped(&record).unwrap(),
+        Err(_) => log.rejected(&[record]).unwrap(),
+      }
+    }
+    log.finish().unwrap();
+  }
+
+  fn available(engine: &Engine) -> String {
+    engine.account(1).unwrap().map(|a| a.balance("USD").available.to_string()).unwrap_or_default()
+  }
+
+  #[test]
+  fn test_replay_to_record_and_time() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("records.log");
+    logged(
+      &path,
+      vec![
+        record(TransactionType::Deposit, 1, "10", None),
+        record(TransactionType::Withdrawal, 2, "50", None),
+        record(TransactionType::Deposit, 3, "5", None),
+      ],
+    );
+    // Appending, as over a saved state, numbers on
+    logged(&path, vec![record(TransactionType::Deposit, 4, "1", None)]);
+
+    let mut engine = Engine::new();
+    assert_eq!(replay(&mut engine, &path, Until::End).unwrap(), 4);
+    assert_eq!(available(&engine), "16");
+
+    let mut engine = Engine::new();
+    assert_eq!(replay(&mut engine, &path, Until::Record(2)).unwrap(), 2);
+    assert_eq!(available(&engine), "10");
+
+    let third = DateTime::from_timestamp(1_700_000_000 + 3 * 60, 0).unwrap();
+    let mut engine = Engine::new();
+    assert_eq!(replay(&mut engine, &path, Until::Time(third)).unwrap(), 3);
+    assert_eq!(available(&engine), "15");
+  }
+
+  #[test]
+  fn test_cut_inside_batch_leaves_it_out() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("records.log");
+    let mut log = RecordLog::open(&path, false).unwrap();
+    log.applied(&record(TransactionType::Deposit, 1, "10", None)).unwrap();
+    log.applied(&record(TransactionType::Deposit, 2, "1", Some(7))).unwrap();
+    log.applied(&record(TransactionType::Deposit, 3, "1", Some(7))).unwrap();
+    log.finish().unwrap();
+
+    let mut engine = Engine::new();
+    assert_eq!(replay(&mut engine, &path, Until::Record(2)).unwrap(), 1);
+    assert_eq!(available(&engine), "10");
+    let mut engine = Engine::new();
+    assert_eq!(replay(&mut engine, &path, Until::End).unwrap(), 3);
+    assert_eq!(available(&engine), "12");
+  }
+
+  #[test]
+  fn test_divergence_reported() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("records.log");
+    let mut log = RecordLog::open(&path, false).unwrap();
+    log.applied(&record(TransactionType::Withdrawal, 1, "10", None)).unwrap();
+    log.finish().unwrap();
+
+    let error = replay(&mut Engine::new(), &path, Until::End).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "replay diverged at record 1: logged as applied, replayed as rejected"
+    );
+  }
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::transaction::{StoredTransaction, TransactionRecord, TransactionType};
+
+/// Fraud and AML screening rules from a local TOML file, checked against the client's
+/// stored transactions before a record touches any balance. Each kind of rule is a
+/// list of tables:
+///
+/// ```toml
+/// [[rapid_cycle]]
+/// name = "deposit withdrawn within the hour"
+/// action = "flag"
+/// within_minutes = 60
+/// min_percent = "90"
+///
+/// [[dispute_count]]
+/// name = "too many disputes"
+/// action = "block"
+/// max = 3
+///
+/// [[structuring]]
+/// name = "deposits just under 10k"
+/// action = "flag"
+/// threshold = "10000"
+/// margin_percent = "10"
+/// count = 3
+/// within_hours = 24
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleSet {
+  rapid_cycle: Vec<RapidCycle>,
+  dispute_count: Vec<DisputeCount>,
+  structuring: Vec<Structuring>,
+}
+
+/// What a matching rule does to the record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+  /// The record goes ahead and an alert is written
+  Flag,
+  /// The record is rejected with `Blocked` and an alert is written
+  Block,
+}
+
+impl fmt::Display for RuleAction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      RuleAction::Flag => "flag",
+      RuleAction::Block => "block",
+    })
+  }
+}
+
+/// A withdrawal of at least `min_percent` of a deposit made in the same currency within
+/// the last `within_minutes`. Needs timestamps on both
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RapidCycle {
+  name: String,
+  action: RuleAction,
+  within_minutes: u32,
+  #[serde(default = "hundred")]
+  min_percent: Decimal,
+}
+
+/// A dispute that takes the client past `max` disputes, counting every one ever raised
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DisputeCount {
+  name: String,
+  action: RuleAction,
+  max: u32,
+}
+
+/// The `count`-th deposit from `threshold` less `margin_percent` up to just under
+/// `threshold`. With `within_hours` only deposits that close to this one count
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Structuring {
+  name: String,
+  action: RuleAction,
+  threshold: Decimal,
+  margin_percent: Decimal,
+  count: u32,
+  within_hours: Option<u32>,
+}
+
+fn hundred() -> Decimal {
+  Decimal::ONE_HUNDRED
+}
+
+/// A rule that matched a record
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+  pub tx: u32,
+  pub client: u16,
+  pub tx_type: TransactionType,
+  pub rule: String,
+  pub action: RuleAction,
+  pub timestamp: Option<DateTime<Utc>>,
+}
+
+impl RuleSet {
+  pub fn load(path: &Path) -> Result<Self> {
+    let context = || format!("Failed to load rules '{}'", path.display());
+    let text = fs::read_to_string(path).with_context(context)?;
+    Self::from_toml(&text).with_context(context)
+  }
+
+  pub fn from_toml(text: &str) -> Result<Self> {
+    let rules: Self = toml::from_str(text)?;
+    for rule in &rules.structuring {
+      if rule.threshold <= Decimal::ZERO || rule.count == 0 {
+        bail!("structuring rule '{}' needs a positive threshold and count", rule.name);
+      }
+      if rule.margin_percent <= Decimal::ZERO || rule.margin_percent > Decimal::ONE_HUNDRED {
+        bail!("structuring rule '{}' needs a margin_percent from 0 to 100", rule.name);
+      }
+    }
+    Ok(rules)
+  }
+
+  /// Whether any rule looks at records of this type, the others need no history
+  pub fn screens(&self, tx_type: TransactionType) -> bool {
+    match tx_type {
+      TransactionType::Withdrawal => !self.rapid_cycle.is_empty(),
+      TransactionType::Deposit => !self.structuring.is_empty(),
+      TransactionType::Dispute => !self.dispute_count.is_empty(),
+      _ => false,
+    }
+  }
+
+  /// Every rule `record` matches. `history` is the client's stored transactions, in
+  /// `currency` for the amount based rules
+  pub fn evaluate(
+    &self,
+    record: &TransactionRecord,
+    currency: &str,
+    history: &[(u32, StoredTransaction)],
+  ) -> Vec<Alert> {
+    let alert = |rule: &str, action: RuleAction| Alert {
+      tx: record.tx,
+      client: record.client,
+      tx_type: record.tx_type,
+      rule: rule.to_string(),
+      action,
+      timestamp: record.timestamp,
+    };
+    let in_currency =
+      || history.iter().map(|(_, stored)| stored).filter(move |stored| stored.currency == currency);
+    let mut alerts = Vec::new();
+
+    match (record.tx_type, record.amount) {
+      (TransactionType::Withdrawal, Some(amount)) => {
+        let Some(at) = record.timestamp else {
+          return alerts;
+        };
+        for rule in &self.rapid_cycle {
+          let since = at - Duration::minutes(rule.within_minutes.into());
+          let cycled = in_currency().any(|stored| {
+            stored.tx_type == TransactionType::Deposit
+              && stored.timestamp.is_some_and(|deposited| deposited >= since && deposited <= at)
+              && amount * Decimal::ONE_HUNDRED >= stored.amount * rule.min_percent
+          });
+          if cycled {
+            alerts.push(alert(&rule.name, rule.action));
+          }
+        }
+      }
+      (TransactionType::Deposit, Some(amount)) => {
+        for rule in &self.structuring {
+          let floor =
+            rule.threshold * (Decimal::ONE_HUNDRED - rule.margin_percent) / Decimal::ONE_HUNDRED;
+          let in_band = |amount: Decimal| amount >= floor && amount < rule.threshold;
+          if !in_band(amount) {
+            continue;
+          }
+          let window = rule.within_hours.zip(record.timestamp);
+          let close = |stored: &StoredTransaction| match window {
+            Some((hours, at)) => {
+              stored.timestamp.is_some_and(|ts| (at - ts).abs() <= Duration::hours(hours.into()))
+            }
+            None => true,
+          };
+          let earlier = in_currency()
+            .filter(|stored| stored.tx_type == TransactionType::Deposit)
+            .filter(|stored| in_band(stored.amount) && close(stored))
+            .count();
+          if earlier + 1 >= rule.count as usize {
+            alerts.push(alert(&rule.name, rule.action));
+          }
+        }
+      }
+      (TransactionType::Dispute, _) => {
+        let raised = history
+          .iter()
+          .flat_map(|(_, stored)| &stored.history)
+          .filter(|entry| entry.action == TransactionType::Dispute)
+          .count();
+        for rule in &self.dispute_count {
+          if raised + 1 > rule.max as usize {
+            alerts.push(alert(&rule.name, rule.action));
+          }
+        }
+      }
+      _ => {}
+    }
+    alerts
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transaction::{DisputeState, parse_timestamp};
+
+  const USD: &str = "USD";
+
+  fn record(tx_type: TransactionType, amount: Option<i64>, at: Option<&str>) -> TransactionRecord {
+    TransactionRecord {
+      tx_type,
+      client: 1,
+      tx: 100,
+      amount: amount.map(Decimal::from),
+      timestamp: at.map(|at| parse_timestamp(at).unwrap()),
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  fn deposit(tx: u32, amount: i64, at: &str) -> (u32, StoredTransaction) {
+    let at = Some(parse_timestamp(at).unwrap());
+    (tx, StoredTransaction::new(TransactionType::Deposit, 1, USD, Decimal::from(amount), at))
+  }
+
+  #[test]
+  fn test_rapid_cycle() {
+    let rules = RuleSet::from_toml(
+      "[[rapid_cycle]]\nname = \"cycle\"\naction = \"flag\"\nwithin_minutes = 60\nmin_percent = \"90\"",
+    )
+    .unwrap();
+    let history = [deposit(1, 1000, "2024-01-01T10:00:00Z")];
+    let withdrawal = |amount, at| record(TransactionType::Withdrawal, Some(amount), Some(at));
+
+    let alerts = rules.evaluate(&withdrawal(950, "2024-01-01T10:30:00Z"), USD, &history);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!((alerts[0].rule.as_str(), alerts[0].action), ("cycle", RuleAction::Flag));
+    // Too small, too late, another currency
+    assert!(rules.evaluate(&withdrawal(800, "2024-01-01T10:30:00Z"), USD, &history).is_empty());
+    assert!(rules.evaluate(&withdrawal(950, "2024-01-01T11:30:01Z"), USD, &history).is_empty());
+    assert!(rules.evaluate(&withdrawal(950, "2024-01-01T10:30:00Z"), "EUR", &history).is_empty());
+  }
+
+  #[test]
+  fn test_dispute_count() {
+    let rules =
+      RuleSet::from_toml("[[dispute_count]]\nname = \"disputes\"\naction = \"block\"\nmax = 1")
+        .unwrap();
+    let dispute = record(TransactionType::Dispute, None, None);
+    let (tx, mut stored) = deposit(1, 10, "2024-01-01T10:00:00Z");
+    assert!(rules.evaluate(&dispute, USD, &[(tx, stored.clone())]).is_empty());
+
+    stored.transition(TransactionType::Dispute, DisputeState::Disputed, None);
+    stored.transition(TransactionType::Resolve, DisputeState::Resolved, None);
+    let alerts = rules.evaluate(&dispute, USD, &[(tx, stored)]);
+    assert_eq!(alerts[0].action, RuleAction::Block);
+
+    // Only disputes need the client's history
+    assert!(rules.screens(TransactionType::Dispute));
+    assert!(!rules.screens(TransactionType::Deposit));
+  }
+
+  #[test]
+  fn test_structuring() {
+    let rules = RuleSet::from_toml(
+      "[[structuring]]\nname = \"under 10k\"\naction = \"flag\"\nthreshold = \"10000\"\n\
+       margin_percent = \"10\"\ncount = 3\nwithin_hours = 24",
+    )
+    .unwrap();
+    let history = [
+      deposit(1, 9500, "2024-01-01T08:00:00Z"),
+      deposit(2, 9900, "2024-01-01T12:00:00Z"),
+      deposit(3, 5000, "2024-01-01T13:00:00Z"),
+    ];
+    let deposit = |amount, at| record(TransactionType::Deposit, Some(amount), Some(at));
+
+    assert_eq!(rules.evaluate(&deposit(9000, "2024-01-01T20:00:00Z"), USD, &history).len(), 1);
+    // At the threshold is not structuring, and the earlier ones are out of the window
+    assert!(rules.evaluate(&deposit(10000, "2024-01-01T20:00:00Z"), USD, &history).is_empty());
+    assert!(rules.evaluate(&deposit(9000, "2024-01-02T10:00:00Z"), USD, &history).is_empty());
+  }
+
+  #[test]
+  fn test_invalid_rules_rejected() {
+    assert!(RuleSet::from_toml("[[velocity]]\nname = \"x\"").is_err());
+    assert!(
+      RuleSet::from_toml("[[dispute_count]]\nname = \"x\"\naction = \"warn\"\nmax = 1").is_err()
+    );
+    assert!(
+      RuleSet::from_toml(
+        "[[structuring]]\nname = \"x\"\naction = \"flag\"\nthreshold = \"100\"\n\
+         margin_percent = \"0\"\ncount = 2"
+      )
+      .is_err()
+    );
+    let empty = RuleSet::from_toml("").unwrap();
+    assert!(
+      !empty.screens(TransactionType::Deposit) && !empty.screens(TransactionType::Withdrawal)
+    );
+  }
+}
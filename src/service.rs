@@ -0,0 +1,448 @@
+use std::fmt::Display;
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{Result, anyhow};
+use rust_decimal::Decimal;
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, warn};
+
+use crate::account::{Account, AccountError};
+use crate::engine::{Engine, EngineError, Outcome};
+use crate::metrics::Metrics;
+use crate::notify::{self, Deliveries};
+use crate::rules::Alert;
+use crate::transaction::{StoredTransaction, TransactionRecord};
+
+/// Threads taking requests. They all queue on the engine, so more only helps with slow clients
+const WORKERS: usize = 4;
+/// Largest request body read, a batch of a few thousand records
+const MAX_BODY: u64 = 1 << 20;
+
+/// The engine as a local HTTP/JSON service:
+///
+/// - `POST /transactions` applies one record (a JSON object) or a batch (an array)
+/// - `GET /accounts` and `GET /accounts/<client>`
+/// - `GET /transactions/<tx>` a stored transaction with its dispute state and history
+///
+/// Every request holds the engine for as long as it runs, so records are applied one at a
+/// time in the order they arrive and tx ids stay unique across all clients of the service
+pub struct Service {
+  engine: Arc<Mutex<Engine>>,
+  metrics: Option<Arc<Metrics>>,
+  deliveries: Option<Deliveries>,
+  /// Batch ids handed to the engine, only used in its error messages
+  batches: AtomicU32,
+}
+
+/// HTTP status and JSON body
+type Reply = (u16, Value);
+
+impl Service {
+  pub fn new(
+    engine: Arc<Mutex<Engine>>,
+    metrics: Option<Arc<Metrics>>,
+    deliveries: Option<Deliveries>,
+  ) -> Self {
+    Self { engine, metrics, deliveries, batches: AtomicU32::new(0) }
+  }
+
+  /// Answers requests on `addr` until the process is stopped
+  pub fn run(self, addr: &str) -> Result<()> {
+    let server =
+      Server::http(addr).map_err(|e| anyhow!("Failed to listen on '{}': {}", addr, e))?;
+    debug!(addr, "Serving the engine");
+    let server = Arc::new(server);
+    let service = Arc::new(self);
+    let workers: Vec<_> = (0..WORKERS)
+      .map(|_| {
+        let (server, service) = (Arc::clone(&server), Arc::clone(&service));
+        thread::spawn(move || {
+          for request in server.incoming_requests() {
+            service.handle(request);
+          }
+        })
+      })
+      .collect();
+    for worker in workers {
+      let _ = worker.join();
+    }
+    Ok(())
+  }
+
+  fn handle(&self, mut request: Request) {
+    // One byte over the limit tells a body that was cut short from one that fits exactly
+    let mut body = Vec::new();
+    let (status, reply) = match request.as_reader().take(MAX_BODY + 1).read_to_end(&mut body) {
+      Ok(_) if body.len() as u64 > MAX_BODY => {
+        error(413, "PayloadTooLarge", format!("bodies are limited to {} bytes", MAX_BODY))
+      }
+      Ok(_) => match String::from_utf8(body) {
+        Ok(body) => self.route(request.method(), request.url(), &body),
+        Err(e) => error(400, "InvalidBody", e),
+      },
+      Err(e) => error(400, "InvalidBody", e),
+    };
+    let header = Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    let response = Response::from_string(reply.to_string()).with_status_code(status);
+    if let Err(e) = request.respond(response.with_header(header)) {
+      warn!(error = %e, "Failed to answer a request");
+    }
+  }
+
+  fn route(&self, method: &Method, url: &str, body: &str) -> Reply {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+      (Method::Post, ["transactions"]) => self.post_transactions(body),
+      (Method::Get, ["accounts"]) => self.accounts(),
+      (Method::Get, ["accounts", client]) => match client.parse() {
+        Ok(client) => self.account(client),
+        Err(e) => error(400, "InvalidClient", e),
+      },
+      (Method::Get, ["transactions", tx]) => match tx.parse() {
+        Ok(tx) => self.transaction(tx),
+        Err(e) => error(400, "InvalidTransaction", e),
+      },
+      (_, ["transactions"] | ["accounts"] | ["accounts", _] | ["transactions", _]) => {
+        error(405, "MethodNotAllowed", format!("{} is not allowed on {}", method, path))
+      }
+      _ => error(404, "NotFound", format!("no route for {}", path)),
+    }
+  }
+
+  fn engine(&self) -> MutexGuard<'_, Engine> {
+    self.engine.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  fn post_transactions(&self, body: &str) -> Reply {
+    let value: Value = match serde_json::from_str(body) {
+      Ok(value) => value,
+      Err(e) => return error(400, "InvalidJson", e),
+    };
+    let started = Instant::now();
+    let reply = if value.is_array() {
+      match serde_json::from_value::<Vec<TransactionRecord>>(value) {
+        Ok(records) if records.is_empty() => error(400, "EmptyBatch", "a batch needs a record"),
+        Ok(records) => self.apply_batch(records),
+        Err(e) => error(400, "InvalidRecord", e),
+      }
+    } else {
+      match serde_json::from_value::<TransactionRecord>(value) {
+        Ok(record) => self.apply(record),
+        Err(e) => error(400, "InvalidRecord", e),
+      }
+    };
+    if let Some(metrics) = &self.metrics {
+      metrics.observe(started.elapsed());
+    }
+    reply
+  }
+
+  fn apply(&self, record: TransactionRecord) -> Reply {
+    let (tx, tx_type) = (record.tx, record.tx_type);
+    let mut engine = self.engine();
+    let result = engine.process(record);
+    notify::flush(&mut engine, self.deliveries.as_ref());
+    let alerts = alerts(engine.take_alerts());
+    match result {
+      Ok(outcome) => {
+        if let (Some(metrics), Outcome::Applied) = (&self.metrics, outcome) {
+          metrics.applied(tx_type);
+        }
+        (200, json!({ "tx": tx, "outcome": outcome_name(outcome), "alerts": alerts }))
+      }
+      Err(e) => self.rejected(&e, alerts),
+    }
+  }
+
+  fn apply_batch(&self, records: Vec<TransactionRecord>) -> Reply {
+    let batch = self.batches.fetch_add(1, Ordering::Relaxed) + 1;
+    let applied: Vec<_> = records.iter().map(|record| (record.tx, record.tx_type)).collect();
+    let mut engine = self.engine();
+    let result = engine.process_batch(batch, records);
+    notify::flush(&mut engine, self.deliveries.as_ref());
+    let alerts = alerts(engine.take_alerts());
+    match result {
+      Ok(outcomes) => {
+        let outcomes: Vec<Value> = applied
+          .iter()
+          .zip(outcomes)
+          .map(|(&(tx, tx_type), outcome)| {
+            if let (Some(metrics), Outcome::Applied) = (&self.metrics, outcome) {
+              metrics.applied(tx_type);
+            }
+            json!({ "tx": tx, "outcome": outcome_name(outcome) })
+          })
+          .collect();
+        (200, json!({ "batch": batch, "outcomes": outcomes, "alerts": alerts }))
+      }
+      Err(e) => self.rejected(&e, alerts),
+    }
+  }
+
+  fn rejected(&self, e: &EngineError, alerts: Vec<Value>) -> Reply {
+    debug!(error = %e, "Rejected a posted record");
+    if let Some(metrics) = &self.metrics {
+      metrics.rejected(e);
+    }
+    let (status, mut reply) = error(status(e.cause()), e.cause().code(), e);
+    if let EngineError::BatchFailed { batch, tx, .. } = e {
+      reply["error"]["batch"] = json!(batch);
+      reply["error"]["tx"] = json!(tx);
+    }
+    reply["alerts"] = Value::Array(alerts);
+    (status, reply)
+  }
+
+  fn accounts(&self) -> Reply {
+    let engine = self.engine();
+    match engine.accounts() {
+      Ok(mut accounts) => {
+        accounts.sort_by_key(|account| account.client);
+        let accounts: Vec<Value> =
+          accounts.iter().map(|account| account_view(&engine, account)).collect();
+        (200, Value::Array(accounts))
+      }
+      Err(e) => error(status(&e), e.code(), e),
+    }
+  }
+
+  fn account(&self, client: u16) -> Reply {
+    let engine = self.engine();
+    match engine.account(client) {
+      Ok(Some(account)) => (200, account_view(&engine, &account)),
+      Ok(None) => {
+        let e = EngineError::ClientNotFound { client };
+        error(status(&e), e.code(), e)
+      }
+      Err(e) => error(status(&e), e.code(), e),
+    }
+  }
+
+  fn transaction(&self, tx: u32) -> Reply {
+    let engine = self.engine();
+    match engine.transaction(tx) {
+      Ok(Some(stored)) => (200, transaction_view(&engine, tx, &stored)),
+      Ok(None) => {
+        let e = EngineError::TransactionNotFound { tx };
+        error(status(&e), e.code(), e)
+      }
+      Err(e) => error(status(&e), e.code(), e),
+    }
+  }
+}
+
+/// The HTTP status of a rejected record: 404 for what does not exist, 409 for what
+/// clashes with the current state, 500 for storage failures and 422 for the rest
+fn status(e: &EngineError) -> u16 {
+  match e {
+    EngineError::TransactionNotFound { .. } | EngineError::ClientNotFound { .. } => 404,
+    EngineError::DuplicateTransaction { .. }
+    | EngineError::ConflictingDuplicate { .. }
+    | EngineError::AlreadyDisputed { .. }
+    | EngineError::NotUnderDispute { .. }
+    | EngineError::InvalidTransition { .. }
+    | EngineError::TimestampOutOfOrder { .. }
+    | EngineError::AccountError { error: AccountError::AccountLocked, .. } => 409,
+    EngineError::Storage(_) => 500,
+    _ => 422,
+  }
+}
+
+fn error(status: u16, code: &str, message: impl Display) -> Reply {
+  (status, json!({ "error": { "code": code, "message": message.to_string() } }))
+}
+
+fn outcome_name(outcome: Outcome) -> &'static str {
+  match outcome {
+    Outcome::Applied => "applied",
+    Outcome::Skipped => "skipped",
+  }
+}
+
+fn alerts(alerts: Vec<Alert>) -> Vec<Value> {
+  let alert = |alert: Alert| json!({ "tx": alert.tx, "rule": alert.rule, "action": alert.action.to_string() });
+  alerts.into_iter().map(alert).collect()
+}
+
+/// Amounts are strings at their currency's precision, like the CSV output
+fn amount(engine: &Engine, currency: &str, amount: Decimal) -> String {
+  crate::format_decimal(amount, engine.config().precision.scale(currency))
+}
+
+fn account_view(engine: &Engine, account: &Account) -> Value {
+  let balances: serde_json::Map<String, Value> = account
+    .balances
+    .iter()
+    .map(|(currency, balance)| {
+      let view = json!({
+        "available": amount(engine, currency, balance.available),
+        "held": amount(engine, currency, balance.held),
+        "total": amount(engine, currency, balance.total()),
+      });
+      (currency.clone(), view)
+    })
+    .collect();
+  json!({ "client": account.client, "locked": account.locked, "balances": balances })
+}
+
+fn transaction_view(engine: &Engine, tx: u32, stored: &StoredTransaction) -> Value {
+  let timestamp = |ts: Option<chrono::DateTime<chrono::Utc>>| ts.map(|ts| ts.to_rfc3339());
+  let history: Vec<Value> = stored
+    .history
+    .iter()
+    .map(|transition| {
+      json!({
+        "action": transition.action.to_string(),
+        "from": transition.from.to_string(),
+        "to": transition.to.to_string(),
+        "timestamp": timestamp(transition.timestamp),
+      })
+    })
+    .collect();
+  json!({
+    "tx": tx,
+    "type": stored.tx_type.to_string(),
+    "client": stored.client,
+    "currency": stored.currency,
+    "amount": amount(engine, &stored.currency, stored.amount),
+    "timestamp": timestamp(stored.timestamp),
+    "state": stored.state.to_string(),
+    "history": history,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::notify::{Notifier, NotifyConfig, SinkConfig};
+
+  fn service() -> Service {
+    Service::new(Arc::new(Mutex::new(Engine::new())), None, None)
+  }
+
+  fn post(service: &Service, body: &str) -> Reply {
+    service.route(&Method::Post, "/transactions", body)
+  }
+
+  fn get(service: &Service, path: &str) -> Reply {
+    service.route(&Method::Get, path, "")
+  }
+
+  #[test]
+  fn test_post_and_get_account() {
+    let service = service();
+    let (status, reply) =
+      post(&service, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}"#);
+    assert_eq!(status, 200);
+    assert_eq!(reply["outcome"], "applied");
+
+    let (status, reply) = get(&service, "/accounts/1");
+    assert_eq!(status, 200);
+    assert_eq!(reply["balances"]["USD"]["available"], "10.5000");
+    assert_eq!(reply["locked"], false);
+    assert_eq!(get(&service, "/accounts").1.as_array().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_engine_errors_are_structured() {
+    let service = service();
+    post(&service, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#);
+
+    let (status, reply) =
+      post(&service, r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "50"}"#);
+    assert_eq!(status, 422);
+    assert_eq!(reply["error"]["code"], "InsufficientFunds");
+    let (status, reply) =
+      post(&service, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#);
+    assert_eq!((status, reply["error"]["code"].as_str()), (409, Some("DuplicateTransaction")));
+    let (status, reply) = post(&service, r#"{"type": "dispute", "client": 1, "tx": 9}"#);
+    assert_eq!((status, reply["error"]["code"].as_str()), (404, Some("TransactionNotFound")));
+  }
+
+  #[test]
+  fn test_batch_is_all_or_nothing() {
+    let service = service();
+    let (status, reply) = post(
+      &service,
+      r#"[{"type": "deposit", "client": 1, "tx": 1, "amount": "10"},
+          {"type": "withdrawal", "client": 1, "tx": 2, "amount": "50"}]"#,
+    );
+    assert_eq!(status, 422);
+    assert_eq!(reply["error"]["tx"], 2);
+    assert_eq!(get(&service, "/accounts/1").0, 404);
+
+    let (status, reply) = post(
+      &service,
+      r#"[{"type": "deposit", "client": 1, "tx": 1, "amount": "10"},
+          {"type": "withdrawal", "client": 1, "tx": 2, "amount": "5"}]"#,
+    );
+    assert_eq!(status, 200);
+    assert_eq!(reply["outcomes"][1]["outcome"], "applied");
+  }
+
+  #[test]
+  fn test_transaction_dispute_state() {
+    let service = service();
+    post(&service, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#);
+    post(&service, r#"{"type": "dispute", "client": 1, "tx": 1}"#);
+
+    let (status, reply) = get(&service, "/transactions/1");
+    assert_eq!(status, 200);
+    assert_eq!(reply["state"], "disputed");
+    assert_eq!(reply["history"][0]["action"], "dispute");
+    assert_eq!(reply["amount"], "10.0000");
+  }
+
+  #[test]
+  fn test_bad_requests() {
+    let service = service();
+    assert_eq!(post(&service, "{").1["error"]["code"], "InvalidJson");
+    assert_eq!(post(&service, r#"{"type": "refund"}"#).1["error"]["code"], "InvalidRecord");
+    assert_eq!(post(&service, "[]").1["error"]["code"], "EmptyBatch");
+    let (_, reply) = post(&service, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.5}"#);
+    assert_eq!(reply["error"]["code"], "InvalidRecord");
+    assert!(reply["error"]["message"].as_str().unwrap().contains(r#"like "10.5""#));
+    assert_eq!(get(&service, "/accounts/x").0, 400);
+    assert_eq!(get(&service, "/nope").0, 404);
+    assert_eq!(service.route(&Method::Delete, "/accounts", "").0, 405);
+  }
+
+  #[test]
+  fn test_notifies_and_drains_events() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("notifications.ndjson");
+    let config =
+      NotifyConfig { sinks: vec![SinkConfig::File { path: path.clone() }], ..Default::default() };
+    let engine = Arc::new(Mutex::new(Engine::new().with_events()));
+    let deliveries = Deliveries::spawn(Notifier::new(config), Default::default());
+    let service = Service::new(Arc::clone(&engine), None, Some(deliveries));
+    post(&service, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#);
+    post(
+      &service,
+      r#"[{"type": "dispute", "client": 1, "tx": 1},
+          {"type": "chargeback", "client": 1, "tx": 1}]"#,
+    );
+
+    // Delivered by the notifier's own thread
+    let mut triggers = Vec::new();
+    for _ in 0..100 {
+      let text = std::fs::read_to_string(&path).unwrap_or_default();
+      triggers = text
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["trigger"].clone())
+        .collect();
+      if triggers.len() == 2 {
+        break;
+      }
+      thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(triggers, ["chargeback", "account_locked"]);
+    assert!(engine.lock().unwrap().take_events().is_empty());
+  }
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{Storage, StorageError};
+use crate::account::Account;
+use crate::transaction::StoredTransaction;
+
+/// The original in process backend, two hashmaps.
+/// A storage transaction keeps the previous value of everything it overwrites so a
+/// rollback can put it back
+#[derive(Default)]
+pub struct MemoryStorage {
+  accounts: HashMap<u16, Account>,
+  transactions: HashMap<u32, StoredTransaction>,
+  /// The tx ids of each client, so `client_transactions` does not scan every transaction.
+  /// A stored transaction never changes client
+  by_client: HashMap<u16, HashSet<u32>>,
+  undo: Option<Vec<Undo>>,
+}
+
+enum Undo {
+  Account(u16, Option<Account>),
+  Transaction(u32, Option<StoredTransaction>),
+}
+
+impl MemoryStorage {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn account(&self, client: u16) -> Result<Option<Account>, StorageError> {
+    Ok(self.accounts.get(&client).cloned())
+  }
+
+  fn save_account(&mut self, account: &Account) -> Result<(), StorageError> {
+    let previous = self.accounts.insert(account.client, account.clone());
+    if let Some(undo) = self.undo.as_mut() {
+      undo.push(Undo::Account(account.client, previous));
+    }
+    Ok(())
+  }
+
+  fn accounts(&self) -> Result<Vec<Account>, StorageError> {
+    Ok(self.accounts.values().cloned().collect())
+  }
+
+  fn transaction(&self, tx: u32) -> Result<Option<StoredTransaction>, StorageError> {
+    Ok(self.transactions.get(&tx).cloned())
+  }
+
+  fn contains_transaction(&self, tx: u32) -> Result<bool, StorageError> {
+    Ok(self.transactions.contains_key(&tx))
+  }
+
+  fn transaction_count(&self) -> Result<u64, StorageError> {
+    Ok(self.transactions.len() as u64)
+  }
+
+  fn save_transaction(&mut self, tx: u32, stored: &StoredTransaction) -> Result<(), StorageError> {
+    let previous = self.transactions.insert(tx, stored.clone());
+    self.by_client.entry(stored.client).or_default().insert(tx);
+    if let Some(undo) = self.undo.as_mut() {
+      undo.push(Undo::Transaction(tx, previous));
+    }
+    Ok(())
+  }
+
+  fn client_transactions(
+    &self,
+    client: u16,
+  ) -> Result<Vec<(u32, StoredTransaction)>, StorageError> {
+    let Some(txs) = self.by_client.get(&client) else {
+      return Ok(Vec::new());
+    };
+    Ok(txs.iter().filter_map(|tx| Some((*tx, self.transactions.get(tx)?.clone()))).collect())
+  }
+
+  fn begin(&mut self) -> Result<(), StorageError> {
+    if self.undo.is_some() {
+      return Err(StorageError::NestedTransaction);
+    }
+    self.undo = Some(Vec::new());
+    Ok(())
+  }
+
+  fn commit(&mut self) -> Result<(), StorageError> {
+    self.undo.take().map(|_| ()).ok_or(StorageError::NoTransaction)
+  }
+
+  fn rollback(&mut self) -> Result<(), StorageError> {
+    let undo = self.undo.take().ok_or(StorageError::NoTransaction)?;
+    // Newest first so a value written twice ends up at its original state
+    for entry in undo.into_iter().rev() {
+      match entry {
+        Undo::Account(client, Some(account)) => {
+          self.accounts.insert(client, account);
+        }
+        Undo::Account(client, None) => {
+          self.accounts.remove(&client);
+        }
+        Undo::Transaction(tx, Some(stored)) => {
+          self.transactions.insert(tx, stored);
+        }
+        Undo::Transaction(tx, None) => {
+          if let Some(stored) = self.transactions.remove(&tx) {
+            if let Some(txs) = self.by_client.get_mut(&stored.client) {
+              txs.remove(&tx);
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
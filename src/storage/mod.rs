@@ -0,0 +1,245 @@
+mod memory;
+mod sqlite;
+
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::account::Account;
+use crate::transaction::StoredTransaction;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+/// Where the engine keeps accounts and the transactions that can still be disputed.
+/// The engine reads a copy, changes it and saves it back, so a backend never has to
+/// hand out references into its own state.
+///
+/// Writes between `begin` and `commit` must land together, `rollback` throws them away.
+pub trait Storage: Send {
+  fn account(&self, client: u16) -> Result<Option<Account>, StorageError>;
+  fn save_account(&mut self, account: &Account) -> Result<(), StorageError>;
+  /// All accounts, in no particular order
+  fn accounts(&self) -> Result<Vec<Account>, StorageError>;
+
+  fn transaction(&self, tx: u32) -> Result<Option<StoredTransaction>, StorageError>;
+  fn contains_transaction(&self, tx: u32) -> Result<bool, StorageError>;
+  fn transaction_count(&self) -> Result<u64, StorageError>;
+  fn save_transaction(&mut self, tx: u32, stored: &StoredTransaction) -> Result<(), StorageError>;
+  /// Every stored transaction of one client with its tx id, in no particular order
+  fn client_transactions(&self, client: u16)
+  -> Result<Vec<(u32, StoredTransaction)>, StorageError>;
+
+  fn begin(&mut self) -> Result<(), StorageError>;
+  fn commit(&mut self) -> Result<(), StorageError>;
+  fn rollback(&mut self) -> Result<(), StorageError>;
+}
+
+/// Opens a backend from its command line spec: `memory`, `sqlite` for an in memory
+/// database or `sqlite:<path>` for a database file
+pub fn open(spec: &str) -> Result<Box<dyn Storage>, StorageError> {
+  match spec {
+    "memory" => Ok(Box::new(MemoryStorage::new())),
+    "sqlite" => Ok(Box::new(SqliteStorage::open_in_memory()?)),
+    _ => match spec.strip_prefix("sqlite:") {
+      Some(path) => Ok(Box::new(SqliteStorage::open(path)?)),
+      None => Err(StorageError::UnknownBackend(spec.to_string())),
+    },
+  }
+}
+
+/// A state saved with `sqlite:<path>`, for commands that only read it
+pub fn open_read_only(path: &Path) -> Result<Box<dyn Storage>, StorageError> {
+  Ok(Box::new(SqliteStorage::open_read_only(&path.to_string_lossy())?))
+}
+
+/// Whether a backend spec keeps its state after the process exits
+pub fn is_persistent(spec: Option<&str>) -> bool {
+  spec.is_some_and(|spec| spec.starts_with("sqlite:"))
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+  #[error("unknown storage backend '{0}', expected memory, sqlite or sqlite:<path>")]
+  UnknownBackend(String),
+  #[error("sqlite: {0}")]
+  Sqlite(#[from] rusqlite::Error),
+  #[error("corrupt stored value: {0}")]
+  Corrupt(String),
+  #[error("no storage transaction in progress")]
+  NoTransaction,
+  #[error("a storage transaction is already in progress")]
+  NestedTransaction,
+  #[error(
+    "saved state has schema version {version}, this build reads {expected}. \
+     Run once with --storage sqlite:<path> to migrate it"
+  )]
+  Outdated { version: u32, expected: u32 },
+  #[error("saved state has schema version {version}, newer than the {expected} this build reads")]
+  Newer { version: u32, expected: u32 },
+}
+
+/// The same checks are run against every backend
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::account::DEFAULT_CURRENCY;
+  use crate::transaction::{Conversion, DisputeState, Fee, TransactionType};
+  use chrono::Utc;
+  use rust_decimal::Decimal;
+
+  fn backends() -> Vec<Box<dyn Storage>> {
+    vec![open("memory").unwrap(), open("sqlite").unwrap()]
+  }
+
+  fn account(client: u16, available: i64) -> Account {
+    let mut account = Account::new(client);
+    account.deposit(DEFAULT_CURRENCY, Decimal::new(available, 2)).unwrap();
+    account
+  }
+
+  #[test]
+  fn test_open_unknown_backend() {
+    assert!(matches!(open("redis"), Err(StorageError::UnknownBackend(_))));
+  }
+
+  #[test]
+  fn test_account_round_trip() {
+    for mut storage in backends() {
+      assert!(storage.account(1).unwrap().is_none());
+
+      let mut saved = account(1, 12345);
+      saved.deposit("EUR", Decimal::new(2, 0)).unwrap();
+      saved.hold("EUR", Decimal::new(1, 4), Decimal::ZERO).unwrap();
+      saved.locked = true;
+      saved.last_timestamp = Some(Utc::now());
+      storage.save_account(&saved).unwrap();
+
+      let loaded = storage.account(1).unwrap().unwrap();
+      assert_eq!(loaded.balances, saved.balances);
+      assert_eq!(loaded.balance("EUR").held, Decimal::new(1, 4));
+      assert!(loaded.locked);
+      assert_eq!(loaded.last_timestamp, saved.last_timestamp);
+      let all = storage.accounts().unwrap();
+      assert_eq!(all.len(), 1);
+      assert_eq!(all[0].balances, saved.balances);
+    }
+  }
+
+  #[test]
+  fn test_transaction_round_trip() {
+    for mut storage in backends() {
+      assert!(!storage.contains_transaction(7).unwrap());
+
+      let mut stored = StoredTransaction::new(
+        TransactionType::Deposit,
+        3,
+        "EUR",
+        Decimal::new(50, 1),
+        Some(Utc::now()),
+      );
+      stored.transition(TransactionType::Dispute, DisputeState::Disputed, None);
+      stored.conversion = Some(Conversion {
+        to_currency: "USD".to_string(),
+        rate: Decimal::new(10825, 4),
+        converted: Decimal::new(5412, 3),
+      });
+      stored.fees.push(Fee { tx_type: TransactionType::Deposit, amount: Decimal::new(25, 2) });
+      storage.save_transaction(7, &stored).unwrap();
+      stored.fees.push(Fee { tx_type: TransactionType::Chargeback, amount: Decimal::new(15, 0) });
+      stored.transition(TransactionType::Resolve, DisputeState::Resolved, Some(Utc::now()));
+      stored.transition(TransactionType::Dispute, DisputeState::Disputed, None);
+      storage.save_transaction(7, &stored).unwrap();
+
+      assert!(storage.contains_transaction(7).unwrap());
+      assert_eq!(storage.transaction_count().unwrap(), 1);
+      let loaded = storage.transaction(7).unwrap().unwrap();
+      assert_eq!(loaded.tx_type, TransactionType::Deposit);
+      assert_eq!(loaded.client, 3);
+      assert_eq!(loaded.currency, "EUR");
+      assert_eq!(loaded.amount, Decimal::new(50, 1));
+      assert_eq!(loaded.state, DisputeState::Disputed);
+      assert_eq!(loaded.timestamp, stored.timestamp);
+      assert_eq!(loaded.history, stored.history);
+      assert_eq!(loaded.conversion, stored.conversion);
+      assert_eq!(loaded.fees, stored.fees);
+    }
+  }
+
+  #[test]
+  fn test_client_transactions() {
+    for mut storage in backends() {
+      for (tx, client) in [(1, 1), (2, 2), (3, 1)] {
+        let stored =
+          StoredTransaction::new(TransactionType::Deposit, client, "USD", Decimal::ONE, None);
+        storage.save_transaction(tx, &stored).unwrap();
+      }
+
+      let mut txs: Vec<u32> =
+        storage.client_transactions(1).unwrap().into_iter().map(|(tx, _)| tx).collect();
+      txs.sort();
+      assert_eq!(txs, vec![1, 3]);
+      assert!(storage.client_transactions(9).unwrap().is_empty());
+    }
+  }
+
+  #[test]
+  fn test_commit_keeps_writes() {
+    for mut storage in backends() {
+      storage.begin().unwrap();
+      storage.save_account(&account(1, 100)).unwrap();
+      storage.commit().unwrap();
+
+      assert_eq!(
+        storage.account(1).unwrap().unwrap().balance(DEFAULT_CURRENCY).available,
+        Decimal::new(100, 2)
+      );
+    }
+  }
+
+  #[test]
+  fn test_rollback_discards_writes() {
+    for mut storage in backends() {
+      storage.save_account(&account(1, 100)).unwrap();
+
+      storage.begin().unwrap();
+      storage.save_account(&account(1, 500)).unwrap();
+      storage.save_account(&account(2, 500)).unwrap();
+      storage
+        .save_transaction(
+          1,
+          &StoredTransaction::new(TransactionType::Deposit, 2, "USD", Decimal::ONE, None),
+        )
+        .unwrap();
+      storage.rollback().unwrap();
+
+      assert_eq!(
+        storage.account(1).unwrap().unwrap().balance(DEFAULT_CURRENCY).available,
+        Decimal::new(100, 2)
+      );
+      assert!(storage.account(2).unwrap().is_none());
+      assert!(!storage.contains_transaction(1).unwrap());
+      assert!(storage.client_transactions(2).unwrap().is_empty());
+    }
+  }
+
+  #[test]
+  fn test_commit_without_begin() {
+    for mut storage in backends() {
+      assert!(matches!(storage.commit(), Err(StorageError::NoTransaction)));
+      assert!(matches!(storage.rollback(), Err(StorageError::NoTransaction)));
+    }
+  }
+
+  #[test]
+  fn test_nested_begin_refused() {
+    for mut storage in backends() {
+      storage.begin().unwrap();
+      storage.save_account(&account(1, 100)).unwrap();
+      assert!(matches!(storage.begin(), Err(StorageError::NestedTransaction)));
+      // The open transaction is untouched and still rolls back
+      storage.rollback().unwrap();
+      assert!(storage.account(1).unwrap().is_none());
+    }
+  }
+}
@@ -0,0 +1,571 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row, params};
+use rust_decimal::Decimal;
+use tracing::debug;
+
+use super::{Storage, StorageError};
+use crate::account::{Account, Balance};
+use crate::transaction::{
+  Conversion, DisputeState, Fee, StoredTransaction, TransactionType, Transition,
+};
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many have run
+/// so an existing database only gets the new ones. Never edit an entry, add a new one.
+const MIGRATIONS: &[&str] = &[
+  // 1: accounts and stored transactions. Decimals are TEXT so no precision is lost
+  "CREATE TABLE accounts (
+     client         INTEGER PRIMARY KEY,
+     available      TEXT    NOT NULL,
+     held           TEXT    NOT NULL,
+     locked         INTEGER NOT NULL,
+     last_timestamp TEXT
+   );
+   CREATE TABLE transactions (
+     tx        INTEGER PRIMARY KEY,
+     tx_type   TEXT    NOT NULL,
+     client    INTEGER NOT NULL,
+     amount    TEXT    NOT NULL,
+     disputed  INTEGER NOT NULL,
+     timestamp TEXT
+   );
+   CREATE INDEX transactions_client ON transactions (client);",
+  // 2: dispute, resolve and chargeback history per transaction
+  "CREATE TABLE transaction_history (
+     tx        INTEGER NOT NULL,
+     seq       INTEGER NOT NULL,
+     action    TEXT    NOT NULL,
+     timestamp TEXT,
+     PRIMARY KEY (tx, seq)
+   );",
+  // 3: explicit dispute state instead of the disputed flag, and the states on each
+  // history entry. Both are worked out from the history that is already there
+  "ALTER TABLE transactions ADD COLUMN state TEXT NOT NULL DEFAULT 'normal';
+   UPDATE transactions SET state = COALESCE(
+     (SELECT CASE action WHEN 'dispute' THEN 'disputed'
+                         WHEN 'resolve' THEN 'resolved'
+                         ELSE 'charged_back' END
+        FROM transaction_history h WHERE h.tx = transactions.tx ORDER BY seq DESC LIMIT 1),
+     CASE disputed WHEN 0 THEN 'normal' ELSE 'disputed' END);
+   ALTER TABLE transactions DROP COLUMN disputed;
+   ALTER TABLE transaction_history ADD COLUMN from_state TEXT NOT NULL DEFAULT 'normal';
+   ALTER TABLE transaction_history ADD COLUMN to_state TEXT NOT NULL DEFAULT 'normal';
+   UPDATE transaction_history SET to_state = CASE action WHEN 'dispute' THEN 'disputed'
+                                                         WHEN 'resolve' THEN 'resolved'
+                                                         ELSE 'charged_back' END;
+   UPDATE transaction_history SET from_state = COALESCE(
+     (SELECT p.to_state FROM transaction_history p
+        WHERE p.tx = transaction_history.tx AND p.seq = transaction_history.seq - 1),
+     'normal');",
+  // 4: balances per currency. Everything stored so far was in the default currency (USD)
+  "CREATE TABLE balances (
+     client    INTEGER NOT NULL,
+     currency  TEXT    NOT NULL,
+     available TEXT    NOT NULL,
+     held      TEXT    NOT NULL,
+     PRIMARY KEY (client, currency)
+   );
+   INSERT INTO balances SELECT client, 'USD', available, held FROM accounts;
+   ALTER TABLE accounts DROP COLUMN available;
+   ALTER TABLE accounts DROP COLUMN held;
+   ALTER TABLE transactions ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';",
+  // 5: the journal of convert records, the rate used and what was credited
+  "CREATE TABLE conversions (
+     tx          INTEGER PRIMARY KEY,
+     to_currency TEXT    NOT NULL,
+     rate        TEXT    NOT NULL,
+     converted   TEXT    NOT NULL
+   );",
+  // 6: fee postings, at most one per record type on a transaction
+  "CREATE TABLE fees (
+     tx      INTEGER NOT NULL,
+     tx_type TEXT    NOT NULL,
+     amount  TEXT    NOT NULL,
+     PRIMARY KEY (tx, tx_type)
+   );",
+  // 7: how far interest has been credited per balance
+  "ALTER TABLE balances ADD COLUMN accrued_to TEXT;",
+];
+
+/// Accounts and stored transactions in an SQLite database so other tools can read
+/// the state while, or after, the engine runs
+pub struct SqliteStorage {
+  conn: Connection,
+}
+
+impl SqliteStorage {
+  pub fn open(path: &str) -> Result<Self, StorageError> {
+    let conn = Connection::open(path)?;
+    // WAL lets readers look at the database while we write, NORMAL keeps a commit per record affordable
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    Self::migrate(conn)
+  }
+
+  /// For commands that only look at a saved state: nothing is written to the file, not even
+  /// the journal mode, so a state from another version is refused instead of migrated
+  pub fn open_read_only(path: &str) -> Result<Self, StorageError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let (version, expected) = (schema_version(&conn)?, MIGRATIONS.len() as u32);
+    if version < expected {
+      return Err(StorageError::Outdated { version, expected });
+    }
+    Ok(Self { conn })
+  }
+
+  pub fn open_in_memory() -> Result<Self, StorageError> {
+    Self::migrate(Connection::open_in_memory()?)
+  }
+
+  fn migrate(mut conn: Connection) -> Result<Self, StorageError> {
+    let version = schema_version(&conn)?;
+    for (version, sql) in (1u32..).zip(MIGRATIONS).skip(version as usize) {
+      debug!(version, "Applying schema migration");
+      let tx = conn.transaction()?;
+      tx.execute_batch(sql)?;
+      tx.pragma_update(None, "user_version", version)?;
+      tx.commit()?;
+    }
+    Ok(Self { conn })
+  }
+}
+
+/// `PRAGMA user_version`, refused when a newer build wrote the database
+fn schema_version(conn: &Connection) -> Result<u32, StorageError> {
+  let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+  let expected = MIGRATIONS.len() as u32;
+  if version > expected {
+    return Err(StorageError::Newer { version, expected });
+  }
+  Ok(version)
+}
+
+fn decimal(row: &Row, index: usize) -> Result<Decimal, StorageError> {
+  let text: String = row.get(index)?;
+  Decimal::from_str(&text).map_err(|e| StorageError::Corrupt(format!("{}: {}", text, e)))
+}
+
+fn timestamp(row: &Row, index: usize) -> Result<Option<DateTime<Utc>>, StorageError> {
+  let text: Option<String> = row.get(index)?;
+  text
+    .map(|text| {
+      DateTime::parse_from_rfc3339(&text)
+        .map(|ts| ts.to_utc())
+        .map_err(|e| StorageError::Corrupt(format!("{}: {}", text, e)))
+    })
+    .transpose()
+}
+
+fn account_from_row(row: &Row) -> Result<Account, StorageError> {
+  Ok(Account {
+    client: row.get(0)?,
+    balances: BTreeMap::new(),
+    locked: row.get(1)?,
+    last_timestamp: timestamp(row, 2)?,
+  })
+}
+
+fn balance_from_row(row: &Row) -> Result<(u16, String, Balance), StorageError> {
+  let balance =
+    Balance { available: decimal(row, 2)?, held: decimal(row, 3)?, accrued_to: timestamp(row, 4)? };
+  Ok((row.get(0)?, row.get(1)?, balance))
+}
+
+fn transaction_from_row(row: &Row) -> Result<StoredTransaction, StorageError> {
+  let tx_type: String = row.get(0)?;
+  Ok(StoredTransaction {
+    tx_type: TransactionType::from_str(&tx_type).map_err(StorageError::Corrupt)?,
+    client: row.get(1)?,
+    currency: row.get(2)?,
+    amount: decimal(row, 3)?,
+    state: state(row, 4)?,
+    timestamp: timestamp(row, 5)?,
+    history: Vec::new(),
+    conversion: None,
+    fees: Vec::new(),
+  })
+}
+
+fn conversion_from_row(row: &Row) -> Result<Conversion, StorageError> {
+  Ok(Conversion { to_currency: row.get(0)?, rate: decimal(row, 1)?, converted: decimal(row, 2)? })
+}
+
+fn fee_from_row(row: &Row) -> Result<Fee, StorageError> {
+  let tx_type: String = row.get(0)?;
+  Ok(Fee {
+    tx_type: TransactionType::from_str(&tx_type).map_err(StorageError::Corrupt)?,
+    amount: decimal(row, 1)?,
+  })
+}
+
+fn state(row: &Row, index: usize) -> Result<DisputeState, StorageError> {
+  let text: String = row.get(index)?;
+  DisputeState::from_str(&text).map_err(StorageError::Corrupt)
+}
+
+fn history_from_row(row: &Row) -> Result<Transition, StorageError> {
+  let action: String = row.get(0)?;
+  Ok(Transition {
+    action: TransactionType::from_str(&action).map_err(StorageError::Corrupt)?,
+    from: state(row, 1)?,
+    to: state(row, 2)?,
+    timestamp: timestamp(row, 3)?,
+  })
+}
+
+const ACCOUNT_COLUMNS: &str = "client, locked, last_timestamp";
+const BALANCE_COLUMNS: &str = "client, currency, available, held, accrued_to";
+
+impl Storage for SqliteStorage {
+  fn account(&self, client: u16) -> Result<Option<Account>, StorageError> {
+    let mut stmt = self
+      .conn
+      .prepare_cached(&format!("SELECT {} FROM accounts WHERE client = ?1", ACCOUNT_COLUMNS))?;
+    let mut rows = stmt.query([client])?;
+    let Some(mut account) = rows.next()?.map(account_from_row).transpose()? else {
+      return Ok(None);
+    };
+
+    let mut stmt = self
+      .conn
+      .prepare_cached(&format!("SELECT {} FROM balances WHERE client = ?1", BALANCE_COLUMNS))?;
+    let mut rows = stmt.query([client])?;
+    while let Some(row) = rows.next()? {
+      let (_, currency, balance) = balance_from_row(row)?;
+      account.balances.insert(currency, balance);
+    }
+    Ok(Some(account))
+  }
+
+  fn save_account(&mut self, account: &Account) -> Result<(), StorageError> {
+    let mut stmt = self.conn.prepare_cached(&format!(
+      "INSERT OR REPLACE INTO accounts ({}) VALUES (?1, ?2, ?3)",
+      ACCOUNT_COLUMNS
+    ))?;
+    stmt.execute(params![
+      account.client,
+      account.locked,
+      account.last_timestamp.map(|ts| ts.to_rfc3339()),
+    ])?;
+
+    // Currencies are never removed from an account, so replacing each one is enough
+    let mut stmt = self.conn.prepare_cached(&format!(
+      "INSERT OR REPLACE INTO balances ({}) VALUES (?1, ?2, ?3, ?4, ?5)",
+      BALANCE_COLUMNS
+    ))?;
+    for (currency, balance) in &account.balances {
+      stmt.execute(params![
+        account.client,
+        currency,
+        balance.available.to_string(),
+        balance.held.to_string(),
+        balance.accrued_to.map(|ts| ts.to_rfc3339()),
+      ])?;
+    }
+    Ok(())
+  }
+
+  fn accounts(&self) -> Result<Vec<Account>, StorageError> {
+    let mut stmt =
+      self.conn.prepare_cached(&format!("SELECT {} FROM accounts", ACCOUNT_COLUMNS))?;
+    let mut rows = stmt.query([])?;
+    let mut accounts = HashMap::new();
+    while let Some(row) = rows.next()? {
+      let account = account_from_row(row)?;
+      accounts.insert(account.client, account);
+    }
+
+    let mut stmt =
+      self.conn.prepare_cached(&format!("SELECT {} FROM balances", BALANCE_COLUMNS))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+      let (client, currency, balance) = balance_from_row(row)?;
+      if let Some(account) = accounts.get_mut(&client) {
+        account.balances.insert(currency, balance);
+      }
+    }
+    Ok(accounts.into_values().collect())
+  }
+
+  fn transaction(&self, tx: u32) -> Result<Option<StoredTransaction>, StorageError> {
+    let mut stmt = self.conn.prepare_cached(
+      "SELECT tx_type, client, currency, amount, state, timestamp FROM transactions WHERE tx = ?1",
+    )?;
+    let mut rows = stmt.query([tx])?;
+    let Some(mut stored) = rows.next()?.map(transaction_from_row).transpose()? else {
+      return Ok(None);
+    };
+
+    let mut stmt = self.conn.prepare_cached(
+      "SELECT action, from_state, to_state, timestamp FROM transaction_history
+       WHERE tx = ?1 ORDER BY seq",
+    )?;
+    let mut rows = stmt.query([tx])?;
+    while let Some(row) = rows.next()? {
+      stored.history.push(history_from_row(row)?);
+    }
+
+    let mut stmt = self
+      .conn
+      .prepare_cached("SELECT to_currency, rate, converted FROM conversions WHERE tx = ?1")?;
+    let mut rows = stmt.query([tx])?;
+    stored.conversion = rows.next()?.map(conversion_from_row).transpose()?;
+
+    let mut stmt =
+      self.conn.prepare_cached("SELECT tx_type, amount FROM fees WHERE tx = ?1 ORDER BY rowid")?;
+    let mut rows = stmt.query([tx])?;
+    while let Some(row) = rows.next()? {
+      stored.fees.push(fee_from_row(row)?);
+    }
+    Ok(Some(stored))
+  }
+
+  fn contains_transaction(&self, tx: u32) -> Result<bool, StorageError> {
+    let mut stmt = self.conn.prepare_cached("SELECT 1 FROM transactions WHERE tx = ?1")?;
+    Ok(stmt.query_row([tx], |_| Ok(())).optional()?.is_some())
+  }
+
+  fn transaction_count(&self) -> Result<u64, StorageError> {
+    let mut stmt = self.conn.prepare_cached("SELECT COUNT(*) FROM transactions")?;
+    let count: i64 = stmt.query_row([], |row| row.get(0))?;
+    Ok(count as u64)
+  }
+
+  fn save_transaction(&mut self, tx: u32, stored: &StoredTransaction) -> Result<(), StorageError> {
+    let mut stmt = self.conn.prepare_cached(
+      "INSERT OR REPLACE INTO transactions (tx, tx_type, client, currency, amount, state, timestamp)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    stmt.execute(params![
+      tx,
+      stored.tx_type.to_string(),
+      stored.client,
+      stored.currency,
+      stored.amount.to_string(),
+      stored.state.to_string(),
+      stored.timestamp.map(|ts| ts.to_rfc3339()),
+    ])?;
+
+    if let Some(conversion) = &stored.conversion {
+      let mut stmt = self.conn.prepare_cached(
+        "INSERT OR REPLACE INTO conversions (tx, to_currency, rate, converted)
+         VALUES (?1, ?2, ?3, ?4)",
+      )?;
+      stmt.execute(params![
+        tx,
+        conversion.to_currency,
+        conversion.rate.to_string(),
+        conversion.converted.to_string(),
+      ])?;
+    }
+
+    let mut stmt = self
+      .conn
+      .prepare_cached("INSERT OR IGNORE INTO fees (tx, tx_type, amount) VALUES (?1, ?2, ?3)")?;
+    for fee in &stored.fees {
+      stmt.execute(params![tx, fee.tx_type.to_string(), fee.amount.to_string()])?;
+    }
+
+    // History only ever grows, so only the entries past what is stored need inserting
+    let mut stmt =
+      self.conn.prepare_cached("SELECT COUNT(*) FROM transaction_history WHERE tx = ?1")?;
+    let stored_len: u32 = stmt.query_row([tx], |row| row.get(0))?;
+    let mut stmt = self.conn.prepare_cached(
+      "INSERT INTO transaction_history (tx, seq, action, from_state, to_state, timestamp)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for (seq, entry) in (0u32..).zip(&stored.history).skip(stored_len as usize) {
+      stmt.execute(params![
+        tx,
+        seq,
+        entry.action.to_string(),
+        entry.from.to_string(),
+        entry.to.to_string(),
+        entry.timestamp.map(|ts| ts.to_rfc3339())
+      ])?;
+    }
+    Ok(())
+  }
+
+  fn client_transactions(
+    &self,
+    client: u16,
+  ) -> Result<Vec<(u32, StoredTransaction)>, StorageError> {
+    let mut stmt = self.conn.prepare_cached("SELECT tx FROM transactions WHERE client = ?1")?;
+    let txs = stmt.query_map([client], |row| row.get(0))?.collect::<Result<Vec<u32>, _>>()?;
+    let mut found = Vec::with_capacity(txs.len());
+    for tx in txs {
+      if let Some(stored) = self.transaction(tx)? {
+        found.push((tx, stored));
+      }
+    }
+    Ok(found)
+  }
+
+  fn begin(&mut self) -> Result<(), StorageError> {
+    if !self.conn.is_autocommit() {
+      return Err(StorageError::NestedTransaction);
+    }
+    self.conn.execute_batch("BEGIN")?;
+    Ok(())
+  }
+
+  fn commit(&mut self) -> Result<(), StorageError> {
+    if self.conn.is_autocommit() {
+      return Err(StorageError::NoTransaction);
+    }
+    self.conn.execute_batch("COMMIT")?;
+    Ok(())
+  }
+
+  fn rollback(&mut self) -> Result<(), StorageError> {
+    if self.conn.is_autocommit() {
+      return Err(StorageError::NoTransaction);
+    }
+    self.conn.execute_batch("ROLLBACK")?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::account::DEFAULT_CURRENCY;
+
+  #[test]
+  fn test_reopen_keeps_state_and_schema_version() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("state.db");
+    let path = path.to_str().unwrap();
+
+    let mut storage = SqliteStorage::open(path).unwrap();
+    storage.save_account(&Account::new(9)).unwrap();
+    drop(storage);
+
+    let storage = SqliteStorage::open(path).unwrap();
+    assert!(storage.account(9).unwrap().is_some());
+    let version: u32 =
+      storage.conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+    assert_eq!(version as usize, MIGRATIONS.len());
+  }
+
+  #[test]
+  fn test_corrupt_decimal_reported() {
+    let mut storage = SqliteStorage::open_in_memory().unwrap();
+    let mut account = Account::new(1);
+    account.deposit("USD", Decimal::ONE).unwrap();
+    storage.save_account(&account).unwrap();
+    storage.conn.execute("UPDATE balances SET available = 'lots'", []).unwrap();
+
+    assert!(matches!(storage.account(1), Err(StorageError::Corrupt(_))));
+  }
+
+  #[test]
+  fn test_migrate_disputed_flag_to_state() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(MIGRATIONS[0]).unwrap();
+    conn.execute_batch(MIGRATIONS[1]).unwrap();
+    conn.pragma_update(None, "user_version", 2).unwrap();
+    conn
+      .execute_batch(
+        "INSERT INTO transactions VALUES (1, 'deposit', 1, '1', 0, NULL);
+         INSERT INTO transactions VALUES (2, 'deposit', 1, '1', 0, NULL);
+         INSERT INTO transactions VALUES (3, 'deposit', 1, '1', 1, NULL);
+         INSERT INTO transaction_history VALUES (2, 0, 'dispute', NULL);
+         INSERT INTO transaction_history VALUES (2, 1, 'chargeback', NULL);",
+      )
+      .unwrap();
+
+    let storage = SqliteStorage::migrate(conn).unwrap();
+    assert_eq!(storage.transaction(1).unwrap().unwrap().state, DisputeState::Normal);
+    assert_eq!(storage.transaction(3).unwrap().unwrap().state, DisputeState::Disputed);
+
+    let charged_back = storage.transaction(2).unwrap().unwrap();
+    assert_eq!(charged_back.state, DisputeState::ChargedBack);
+    let states: Vec<_> = charged_back.history.iter().map(|t| (t.from, t.to)).collect();
+    assert_eq!(
+      states,
+      vec![
+        (DisputeState::Normal, DisputeState::Disputed),
+        (DisputeState::Disputed, DisputeState::ChargedBack)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_migrate_balances_to_default_currency() {
+    let conn = Connection::open_in_memory().unwrap();
+    for sql in &MIGRATIONS[..3] {
+      conn.execute_batch(sql).unwrap();
+    }
+    conn.pragma_update(None, "user_version", 3).unwrap();
+    conn
+      .execute_batch(
+        "INSERT INTO accounts VALUES (1, '7.5', '2.5', 0, NULL);
+         INSERT INTO transactions (tx, tx_type, client, amount, timestamp, state)
+           VALUES (1, 'deposit', 1, '10', NULL, 'disputed');",
+      )
+      .unwrap();
+
+    let storage = SqliteStorage::migrate(conn).unwrap();
+    let account = storage.account(1).unwrap().unwrap();
+    assert_eq!(account.balance(DEFAULT_CURRENCY).available, Decimal::new(75, 1));
+    assert_eq!(account.balance(DEFAULT_CURRENCY).held, Decimal::new(25, 1));
+    assert_eq!(storage.transaction(1).unwrap().unwrap().currency, DEFAULT_CURRENCY);
+  }
+
+  #[test]
+  fn test_read_only_leaves_file_alone() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("state.db");
+    let path = path.to_str().unwrap();
+    let conn = Connection::open(path).unwrap();
+    for sql in &MIGRATIONS[..3] {
+      conn.execute_batch(sql).unwrap();
+    }
+    conn.pragma_update(None, "user_version", 3).unwrap();
+    drop(conn);
+
+    assert!(matches!(
+      SqliteStorage::open_read_only(path),
+      Err(StorageError::Outdated { version: 3, .. })
+    ));
+    let conn = Connection::open(path).unwrap();
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+    assert_eq!(version, 3);
+    drop(conn);
+
+    let mut storage = SqliteStorage::open(path).unwrap();
+    storage.save_account(&Account::new(9)).unwrap();
+    storage.conn.pragma_update(None, "journal_mode", "DELETE").unwrap();
+    drop(storage);
+    let mut storage = SqliteStorage::open_read_only(path).unwrap();
+    assert!(storage.account(9).unwrap().is_some());
+    assert!(storage.save_account(&Account::new(10)).is_err());
+    let mode: String =
+      storage.conn.pragma_query_value(None, "journal_mode", |row| row.get(0)).unwrap();
+    assert_eq!(mode, "delete");
+  }
+
+  #[test]
+  fn test_newer_schema_refused() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("state.db");
+    let path = path.to_str().unwrap();
+    let newer = MIGRATIONS.len() as u32 + 1;
+    drop(SqliteStorage::open(path).unwrap());
+    let conn = Connection::open(path).unwrap();
+    conn.pragma_update(None, "user_version", newer).unwrap();
+    drop(conn);
+
+    assert!(
+      matches!(SqliteStorage::open(path), Err(StorageError::Newer { version, .. }) if version == newer)
+    );
+    assert!(matches!(
+      SqliteStorage::open_read_only(path),
+      Err(StorageError::Newer { version, .. }) if version == newer
+    ));
+  }
+}
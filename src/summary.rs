@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::Instant;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::engine::{Engine, EngineError};
+use crate::transaction::{StoredTransaction, TransactionRecord, TransactionType};
+
+/// How `--summary` is written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+  #[default]
+  Text,
+  Json,
+}
+
+impl FromStr for SummaryFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(SummaryFormat::Text),
+      "json" => Ok(SummaryFormat::Json),
+      _ => Err(format!("unknown summary format '{}', expected text or json", s)),
+    }
+  }
+}
+
+/// Statistics of one run, collected while the records are processed
+#[derive(Debug, Serialize)]
+pub struct Summary {
+  /// Applied records per type
+  pub processed: BTreeMap<TransactionType, u64>,
+  /// Rejected records per type, every member of a rolled back batch counts
+  pub rejected: BTreeMap<TransactionType, u64>,
+  /// Replays skipped in idempotent mode
+  pub skipped: u64,
+  /// Rows that could not be parsed
+  pub unparsable: u64,
+  /// Rejections per `EngineError::code`, a rolled back batch counts the error that failed it
+  pub errors: BTreeMap<&'static str, u64>,
+  /// Amounts moved by the applied records, per currency
+  pub totals: BTreeMap<String, Totals>,
+  pub locked_accounts: u64,
+  /// The most transactions stored at once during the run, with those of a restored state.
+  /// A rolled back batch counts at its high point
+  pub peak_stored_transactions: u64,
+  /// Stored transactions at the end of the run, with those of a restored state
+  pub stored_transactions: u64,
+  pub elapsed_seconds: f64,
+  /// Input rows per second
+  pub throughput: f64,
+  #[serde(skip)]
+  started: Instant,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Totals {
+  pub deposited: Decimal,
+  pub withdrawn: Decimal,
+  /// Put on hold by disputes, whatever happened to them later
+  pub held: Decimal,
+  pub charged_back: Decimal,
+}
+
+impl Summary {
+  /// Starts the clock
+  pub fn new() -> Self {
+    Self {
+      processed: BTreeMap::new(),
+      rejected: BTreeMap::new(),
+      skipped: 0,
+      unparsable: 0,
+      errors: BTreeMap::new(),
+      totals: BTreeMap::new(),
+      locked_accounts: 0,
+      peak_stored_transactions: 0,
+      stored_transactions: 0,
+      elapsed_seconds: 0.0,
+      throughput: 0.0,
+      started: Instant::now(),
+    }
+  }
+
+  /// `stored` is the transaction the record created or changed
+  pub fn applied(&mut self, record: &TransactionRecord, stored: Option<&StoredTransaction>) {
+    *self.processed.entry(record.tx_type).or_default() += 1;
+    let Some(stored) = stored else {
+      return;
+    };
+    let totals = self.totals.entry(stored.currency.clone()).or_default();
+    match record.tx_type {
+      TransactionType::Deposit => totals.deposited += stored.amount,
+      TransactionType::Withdrawal => totals.withdrawn += stored.amount,
+      TransactionType::Dispute => totals.held += stored.disputed_amount(),
+      TransactionType::Chargeback => totals.charged_back += stored.disputed_amount(),
+      _ => {}
+    }
+  }
+
+  pub fn skipped(&mut self) {
+    self.skipped += 1;
+  }
+
+  pub fn unparsable(&mut self) {
+    self.unparsable += 1;
+  }
+
+  /// `records` is the rejected record, or every member of a rolled back batch
+  pub fn rejected(&mut self, error: &EngineError, records: &[TransactionRecord]) {
+    for record in records {
+      *self.rejected.entry(record.tx_type).or_default() += 1;
+    }
+    *self.errors.entry(error.cause().code()).or_default() += 1;
+  }
+
+  /// Stops the clock and takes the figures that come from the final state
+  pub fn finish(&mut self, engine: &Engine) -> Result<(), EngineError> {
+    self.locked_accounts =
+      engine.accounts()?.iter().filter(|account| account.locked).count() as u64;
+    self.peak_stored_transactions = engine.peak_transaction_count()?;
+    self.stored_transactions = engine.transaction_count()?;
+    self.elapsed_seconds = self.started.elapsed().as_secs_f64();
+    if self.elapsed_seconds > 0.0 {
+      self.throughput = self.rows() as f64 / self.elapsed_seconds;
+    }
+    Ok(())
+  }
+
+  fn rows(&self) -> u64 {
+    let processed: u64 = self.processed.values().sum();
+    let rejected: u64 = self.rejected.values().sum();
+    processed + rejected + self.skipped + self.unparsable
+  }
+
+  pub fn write(&self, out: &mut impl Write, format: SummaryFormat) -> io::Result<()> {
+    match format {
+      SummaryFormat::Json => {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+      }
+      SummaryFormat::Text => self.write_text(out),
+    }
+  }
+
+  fn write_text(&self, out: &mut impl Write) -> io::Result<()> {
+    let processed: u64 = self.processed.values().sum();
+    let rejected: u64 = self.rejected.values().sum();
+    writeln!(out, "Run summary")?;
+    writeln!(
+      out,
+      "  rows: {} ({} processed, {} rejected, {} skipped, {} unparsable)",
+      self.rows(),
+      processed,
+      rejected,
+      self.skipped,
+      self.unparsable
+    )?;
+    writeln!(out, "  elapsed: {:.3}s, {:.0} rows/s", self.elapsed_seconds, self.throughput)?;
+    writeln!(out, "  locked accounts: {}", self.locked_accounts)?;
+    writeln!(
+      out,
+      "  stored transactions: {} (peak {})",
+      self.stored_transactions, self.peak_stored_transactions
+    )?;
+
+    let types: BTreeSet<_> = self.processed.keys().chain(self.rejected.keys()).collect();
+    if !types.is_empty() {
+      writeln!(out, "By type (processed / rejected)")?;
+      for tx_type in types {
+        let count = |counts: &BTreeMap<TransactionType, u64>| counts.get(tx_type).copied();
+        writeln!(
+          out,
+          "  {:<16} {} / {}",
+          tx_type.to_string(),
+          count(&self.processed).unwrap_or_default(),
+          count(&self.rejected).unwrap_or_default()
+        )?;
+      }
+    }
+    if !self.errors.is_empty() {
+      writeln!(out, "Errors")?;
+      for (code, count) in &self.errors {
+        writeln!(out, "  {:<28} {}", code, count)?;
+      }
+    }
+    for (currency, totals) in &self.totals {
+      writeln!(out, "Totals {}", currency)?;
+      writeln!(out, "  deposited     {}", totals.deposited)?;
+      writeln!(out, "  withdrawn     {}", totals.withdrawn)?;
+      writeln!(out, "  held          {}", totals.held)?;
+      writeln!(out, "  charged back  {}", totals.charged_back)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::Outcome;
+
+  fn record(tx_type: TransactionType, tx: u32, amount: Option<i64>) -> TransactionRecord {
+    TransactionRecord {
+      tx_type,
+      client: 1,
+      tx,
+      amount: amount.map(Decimal::from),
+      timestamp: None,
+      batch: None,
+      currency: None,
+      to_currency: None,
+    }
+  }
+
+  /// Runs the records through an engine the way main does
+  fn summarize(records: Vec<TransactionRecord>) -> Summary {
+    let mut engine = Engine::new();
+    let mut summary = Summary::new();
+    for record in records {
+      match engine.process(record.clone()) {
+        Ok(Outcome::Applied) => {
+          summary.applied(&record, engine.transaction(record.tx).unwrap().as_ref())
+        }
+        Ok(Outcome::Skipped) => summary.skipped(),
+        Err(e) => summary.rejected(&e, &[record]),
+      }
+    }
+    summary.finish(&engine).unwrap();
+    summary
+  }
+
+  #[test]
+  fn test_counts_and_totals() {
+    let summary = summarize(vec![
+      record(TransactionType::Deposit, 1, Some(100)),
+      record(TransactionType::Deposit, 2, Some(50)),
+      record(TransactionType::Withdrawal, 3, Some(30)),
+      record(TransactionType::Withdrawal, 4, Some(500)),
+      record(TransactionType::Dispute, 2, None),
+      record(TransactionType::Chargeback, 2, None),
+      record(TransactionType::Deposit, 5, Some(1)),
+    ]);
+
+    assert_eq!(summary.processed[&TransactionType::Deposit], 2);
+    assert_eq!(summary.rejected[&TransactionType::Withdrawal], 1);
+    assert_eq!(summary.rejected[&TransactionType::Deposit], 1);
+    assert_eq!(summary.errors, BTreeMap::from([("AccountLocked", 1), ("InsufficientFunds", 1)]));
+    let totals = &summary.totals["USD"];
+    assert_eq!(totals.deposited, Decimal::new(150, 0));
+    assert_eq!(totals.withdrawn, Decimal::new(30, 0));
+    assert_eq!((totals.held, totals.charged_back), (Decimal::new(50, 0), Decimal::new(50, 0)));
+    assert_eq!(summary.locked_accounts, 1);
+    assert_eq!((summary.stored_transactions, summary.peak_stored_transactions), (3, 3));
+    assert_eq!(summary.rows(), 7);
+  }
+
+  #[test]
+  fn test_json() {
+    let summary = summarize(vec![record(TransactionType::Deposit, 1, Some(10))]);
+    let mut out = Vec::new();
+    summary.write(&mut out, SummaryFormat::Json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json["processed"]["deposit"], 1);
+    assert_eq!(json["totals"]["USD"]["deposited"], "10");
+    assert_eq!(json["locked_accounts"], 0);
+    assert!(json["throughput"].is_number());
+  }
+
+  #[test]
+  fn test_text() {
+    let summary = summarize(vec![
+      record(TransactionType::Deposit, 1, Some(10)),
+      record(TransactionType::Withdrawal, 2, Some(20)),
+    ]);
+    let mut out = Vec::new();
+    summary.write(&mut out, SummaryFormat::Text).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.contains("rows: 2 (1 processed, 1 rejected, 0 skipped, 0 unparsable)"));
+    assert!(text.contains("  deposit          1 / 0\n  withdrawal       0 / 1\n"));
+    assert!(text.contains("  InsufficientFunds            1\n"));
+    assert!(text.contains("Totals USD\n  deposited     10\n"));
+  }
+
+  #[test]
+  fn test_format_from_str() {
+    assert_eq!("json".parse(), Ok(SummaryFormat::Json));
+    assert!("yaml".parse::<SummaryFormat>().is_err());
+  }
+}
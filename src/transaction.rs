@@ -1,8 +1,12 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 ///  The transactions described in the spec.  HUMAN GENERATED CODE
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
   Deposit,
@@ -10,6 +14,48 @@ pub enum TransactionType {
   Dispute,
   Resolve,
   Chargeback,
+  /// The merchant won the dispute back, reverses a chargeback
+  #[serde(alias = "chargeback_reversal")]
+  Representment,
+  /// Moves value between two currencies of the same client
+  Convert,
+  /// Control record, credits the interest earned by the client's available balance
+  #[serde(rename = "accrue_interest")]
+  AccrueInterest,
+}
+
+impl fmt::Display for TransactionType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      TransactionType::Deposit => "deposit",
+      TransactionType::Withdrawal => "withdrawal",
+      TransactionType::Dispute => "dispute",
+      TransactionType::Resolve => "resolve",
+      TransactionType::Chargeback => "chargeback",
+      TransactionType::Representment => "representment",
+      TransactionType::Convert => "convert",
+      TransactionType::AccrueInterest => "accrue_interest",
+    };
+    f.write_str(name)
+  }
+}
+
+impl FromStr for TransactionType {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "deposit" => Ok(TransactionType::Deposit),
+      "withdrawal" => Ok(TransactionType::Withdrawal),
+      "dispute" => Ok(TransactionType::Dispute),
+      "resolve" => Ok(TransactionType::Resolve),
+      "chargeback" => Ok(TransactionType::Chargeback),
+      "representment" | "chargeback_reversal" => Ok(TransactionType::Representment),
+      "convert" => Ok(TransactionType::Convert),
+      "accrue_interest" => Ok(TransactionType::AccrueInterest),
+      _ => Err(format!("unknown transaction type '{}'", s)),
+    }
+  }
 }
 
 ///  The CSV input deserialized for serde.
@@ -21,6 +67,19 @@ pub struct TransactionRecord {
   pub tx: u32,
   #[serde(default, deserialize_with = "deserialize_optional_decimal")]
   pub amount: Option<Decimal>,
+  ///  Optional column, RFC 3339 or unix epoch seconds
+  #[serde(default, deserialize_with = "deserialize_optional_timestamp")]
+  pub timestamp: Option<DateTime<Utc>>,
+  ///  Optional column, consecutive records with the same batch id are applied all or nothing
+  #[serde(default)]
+  pub batch: Option<u32>,
+  ///  Optional column, currency code of a deposit or withdrawal. Disputes use the
+  ///  currency of the transaction they refer to
+  #[serde(default, deserialize_with = "deserialize_optional_currency")]
+  pub currency: Option<String>,
+  ///  Optional column, the currency a convert record buys
+  #[serde(default, deserialize_with = "deserialize_optional_currency")]
+  pub to_currency: Option<String>,
 }
 
 ///  THis is needed to address empty strings in the csv
@@ -30,8 +89,8 @@ where
 {
   use serde::de::Error;
 
-  let s: Option<String> = Option::deserialize(deserializer)?;
-  match s {
+  let s: Option<DecimalText> = Option::deserialize(deserializer)?;
+  match s.map(|text| text.0) {
     None => Ok(None),
     Some(s) if s.trim().is_empty() => Ok(None),
     Some(s) => s
@@ -42,18 +101,225 @@ where
   }
 }
 
+///  The text of a decimal. A JSON number is refused, it would have gone through a float
+struct DecimalText(String);
+
+impl<'de> Deserialize<'de> for DecimalText {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    struct Text;
+
+    impl serde::de::Visitor<'_> for Text {
+      type Value = DecimalText;
+
+      fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a decimal as a string, like \"10.5\"")
+      }
+
+      fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<DecimalText, E> {
+        Ok(DecimalText(s.to_string()))
+      }
+    }
+
+    deserializer.deserialize_str(Text)
+  }
+}
+
+///  Same idea as the decimal, an empty column means no timestamp.
+///  All digits is taken as epoch seconds, anything else has to be RFC 3339
+fn deserialize_optional_timestamp<'de, D>(
+  deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  use serde::de::Error;
+
+  let s: Option<DecimalText> = Option::deserialize(deserializer)?;
+  match s.map(|text| text.0) {
+    None => Ok(None),
+    Some(s) if s.trim().is_empty() => Ok(None),
+    Some(s) => parse_timestamp(s.trim()).map(Some).map_err(D::Error::custom),
+  }
+}
+
+///  Currency codes are case insensitive and kept upper case
+fn deserialize_optional_currency<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  use serde::de::Error;
+
+  let s: Option<DecimalText> = Option::deserialize(deserializer)?;
+  match s.map(|text| text.0) {
+    None => Ok(None),
+    Some(s) if s.trim().is_empty() => Ok(None),
+    Some(s) => parse_currency(s.trim()).map(Some).map_err(D::Error::custom),
+  }
+}
+
+pub fn parse_currency(s: &str) -> Result<String, String> {
+  if s.len() > 12 || !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+    return Err(format!("invalid currency '{}'", s));
+  }
+  Ok(s.to_ascii_uppercase())
+}
+
+pub fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+  if let Ok(secs) = s.parse::<i64>() {
+    return DateTime::from_timestamp(secs, 0)
+      .ok_or_else(|| format!("invalid timestamp: {} is out of range", secs));
+  }
+  DateTime::parse_from_rfc3339(s)
+    .map(|ts| ts.with_timezone(&Utc))
+    .map_err(|e| format!("invalid timestamp: {}", e))
+}
+
 /// the  stored transaction (deposit/withdrawal) that may be referenced by disputes
 #[derive(Debug, Clone)]
 pub struct StoredTransaction {
   pub tx_type: TransactionType,
   pub client: u16,
+  pub currency: String,
+  pub amount: Decimal,
+  pub state: DisputeState,
+  pub timestamp: Option<DateTime<Utc>>,
+  /// Every state change caused by a dispute, resolve or chargeback, oldest first
+  pub history: Vec<Transition>,
+  /// What a convert record bought, `amount` and `currency` are what it sold
+  pub conversion: Option<Conversion>,
+  /// Fees charged in `currency` by the records that touched this transaction
+  pub fees: Vec<Fee>,
+}
+
+/// A fee posting, debited from available next to the record that caused it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fee {
+  /// The deposit, withdrawal or chargeback the fee was charged on
+  pub tx_type: TransactionType,
   pub amount: Decimal,
-  pub disputed: bool,
+}
+
+/// The journal entry of one conversion
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversion {
+  pub to_currency: String,
+  /// The rate from the table at the time of the conversion
+  pub rate: Decimal,
+  /// `amount * rate` after rounding, credited in `to_currency`
+  pub converted: Decimal,
+}
+
+/// Where a stored transaction is in the dispute process
+///
+/// ```text
+/// normal --dispute--> disputed --resolve--> resolved --dispute--> disputed ...
+///                        |
+///                        +--chargeback--> charged_back --representment--> represented
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeState {
+  Normal,
+  Disputed,
+  Resolved,
+  ChargedBack,
+  Represented,
+}
+
+impl DisputeState {
+  /// The state `action` moves to, None when it is not allowed from here
+  pub fn next(self, action: TransactionType) -> Option<Self> {
+    use DisputeState::*;
+    match (self, action) {
+      (Normal | Resolved, TransactionType::Dispute) => Some(Disputed),
+      (Disputed, TransactionType::Resolve) => Some(Resolved),
+      (Disputed, TransactionType::Chargeback) => Some(ChargedBack),
+      (ChargedBack, TransactionType::Representment) => Some(Represented),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for DisputeState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      DisputeState::Normal => "normal",
+      DisputeState::Disputed => "disputed",
+      DisputeState::Resolved => "resolved",
+      DisputeState::ChargedBack => "charged_back",
+      DisputeState::Represented => "represented",
+    };
+    f.write_str(name)
+  }
+}
+
+impl FromStr for DisputeState {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "normal" => Ok(DisputeState::Normal),
+      "disputed" => Ok(DisputeState::Disputed),
+      "resolved" => Ok(DisputeState::Resolved),
+      "charged_back" => Ok(DisputeState::ChargedBack),
+      "represented" => Ok(DisputeState::Represented),
+      _ => Err(format!("unknown dispute state '{}'", s)),
+    }
+  }
+}
+
+/// One state change of a stored transaction and the record that caused it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+  pub action: TransactionType,
+  pub from: DisputeState,
+  pub to: DisputeState,
+  pub timestamp: Option<DateTime<Utc>>,
 }
 
 impl StoredTransaction {
-  pub fn new(tx_type: TransactionType, client: u16, amount: Decimal) -> Self {
-    Self { tx_type, client, amount, disputed: false }
+  pub fn new(
+    tx_type: TransactionType,
+    client: u16,
+    currency: &str,
+    amount: Decimal,
+    timestamp: Option<DateTime<Utc>>,
+  ) -> Self {
+    Self {
+      tx_type,
+      client,
+      currency: currency.to_string(),
+      amount,
+      state: DisputeState::Normal,
+      timestamp,
+      history: Vec::new(),
+      conversion: None,
+      fees: Vec::new(),
+    }
+  }
+
+  /// The fee charged by the `tx_type` record, a transaction sees each at most once
+  pub fn fee(&self, tx_type: TransactionType) -> Option<Decimal> {
+    self.fees.iter().find(|fee| fee.tx_type == tx_type).map(|fee| fee.amount)
+  }
+
+  /// What a dispute holds and a chargeback takes back: the amount less the deposit fee,
+  /// which the client never had available
+  pub fn disputed_amount(&self) -> Decimal {
+    self.amount - self.fee(TransactionType::Deposit).unwrap_or_default()
+  }
+
+  /// Moves to `to` and keeps the transition in the history
+  pub fn transition(
+    &mut self,
+    action: TransactionType,
+    to: DisputeState,
+    timestamp: Option<DateTime<Utc>>,
+  ) {
+    self.history.push(Transition { action, from: self.state, to, timestamp });
+    self.state = to;
   }
 }
 
@@ -84,4 +350,144 @@ mod tests {
     assert_eq!(record.tx_type, TransactionType::Dispute);
     assert_eq!(record.amount, None);
   }
+
+  #[test]
+  fn test_deserialize_representment_aliases() {
+    let data = "type,client,tx,amount\nrepresentment,1,1,\nchargeback_reversal,1,2,";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let records: Vec<TransactionRecord> = reader.deserialize().map(|r| r.unwrap()).collect();
+    assert_eq!(records[0].tx_type, TransactionType::Representment);
+    assert_eq!(records[1].tx_type, TransactionType::Representment);
+    assert_eq!("chargeback_reversal".parse(), Ok(TransactionType::Representment));
+  }
+
+  #[test]
+  fn test_deserialize_without_timestamp_column() {
+    let data = "type,client,tx,amount\ndeposit,1,1,1.0";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let record: TransactionRecord = reader.deserialize().next().unwrap().unwrap();
+    assert_eq!(record.timestamp, None);
+  }
+
+  #[test]
+  fn test_deserialize_timestamp_rfc3339_and_epoch() {
+    let data = "type,client,tx,amount,timestamp\n\
+                deposit,1,1,1.0,2024-01-02T03:04:05Z\n\
+                deposit,1,2,1.0,1704164645\n\
+                dispute,1,1,,";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let records: Vec<TransactionRecord> = reader.deserialize().map(|r| r.unwrap()).collect();
+    let expected = DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z").unwrap().to_utc();
+    assert_eq!(records[0].timestamp, Some(expected));
+    assert_eq!(records[1].timestamp, Some(expected));
+    assert_eq!(records[2].timestamp, None);
+  }
+
+  #[test]
+  fn test_deserialize_batch_column() {
+    let data = "type,client,tx,amount,timestamp,batch\ndeposit,1,1,1.0,,7\ndeposit,1,2,1.0,,";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let records: Vec<TransactionRecord> = reader.deserialize().map(|r| r.unwrap()).collect();
+    assert_eq!(records[0].batch, Some(7));
+    assert_eq!(records[1].batch, None);
+  }
+
+  #[test]
+  fn test_deserialize_currency_column() {
+    let data =
+      "type,client,tx,amount,currency\ndeposit,1,1,1.0,eur\ndeposit,1,2,1.0,\ndispute,1,1,,";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let records: Vec<TransactionRecord> = reader.deserialize().map(|r| r.unwrap()).collect();
+    assert_eq!(records[0].currency.as_deref(), Some("EUR"));
+    assert_eq!(records[1].currency, None);
+    assert_eq!(records[2].currency, None);
+  }
+
+  #[test]
+  fn test_deserialize_convert() {
+    let data = "type,client,tx,amount,currency,to_currency\nconvert,1,1,10.0,EUR,usd";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let record: TransactionRecord = reader.deserialize().next().unwrap().unwrap();
+    assert_eq!(record.tx_type, TransactionType::Convert);
+    assert_eq!(record.currency.as_deref(), Some("EUR"));
+    assert_eq!(record.to_currency.as_deref(), Some("USD"));
+  }
+
+  #[test]
+  fn test_deserialize_accrue_interest() {
+    let data = "type,client,tx,amount,timestamp\naccrue_interest,1,9,,2024-02-01T00:00:00Z";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let record: TransactionRecord = reader.deserialize().next().unwrap().unwrap();
+    assert_eq!(record.tx_type, TransactionType::AccrueInterest);
+    assert_eq!(record.amount, None);
+    assert_eq!("accrue_interest".parse(), Ok(TransactionType::AccrueInterest));
+  }
+
+  #[test]
+  fn test_deserialize_invalid_currency() {
+    let data = "type,client,tx,amount,currency\ndeposit,1,1,1.0,US$";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let result: Result<TransactionRecord, _> = reader.deserialize().next().unwrap();
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_deserialize_invalid_timestamp() {
+    let data = "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,yesterday";
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+
+    let result: Result<TransactionRecord, _> = reader.deserialize().next().unwrap();
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_dispute_state_transitions() {
+    use DisputeState::*;
+    use TransactionType::*;
+
+    assert_eq!(Normal.next(Dispute), Some(Disputed));
+    assert_eq!(Disputed.next(Resolve), Some(Resolved));
+    assert_eq!(Disputed.next(Chargeback), Some(ChargedBack));
+    assert_eq!(Resolved.next(Dispute), Some(Disputed));
+
+    assert_eq!(Normal.next(Resolve), None);
+    assert_eq!(Normal.next(Chargeback), None);
+    assert_eq!(Disputed.next(Dispute), None);
+    assert_eq!(Resolved.next(Chargeback), None);
+    assert_eq!(ChargedBack.next(Dispute), None);
+    assert_eq!(ChargedBack.next(Resolve), None);
+    assert_eq!(ChargedBack.next(Representment), Some(Represented));
+    assert_eq!(Disputed.next(Representment), None);
+    assert_eq!(Represented.next(Dispute), None);
+    assert_eq!(Represented.next(Representment), None);
+  }
+
+  #[test]
+  fn test_dispute_state_names_round_trip() {
+    use DisputeState::*;
+
+    for state in [Normal, Disputed, Resolved, ChargedBack, Represented] {
+      assert_eq!(state.to_string().parse::<DisputeState>(), Ok(state));
+    }
+  }
+
+  #[test]
+  fn test_transition_recorded() {
+    let mut stored = StoredTransaction::new(TransactionType::Deposit, 1, "USD", Decimal::ONE, None);
+    stored.transition(TransactionType::Dispute, DisputeState::Disputed, None);
+    stored.transition(TransactionType::Resolve, DisputeState::Resolved, None);
+
+    assert_eq!(stored.state, DisputeState::Resolved);
+    assert_eq!(stored.history.len(), 2);
+    assert_eq!(stored.history[1].from, DisputeState::Disputed);
+    assert_eq!(stored.history[1].to, DisputeState::Resolved);
+  }
 }
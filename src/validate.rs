@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+use tracing::warn;
+
+use crate::batch::{Batcher, Unit};
+use crate::engine::{Engine, Outcome};
+use crate::transaction::{TransactionRecord, TransactionType};
+
+const UNKNOWN_TYPE: &str = "unknown_type";
+const MALFORMED_DECIMAL: &str = "malformed_decimal";
+const MALFORMED_ROW: &str = "malformed_row";
+
+/// What a dry run found. The rows go through a scratch engine like in a real run, only
+/// the counts come out of it
+#[derive(Debug, Default, PartialEq)]
+pub struct Validation {
+  pub rows: u64,
+  pub applied: u64,
+  /// Replays skipped in idempotent mode
+  pub skipped: u64,
+  /// Rows that were not applied: unparsable, rejected or in a rolled back batch
+  pub failed: u64,
+  /// Unparsable rows by reason: unknown_type, malformed_decimal or malformed_row
+  pub parse_errors: BTreeMap<&'static str, u64>,
+  /// Rejections by `EngineError::code`, a rolled back batch counts the error that failed it
+  pub engine_errors: BTreeMap<&'static str, u64>,
+}
+
+/// Runs every row of `reader` through `engine`, the reason for each failure goes to `errors`
+pub fn validate<R: Read>(
+  engine: &mut Engine,
+  reader: &mut csv::Reader<R>,
+  errors: &mut dyn Write,
+) -> Result<Validation> {
+  let headers = reader.byte_headers()?.clone();
+  let mut validation = Validation::default();
+  let mut batcher = Batcher::new();
+
+  for row in reader.byte_records() {
+    validation.rows += 1;
+    let parsed = row.map_err(|e| (e, MALFORMED_ROW)).and_then(|row| {
+      row
+        .deserialize::<TransactionRecord>(Some(&headers))
+        .map_err(|e| (e, classify(&headers, &row)))
+    });
+    match parsed {
+      Ok(record) => {
+        for unit in batcher.push(record) {
+          validation.process(engine, unit, errors);
+        }
+      }
+      Err((e, reason)) => {
+        warn!(error = %e, "Failed to parse record");
+        let _ = writeln!(errors, "Failed to parse record: {}", e);
+        *validation.parse_errors.entry(reason).or_default() += 1;
+        validation.failed += 1;
+      }
+    }
+  }
+  if let Some(unit) = batcher.finish() {
+    validation.process(engine, unit, errors);
+  }
+  Ok(validation)
+}
+
+/// Why a row did not parse, checked on the columns that usually go wrong
+fn classify(headers: &ByteRecord, row: &ByteRecord) -> &'static str {
+  let field = |name: &str| {
+    let index = headers.iter().position(|header| header == name.as_bytes())?;
+    row.get(index).map(String::from_utf8_lossy)
+  };
+  if field("type").is_some_and(|tx_type| TransactionType::from_str(&tx_type).is_err()) {
+    return UNKNOWN_TYPE;
+  }
+  if field("amount").is_some_and(|amount| !amount.is_empty() && amount.parse::<Decimal>().is_err())
+  {
+    return MALFORMED_DECIMAL;
+  }
+  MALFORMED_ROW
+}
+
+impl Validation {
+  fn process(&mut self, engine: &mut Engine, unit: Unit, errors: &mut dyn Write) {
+    let (size, result) = match unit {
+      Unit::Single(record) => (1, engine.process(record).map(|outcome| vec![outcome])),
+      Unit::Batch(id, records) => (records.len() as u64, engine.process_batch(id, records)),
+    };
+    // Nothing reads the alerts of a dry run
+    engine.take_alerts();
+
+    match result {
+      Ok(outcomes) => {
+        for outcome in outcomes {
+          match outcome {
+            Outcome::Applied => self.applied += 1,
+            Outcome::Skipped => self.skipped += 1,
+          }
+        }
+      }
+      Err(error) => {
+        warn!(error = %error, "Transaction processing failed");
+        let _ = writeln!(errors, "{}", error);
+        *self.engine_errors.entry(error.cause().code()).or_default() += 1;
+        self.failed += size;
+      }
+    }
+  }
+
+  /// Failed rows as a percentage of all rows
+  pub fn error_rate(&self) -> Decimal {
+    if self.rows == 0 {
+      return Decimal::ZERO;
+    }
+    Decimal::from(self.failed) * Decimal::ONE_HUNDRED / Decimal::from(self.rows)
+  }
+
+  /// Fails when more than `max_error_rate` percent of the rows failed
+  pub fn check(&self, max_error_rate: Decimal) -> Result<()> {
+    let rate = self.error_rate();
+    if rate > max_error_rate {
+      bail!("error rate of {:.2}% is above the allowed {}%", rate, max_error_rate);
+    }
+    Ok(())
+  }
+
+  /// The report as `category,name,value` lines: the totals, then the parse and engine
+  /// errors by kind
+  pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "category,name,value")?;
+    let totals = [
+      ("rows", self.rows),
+      ("applied", self.applied),
+      ("skipped", self.skipped),
+      ("failed", self.failed),
+    ];
+    for (name, value) in totals {
+      writeln!(out, "total,{},{}", name, value)?;
+    }
+    writeln!(out, "total,error_percent,{:.2}", self.error_rate())?;
+    for (name, count) in &self.parse_errors {
+      writeln!(out, "parse,{},{}", name, count)?;
+    }
+    for (name, count) in &self.engine_errors {
+      writeln!(out, "engine,{},{}", name, count)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run(csv: &str) -> Validation {
+    let mut reader =
+      csv::ReaderBuilder::new().trim(csv::Trim::All).flexible(true).from_reader(csv.as_bytes());
+    validate(&mut Engine::new(), &mut reader, &mut io::sink()).unwrap()
+  }
+
+  #[test]
+  fn test_counts_by_kind() {
+    let validation = run(
+      "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,1,5.0
+withdrawal,1,2,50.0
+transfer,1,3,1.0
+deposit,1,4,1.0.0
+deposit,x,5,1.0
+dispute,1,1,
+",
+    );
+    assert_eq!((validation.rows, validation.applied, validation.failed), (7, 2, 5));
+    assert_eq!(
+      validation.parse_errors,
+      BTreeMap::from([(MALFORMED_DECIMAL, 1), (MALFORMED_ROW, 1), (UNKNOWN_TYPE, 1)])
+    );
+    assert_eq!(
+      validation.engine_errors,
+      BTreeMap::from([("DuplicateTransaction", 1), ("InsufficientFunds", 1)])
+    );
+  }
+
+  #[test]
+  fn test_rolled_back_batch_fails_every_member() {
+    let validation = run(
+      "\
+type,client,tx,amount,batch
+deposit,1,1,10.0,7
+withdrawal,1,2,50.0,7
+deposit,1,3,1.0,
+",
+    );
+    assert_eq!((validation.applied, validation.failed), (1, 2));
+    assert_eq!(validation.engine_errors, BTreeMap::from([("InsufficientFunds", 1)]));
+  }
+
+  #[test]
+  fn test_error_rate_threshold() {
+    let validation = Validation { rows: 8, failed: 2, ..Default::default() };
+    assert_eq!(validation.error_rate(), Decimal::new(25, 0));
+    assert!(validation.check(Decimal::new(25, 0)).is_ok());
+    assert!(validation.check(Decimal::new(10, 0)).is_err());
+    assert_eq!(Validation::default().error_rate(), Decimal::ZERO);
+  }
+
+  #[test]
+  fn test_report() {
+    let validation = run("type,client,tx,amount\ndeposit,1,1,10.0\nrefund,1,2,1.0\n");
+    let mut out = Vec::new();
+    validation.write(&mut out).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "category,name,value\n\
+       total,rows,2\n\
+       total,applied,1\n\
+       total,skipped,0\n\
+       total,failed,1\n\
+       total,error_percent,50.00\n\
+       parse,unknown_type,1\n"
+    );
+  }
+}
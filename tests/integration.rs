@@ -5,13 +5,65 @@
 //! PROMPT: Generate integration tests for the code in this project
 
 use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 use predicates::prelude::*;
 use std::fs;
 use tempfile::TempDir;
 
-/// Get a command for the toypayments binary
+/// Get a command for the toypayments binary.
+/// This file is also compiled as the `integration_sqlite` test target, which runs
+/// every test again on the SQLite backend
 fn toypayments() -> Command {
-  Command::cargo_bin("toypayments").unwrap()
+  let mut cmd = cargo_bin_cmd!("toypayments");
+  if env!("CARGO_CRATE_NAME") == "integration_sqlite" {
+    cmd.arg("--storage").arg("sqlite");
+  }
+  cmd
+}
+
+/// The binary as a plain process, for tests that talk to it while it runs
+fn toypayments_process() -> std::process::Command {
+  let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_toypayments"));
+  if env!("CARGO_CRATE_NAME") == "integration_sqlite" {
+    cmd.arg("--storage").arg("sqlite");
+  }
+  cmd
+}
+
+/// A local address nothing listens on right now
+fn free_addr() -> String {
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  listener.local_addr().unwrap().to_string()
+}
+
+/// Plain HTTP/1.0 request, returns the status line and the body
+fn http(addr: &str, method: &str, path: &str, body: &str) -> std::io::Result<(String, String)> {
+  use std::io::{Read, Write};
+  let mut stream = std::net::TcpStream::connect(addr)?;
+  write!(
+    stream,
+    "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+    method,
+    path,
+    addr,
+    body.len(),
+    body
+  )?;
+  let mut response = String::new();
+  stream.read_to_string(&mut response)?;
+  let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+  Ok((head.lines().next().unwrap_or_default().to_string(), body.to_string()))
+}
+
+/// Retries `check` for up to five seconds, for state a running process reaches on its own
+fn eventually<T>(mut check: impl FnMut() -> Option<T>) -> T {
+  for _ in 0..100 {
+    if let Some(value) = check() {
+      return value;
+    }
+    std::thread::sleep(std::time::Duration::from_millis(50));
+  }
+  panic!("condition not reached in time");
 }
 
 /// Create a temp directory with a CSV file
@@ -1177,3 +1229,1436 @@ dispute,1,1,
     // Can't hold 100 when only 70 available, dispute fails
     .stdout(predicate::str::contains("1,70.0000,0.0000,70.0000,false"));
 }
+
+// =============================================================================
+// TIMESTAMP TESTS
+// =============================================================================
+
+#[test]
+fn test_timestamp_column_optional_per_row() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,100.0,2024-01-01T10:00:00Z
+deposit,1,2,50.0,
+withdrawal,1,3,25.0,1704106800
+";
+  let (_dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,125.0000,0.0000,125.0000,false"));
+}
+
+#[test]
+fn test_timestamp_out_of_order_rejected_and_reported() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,100.0,2024-01-02T00:00:00Z
+deposit,2,2,10.0,2024-01-01T00:00:00Z
+deposit,1,3,50.0,2024-01-01T00:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,100.0000,0.0000,100.0000,false"))
+    .stdout(predicate::str::contains("2,10.0000,0.0000,10.0000,false"));
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert!(errors.contains("tx 3 (client 1): timestamp"));
+  assert!(errors.contains("(at 2024-01-01T00:00:00+00:00)"));
+}
+
+#[test]
+fn test_dispute_window_from_config() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,100.0,2024-01-01T00:00:00Z
+deposit,1,2,100.0,2024-03-01T00:00:00Z
+dispute,1,1,,2024-03-05T00:00:00Z
+dispute,1,2,,2024-03-05T00:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "dispute_window_days = 30\n").unwrap();
+
+  toypayments()
+    .arg("--config")
+    .arg(&config)
+    .arg(&path)
+    .assert()
+    .success()
+    // Only the recent deposit can still be disputed
+    .stdout(predicate::str::contains("1,100.0000,100.0000,200.0000,false"));
+}
+
+#[test]
+fn test_statements_carry_timestamps() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,100.0,2024-01-01T00:00:00Z
+withdrawal,1,2,500.0,2024-01-02T00:00:00Z
+dispute,1,1,,2024-01-03T00:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  let statements = dir.path().join("statements.csv");
+
+  toypayments().arg("--statements").arg(&statements).arg(&path).assert().success();
+
+  let content = fs::read_to_string(&statements).unwrap();
+  let lines: Vec<&str> = content.lines().collect();
+  assert_eq!(lines.len(), 3);
+  assert_eq!(lines[0], "client,tx,type,amount,currency,timestamp,available,held,total");
+  assert_eq!(
+    lines[1],
+    "1,1,deposit,100.0000,USD,2024-01-01T00:00:00+00:00,100.0000,0.0000,100.0000"
+  );
+  assert_eq!(lines[2], "1,1,dispute,,USD,2024-01-03T00:00:00+00:00,0.0000,100.0000,100.0000");
+}
+
+#[test]
+fn test_missing_config_file_error() {
+  let (_dir, path) = create_test_csv("type,client,tx,amount\n");
+
+  toypayments()
+    .arg("--config")
+    .arg("nonexistent.toml")
+    .arg(&path)
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("Failed to read config"));
+}
+
+// =============================================================================
+// STORAGE BACKEND TESTS
+// =============================================================================
+
+#[test]
+fn test_sqlite_file_state_survives_runs() {
+  let dir = TempDir::new().unwrap();
+  let db = dir.path().join("state.db");
+  let storage = format!("sqlite:{}", db.display());
+
+  let first = dir.path().join("first.csv");
+  fs::write(&first, "type,client,tx,amount\ndeposit,1,1,100.0\n").unwrap();
+  let second = dir.path().join("second.csv");
+  fs::write(&second, "type,client,tx,amount\ndeposit,1,1,100.0\ndispute,1,1,\n").unwrap();
+
+  cargo_bin_cmd!("toypayments").arg("--storage").arg(&storage).arg(&first).assert().success();
+
+  // The replayed deposit is a duplicate, the dispute finds the deposit from the first run
+  cargo_bin_cmd!("toypayments")
+    .arg("--storage")
+    .arg(&storage)
+    .arg(&second)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,0.0000,100.0000,100.0000,false"));
+}
+
+#[test]
+fn test_unknown_storage_backend() {
+  let (_dir, path) = create_test_csv("type,client,tx,amount\n");
+
+  cargo_bin_cmd!("toypayments")
+    .arg("--storage")
+    .arg("redis")
+    .arg(&path)
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("unknown storage backend"));
+}
+
+// =============================================================================
+// BATCH TESTS
+// =============================================================================
+
+#[test]
+fn test_batch_all_or_nothing() {
+  let csv = "\
+type,client,tx,amount,timestamp,batch
+deposit,1,1,100.0,,
+deposit,2,2,100.0,,
+withdrawal,1,3,50.0,,7
+withdrawal,2,4,50.0,,7
+withdrawal,1,5,40.0,,8
+withdrawal,2,6,80.0,,8
+deposit,3,7,5.0,,
+";
+  let (dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg(&path)
+    .assert()
+    .success()
+    // Batch 7 applied, batch 8 rolled back because client 2 cannot cover 80
+    .stdout(predicate::str::contains("1,50.0000,0.0000,50.0000,false"))
+    .stdout(predicate::str::contains("2,50.0000,0.0000,50.0000,false"))
+    .stdout(predicate::str::contains("3,5.0000,0.0000,5.0000,false"));
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert!(errors.contains("batch 8 rolled back, tx 6 failed"));
+}
+
+#[test]
+fn test_batch_rollback_leaves_no_new_accounts() {
+  let csv = "\
+type,client,tx,amount,timestamp,batch
+deposit,1,1,10.0,,1
+withdrawal,1,2,20.0,,1
+";
+  let (_dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::is_match("^client,available,held,total,locked\n$").unwrap());
+}
+
+// =============================================================================
+// IDEMPOTENCY TESTS
+// =============================================================================
+
+#[test]
+fn test_idempotent_rerun_on_restored_state() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,2,50.0
+dispute,1,1,
+resolve,1,1,
+dispute,1,2,
+";
+  let (dir, path) = create_test_csv(csv);
+  let storage = format!("sqlite:{}", dir.path().join("state.db").display());
+  let expected = "1,100.0000,50.0000,150.0000,false";
+
+  let run = || {
+    cargo_bin_cmd!("toypayments")
+      .current_dir(dir.path())
+      .args(["--idempotent", "--storage", &storage])
+      .arg(&path)
+      .assert()
+      .success()
+      .stdout(predicate::str::contains(expected));
+  };
+  run();
+  run();
+
+  // Every record of the second run was an exact copy, nothing was rejected
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(errors, "");
+}
+
+#[test]
+fn test_idempotent_conflicting_duplicate_reported() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,1,100.0
+deposit,1,1,75.0
+";
+  let (dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--idempotent")
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,100.0000,0.0000,100.0000,false"));
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(errors.lines().collect::<Vec<_>>(), vec!["tx 1: duplicate transaction ID"]);
+}
+
+// =============================================================================
+// REPRESENTMENT TESTS
+// =============================================================================
+
+#[test]
+fn test_representment_restores_charged_back_funds() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,2,20.0
+dispute,1,1,
+chargeback,1,1,
+chargeback_reversal,1,1,
+representment,1,2,
+";
+  let (dir, path) = create_test_csv(csv);
+
+  // Stays locked under the default policy
+  toypayments()
+    .current_dir(dir.path())
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,120.0000,0.0000,120.0000,true"));
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(
+    errors.lines().collect::<Vec<_>>(),
+    vec!["tx 2: representment is not allowed once the transaction is normal"]
+  );
+}
+
+#[test]
+fn test_representment_unlock_policy() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,100.0
+dispute,1,1,
+chargeback,1,1,
+representment,1,1,
+deposit,1,2,5.0
+";
+  let (dir, path) = create_test_csv(csv);
+  let config = dir.path().join("config.toml");
+  fs::write(&config, "representment_unlock = \"always\"\n").unwrap();
+
+  toypayments()
+    .arg("--config")
+    .arg(&config)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,105.0000,0.0000,105.0000,false"));
+}
+
+// =============================================================================
+// CURRENCY TESTS
+// =============================================================================
+
+#[test]
+fn test_row_per_client_per_currency() {
+  let csv = "\
+type,client,tx,amount,currency
+deposit,1,1,100.0,USD
+deposit,1,2,50.0,eur
+withdrawal,1,3,20.0,EUR
+deposit,2,4,10.0,
+dispute,1,1,,
+";
+  let (_dir, path) = create_test_csv(csv);
+
+  toypayments().arg(&path).assert().success().stdout(
+    "client,currency,available,held,total,locked\n\
+     1,EUR,30.0000,0.0000,30.0000,false\n\
+     1,USD,0.0000,100.0000,100.0000,false\n\
+     2,USD,10.0000,0.0000,10.0000,false\n",
+  );
+}
+
+#[test]
+fn test_dispute_uses_deposit_currency() {
+  let csv = "\
+type,client,tx,amount,currency
+deposit,1,1,100.0,USD
+deposit,1,2,50.0,EUR
+dispute,1,2,,
+chargeback,1,2,,
+dispute,1,1,,GBP
+";
+  let (dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,EUR,0.0000,0.0000,0.0000,true"))
+    .stdout(predicate::str::contains("1,USD,100.0000,0.0000,100.0000,true"));
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(
+    errors.lines().collect::<Vec<_>>(),
+    vec!["tx 1: currency mismatch (expected USD, got GBP)"]
+  );
+}
+
+// =============================================================================
+// CONVERSION TESTS
+// =============================================================================
+
+#[test]
+fn test_convert_with_rate_file_and_journal() {
+  let csv = "\
+type,client,tx,amount,currency,to_currency
+deposit,1,1,100.0,EUR,
+convert,1,2,40.0,EUR,USD
+convert,1,3,1.0,USD,EUR
+";
+  let (dir, path) = create_test_csv(csv);
+  fs::write(dir.path().join("rates.csv"), "from,to,rate\nEUR,USD,1.0825\n").unwrap();
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "[conversion]\nrates = \"rates.csv\"\n[precision.currencies]\nUSD = 2\n")
+    .unwrap();
+  let journal = dir.path().join("journal.csv");
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg(&config)
+    .arg("--journal")
+    .arg(&journal)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "client,currency,available,held,total,locked\n\
+       1,EUR,60.0000,0.0000,60.0000,false\n\
+       1,USD,43.30,0.00,43.30,false\n",
+    );
+
+  let journal = fs::read_to_string(&journal).unwrap();
+  assert_eq!(
+    journal.lines().collect::<Vec<_>>(),
+    vec!["tx,client,from,to,amount,rate,converted,timestamp", "2,1,EUR,USD,40.0,1.0825,43.30,"]
+  );
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(errors.lines().collect::<Vec<_>>(), vec!["tx 3: no rate from USD to EUR"]);
+}
+
+#[test]
+fn test_missing_rate_file_error() {
+  let (dir, path) = create_test_csv("type,client,tx,amount\n");
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "[conversion]\nrates = \"missing.toml\"\n").unwrap();
+
+  toypayments()
+    .arg("--config")
+    .arg(&config)
+    .arg(&path)
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("Failed to load rates"));
+}
+
+// =============================================================================
+// PRECISION TESTS
+// =============================================================================
+
+#[test]
+fn test_excess_precision_rejected() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,1.23456
+deposit,1,2,2.5
+";
+  let (dir, path) = create_test_csv(csv);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,2.5000,0.0000,2.5000,false"));
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(
+    errors.lines().collect::<Vec<_>>(),
+    vec!["tx 1: 1.23456 has more than 4 decimal places allowed for USD"]
+  );
+}
+
+#[test]
+fn test_excess_precision_rounded_per_currency() {
+  let csv = "\
+type,client,tx,amount,currency
+deposit,1,1,0.00005,
+deposit,1,2,0.00005,
+deposit,1,3,0.00015,
+deposit,1,4,1234.5,JPY
+";
+  let (dir, path) = create_test_csv(csv);
+  let config = dir.path().join("engine.toml");
+  fs::write(
+    &config,
+    "[precision]\nexcess = \"round\"\nrounding = \"half_even\"\n[precision.currencies]\nJPY = 0\n",
+  )
+  .unwrap();
+
+  // Half even: 0.0000 + 0.0000 + 0.0002, and 1234 yen
+  toypayments().arg("--config").arg(&config).arg(&path).assert().success().stdout(
+    "client,currency,available,held,total,locked\n\
+     1,JPY,1234,0,1234,false\n\
+     1,USD,0.0002,0.0000,0.0002,false\n",
+  );
+}
+
+// =============================================================================
+// WITHDRAWAL LIMIT TESTS
+// =============================================================================
+
+#[test]
+fn test_withdrawal_limits_from_file() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,1000.0,2024-01-01T08:00:00Z
+deposit,2,2,1000.0,2024-01-01T08:00:00Z
+withdrawal,1,3,150.0,2024-01-01T09:00:00Z
+withdrawal,2,4,150.0,2024-01-01T09:00:00Z
+withdrawal,2,5,100.0,2024-01-01T10:00:00Z
+withdrawal,2,6,100.0,2024-01-01T11:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  fs::write(
+    dir.path().join("limits.toml"),
+    "[default]\nmax_withdrawal = \"100\"\n\n\
+     [tiers.gold]\nmax_withdrawal = \"500\"\nmax_daily_outflow = \"300\"\n\n\
+     [clients.2]\ntier = \"gold\"\n",
+  )
+  .unwrap();
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "limits = \"limits.toml\"\n").unwrap();
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg(&config)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "client,available,held,total,locked\n\
+       1,1000.0000,0.0000,1000.0000,false\n\
+       2,750.0000,0.0000,750.0000,false\n",
+    );
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  let errors: Vec<_> = errors.lines().collect();
+  assert_eq!(errors.len(), 2);
+  assert!(errors[0].starts_with("tx 3 (client 1): withdrawal of 150.0 is over the limit of 100"));
+  assert!(
+    errors[1].starts_with("tx 6 (client 2): daily outflow of 350.0 is over the limit of 300")
+  );
+}
+
+#[test]
+fn test_invalid_limits_file_error() {
+  let (dir, path) = create_test_csv("type,client,tx,amount\n");
+  fs::write(dir.path().join("limits.toml"), "[clients.1]\ntier = \"platinum\"\n").unwrap();
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "limits = \"limits.toml\"\n").unwrap();
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg(&config)
+    .arg(&path)
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("Failed to load limits"));
+}
+
+// =============================================================================
+// CREDIT LINE TESTS
+// =============================================================================
+
+#[test]
+fn test_credit_line_output_column() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,50.0
+withdrawal,1,2,80.0
+deposit,2,3,10.0
+withdrawal,2,4,20.0
+";
+  let (dir, path) = create_test_csv(csv);
+  fs::write(dir.path().join("limits.toml"), "[clients.1]\ncredit = { USD = \"100\" }\n").unwrap();
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "limits = \"limits.toml\"\n").unwrap();
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg(&config)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "client,available,held,total,locked,credit_remaining\n\
+       1,-30.0000,0.0000,-30.0000,false,70.0000\n\
+       2,10.0000,0.0000,10.0000,false,0.0000\n",
+    );
+}
+
+// =============================================================================
+// FEE TESTS
+// =============================================================================
+
+#[test]
+fn test_fees_in_statements_and_summary() {
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,100.0
+withdrawal,1,2,10.0
+withdrawal,1,3,20.0
+deposit,2,4,50.0
+deposit,2,5,50.0
+dispute,2,4,
+chargeback,2,4,
+";
+  let (dir, path) = create_test_csv(csv);
+  let config = dir.path().join("engine.toml");
+  fs::write(
+    &config,
+    "[fees.withdrawal]\nfixed = \"0.25\"\npercent = \"1\"\n\n[fees.chargeback]\nfixed = \"15\"\n",
+  )
+  .unwrap();
+  let statements = dir.path().join("statements.csv");
+  let summary = dir.path().join("fees.csv");
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg(&config)
+    .arg("--statements")
+    .arg(&statements)
+    .arg("--fee-summary")
+    .arg(&summary)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "client,available,held,total,locked\n\
+       1,69.2000,0.0000,69.2000,false\n\
+       2,35.0000,0.0000,35.0000,true\n",
+    );
+
+  let statements = fs::read_to_string(&statements).unwrap();
+  let lines: Vec<_> = statements.lines().collect();
+  assert_eq!(
+    lines[2..4],
+    [
+      "1,2,withdrawal,10.0000,USD,,90.0000,0.0000,90.0000",
+      "1,2,withdrawal_fee,0.3500,USD,,89.6500,0.0000,89.6500",
+    ]
+  );
+  assert_eq!(
+    lines[lines.len() - 2..],
+    [
+      "2,4,chargeback,,USD,,50.0000,0.0000,50.0000",
+      "2,4,chargeback_fee,15.0000,USD,,35.0000,0.0000,35.0000",
+    ]
+  );
+
+  let summary = fs::read_to_string(&summary).unwrap();
+  assert_eq!(
+    summary.lines().collect::<Vec<_>>(),
+    vec![
+      "type,currency,count,total",
+      "chargeback_fee,USD,1,15.0000",
+      "withdrawal_fee,USD,2,0.8000"
+    ]
+  );
+}
+
+// =============================================================================
+// INTEREST TESTS
+// =============================================================================
+
+#[test]
+fn test_accrue_interest_control_record() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,1000.0,2024-01-01T00:00:00Z
+deposit,2,2,1000.0,2024-01-01T00:00:00Z
+accrue_interest,1,3,,2024-01-31T00:00:00Z
+accrue_interest,2,4,,
+dispute,1,3,,2024-02-01T00:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  let config = dir.path().join("engine.toml");
+  fs::write(&config, "[interest]\nannual_percent = \"3.6\"\nday_count = \"actual_360\"\n").unwrap();
+  let statements = dir.path().join("statements.csv");
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg(&config)
+    .arg("--statements")
+    .arg(&statements)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "client,available,held,total,locked\n\
+       1,1003.0000,0.0000,1003.0000,false\n\
+       2,1000.0000,0.0000,1000.0000,false\n",
+    );
+
+  let statements = fs::read_to_string(&statements).unwrap();
+  assert!(statements.lines().any(|line| line
+    == "1,3,accrue_interest,3.0000,USD,2024-01-31T00:00:00+00:00,1003.0000,0.0000,1003.0000"));
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  let errors: Vec<_> = errors.lines().collect();
+  assert_eq!(errors[0], "tx 4: accrue_interest requires a timestamp");
+  assert!(errors[1].starts_with("tx 3: accrue_interest transactions cannot be disputed"));
+}
+
+// =============================================================================
+// SCREENING RULE TESTS
+// =============================================================================
+
+#[test]
+fn test_screening_rules_flag_and_block() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,500.0,2024-01-01T10:00:00Z
+withdrawal,1,2,480.0,2024-01-01T10:20:00Z
+deposit,2,3,100.0,2024-01-01T11:00:00Z
+dispute,2,3,,2024-01-01T12:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  fs::write(dir.path().join("engine.toml"), "rules = \"rules.toml\"\n").unwrap();
+  fs::write(
+    dir.path().join("rules.toml"),
+    "[[rapid_cycle]]\nname = \"cycled\"\naction = \"flag\"\nwithin_minutes = 60\n\
+     min_percent = \"90\"\n\n[[dispute_count]]\nname = \"no disputes\"\naction = \"block\"\nmax = 0\n",
+  )
+  .unwrap();
+  let alerts = dir.path().join("alerts.csv");
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg("engine.toml")
+    .arg("--alerts")
+    .arg(&alerts)
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "client,available,held,total,locked\n\
+       1,20.0000,0.0000,20.0000,false\n\
+       2,100.0000,0.0000,100.0000,false\n",
+    );
+
+  let alerts = fs::read_to_string(&alerts).unwrap();
+  assert_eq!(
+    alerts.lines().collect::<Vec<_>>(),
+    [
+      "tx,client,type,rule,action,timestamp",
+      "2,1,withdrawal,cycled,flag,2024-01-01T10:20:00+00:00",
+      "3,2,dispute,no disputes,block,2024-01-01T12:00:00+00:00",
+    ]
+  );
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert!(errors.contains("tx 3: blocked by screening rule 'no disputes'"), "{}", errors);
+}
+
+#[test]
+fn test_invalid_rules_file_error() {
+  let (dir, path) = create_test_csv("type,client,tx,amount\ndeposit,1,1,1.0\n");
+  fs::write(dir.path().join("engine.toml"), "rules = \"rules.toml\"\n").unwrap();
+  fs::write(dir.path().join("rules.toml"), "[[velocity]]\nname = \"x\"\n").unwrap();
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg("engine.toml")
+    .arg(&path)
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("Failed to load rules"));
+}
+
+// =============================================================================
+// DRY RUN TESTS
+// =============================================================================
+
+const DRY_RUN_CSV: &str = "\
+type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,1,50.0
+withdrawal,1,2,500.0
+refund,1,3,1.0
+deposit,2,4,abc
+deposit,2,5,25.0
+deposit,2,6,25.0
+deposit,2,7,25.0
+";
+
+#[test]
+fn test_dry_run_reports_without_accounts() {
+  let (dir, path) = create_test_csv(DRY_RUN_CSV);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--dry-run")
+    .arg("--max-error-rate")
+    .arg("50")
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(
+      "category,name,value\n\
+       total,rows,8\n\
+       total,applied,4\n\
+       total,skipped,0\n\
+       total,failed,4\n\
+       total,error_percent,50.00\n\
+       parse,malformed_decimal,1\n\
+       parse,unknown_type,1\n\
+       engine,DuplicateTransaction,1\n\
+       engine,InsufficientFunds,1\n",
+    );
+
+  let errors = fs::read_to_string(dir.path().join("errors.log")).unwrap();
+  assert_eq!(errors.lines().count(), 4);
+}
+
+#[test]
+fn test_dry_run_fails_above_threshold() {
+  let (dir, path) = create_test_csv(DRY_RUN_CSV);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--dry-run")
+    .arg("--max-error-rate")
+    .arg("10")
+    .arg(&path)
+    .assert()
+    .failure()
+    .stdout(predicate::str::contains("total,error_percent,50.00"))
+    .stderr(predicate::str::contains("error rate of 50.00% is above the allowed 10%"));
+}
+
+#[test]
+fn test_dry_run_of_clean_file() {
+  let (dir, path) = create_test_csv("type,client,tx,amount\ndeposit,1,1,1.0\n");
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--dry-run")
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("total,failed,0\n"))
+    .stdout(predicate::str::contains("client,").not());
+}
+
+// =============================================================================
+// RUN SUMMARY TESTS
+// =============================================================================
+
+const SUMMARY_CSV: &str = "\
+type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,2,40.0
+withdrawal,1,3,500.0
+dispute,1,2,
+chargeback,1,2,
+deposit,2,4,oops
+";
+
+#[test]
+fn test_summary_to_stderr() {
+  let (dir, path) = create_test_csv(SUMMARY_CSV);
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--summary")
+    .arg("-")
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::starts_with("client,available,held,total,locked\n"))
+    .stderr(predicate::str::contains("rows: 6 (4 processed, 1 rejected, 0 skipped, 1 unparsable)"))
+    .stderr(predicate::str::contains("locked accounts: 1"))
+    .stderr(predicate::str::contains("stored transactions: 2"))
+    .stderr(predicate::str::contains("  InsufficientFunds            1\n"));
+}
+
+#[test]
+fn test_summary_as_json_file() {
+  let (dir, path) = create_test_csv(SUMMARY_CSV);
+  let summary = dir.path().join("summary.json");
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--summary")
+    .arg(&summary)
+    .arg("--summary-format")
+    .arg("json")
+    .arg(&path)
+    .assert()
+    .success();
+
+  let summary: serde_json::Value =
+    serde_json::from_str(&fs::read_to_string(&summary).unwrap()).unwrap();
+  assert_eq!(summary["processed"]["deposit"], 2);
+  assert_eq!(summary["rejected"]["withdrawal"], 1);
+  assert_eq!(summary["errors"]["InsufficientFunds"], 1);
+  assert_eq!(summary["unparsable"], 1);
+  assert_eq!(summary["totals"]["USD"]["deposited"], "140.0");
+  assert_eq!(summary["totals"]["USD"]["charged_back"], "40.0");
+  assert_eq!(summary["locked_accounts"], 1);
+}
+
+// =============================================================================
+// METRICS TESTS
+// =============================================================================
+
+#[test]
+fn test_metrics_scraped_while_reading_stdin() {
+  use std::io::Write;
+  use std::process::Stdio;
+
+  let dir = TempDir::new().unwrap();
+  let addr = free_addr();
+  let mut child = toypayments_process()
+    .current_dir(dir.path())
+    .arg("--metrics")
+    .arg(&addr)
+    .arg("-")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+
+  let mut stdin = child.stdin.take().unwrap();
+  writeln!(stdin, "type,client,tx,amount").unwrap();
+  writeln!(stdin, "deposit,1,1,100.0\ndeposit,2,2,40.0\nwithdrawal,2,3,90.0\ndispute,1,1,")
+    .unwrap();
+  stdin.flush().unwrap();
+
+  let metrics = eventually(|| {
+    let (status, body) = http(&addr, "GET", "/metrics", "").ok()?;
+    (status.contains("200") && body.contains("toypayments_records_total{type=\"dispute\"} 1"))
+      .then_some(body)
+  });
+  for line in [
+    "toypayments_records_total{type=\"deposit\"} 2",
+    "toypayments_rejections_total{code=\"InsufficientFunds\"} 1",
+    "toypayments_accounts 2",
+    "toypayments_stored_transactions 2",
+    "toypayments_held{currency=\"USD\"} 100.0",
+    "toypayments_record_duration_seconds_count 4",
+  ] {
+    assert!(metrics.lines().any(|l| l == line), "missing {}\n{}", line, metrics);
+  }
+  let (status, _) = http(&addr, "GET", "/nope", "").unwrap();
+  assert!(status.contains("404"), "{}", status);
+
+  // Closing stdin ends the run as usual
+  drop(stdin);
+  let output = child.wait_with_output().unwrap();
+  assert!(output.status.success());
+  assert_eq!(
+    String::from_utf8(output.stdout).unwrap(),
+    "client,available,held,total,locked\n\
+     1,0.0000,100.0000,100.0000,false\n\
+     2,40.0000,0.0000,40.0000,false\n"
+  );
+}
+
+// ============================================================================
+// SERVICE TESTS
+// ============================================================================
+
+#[test]
+fn test_service_applies_posted_records() {
+  use std::process::Stdio;
+
+  let dir = TempDir::new().unwrap();
+  let addr = free_addr();
+  let mut child = toypayments_process()
+    .current_dir(dir.path())
+    .arg("--serve")
+    .arg(&addr)
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+
+  let post = |body: &str| http(&addr, "POST", "/transactions", body);
+  let (status, body) =
+    eventually(|| post(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "100.0"}"#).ok());
+  assert!(status.contains("200"), "{} {}", status, body);
+  assert!(body.contains(r#""outcome":"applied""#), "{}", body);
+
+  // A batch is applied in full or not at all
+  let (status, body) = post(
+    r#"[{"type": "deposit", "client": 2, "tx": 2, "amount": "40"},
+        {"type": "withdrawal", "client": 2, "tx": 3, "amount": "90"}]"#,
+  )
+  .unwrap();
+  assert!(status.contains("422"), "{}", status);
+  assert!(body.contains(r#""code":"InsufficientFunds""#), "{}", body);
+  let (status, _) = http(&addr, "GET", "/accounts/2", "").unwrap();
+  assert!(status.contains("404"), "{}", status);
+
+  let (status, body) = post(r#"{"type": "deposit", "client": 2, "tx": 1, "amount": "5"}"#).unwrap();
+  assert!(status.contains("409"), "{}", status);
+  assert!(body.contains(r#""code":"DuplicateTransaction""#), "{}", body);
+  let (status, body) = post("not json").unwrap();
+  assert!(status.contains("400"), "{}", status);
+  assert!(body.contains("InvalidJson"), "{}", body);
+  // One byte over the 1 MiB limit
+  let (status, body) = post(&" ".repeat((1 << 20) + 1)).unwrap();
+  assert!(status.contains("413"), "{}", status);
+  assert!(body.contains("PayloadTooLarge"), "{}", body);
+
+  post(r#"{"type": "dispute", "client": 1, "tx": 1}"#).unwrap();
+  let (_, body) = http(&addr, "GET", "/transactions/1", "").unwrap();
+  assert!(body.contains(r#""state":"disputed""#), "{}", body);
+  let (_, body) = http(&addr, "GET", "/accounts", "").unwrap();
+  assert!(body.contains(r#""held":"100.0000""#), "{}", body);
+  assert!(body.contains(r#""locked":false"#), "{}", body);
+
+  child.kill().unwrap();
+  child.wait().unwrap();
+}
+
+#[test]
+fn test_serve_rejects_input_file() {
+  toypayments()
+    .arg("--serve")
+    .arg("127.0.0.1:0")
+    .arg("transactions.csv")
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("--serve and --listen take no input file"));
+}
+
+// ============================================================================
+// TCP INGESTION TESTS
+// ============================================================================
+
+#[test]
+fn test_tcp_lines_acknowledged_across_connections() {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpStream;
+  use std::process::Stdio;
+
+  let dir = TempDir::new().unwrap();
+  let addr = free_addr();
+  let mut child = toypayments_process()
+    .current_dir(dir.path())
+    .arg("--listen")
+    .arg(&addr)
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+
+  let connect = || {
+    let stream = eventually(|| TcpStream::connect(&addr).ok());
+    (BufReader::new(stream.try_clone().unwrap()), stream)
+  };
+  let send = |(reader, stream): &mut (BufReader<TcpStream>, TcpStream), line: &str| {
+    writeln!(stream, "{}", line).unwrap();
+    let mut ack = String::new();
+    reader.read_line(&mut ack).unwrap();
+    ack.trim_end().to_string()
+  };
+
+  let mut first = connect();
+  let mut second = connect();
+  assert_eq!(send(&mut first, "deposit,1,1,100.0"), "OK");
+  // A header is only taken as the first line of a connection
+  assert_eq!(send(&mut second, "type,client,tx,amount,currency"), "OK");
+  // Tx ids are shared by every connection
+  assert_eq!(
+    send(&mut second, "deposit,2,1,50.0,EUR"),
+    "ERR DuplicateTransaction tx 1: duplicate transaction ID"
+  );
+  assert_eq!(send(&mut second, "deposit,2,2,50.0,EUR"), "OK");
+  assert!(send(&mut first, "withdrawal,1,3,500.0").starts_with("ERR InsufficientFunds"));
+  assert!(send(&mut first, "deposit,1,x,1.0").starts_with("ERR InvalidRecord"));
+  assert_eq!(send(&mut first, "dispute,1,1,"), "OK");
+  assert_eq!(send(&mut second, "chargeback,1,1,"), "OK");
+  assert_eq!(
+    send(&mut first, "deposit,1,4,1.0"),
+    "ERR AccountLocked tx 4 (client 1): account is locked"
+  );
+
+  // Rows would be applied one at a time, so a batch column is refused and the
+  // connection closed before any row is taken
+  let mut batched = connect();
+  assert_eq!(
+    send(&mut batched, "type,client,tx,amount,batch"),
+    "ERR UnsupportedColumn batches are not supported over TCP"
+  );
+  let mut rest = String::new();
+  assert_eq!(batched.0.read_line(&mut rest).unwrap(), 0);
+
+  child.kill().unwrap();
+  child.wait().unwrap();
+}
+
+// ============================================================================
+// EVENT STREAM TESTS
+// ============================================================================
+
+#[test]
+fn test_events_written_in_applied_order() {
+  let csv = "\
+type,client,tx,amount,batch
+deposit,1,1,100.0,
+deposit,1,5,50.0,
+withdrawal,1,2,30.0,
+deposit,2,3,10.0,7
+withdrawal,2,4,50.0,7
+dispute,1,1,,
+chargeback,1,1,,
+";
+  let (dir, path) = create_test_csv(csv);
+  let events = dir.path().join("events.ndjson");
+
+  toypayments().current_dir(dir.path()).arg("--events").arg(&events).arg(&path).assert().success();
+
+  let events: Vec<serde_json::Value> = fs::read_to_string(&events)
+    .unwrap()
+    .lines()
+    .map(|line| serde_json::from_str(line).unwrap())
+    .collect();
+  let kinds: Vec<&str> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+  // The rolled back batch left nothing behind
+  assert_eq!(kinds, ["deposited", "deposited", "withdrew", "held", "charged_back", "locked"]);
+  assert_eq!(events[5]["seq"], 6);
+  assert_eq!(events[2]["amount"], "30.0000");
+  assert_eq!(events[2]["before"]["balances"]["USD"]["available"], "150.0000");
+  assert_eq!(events[2]["after"]["balances"]["USD"]["available"], "120.0000");
+  assert_eq!(events[3]["after"]["balances"]["USD"]["held"], "100.0000");
+  assert_eq!(events[4]["after"]["balances"]["USD"]["total"], "20.0000");
+  assert_eq!(events[4]["after"]["locked"], false);
+  assert_eq!(events[5]["after"]["locked"], true);
+}
+
+// ============================================================================
+// NOTIFICATION TESTS
+// ============================================================================
+
+#[test]
+fn test_notifications_delivered_with_retry_and_dead_letter() {
+  // Stands in for the risk team's webhook, failing the first attempt
+  let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/hooks", server.server_addr().to_ip().unwrap());
+  let hook = std::thread::spawn(move || {
+    let mut bodies = Vec::new();
+    for attempt in 0..4 {
+      let mut request = server.recv().unwrap();
+      let mut body = String::new();
+      request.as_reader().read_to_string(&mut body).unwrap();
+      let status = if attempt == 0 { 500 } else { 200 };
+      if status == 200 {
+        bodies.push(body);
+      }
+      request.respond(tiny_http::Response::empty(status)).unwrap();
+    }
+    bodies
+  });
+
+  let csv = "\
+type,client,tx,amount
+deposit,1,1,5000.0
+deposit,2,2,10.0
+dispute,2,2,
+dispute,1,1,
+chargeback,1,1,
+";
+  let (dir, path) = create_test_csv(csv);
+  fs::write(dir.path().join("engine.toml"), "notifications = \"notify.toml\"\n").unwrap();
+  fs::write(
+    dir.path().join("notify.toml"),
+    format!(
+      "large_dispute = \"1000\"\nretries = 1\nretry_delay_ms = 10\ndead_letter = \"dead.ndjson\"\n\
+       [[sink]]\ntype = \"http\"\nurl = \"{}\"\n\
+       [[sink]]\ntype = \"file\"\npath = \"risk.ndjson\"\n\
+       [[sink]]\ntype = \"command\"\nprogram = \"false\"\n",
+      url
+    ),
+  )
+  .unwrap();
+
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg("engine.toml")
+    .arg(&path)
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("1,0.0000,0.0000,0.0000,true"));
+
+  let bodies = hook.join().unwrap();
+  let triggers = |lines: Vec<String>| -> Vec<String> {
+    lines
+      .iter()
+      .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+      .map(|value| value["trigger"].as_str().unwrap().to_string())
+      .collect()
+  };
+  // The small dispute is not worth a notification
+  let expected = ["large_dispute", "chargeback", "account_locked"];
+  assert_eq!(triggers(bodies), expected);
+  let file = fs::read_to_string(dir.path().join("risk.ndjson")).unwrap();
+  assert_eq!(triggers(file.lines().map(String::from).collect()), expected);
+
+  // The command always fails, every notification ends up in the dead letter file
+  let dead = fs::read_to_string(dir.path().join("dead.ndjson")).unwrap();
+  let dead: Vec<serde_json::Value> =
+    dead.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+  assert_eq!(dead.len(), 3);
+  assert_eq!(dead[0]["sink"], "command false");
+  assert_eq!(dead[0]["notification"]["trigger"], "large_dispute");
+  assert_eq!(dead[0]["notification"]["held"], "5000.0000");
+
+  // A dry run notifies nobody
+  fs::remove_file(dir.path().join("risk.ndjson")).unwrap();
+  toypayments()
+    .current_dir(dir.path())
+    .arg("--config")
+    .arg("engine.toml")
+    .arg("--dry-run")
+    .arg(&path)
+    .assert()
+    .success();
+  assert!(!dir.path().join("risk.ndjson").exists());
+}
+
+// ============================================================================
+// QUERY TESTS
+// ============================================================================
+
+#[test]
+fn test_query_saved_state() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,42,1,100.0,2024-03-01T09:00:00Z
+deposit,42,2,25.0,2024-03-01T10:00:00Z
+dispute,42,1,,2024-03-02T09:00:00Z
+chargeback,42,1,,2024-03-05T09:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  let db = dir.path().join("state.db");
+  cargo_bin_cmd!("toypayments")
+    .current_dir(dir.path())
+    .arg("--storage")
+    .arg(format!("sqlite:{}", db.display()))
+    .arg(&path)
+    .assert()
+    .success();
+
+  cargo_bin_cmd!("toypayments").arg("query").arg(&db).arg("42").assert().success().stdout(
+    "client,currency,available,held,total,locked\n\
+       42,USD,25.0000,0.0000,25.0000,true\n\
+       \n\
+       tx,type,currency,amount,state,timestamp,history\n\
+       1,deposit,USD,100.0000,charged_back,2024-03-01T09:00:00+00:00,\
+       dispute@2024-03-02T09:00:00+00:00 chargeback@2024-03-05T09:00:00+00:00\n\
+       2,deposit,USD,25.0000,normal,2024-03-01T10:00:00+00:00,\n\
+       \n\
+       client 42 is locked by the chargeback of tx 1 at 2024-03-05T09:00:00+00:00 \
+       (deposit of 100.0000 USD, now charged_back)\n",
+  );
+
+  cargo_bin_cmd!("toypayments")
+    .arg("query")
+    .arg(&db)
+    .arg("7")
+    .arg("lock")
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("client 7 not found"));
+  cargo_bin_cmd!("toypayments")
+    .arg("query")
+    .arg(dir.path().join("missing.db"))
+    .arg("42")
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("No saved state"));
+}
+
+#[test]
+fn test_saved_state_is_only_read() {
+  let (dir, path) = create_test_csv("type,client,tx,amount\ndeposit,1,1,10.0\n");
+  let db = dir.path().join("state.db");
+  cargo_bin_cmd!("toypayments")
+    .arg("--storage")
+    .arg(format!("sqlite:{}", db.display()))
+    .arg(&path)
+    .assert()
+    .success();
+  let saved = fs::read(&db).unwrap();
+
+  cargo_bin_cmd!("toypayments").arg("query").arg(&db).arg("1").assert().success();
+  cargo_bin_cmd!("toypayments").arg("diff").arg(&db).arg(&db).assert().success();
+  let bank = dir.path().join("bank.csv");
+  fs::write(&bank, "client,total\n1,10\n").unwrap();
+  cargo_bin_cmd!("toypayments").arg("reconcile").arg(&db).arg(&bank).assert().success();
+  assert_eq!(fs::read(&db).unwrap(), saved);
+}
+
+#[test]
+fn test_replay_record_log() {
+  let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,100.0,2024-03-01T09:00:00Z
+withdrawal,1,2,500.0,2024-03-01T10:00:00Z
+deposit,2,3,20.0,2024-03-02T09:00:00Z
+dispute,1,1,,2024-03-03T09:00:00Z
+chargeback,1,1,,2024-03-04T09:00:00Z
+";
+  let (dir, path) = create_test_csv(csv);
+  let log = dir.path().join("records.log");
+  let output = toypayments().arg("--record-log").arg(&log).arg(&path).assert().success();
+  let end = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+
+  // The rejected withdrawal is logged too, so record numbers follow the input
+  let logged = fs::read_to_string(&log).unwrap();
+  assert!(logged.starts_with("seq,outcome,type,client,tx,amount,timestamp"));
+  assert!(logged.contains("\n2,rejected,withdrawal,1,2,500"));
+
+  cargo_bin_cmd!("toypayments").arg("replay").arg(&log).assert().success().stdout(end);
+  cargo_bin_cmd!("toypayments")
+    .arg("replay")
+    .arg(&log)
+    .arg("--at-record")
+    .arg("3")
+    .assert()
+    .success()
+    .stdout("client,available,held,total,locked\n1,100.0000,0.0000,100.0000,false\n2,20.0000,0.0000,20.0000,false\n");
+  cargo_bin_cmd!("toypayments")
+    .arg("replay")
+    .arg(&log)
+    .arg("--at")
+    .arg("2024-03-03T12:00:00Z")
+    .assert()
+    .success()
+    .stdout("client,available,held,total,locked\n1,0.0000,100.0000,100.0000,false\n2,20.0000,0.0000,20.0000,false\n");
+}
+
+#[test]
+fn test_record_log_over_two_runs() {
+  let (dir, path) = create_test_csv("type,client,tx,amount\ndeposit,1,1,10.0\n");
+  let log = dir.path().join("records.log");
+  let replay = || cargo_bin_cmd!("toypayments").arg("replay").arg(&log).assert().success();
+
+  // Each run starts from empty accounts, so the log only holds the last one
+  for _ in 0..2 {
+    toypayments().arg("--record-log").arg(&log).arg(&path).assert().success();
+  }
+  assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 2);
+  replay().stdout("client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n");
+
+  // A saved state carries over, and so does the log: the second run's deposit is a duplicate
+  let state = format!("sqlite:{}", dir.path().join("state.db").display());
+  fs::remove_file(&log).unwrap();
+  for _ in 0..2 {
+    cargo_bin_cmd!("toypayments")
+      .arg("--storage")
+      .arg(&state)
+      .arg("--record-log")
+      .arg(&log)
+      .arg(&path)
+      .assert()
+      .success();
+  }
+  let logged = fs::read_to_string(&log).unwrap();
+  assert_eq!(logged.lines().count(), 3);
+  assert!(logged.contains("\n2,rejected,deposit,1,1,10.0"));
+  replay().stdout("client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n");
+}
+
+#[test]
+fn test_diff_account_states() {
+  let (dir, path) = create_test_csv(
+    "type,client,tx,amount\ndeposit,1,1,100.0\ndeposit,2,2,20.0\nwithdrawal,2,3,5.0\n",
+  );
+  let before = dir.path().join("before.csv");
+  let output = toypayments().arg(&path).assert().success();
+  fs::write(&before, &output.get_output().stdout).unwrap();
+
+  // The changed run is saved as a state instead of an output
+  let changed = dir.path().join("changed.csv");
+  fs::write(&changed, "type,client,tx,amount\ndeposit,1,1,100.0\ndeposit,2,2,20.005\n").unwrap();
+  let db = dir.path().join("after.db");
+  cargo_bin_cmd!("toypayments")
+    .arg("--storage")
+    .arg(format!("sqlite:{}", db.display()))
+    .arg(&changed)
+    .assert()
+    .success();
+
+  let report = "client,currency,status,available_diff,held_diff,total_diff,locked_before,locked_after\n\
+                2,USD,changed,5.0050,0.0000,5.0050,false,false\n\
+                \n\
+                currency,rows,changed,available_diff,held_diff,total_diff,locked,unlocked\n\
+                USD,2,1,5.0050,0.0000,5.0050,0,0\n";
+  cargo_bin_cmd!("toypayments")
+    .arg("diff")
+    .arg(&before)
+    .arg(&db)
+    .assert()
+    .failure()
+    .stdout(report)
+    .stderr(predicate::str::contains("1 of 2 rows differ by more than 0"));
+  cargo_bin_cmd!("toypayments")
+    .arg("diff")
+    .arg("--tolerance")
+    .arg("10")
+    .arg(&before)
+    .arg(&db)
+    .assert()
+    .success()
+    .stdout(report);
+  cargo_bin_cmd!("toypayments").arg("diff").arg(&before).arg(&before).assert().success();
+}
+
+#[test]
+fn test_reconcile_against_bank_balances() {
+  let (dir, path) = create_test_csv(
+    "type,client,tx,amount\ndeposit,1,1,100.0\ndeposit,2,2,20.0\ndispute,2,2,\nchargeback,2,2,\n",
+  );
+  let db = dir.path().join("state.db");
+  cargo_bin_cmd!("toypayments")
+    .arg("--storage")
+    .arg(format!("sqlite:{}", db.display()))
+    .arg(&path)
+    .assert()
+    .success();
+
+  let bank = dir.path().join("bank.csv");
+  fs::write(&bank, "client,total,locked\n1,100.00,false\n2,0,true\n").unwrap();
+  cargo_bin_cmd!("toypayments").arg("reconcile").arg(&db).arg(&bank).assert().success();
+
+  fs::write(&bank, "client,total,locked\n1,99.50,false\n2,0,false\n3,5,false\n").unwrap();
+  cargo_bin_cmd!("toypayments")
+    .arg("reconcile")
+    .arg(&db)
+    .arg(&bank)
+    .assert()
+    .failure()
+    .stdout(
+      "client,currency,break,field,expected,actual,difference\n\
+       1,USD,amount_mismatch,total,99.5000,100.0000,0.5000\n\
+       2,USD,lock_mismatch,locked,false,true,\n\
+       3,USD,missing_client,total,5.0000,,-5.0000\n\
+       \n\
+       currency,expected_rows,engine_rows,matched,missing_client,amount_mismatch,\
+       lock_mismatch,expected_total,engine_total,difference\n\
+       USD,3,2,0,1,1,1,104.5000,100.0000,-4.5000\n",
+    )
+    .stderr(predicate::str::contains("reconciliation found 3 breaks"));
+  cargo_bin_cmd!("toypayments")
+    .arg("reconcile")
+    .arg("--tolerance")
+    .arg("1")
+    .arg(&db)
+    .arg(&bank)
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("reconciliation found 2 breaks"));
+}
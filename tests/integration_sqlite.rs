@@ -0,0 +1,5 @@
+//! The integration suite again, with every run using `--storage sqlite`.
+//! See `toypayments()` in integration.rs
+
+#[path = "integration.rs"]
+mod integration;